@@ -0,0 +1,468 @@
+//! Colour management with LittleCMS: embedded profiles of decoded images,
+//! the display profile, soft proofing and the colour spaces photos are
+//! exported in.
+//!
+//! Edits are rendered in linear sRGB, the working space. Images with an
+//! embedded profile other than sRGB are converted into it as floats, so
+//! colours outside sRGB survive as values below 0 or above 1 until they
+//! are converted for display or output.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Context, Result};
+use image::{DynamicImage, Rgb32FImage, RgbaImage};
+use lcms2::{
+    CIExyY, CIExyYTRIPLE, ColorSpaceSignature, DisallowCache, Flags, GlobalContext, InfoType, Intent, Locale,
+    PixelFormat, Profile, Tag, TagSignature, ToneCurve, Transform, MLU,
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::config;
+use crate::photo;
+
+type FloatTransform = Transform<[f32; 3], [f32; 3]>;
+
+const D65: CIExyY = CIExyY { x: 0.3127, y: 0.3290, Y: 1.0 };
+
+fn primaries(red: (f64, f64), green: (f64, f64), blue: (f64, f64)) -> CIExyYTRIPLE {
+    let xy = |(x, y)| CIExyY { x, y, Y: 1.0 };
+    CIExyYTRIPLE { Red: xy(red), Green: xy(green), Blue: xy(blue) }
+}
+
+fn srgb_primaries() -> CIExyYTRIPLE {
+    primaries((0.64, 0.33), (0.30, 0.60), (0.15, 0.06))
+}
+
+/// An RGB matrix/shaper profile with a description, as written into
+/// exported files.
+fn rgb_profile(description: &str, primaries: &CIExyYTRIPLE, curve: &ToneCurve) -> Result<Profile> {
+    let mut profile = Profile::new_rgb(&D65, primaries, &[curve, curve, curve])
+        .map_err(|e| anyhow!("Failed to build {} profile: {}", description, e))?;
+    let mut text = MLU::new(1);
+    text.set_text_ascii(description, Locale::none());
+    profile.write_tag(TagSignature::ProfileDescriptionTag, Tag::MLU(&text));
+    Ok(profile)
+}
+
+/// The working space: sRGB primaries, linear.
+pub fn working_profile() -> Profile {
+    rgb_profile("Linear sRGB", &srgb_primaries(), &ToneCurve::new(1.0)).expect("linear sRGB is a valid profile")
+}
+
+/// Parses an ICC profile, accepting only RGB ones.
+pub fn parse_profile(icc: &[u8]) -> Result<Profile> {
+    let profile = Profile::new_icc(icc).map_err(|e| anyhow!("Invalid ICC profile: {}", e))?;
+    if profile.color_space() != ColorSpaceSignature::RgbData {
+        bail!("Not an RGB profile ({:?})", profile.color_space());
+    }
+    Ok(profile)
+}
+
+/// The profile's description, for the UI.
+pub fn profile_name(profile: &Profile) -> String {
+    profile
+        .info(InfoType::Description, Locale::none())
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "Unnamed profile".to_string())
+}
+
+fn float_transform(from: &Profile, to: &Profile, intent: Intent) -> Result<FloatTransform> {
+    Transform::new(from, PixelFormat::RGB_FLT, to, PixelFormat::RGB_FLT, intent)
+        .map_err(|e| anyhow!("Failed to create colour transform: {}", e))
+}
+
+/// Whether `profile` renders the same as sRGB, as most embedded profiles
+/// do. Those images are left as they are.
+fn is_srgb(profile: &Profile) -> bool {
+    let Ok(transform) = float_transform(profile, &Profile::new_srgb(), Intent::RelativeColorimetric) else {
+        return false;
+    };
+    let samples = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [0.5, 0.5, 0.5], [0.9, 0.6, 0.2]];
+    let mut converted = samples;
+    transform.transform_in_place(&mut converted);
+    samples.iter().flatten().zip(converted.iter().flatten()).all(|(a, b)| (a - b).abs() < 1.0 / 512.0)
+}
+
+/// Converts a decoded image with the embedded profile `icc` into the
+/// working space. `None` when the profile is sRGB and nothing changes.
+pub fn to_working(image: &DynamicImage, icc: &[u8]) -> Result<Option<Rgb32FImage>> {
+    let profile = parse_profile(icc)?;
+    if is_srgb(&profile) {
+        return Ok(None);
+    }
+    debug!("Converting from embedded profile {}", profile_name(&profile));
+    let transform = float_transform(&profile, &working_profile(), Intent::Perceptual)?;
+    let mut linear = image.to_rgb32f();
+    transform_image(&transform, &mut linear);
+    Ok(Some(linear))
+}
+
+fn transform_image(transform: &FloatTransform, image: &mut Rgb32FImage) {
+    let mut pixels: Vec<[f32; 3]> = image.pixels().map(|p| p.0).collect();
+    transform.transform_in_place(&mut pixels);
+    for (pixel, converted) in image.pixels_mut().zip(pixels) {
+        pixel.0 = converted;
+    }
+}
+
+/// Colour spaces photos can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    AdobeRgb,
+    DisplayP3,
+}
+
+impl ColorSpace {
+    pub const ALL: [ColorSpace; 3] = [ColorSpace::Srgb, ColorSpace::AdobeRgb, ColorSpace::DisplayP3];
+
+    pub fn profile(self) -> Profile {
+        let profile = match self {
+            ColorSpace::Srgb => return Profile::new_srgb(),
+            ColorSpace::AdobeRgb => rgb_profile(
+                "Adobe RGB (1998)",
+                &primaries((0.64, 0.33), (0.21, 0.71), (0.15, 0.06)),
+                &ToneCurve::new(563.0 / 256.0),
+            ),
+            // Display P3 uses the sRGB curve
+            ColorSpace::DisplayP3 => rgb_profile(
+                "Display P3",
+                &primaries((0.68, 0.32), (0.265, 0.69), (0.15, 0.06)),
+                &ToneCurve::new_parametric(4, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+                    .expect("valid sRGB curve"),
+            ),
+        };
+        profile.expect("built-in profiles are valid")
+    }
+
+    /// The profile as embedded in exported files.
+    pub fn icc(self) -> Vec<u8> {
+        self.profile().icc().expect("built-in profiles serialize")
+    }
+
+    /// Converts a decoded image into this space, encoded and ready to be
+    /// quantized: floats in 0-1 for anything that was linear or is
+    /// converted, the image itself when it already is sRGB.
+    pub fn encode(self, image: &DynamicImage) -> Result<DynamicImage> {
+        if self == ColorSpace::Srgb {
+            return Ok(photo::encode_srgb(image).into_owned());
+        }
+        let mut output = crate::develop::to_linear(image);
+        let transform = float_transform(&working_profile(), &self.profile(), Intent::Perceptual)?;
+        transform_image(&transform, &mut output);
+        Ok(DynamicImage::ImageRgb32F(output))
+    }
+}
+
+impl fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ColorSpace::Srgb => "sRGB",
+            ColorSpace::AdobeRgb => "Adobe RGB (1998)",
+            ColorSpace::DisplayP3 => "Display P3",
+        })
+    }
+}
+
+/// The monitor's profile, with the transform from the working space.
+struct Display {
+    name: String,
+    profile: Profile,
+    transform: FloatTransform,
+}
+
+/// Where the chosen display profile is kept.
+pub fn display_profile_path() -> Result<PathBuf> {
+    Ok(config::config_dir()?.join("display.icc"))
+}
+
+fn load_display() -> Result<Option<Display>> {
+    let path = display_profile_path()?;
+    let icc = match std::fs::read(&path) {
+        Ok(icc) => icc,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let profile = parse_profile(&icc)?;
+    let name = profile_name(&profile);
+    info!("Using display profile {}", name);
+    let transform = float_transform(&working_profile(), &profile, Intent::Perceptual)?;
+    Ok(Some(Display { name, profile, transform }))
+}
+
+/// `None` means the display is taken to be sRGB.
+static DISPLAY: Lazy<Mutex<Option<Display>>> = Lazy::new(|| {
+    Mutex::new(load_display().unwrap_or_else(|e| {
+        warn!("Ignoring display profile: {:#}", e);
+        None
+    }))
+});
+
+/// Name of the display profile in use, `None` for sRGB.
+pub fn display_profile_name() -> Option<String> {
+    DISPLAY.lock().as_ref().map(|display| display.name.clone())
+}
+
+/// Makes the ICC profile at `source` the display profile, or goes back to
+/// sRGB with `None`. The profile is copied into the config directory.
+pub fn set_display_profile(source: Option<&Path>) -> Result<()> {
+    let path = display_profile_path()?;
+    match source {
+        Some(source) => {
+            let icc = std::fs::read(source).with_context(|| format!("Failed to read {}", source.display()))?;
+            parse_profile(&icc)?;
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+            std::fs::write(&path, icc).with_context(|| format!("Failed to write {}", path.display()))?;
+        }
+        None => match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to delete {}", path.display()));
+            }
+            _ => {}
+        },
+    }
+    *DISPLAY.lock() = load_display()?;
+    Ok(())
+}
+
+/// Converts a rendered, linear image for the screen: through the display
+/// profile if one is set, else to sRGB.
+pub fn to_display(image: &Rgb32FImage) -> RgbaImage {
+    let display = DISPLAY.lock();
+    let Some(display) = display.as_ref() else {
+        return photo::encode_srgb(&DynamicImage::ImageRgb32F(image.clone())).to_rgba8();
+    };
+    let mut converted = image.clone();
+    transform_image(&display.transform, &mut converted);
+    DynamicImage::ImageRgb32F(converted).to_rgba8()
+}
+
+/// How colours are fitted into the gamut of a soft-proofed output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenderingIntent {
+    #[default]
+    Perceptual,
+    RelativeColorimetric,
+    Saturation,
+    /// Also simulates the paper's white.
+    AbsoluteColorimetric,
+}
+
+impl RenderingIntent {
+    pub const ALL: [RenderingIntent; 4] = [
+        RenderingIntent::Perceptual,
+        RenderingIntent::RelativeColorimetric,
+        RenderingIntent::Saturation,
+        RenderingIntent::AbsoluteColorimetric,
+    ];
+
+    fn lcms(self) -> Intent {
+        match self {
+            RenderingIntent::Perceptual => Intent::Perceptual,
+            RenderingIntent::RelativeColorimetric => Intent::RelativeColorimetric,
+            RenderingIntent::Saturation => Intent::Saturation,
+            RenderingIntent::AbsoluteColorimetric => Intent::AbsoluteColorimetric,
+        }
+    }
+}
+
+impl fmt::Display for RenderingIntent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RenderingIntent::Perceptual => "Perceptual",
+            RenderingIntent::RelativeColorimetric => "Relative colorimetric",
+            RenderingIntent::Saturation => "Saturation",
+            RenderingIntent::AbsoluteColorimetric => "Absolute colorimetric",
+        })
+    }
+}
+
+/// Soft-proofing options of the viewer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProofSettings {
+    pub enabled: bool,
+    pub intent: RenderingIntent,
+    pub gamut_warning: bool,
+}
+
+/// Out-of-gamut colours are painted over in this.
+const GAMUT_WARNING: [u8; 4] = [255, 0, 255, 255];
+
+/// Proofing works on 16-bit pixels, which LittleCMS clips to the proof
+/// profile's gamut where float transforms are left unbounded.
+type ProofTransform<T> = Transform<[u16; 3], T, GlobalContext, DisallowCache>;
+
+/// The space images are quantized in for proofing: Rec. 2020 primaries
+/// hold about every colour edits produce, and the gamma keeps precision
+/// in the shadows.
+fn proofing_space() -> Profile {
+    rgb_profile("Rec. 2020 (gamma 2.2)", &primaries((0.708, 0.292), (0.170, 0.797), (0.131, 0.046)), &ToneCurve::new(2.2))
+        .expect("Rec. 2020 is a valid profile")
+}
+
+/// Simulates an output profile, such as a printer and paper, on the
+/// display, marking what that output can't reproduce if asked to.
+pub struct SoftProof {
+    name: String,
+    to_proofing_space: Transform<[f32; 3], [f32; 3], GlobalContext, DisallowCache>,
+    proof: ProofTransform<[u8; 3]>,
+    /// Maps colours to themselves, and those outside the output's gamut
+    /// to `alarm`.
+    gamut_check: Option<(ProofTransform<[u16; 3]>, [u16; 3])>,
+}
+
+impl SoftProof {
+    /// Proofs the output profile `icc` (RGB, CMYK, ...) with `intent`,
+    /// for the current display profile.
+    pub fn new(icc: &[u8], intent: RenderingIntent, gamut_warning: bool) -> Result<SoftProof> {
+        let output = Profile::new_icc(icc).map_err(|e| anyhow!("Invalid ICC profile: {}", e))?;
+        let name = profile_name(&output);
+        let proofing_space = proofing_space();
+        let to_proofing_space = Transform::new_flags_context(
+            GlobalContext::new(),
+            &working_profile(),
+            PixelFormat::RGB_FLT,
+            &proofing_space,
+            PixelFormat::RGB_FLT,
+            Intent::RelativeColorimetric,
+            Flags::NO_CACHE,
+        )
+        .map_err(|e| anyhow!("Failed to create colour transform: {}", e))?;
+
+        let display = DISPLAY.lock();
+        let srgb;
+        let display_profile = match display.as_ref() {
+            Some(display) => &display.profile,
+            None => {
+                srgb = Profile::new_srgb();
+                &srgb
+            }
+        };
+        let proof = Transform::new_proofing_context(
+            GlobalContext::new(),
+            &proofing_space,
+            PixelFormat::RGB_16,
+            display_profile,
+            PixelFormat::RGB_8,
+            &output,
+            intent.lcms(),
+            Intent::RelativeColorimetric,
+            Flags::NO_CACHE | Flags::SOFT_PROOFING,
+        )
+        .map_err(|e| anyhow!("Failed to proof {}: {}", name, e))?;
+        let gamut_check = if gamut_warning {
+            let transform = Transform::new_proofing_context(
+                GlobalContext::new(),
+                &proofing_space,
+                PixelFormat::RGB_16,
+                &proofing_space,
+                PixelFormat::RGB_16,
+                &output,
+                intent.lcms(),
+                Intent::RelativeColorimetric,
+                Flags::NO_CACHE | Flags::GAMUT_CHECK,
+            )
+            .map_err(|e| anyhow!("Failed to check the gamut of {}: {}", name, e))?;
+            let [r, g, b, ..] = Transform::<[u16; 3], [u16; 3]>::global_alarm_codes();
+            Some((transform, [r, g, b]))
+        } else {
+            None
+        };
+        debug!("Soft proofing {} with {} intent", name, intent);
+        Ok(SoftProof { name, to_proofing_space, proof, gamut_check })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Converts a rendered, linear image for the screen as it would come
+    /// out of the proofed output. With the gamut warning on, also tells
+    /// which pixels that output can't reproduce; see [`draw_gamut_warning`].
+    pub fn to_display(&self, image: &Rgb32FImage) -> (RgbaImage, Vec<bool>) {
+        let mut encoded: Vec<[f32; 3]> = image.pixels().map(|p| p.0).collect();
+        self.to_proofing_space.transform_in_place(&mut encoded);
+        let quantized: Vec<[u16; 3]> =
+            encoded.iter().map(|p| p.map(|v| (v.clamp(0.0, 1.0) * 65535.0).round() as u16)).collect();
+        let mut proofed = vec![[0u8; 3]; quantized.len()];
+        self.proof.transform_pixels(&quantized, &mut proofed);
+
+        let mut display = RgbaImage::new(image.width(), image.height());
+        for (pixel, [r, g, b]) in display.pixels_mut().zip(proofed) {
+            pixel.0 = [r, g, b, 255];
+        }
+        let Some((transform, alarm)) = &self.gamut_check else {
+            return (display, Vec::new());
+        };
+        let mut checked = quantized.clone();
+        transform.transform_in_place(&mut checked);
+        let near = |a: &[u16; 3], b: &[u16; 3]| a.iter().zip(b).all(|(a, b)| a.abs_diff(*b) <= 2);
+        // Colours in gamut come back as they were
+        let out_of_gamut = quantized.iter().zip(&checked).map(|(original, checked)| checked == alarm && !near(original, alarm)).collect();
+        (display, out_of_gamut)
+    }
+}
+
+/// Paints over the pixels [`SoftProof::to_display`] found out of gamut.
+pub fn draw_gamut_warning(display: &mut RgbaImage, out_of_gamut: &[bool]) {
+    for (pixel, _) in display.pixels_mut().zip(out_of_gamut).filter(|(_, out)| **out) {
+        pixel.0 = GAMUT_WARNING;
+    }
+}
+
+impl fmt::Debug for SoftProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SoftProof").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedded_profiles() {
+        // Pure Adobe RGB green is outside sRGB
+        let green = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(1, 1, image::Rgb([0, 255, 0])));
+        let linear = to_working(&green, &ColorSpace::AdobeRgb.icc()).unwrap().unwrap();
+        let [r, g, b] = linear.get_pixel(0, 0).0;
+        assert!(r < -0.1 && g > 0.9 && b < 0.0, "{:?}", (r, g, b));
+
+        // sRGB images are left alone
+        assert!(to_working(&green, &ColorSpace::Srgb.icc()).unwrap().is_none());
+        assert!(parse_profile(b"not a profile").is_err());
+
+        // And converting back gives the original colour, give or take the
+        // precision profiles are stored with
+        let encoded = ColorSpace::AdobeRgb.encode(&DynamicImage::ImageRgb32F(linear)).unwrap().to_rgb8();
+        let [r, g, b] = encoded.get_pixel(0, 0).0;
+        assert!(r <= 2 && g >= 253 && b <= 2, "{:?}", (r, g, b));
+        assert_eq!(profile_name(&ColorSpace::DisplayP3.profile()), "Display P3");
+    }
+
+    #[test]
+    fn test_soft_proof() {
+        // Working-space green beyond sRGB, an sRGB orange and a grey
+        let image = Rgb32FImage::from_fn(3, 1, |x, _| {
+            image::Rgb([[-0.2, 0.8, 0.1], [0.8, 0.3, 0.05], [0.2, 0.2, 0.2]][x as usize])
+        });
+        let proof = SoftProof::new(&ColorSpace::Srgb.icc(), RenderingIntent::RelativeColorimetric, true).unwrap();
+        let (mut proofed, out_of_gamut) = proof.to_display(&image);
+        assert_eq!(out_of_gamut, [true, false, false]);
+        let [r, g, b, _] = proofed.get_pixel(2, 0).0;
+        assert!(r.abs_diff(124) <= 2 && r == g && g == b, "{:?}", (r, g, b));
+        draw_gamut_warning(&mut proofed, &out_of_gamut);
+        assert_eq!(proofed.get_pixel(0, 0).0, GAMUT_WARNING);
+        assert_ne!(proofed.get_pixel(1, 0).0, GAMUT_WARNING);
+
+        // Adobe RGB holds that green
+        let proof = SoftProof::new(&ColorSpace::AdobeRgb.icc(), RenderingIntent::Perceptual, true).unwrap();
+        assert!(!proof.to_display(&image).1.contains(&true));
+        assert_eq!(proof.name(), "Adobe RGB (1998)");
+    }
+}
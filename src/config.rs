@@ -0,0 +1,14 @@
+//! PhotoFlow's per-user configuration directory.
+
+use std::path::PathBuf;
+use anyhow::{anyhow, Result};
+
+/// `$XDG_CONFIG_HOME/photoflow`, or `~/.config/photoflow`.
+pub fn config_dir() -> Result<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok_or_else(|| anyhow!("Can't find the home directory for the configuration"))?;
+    Ok(config_home.join("photoflow"))
+}
@@ -0,0 +1,89 @@
+//! Per-pixel basic adjustments, in linear light.
+
+use image::Rgb32FImage;
+
+use super::EditParams;
+
+/// Rec. 709 luminance weights for linear RGB.
+pub const LUMA: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Gamma used to move luminance into a roughly perceptual scale for the
+/// tone sliders, so that "shadows" means what it looks like.
+const TONE_GAMMA: f32 = 2.2;
+
+/// Red, green and blue multipliers for relative temperature and tint.
+/// ±100 shifts red against blue by one stop, and green by half a stop.
+pub fn white_balance_multipliers(temperature: f32, tint: f32) -> [f32; 3] {
+    let warm = temperature / 100.0 * 0.5;
+    let magenta = tint / 100.0 * 0.5;
+    [2f32.powf(warm), 2f32.powf(-magenta), 2f32.powf(-warm)]
+}
+
+pub fn srgb_decode(value: f32) -> f32 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn apply(image: &mut Rgb32FImage, params: &EditParams, white_balance: [f32; 3]) {
+    let gain = 2f32.powf(params.exposure);
+    let scale = [gain * white_balance[0], gain * white_balance[1], gain * white_balance[2]];
+    let tone_changed = params.contrast != 0.0
+        || params.highlights != 0.0
+        || params.shadows != 0.0
+        || params.whites != 0.0
+        || params.blacks != 0.0;
+
+    for pixel in image.pixels_mut() {
+        let mut rgb = [pixel[0] * scale[0], pixel[1] * scale[1], pixel[2] * scale[2]];
+        if tone_changed {
+            rgb = tone(rgb, params);
+        }
+        if params.saturation != 0.0 || params.vibrance != 0.0 {
+            rgb = saturate(rgb, params.saturation / 100.0, params.vibrance / 100.0);
+        }
+        pixel.0 = rgb.map(|v| v.max(0.0));
+    }
+}
+
+fn luminance(rgb: [f32; 3]) -> f32 {
+    rgb[0] * LUMA[0] + rgb[1] * LUMA[1] + rgb[2] * LUMA[2]
+}
+
+/// Moves the pixel's luminance along the tone sliders, keeping its hue.
+fn tone(rgb: [f32; 3], params: &EditParams) -> [f32; 3] {
+    let y = luminance(rgb);
+    if y <= 0.0 {
+        return rgb;
+    }
+    let mut l = y.powf(1.0 / TONE_GAMMA);
+
+    // Each slider acts on its own band of tones and fades out towards the
+    // others; masks are computed on the clipped value so highlights above
+    // white don't flip the sign.
+    let band = |l: f32| l.clamp(0.0, 1.0);
+    let c = band(l);
+    l += params.blacks / 100.0 * 0.1 * (1.0 - c).powi(3);
+    l += params.shadows / 100.0 * 0.5 * c * (1.0 - c).powi(2);
+    l += params.highlights / 100.0 * 0.5 * c * c * (1.0 - c);
+    l += params.whites / 100.0 * 0.25 * c.powi(3);
+
+    // Contrast blends towards (or away from) a smoothstep S-curve
+    let c = band(l);
+    l += params.contrast / 100.0 * (c * c * (3.0 - 2.0 * c) - c);
+
+    let ratio = l.max(0.0).powf(TONE_GAMMA) / y;
+    rgb.map(|v| v * ratio)
+}
+
+fn saturate(rgb: [f32; 3], saturation: f32, vibrance: f32) -> [f32; 3] {
+    let y = luminance(rgb);
+    let max = rgb[0].max(rgb[1]).max(rgb[2]);
+    let min = rgb[0].min(rgb[1]).min(rgb[2]);
+    let current = if max > 0.0 { (max - min) / max } else { 0.0 };
+    // Vibrance mostly affects colours that aren't saturated yet
+    let factor = (1.0 + saturation) * (1.0 + vibrance * (1.0 - current));
+    rgb.map(|v| y + (v - y) * factor)
+}
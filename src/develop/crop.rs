@@ -0,0 +1,363 @@
+//! Straightening and cropping.
+
+use std::fmt;
+use image::{Rgb, Rgb32FImage, RgbaImage};
+
+/// Aspect ratio the crop is held to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CropAspect {
+    #[default]
+    Free,
+    /// Same as the uncropped image.
+    Original,
+    /// Width to height for landscape images; turned around for portrait ones.
+    Ratio(u8, u8),
+}
+
+impl CropAspect {
+    pub const ALL: [CropAspect; 7] = [
+        CropAspect::Free,
+        CropAspect::Original,
+        CropAspect::Ratio(1, 1),
+        CropAspect::Ratio(5, 4),
+        CropAspect::Ratio(4, 3),
+        CropAspect::Ratio(3, 2),
+        CropAspect::Ratio(16, 9),
+    ];
+
+    /// Width over height in pixels for an image of `width` x `height`.
+    fn ratio(self, width: u32, height: u32) -> Option<f32> {
+        let image = width as f32 / height as f32;
+        match self {
+            CropAspect::Free => None,
+            CropAspect::Original => Some(image),
+            CropAspect::Ratio(w, h) if image < 1.0 => Some(h as f32 / w as f32),
+            CropAspect::Ratio(w, h) => Some(w as f32 / h as f32),
+        }
+    }
+
+    /// Inverse of `Display`, for reading sidecars.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "free" => Some(CropAspect::Free),
+            "original" => Some(CropAspect::Original),
+            _ => {
+                let (w, h) = value.split_once(':')?;
+                Some(CropAspect::Ratio(w.parse().ok()?, h.parse().ok()?))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CropAspect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CropAspect::Free => f.write_str("free"),
+            CropAspect::Original => f.write_str("original"),
+            CropAspect::Ratio(w, h) => write!(f, "{}:{}", w, h),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CropEdge {
+    Left,
+    Top,
+    Right,
+    Bottom,
+}
+
+impl CropEdge {
+    pub const ALL: [CropEdge; 4] = [CropEdge::Left, CropEdge::Top, CropEdge::Right, CropEdge::Bottom];
+}
+
+impl fmt::Display for CropEdge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Crop rectangle and straightening angle.
+///
+/// The rectangle is in fractions of the image size (0-1) and applies to the
+/// image after it has been rotated by `angle` about its centre.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crop {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+    /// Degrees, clockwise.
+    pub angle: f32,
+    pub aspect: CropAspect,
+}
+
+impl Default for Crop {
+    fn default() -> Self {
+        Self { left: 0.0, top: 0.0, right: 1.0, bottom: 1.0, angle: 0.0, aspect: CropAspect::Free }
+    }
+}
+
+/// Smallest crop, as a fraction of each side.
+const MIN_SIZE: f32 = 0.05;
+
+impl Crop {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn edge(&self, edge: CropEdge) -> f32 {
+        match edge {
+            CropEdge::Left => self.left,
+            CropEdge::Top => self.top,
+            CropEdge::Right => self.right,
+            CropEdge::Bottom => self.bottom,
+        }
+    }
+
+    /// Moves one edge, then restores the aspect ratio by adjusting the
+    /// neighbouring edge.
+    pub fn set_edge(&mut self, edge: CropEdge, value: f32, width: u32, height: u32) {
+        match edge {
+            CropEdge::Left => self.left = value.clamp(0.0, self.right - MIN_SIZE),
+            CropEdge::Top => self.top = value.clamp(0.0, self.bottom - MIN_SIZE),
+            CropEdge::Right => self.right = value.clamp(self.left + MIN_SIZE, 1.0),
+            CropEdge::Bottom => self.bottom = value.clamp(self.top + MIN_SIZE, 1.0),
+        }
+        let Some(ratio) = self.aspect.ratio(width, height) else {
+            return;
+        };
+        // Height and width fractions that give `ratio` in pixels
+        let pixels = width as f32 / height as f32;
+        match edge {
+            CropEdge::Left | CropEdge::Right => {
+                self.bottom = self.top + (self.right - self.left) * pixels / ratio;
+            }
+            CropEdge::Top | CropEdge::Bottom => {
+                self.right = self.left + (self.bottom - self.top) * ratio / pixels;
+            }
+        }
+        self.fit_inside(ratio, pixels);
+    }
+
+    /// Changes the aspect ratio, keeping the crop centred and as large as
+    /// it can be.
+    pub fn set_aspect(&mut self, aspect: CropAspect, width: u32, height: u32) {
+        self.aspect = aspect;
+        let Some(ratio) = aspect.ratio(width, height) else {
+            return;
+        };
+        let pixels = width as f32 / height as f32;
+        let (cx, cy) = ((self.left + self.right) / 2.0, (self.top + self.bottom) / 2.0);
+        let (mut w, mut h) = (self.right - self.left, self.bottom - self.top);
+        if w * pixels / h > ratio {
+            w = h * ratio / pixels;
+        } else {
+            h = w * pixels / ratio;
+        }
+        self.left = cx - w / 2.0;
+        self.right = cx + w / 2.0;
+        self.top = cy - h / 2.0;
+        self.bottom = cy + h / 2.0;
+        self.fit_inside(ratio, pixels);
+    }
+
+    /// Sets the straightening angle and shrinks the crop about its centre
+    /// until it has no empty corners. A crop centred off the rotated image
+    /// is moved to the middle first.
+    pub fn set_angle(&mut self, angle: f32, width: u32, height: u32) {
+        self.angle = angle.clamp(-45.0, 45.0);
+        let (w, h) = (width as f32, height as f32);
+        let (sin, cos) = self.angle.to_radians().sin_cos();
+        // Where an offset from the image centre, in pixels, comes from in
+        // the source; the same mapping as `rotate`
+        let source = |(x, y): (f32, f32)| (cos * x + sin * y, -sin * x + cos * y);
+        // Largest fraction of `by` that can be added to `from` while
+        // staying within the source
+        let reach = |from: (f32, f32), by: (f32, f32)| {
+            let mut t: f32 = 1.0;
+            for (p, d, limit) in [(from.0, by.0, w / 2.0), (from.1, by.1, h / 2.0)] {
+                if p + d > limit {
+                    t = t.min((limit - p) / d);
+                } else if p + d < -limit {
+                    t = t.min((-limit - p) / d);
+                }
+            }
+            t.max(0.0)
+        };
+
+        let mut centre = ((self.left + self.right - 1.0) / 2.0 * w, (self.top + self.bottom - 1.0) / 2.0 * h);
+        if reach((0.0, 0.0), source(centre)) < 1.0 {
+            centre = (0.0, 0.0);
+        }
+        let half = ((self.right - self.left) / 2.0 * w, (self.bottom - self.top) / 2.0 * h);
+        let scale = [(1.0, 1.0), (1.0, -1.0), (-1.0, 1.0), (-1.0, -1.0)]
+            .into_iter()
+            .map(|(x, y)| reach(source(centre), source((x * half.0, y * half.1))))
+            .fold(1.0, f32::min);
+        let half = (half.0 * scale, half.1 * scale);
+        self.left = (centre.0 - half.0) / w + 0.5;
+        self.right = (centre.0 + half.0) / w + 0.5;
+        self.top = (centre.1 - half.1) / h + 0.5;
+        self.bottom = (centre.1 + half.1) / h + 0.5;
+    }
+
+    /// Moves and, if need be, shrinks the rectangle so it lies within the
+    /// image while keeping `ratio`.
+    fn fit_inside(&mut self, ratio: f32, pixels: f32) {
+        let (mut w, mut h) = (self.right - self.left, self.bottom - self.top);
+        if w > 1.0 {
+            w = 1.0;
+            h = w * pixels / ratio;
+        }
+        if h > 1.0 {
+            h = 1.0;
+            w = h * ratio / pixels;
+        }
+        self.left = self.left.clamp(0.0, 1.0 - w);
+        self.top = self.top.clamp(0.0, 1.0 - h);
+        self.right = self.left + w;
+        self.bottom = self.top + h;
+    }
+
+    /// Pixel rectangle (x, y, width, height) in an image of the given size.
+    fn pixels(&self, width: u32, height: u32) -> (u32, u32, u32, u32) {
+        let x = (self.left * width as f32).round() as u32;
+        let y = (self.top * height as f32).round() as u32;
+        let right = ((self.right * width as f32).round() as u32).clamp(x + 1, width);
+        let bottom = ((self.bottom * height as f32).round() as u32).clamp(y + 1, height);
+        (x, y, right - x, bottom - y)
+    }
+}
+
+/// Rotates `image` by `crop.angle` and cuts out the crop rectangle.
+pub fn apply(image: &Rgb32FImage, crop: &Crop) -> Rgb32FImage {
+    let rotated = rotate(image, crop.angle);
+    let (x, y, width, height) = crop.pixels(rotated.width(), rotated.height());
+    image::imageops::crop_imm(&rotated, x, y, width, height).to_image()
+}
+
+/// Rotates about the centre, keeping the canvas size. Corners that fall
+/// outside the source are black.
+pub fn rotate(image: &Rgb32FImage, degrees: f32) -> Rgb32FImage {
+    if degrees == 0.0 {
+        return image.clone();
+    }
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (cx, cy) = (image.width() as f32 / 2.0, image.height() as f32 / 2.0);
+    Rgb32FImage::from_fn(image.width(), image.height(), |x, y| {
+        // Inverse mapping: where in the source does this pixel come from
+        let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+        let sx = cos * dx + sin * dy + cx - 0.5;
+        let sy = -sin * dx + cos * dy + cy - 0.5;
+        bilinear(image, sx, sy)
+    })
+}
+
+pub(super) fn bilinear(image: &Rgb32FImage, x: f32, y: f32) -> Rgb<f32> {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    if x < -0.5 || y < -0.5 || x > width as f32 - 0.5 || y > height as f32 - 0.5 {
+        return Rgb([0.0; 3]);
+    }
+    let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let at = |x: i64, y: i64| image.get_pixel(x.clamp(0, width - 1) as u32, y.clamp(0, height - 1) as u32).0;
+    let (a, b, c, d) = (at(x0, y0), at(x0 + 1, y0), at(x0, y0 + 1), at(x0 + 1, y0 + 1));
+    let mut out = [0.0; 3];
+    for i in 0..3 {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        out[i] = top + (bottom - top) * fy;
+    }
+    Rgb(out)
+}
+
+/// Draws the crop rectangle onto a rendering of the whole (rotated) image:
+/// the area outside is dimmed and the inside gets rule-of-thirds lines.
+pub fn draw_overlay(display: &mut RgbaImage, crop: &Crop) {
+    let (x, y, width, height) = crop.pixels(display.width(), display.height());
+    let (right, bottom) = (x + width, y + height);
+    let thirds_x = [x + width / 3, x + 2 * width / 3];
+    let thirds_y = [y + height / 3, y + 2 * height / 3];
+
+    for (px, py, pixel) in display.enumerate_pixels_mut() {
+        let inside = px >= x && px < right && py >= y && py < bottom;
+        if !inside {
+            for channel in &mut pixel.0[..3] {
+                *channel /= 3;
+            }
+            continue;
+        }
+        let border = px == x || px + 1 == right || py == y || py + 1 == bottom;
+        let third = thirds_x.contains(&px) || thirds_y.contains(&py);
+        if border {
+            pixel.0 = [255, 255, 255, 255];
+        } else if third {
+            for channel in &mut pixel.0[..3] {
+                *channel = *channel / 2 + 128;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aspect_and_angle() {
+        let mut crop = Crop::default();
+        crop.set_aspect(CropAspect::Ratio(1, 1), 600, 400);
+        let (_, _, w, h) = crop.pixels(600, 400);
+        assert_eq!((w, h), (400, 400));
+
+        crop.set_edge(CropEdge::Right, crop.right - 0.1, 600, 400);
+        let (_, _, w, h) = crop.pixels(600, 400);
+        assert!((w as i32 - h as i32).abs() <= 1);
+
+        let mut straightened = Crop::default();
+        straightened.set_angle(10.0, 600, 400);
+        assert!(straightened.left > 0.0 && straightened.right < 1.0);
+        let (_, _, w, h) = straightened.pixels(600, 400);
+        assert!(((w as f32 / h as f32) - 1.5).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_angle_keeps_crop() {
+        // A small crop in the middle already clears the corners
+        let mut crop = Crop { left: 0.4, top: 0.4, right: 0.6, bottom: 0.5, ..Default::default() };
+        let before = crop;
+        crop.set_angle(5.0, 600, 400);
+        for edge in CropEdge::ALL {
+            assert!((crop.edge(edge) - before.edge(edge)).abs() < 1e-6, "{}", edge);
+        }
+
+        // One in a corner shrinks towards its own centre
+        let mut crop = Crop { left: 0.5, top: 0.5, right: 1.0, bottom: 1.0, ..Default::default() };
+        crop.set_angle(10.0, 600, 400);
+        assert!(crop.left > 0.5 && crop.top > 0.5 && crop.right < 1.0 && crop.bottom < 1.0);
+        assert!(((crop.left + crop.right) / 2.0 - 0.75).abs() < 1e-4);
+        assert!(((crop.top + crop.bottom) / 2.0 - 0.75).abs() < 1e-4);
+        assert!(((crop.right - crop.left) - (crop.bottom - crop.top)).abs() < 1e-4);
+
+        // Its corners have image under them
+        let image = Rgb32FImage::from_pixel(600, 400, Rgb([1.0; 3]));
+        let cropped = apply(&image, &crop);
+        let (w, h) = cropped.dimensions();
+        for (x, y) in [(0, 0), (w - 1, 0), (0, h - 1), (w - 1, h - 1)] {
+            assert!(cropped.get_pixel(x, y)[0] > 0.5, "corner {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn test_apply_crops_and_rotates() {
+        let image = Rgb32FImage::from_fn(100, 50, |x, _| Rgb([x as f32 / 100.0; 3]));
+        let crop = Crop { left: 0.5, top: 0.0, right: 1.0, bottom: 0.5, ..Default::default() };
+        let cropped = apply(&image, &crop);
+        assert_eq!(cropped.dimensions(), (50, 25));
+        assert!((cropped.get_pixel(0, 0)[0] - 0.5).abs() < 1e-6);
+
+        let rotated = rotate(&image, 180.0);
+        assert!((rotated.get_pixel(0, 0)[0] - 0.99).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,212 @@
+//! Wavelet noise reduction.
+//!
+//! The image is split into luminance and two colour-difference channels
+//! in a square-root encoding, where photon noise is about equally strong
+//! in shadows and highlights. Each channel is decomposed with an à trous
+//! wavelet transform and the detail coefficients are soft-thresholded
+//! against the noise level estimated at that scale.
+
+use image::Rgb32FImage;
+
+/// Luminance and colour noise reduction, 0-100. `None` takes the default
+/// for the photo's ISO.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NoiseReduction {
+    pub luminance: Option<f32>,
+    pub color: Option<f32>,
+}
+
+impl NoiseReduction {
+    /// Strengths a photo shot at `iso` starts out with: nothing at base
+    /// ISO, about 40 luminance and 50 colour at ISO 6400.
+    pub fn defaults(iso: Option<u32>) -> (f32, f32) {
+        let Some(iso) = iso.filter(|&iso| iso > 0) else {
+            return (0.0, 0.0);
+        };
+        let stops = |base: f32| (iso as f32 / base).log2();
+        ((10.0 * stops(400.0)).clamp(0.0, 60.0), (10.0 * stops(200.0)).clamp(0.0, 70.0))
+    }
+
+    /// Fills in the ISO defaults for rendering. Strengths that come out
+    /// as zero are left `None` so untouched low-ISO photos still count as
+    /// unedited.
+    pub fn resolve(&self, iso: Option<u32>) -> Self {
+        let (luminance, color) = Self::defaults(iso);
+        Self {
+            luminance: Some(self.luminance.unwrap_or(luminance)).filter(|v| *v > 0.0),
+            color: Some(self.color.unwrap_or(color)).filter(|v| *v > 0.0),
+        }
+    }
+}
+
+/// Previews are rendered with fewer wavelet scales, which is much cheaper
+/// and, on the downscaled preview, looks nearly the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Preview,
+    Full,
+}
+
+impl Quality {
+    /// Wavelet scales for luminance and colour. Colour noise is blotchier,
+    /// so it gets more of the coarse scales.
+    fn scales(self) -> (usize, usize) {
+        match self {
+            Quality::Preview => (2, 3),
+            Quality::Full => (4, 5),
+        }
+    }
+}
+
+/// Threshold, in noise standard deviations, at strength 100.
+const MAX_THRESHOLD: f32 = 3.0;
+
+/// Applies resolved strengths; `None` means off here.
+pub fn apply(image: &mut Rgb32FImage, noise: &NoiseReduction, quality: Quality) {
+    let luminance = noise.luminance.unwrap_or(0.0) / 100.0 * MAX_THRESHOLD;
+    let color = noise.color.unwrap_or(0.0) / 100.0 * MAX_THRESHOLD;
+    if luminance <= 0.0 && color <= 0.0 {
+        return;
+    }
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels = width * height;
+    let (mut y, mut cb, mut cr) = (Vec::with_capacity(pixels), Vec::with_capacity(pixels), Vec::with_capacity(pixels));
+    for pixel in image.pixels() {
+        let [r, g, b] = pixel.0.map(|v| v.max(0.0).sqrt());
+        let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+        y.push(luma);
+        cb.push(b - luma);
+        cr.push(r - luma);
+    }
+
+    let (luma_scales, color_scales) = quality.scales();
+    if luminance > 0.0 {
+        denoise_channel(&mut y, width, height, luma_scales, luminance);
+    }
+    if color > 0.0 {
+        denoise_channel(&mut cb, width, height, color_scales, color);
+        denoise_channel(&mut cr, width, height, color_scales, color);
+    }
+
+    for (i, pixel) in image.pixels_mut().enumerate() {
+        let r = y[i] + cr[i];
+        let b = y[i] + cb[i];
+        let g = (y[i] - 0.299 * r - 0.114 * b) / 0.587;
+        pixel.0 = [r, g, b].map(|v| v.max(0.0).powi(2));
+    }
+}
+
+/// Removes noise from one channel in place.
+fn denoise_channel(channel: &mut Vec<f32>, width: usize, height: usize, scales: usize, threshold: f32) {
+    let mut output = vec![0.0; channel.len()];
+    for scale in 0..scales {
+        let smooth = blur(channel, width, height, 1 << scale);
+        let mut detail: Vec<f32> = channel.iter().zip(&smooth).map(|(v, s)| v - s).collect();
+        let limit = threshold * noise_sigma(&detail);
+        for (out, d) in output.iter_mut().zip(&mut detail) {
+            // Soft threshold
+            *out += d.signum() * (d.abs() - limit).max(0.0);
+        }
+        *channel = smooth;
+    }
+    for (value, out) in channel.iter_mut().zip(output) {
+        *value += out;
+    }
+}
+
+/// Robust estimate of the noise in wavelet detail coefficients: the median
+/// absolute value over 0.6745. Real edges are rare enough not to move the
+/// median much.
+fn noise_sigma(detail: &[f32]) -> f32 {
+    // A sample is plenty for a median
+    let step = (detail.len() / 50_000).max(1);
+    let mut sample: Vec<f32> = detail.iter().step_by(step).map(|d| d.abs()).collect();
+    if sample.is_empty() {
+        return 0.0;
+    }
+    let middle = sample.len() / 2;
+    let (_, median, _) = sample.select_nth_unstable_by(middle, f32::total_cmp);
+    *median / 0.6745
+}
+
+/// B3-spline blur with holes of size `step` between the taps, mirrored
+/// at the edges.
+fn blur(channel: &[f32], width: usize, height: usize, step: usize) -> Vec<f32> {
+    const KERNEL: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+    let mirror = |i: isize, len: usize| -> usize {
+        let len = len as isize;
+        let i = if i < 0 { -i } else { i };
+        let i = if i >= len { 2 * (len - 1) - i } else { i };
+        i.clamp(0, len - 1) as usize
+    };
+
+    let mut rows = vec![0.0; channel.len()];
+    for y in 0..height {
+        let row = &channel[y * width..(y + 1) * width];
+        for x in 0..width {
+            rows[y * width + x] = KERNEL
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| weight * row[mirror(x as isize + (k as isize - 2) * step as isize, width)])
+                .sum();
+        }
+    }
+    let mut output = vec![0.0; channel.len()];
+    for y in 0..height {
+        for x in 0..width {
+            output[y * width + x] = KERNEL
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| weight * rows[mirror(y as isize + (k as isize - 2) * step as isize, height) * width + x])
+                .sum();
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random noise in [-1, 1].
+    fn noise(i: u32) -> f32 {
+        let mut x = i.wrapping_mul(2_654_435_761) ^ 0x9e37_79b9;
+        x ^= x >> 15;
+        x = x.wrapping_mul(2_246_822_519);
+        x ^= x >> 13;
+        (x % 2001) as f32 / 1000.0 - 1.0
+    }
+
+    fn deviation(image: &Rgb32FImage, channel: usize) -> f32 {
+        let values: Vec<f32> = image.pixels().map(|p| p[channel]).collect();
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        (values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32).sqrt()
+    }
+
+    #[test]
+    fn test_iso_defaults() {
+        assert_eq!(NoiseReduction::defaults(None), (0.0, 0.0));
+        assert_eq!(NoiseReduction::defaults(Some(100)), (0.0, 0.0));
+        let (luminance, color) = NoiseReduction::defaults(Some(6400));
+        assert!((luminance - 40.0).abs() < 1e-3 && (color - 50.0).abs() < 1e-3);
+        let manual = NoiseReduction { luminance: Some(5.0), color: None };
+        assert_eq!(manual.resolve(Some(6400)), NoiseReduction { luminance: Some(5.0), color: Some(50.0) });
+        assert_eq!(NoiseReduction::default().resolve(Some(100)), NoiseReduction::default());
+    }
+
+    #[test]
+    fn test_reduces_noise_and_keeps_level() {
+        let noisy = Rgb32FImage::from_fn(64, 64, |x, y| {
+            let i = y * 64 + x;
+            image::Rgb([0.2 + 0.04 * noise(3 * i), 0.2 + 0.04 * noise(3 * i + 1), 0.2 + 0.04 * noise(3 * i + 2)])
+        });
+        let mut denoised = noisy.clone();
+        let strength = NoiseReduction { luminance: Some(100.0), color: Some(100.0) };
+        apply(&mut denoised, &strength, Quality::Full);
+        for channel in 0..3 {
+            assert!(deviation(&denoised, channel) < deviation(&noisy, channel) / 2.0);
+        }
+        let mean = |image: &Rgb32FImage| image.pixels().map(|p| p[1]).sum::<f32>() / 4096.0;
+        assert!((mean(&denoised) - mean(&noisy)).abs() < 0.01);
+    }
+}
@@ -0,0 +1,94 @@
+//! Histogram and clipping warnings for the rendered preview.
+
+use image::RgbaImage;
+
+/// Roughly how many pixels the histogram is computed from.
+const SAMPLES: u32 = 65_536;
+
+/// Counts per 8-bit level of the displayed image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub red: [u32; 256],
+    pub green: [u32; 256],
+    pub blue: [u32; 256],
+    pub luma: [u32; 256],
+}
+
+impl Histogram {
+    /// Counts a regular grid of pixels instead of every one, which is
+    /// plenty for the shape and keeps it cheap enough to redo on every
+    /// slider move.
+    pub fn from_display(image: &RgbaImage) -> Self {
+        let mut histogram = Self { red: [0; 256], green: [0; 256], blue: [0; 256], luma: [0; 256] };
+        let pixels = image.width() * image.height();
+        let step = ((pixels / SAMPLES) as f32).sqrt().max(1.0) as u32;
+        for y in (0..image.height()).step_by(step as usize) {
+            for x in (0..image.width()).step_by(step as usize) {
+                let [r, g, b, _] = image.get_pixel(x, y).0;
+                histogram.red[r as usize] += 1;
+                histogram.green[g as usize] += 1;
+                histogram.blue[b as usize] += 1;
+                // Rec. 709 weights on the encoded values, as editors usually show it
+                let luma = 0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32;
+                histogram.luma[luma.round() as usize] += 1;
+            }
+        }
+        histogram
+    }
+
+    /// Tallest bin, for scaling the graph. The end bins are left out: a
+    /// clipped sky would otherwise flatten everything else.
+    pub fn peak(&self) -> u32 {
+        [&self.red, &self.green, &self.blue, &self.luma]
+            .iter()
+            .flat_map(|bins| bins[1..255].iter())
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(1)
+    }
+}
+
+/// Which clipping warnings to draw over the image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClippingWarnings {
+    pub highlights: bool,
+    pub shadows: bool,
+}
+
+/// Marks clipped pixels: red where any channel is at white, blue where
+/// all are at black.
+pub fn draw_clipping(display: &mut RgbaImage, warnings: ClippingWarnings) {
+    if !warnings.highlights && !warnings.shadows {
+        return;
+    }
+    for pixel in display.pixels_mut() {
+        let [r, g, b, _] = pixel.0;
+        if warnings.highlights && (r == 255 || g == 255 || b == 255) {
+            pixel.0 = [255, 0, 0, 255];
+        } else if warnings.shadows && r == 0 && g == 0 && b == 0 {
+            pixel.0 = [0, 90, 255, 255];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_and_clipping() {
+        let mut image = RgbaImage::from_fn(512, 512, |x, _| {
+            if x < 256 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 128, 64, 255]) }
+        });
+        let histogram = Histogram::from_display(&image);
+        let total: u32 = histogram.red.iter().sum();
+        assert!((SAMPLES / 2..=2 * SAMPLES).contains(&total));
+        assert_eq!(histogram.red[0], histogram.red[255]);
+        assert_eq!(histogram.green[128], histogram.red[255]);
+
+        draw_clipping(&mut image, ClippingWarnings { highlights: true, shadows: false });
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0, 255]);
+        assert_eq!(image.get_pixel(300, 0).0, [255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,222 @@
+//! Lens corrections: vignetting, distortion and lateral chromatic
+//! aberration, from the lens profile in the local lensfun database or set
+//! by hand.
+//!
+//! Profiles are applied as measured; a calibration made on a body with a
+//! different sensor size is not rescaled.
+
+use std::fmt;
+use image::{Rgb, Rgb32FImage};
+
+use super::crop::bilinear;
+use crate::lensfun::{self, Calibration, Distortion, Tca, Vignetting};
+use crate::photo::ExifData;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LensCorrection {
+    /// Leave the lens profile out and only apply the manual values.
+    pub ignore_profile: bool,
+    /// Manual values from -100 to 100. Each replaces the profile's
+    /// correction of the same kind; `None` keeps the profile.
+    pub distortion: Option<f32>,
+    pub vignetting: Option<f32>,
+    pub ca_red: Option<f32>,
+    pub ca_blue: Option<f32>,
+    /// Profile for the photo's lens, focal length and aperture. Filled in
+    /// by [`LensCorrection::resolve`] for rendering, never saved.
+    pub profile: Option<Calibration>,
+}
+
+impl LensCorrection {
+    /// Looks up the profile for the lens in `exif`.
+    pub fn resolve(&self, exif: Option<&ExifData>) -> Self {
+        let profile = exif.filter(|_| !self.ignore_profile).and_then(|exif| {
+            let lens = lensfun::database().find(exif.lens.as_deref()?)?;
+            // Without a focal length the shortest one measured is used
+            Some(lens.calibration(exif.focal_length.unwrap_or(0.0), exif.f_number))
+        });
+        Self { profile, ..*self }
+    }
+
+    /// The calibration to apply: the profile with the manual values in place.
+    fn calibration(&self) -> Calibration {
+        let profile = self.profile.unwrap_or_default();
+        let tca = match (self.ca_red, self.ca_blue) {
+            (None, None) => profile.tca,
+            (red, blue) => {
+                let profile = profile.tca.unwrap_or(Tca { red: [1.0, 0.0, 0.0], blue: [1.0, 0.0, 0.0] });
+                // 100 scales the channel by 0.2% against green
+                let scale = |value: Option<f32>, profile: [f32; 3]| value.map_or(profile, |v| [1.0 + v / 100.0 * 0.002, 0.0, 0.0]);
+                Some(Tca { red: scale(red, profile.red), blue: scale(blue, profile.blue) })
+            }
+        };
+        Calibration {
+            // 100 straightens strong barrel distortion, -100 pincushion
+            distortion: self.distortion.map(|v| Distortion::Poly3 { k1: -v / 100.0 * 0.1 }).or(profile.distortion),
+            tca,
+            // 100 brightens the corners by a stop
+            vignetting: self.vignetting.map(|v| Vignetting { k: [-v / 100.0 * 0.5, 0.0, 0.0] }).or(profile.vignetting),
+        }
+    }
+}
+
+/// One manual value of [`LensCorrection`], for sliders and sidecar
+/// properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LensField {
+    Distortion,
+    Vignetting,
+    RedCA,
+    BlueCA,
+}
+
+impl LensField {
+    pub const ALL: [LensField; 4] = [LensField::Distortion, LensField::Vignetting, LensField::RedCA, LensField::BlueCA];
+
+    pub fn get(self, correction: &LensCorrection) -> Option<f32> {
+        match self {
+            LensField::Distortion => correction.distortion,
+            LensField::Vignetting => correction.vignetting,
+            LensField::RedCA => correction.ca_red,
+            LensField::BlueCA => correction.ca_blue,
+        }
+    }
+
+    pub fn set(self, correction: &mut LensCorrection, value: Option<f32>) {
+        let value = value.map(|v| v.clamp(-100.0, 100.0));
+        match self {
+            LensField::Distortion => correction.distortion = value,
+            LensField::Vignetting => correction.vignetting = value,
+            LensField::RedCA => correction.ca_red = value,
+            LensField::BlueCA => correction.ca_blue = value,
+        }
+    }
+}
+
+impl fmt::Display for LensField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Corrects a linear image of the whole frame. Geometry is resampled with
+/// a zoom that keeps the corners filled.
+pub fn apply(image: &mut Rgb32FImage, correction: &LensCorrection) {
+    let calibration = correction.calibration();
+    if let Some(vignetting) = calibration.vignetting {
+        devignette(image, &vignetting);
+    }
+    if calibration.distortion.is_some() || calibration.tca.is_some() {
+        *image = undistort(image, calibration.distortion, calibration.tca);
+    }
+}
+
+fn devignette(image: &mut Rgb32FImage, vignetting: &Vignetting) {
+    let (cx, cy) = (image.width() as f32 / 2.0, image.height() as f32 / 2.0);
+    let half_diagonal = (cx * cx + cy * cy).sqrt();
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let r = ((x as f32 + 0.5 - cx).powi(2) + (y as f32 + 0.5 - cy).powi(2)).sqrt() / half_diagonal;
+        let gain = 1.0 / vignetting.falloff(r).max(0.1);
+        pixel.0 = pixel.0.map(|v| v * gain);
+    }
+}
+
+/// Source radii of the red, green and blue channels for an undistorted
+/// radius, normalised to half the shorter side.
+fn source_radii(r: f32, distortion: Option<Distortion>, tca: Option<Tca>) -> [f32; 3] {
+    let green = distortion.map_or(r, |d| d.distort(r));
+    let channel = |k: [f32; 3]| green * (k[0] + k[1] * green + k[2] * green * green);
+    match tca {
+        Some(tca) => [channel(tca.red), green, channel(tca.blue)],
+        None => [green; 3],
+    }
+}
+
+fn undistort(image: &Rgb32FImage, distortion: Option<Distortion>, tca: Option<Tca>) -> Rgb32FImage {
+    let (cx, cy) = (image.width() as f32 / 2.0, image.height() as f32 / 2.0);
+    let unit = cx.min(cy);
+    let zoom = fill_zoom(cx / unit, cy / unit, distortion, tca);
+
+    Rgb32FImage::from_fn(image.width(), image.height(), |x, y| {
+        let (dx, dy) = ((x as f32 + 0.5 - cx) / unit * zoom, (y as f32 + 0.5 - cy) / unit * zoom);
+        let r = (dx * dx + dy * dy).sqrt();
+        if r == 0.0 {
+            return *image.get_pixel(x, y);
+        }
+        let radii = source_radii(r, distortion, tca);
+        let sample = |radius: f32| {
+            let scale = radius / r * unit;
+            bilinear(image, cx + dx * scale - 0.5, cy + dy * scale - 0.5)
+        };
+        if radii[0] == radii[2] {
+            return sample(radii[1]);
+        }
+        Rgb([sample(radii[0])[0], sample(radii[1])[1], sample(radii[2])[2]])
+    })
+}
+
+/// Largest zoom-out (or smallest zoom-in) that still takes every output
+/// pixel from inside the source. `half_width` and `half_height` are in
+/// normalised units.
+fn fill_zoom(half_width: f32, half_height: f32, distortion: Option<Distortion>, tca: Option<Tca>) -> f32 {
+    // Points around the border that the zoom has to keep inside
+    let border: Vec<(f32, f32)> = (0..=8)
+        .flat_map(|i| {
+            let t = i as f32 / 8.0 * 2.0 - 1.0;
+            [(t * half_width, half_height), (half_width, t * half_height)]
+        })
+        .collect();
+    let fits = |zoom: f32| {
+        border.iter().all(|&(x, y)| {
+            let r = (x * x + y * y).sqrt() * zoom;
+            let reach = source_radii(r, distortion, tca).into_iter().fold(0.0, f32::max) / r;
+            x.abs() * zoom * reach <= half_width + 1e-4 && y.abs() * zoom * reach <= half_height + 1e-4
+        })
+    };
+    let (mut low, mut high) = (0.5, 2.0);
+    if !fits(low) {
+        return low;
+    }
+    for _ in 0..24 {
+        let middle = (low + high) / 2.0;
+        if fits(middle) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_devignetting_brightens_corners() {
+        let mut image = Rgb32FImage::from_pixel(30, 20, Rgb([0.2; 3]));
+        let correction = LensCorrection { vignetting: Some(100.0), ..Default::default() };
+        apply(&mut image, &correction);
+        assert!((image.get_pixel(15, 10)[0] - 0.2).abs() < 0.01);
+        assert!(image.get_pixel(0, 0)[0] > 0.35);
+
+        let mut untouched = Rgb32FImage::from_pixel(30, 20, Rgb([0.2; 3]));
+        apply(&mut untouched, &LensCorrection::default());
+        assert_eq!(untouched, Rgb32FImage::from_pixel(30, 20, Rgb([0.2; 3])));
+    }
+
+    #[test]
+    fn test_distortion_keeps_the_frame_filled() {
+        // A grid of lines on grey; after correction no black corners appear
+        let image = Rgb32FImage::from_fn(60, 40, |x, y| Rgb([if x % 10 == 0 || y % 10 == 0 { 1.0 } else { 0.3 }; 3]));
+        for distortion in [-100.0, 100.0] {
+            let mut corrected = image.clone();
+            let correction = LensCorrection { distortion: Some(distortion), ca_red: Some(50.0), ..Default::default() };
+            apply(&mut corrected, &correction);
+            assert_ne!(corrected, image);
+            for (x, y) in [(0, 0), (59, 0), (0, 39), (59, 39), (30, 0), (0, 20)] {
+                assert!(corrected.get_pixel(x, y)[1] > 0.1, "black at {} {} for {}", x, y, distortion);
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Non-destructive editing: the parameters stored per photo and the
+//! pipeline that renders them onto a decoded image.
+
+mod adjust;
+pub mod crop;
+pub mod denoise;
+pub mod histogram;
+pub mod lens;
+pub mod sharpen;
+pub mod sync;
+pub mod tone;
+
+use std::fmt;
+use std::path::Path;
+use anyhow::Result;
+use image::{imageops::FilterType, DynamicImage, Rgb32FImage};
+
+use crate::color::{self, SoftProof};
+use crate::photo::ExifData;
+use crate::processors::{self, detector, highlights::HighlightMode, raw::RawProcessor, ImageProcessor};
+use crop::Crop;
+use denoise::{NoiseReduction, Quality};
+use lens::LensCorrection;
+use sharpen::Sharpening;
+use tone::{ChannelLevels, ToneCurve};
+
+/// Develop settings for one photo. All zero means "as shot".
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditParams {
+    /// Exposure change in stops.
+    pub exposure: f32,
+    /// White balance relative to as shot, -100 (cooler) to 100 (warmer).
+    pub temperature: f32,
+    /// -100 (greener) to 100 (more magenta).
+    pub tint: f32,
+    // The remaining sliders run from -100 to 100
+    pub contrast: f32,
+    pub highlights: f32,
+    pub shadows: f32,
+    pub whites: f32,
+    pub blacks: f32,
+    pub saturation: f32,
+    pub vibrance: f32,
+    pub noise: NoiseReduction,
+    /// Capture sharpening.
+    pub sharpening: Sharpening,
+    /// How the RAW decoder fills in clipped highlights.
+    pub highlight_mode: HighlightMode,
+    pub lens: LensCorrection,
+    pub crop: Crop,
+    pub levels: ChannelLevels,
+    pub curve: ToneCurve,
+}
+
+impl EditParams {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// These settings with what depends on the photo filled in, ready to
+    /// render: for RAW files, its lens profile and noise reduction defaults
+    /// for its ISO. Other files were already corrected and denoised in
+    /// camera, so only the manual values apply to them.
+    pub fn for_photo(&self, exif: Option<&ExifData>, is_raw: bool) -> Self {
+        let exif = exif.filter(|_| is_raw);
+        Self {
+            noise: self.noise.resolve(exif.and_then(|e| e.iso)),
+            lens: self.lens.resolve(exif),
+            ..self.clone()
+        }
+    }
+
+    /// Channel multipliers for `temperature` and `tint`.
+    pub fn white_balance(&self) -> [f32; 3] {
+        adjust::white_balance_multipliers(self.temperature, self.tint)
+    }
+}
+
+/// One slider of the develop panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adjustment {
+    Exposure,
+    Temperature,
+    Tint,
+    Contrast,
+    Highlights,
+    Shadows,
+    Whites,
+    Blacks,
+    Saturation,
+    Vibrance,
+}
+
+impl Adjustment {
+    pub const ALL: [Adjustment; 10] = [
+        Adjustment::Exposure,
+        Adjustment::Temperature,
+        Adjustment::Tint,
+        Adjustment::Contrast,
+        Adjustment::Highlights,
+        Adjustment::Shadows,
+        Adjustment::Whites,
+        Adjustment::Blacks,
+        Adjustment::Saturation,
+        Adjustment::Vibrance,
+    ];
+
+    /// Slider range and step.
+    pub fn range(self) -> (f32, f32, f32) {
+        match self {
+            Adjustment::Exposure => (-5.0, 5.0, 0.05),
+            _ => (-100.0, 100.0, 1.0),
+        }
+    }
+
+    pub fn get(self, params: &EditParams) -> f32 {
+        match self {
+            Adjustment::Exposure => params.exposure,
+            Adjustment::Temperature => params.temperature,
+            Adjustment::Tint => params.tint,
+            Adjustment::Contrast => params.contrast,
+            Adjustment::Highlights => params.highlights,
+            Adjustment::Shadows => params.shadows,
+            Adjustment::Whites => params.whites,
+            Adjustment::Blacks => params.blacks,
+            Adjustment::Saturation => params.saturation,
+            Adjustment::Vibrance => params.vibrance,
+        }
+    }
+
+    pub fn set(self, params: &mut EditParams, value: f32) {
+        let (min, max, _) = self.range();
+        let value = value.clamp(min, max);
+        match self {
+            Adjustment::Exposure => params.exposure = value,
+            Adjustment::Temperature => params.temperature = value,
+            Adjustment::Tint => params.tint = value,
+            Adjustment::Contrast => params.contrast = value,
+            Adjustment::Highlights => params.highlights = value,
+            Adjustment::Shadows => params.shadows = value,
+            Adjustment::Whites => params.whites = value,
+            Adjustment::Blacks => params.blacks = value,
+            Adjustment::Saturation => params.saturation = value,
+            Adjustment::Vibrance => params.vibrance = value,
+        }
+    }
+}
+
+impl fmt::Display for Adjustment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Long edge of the downscaled copy edits are previewed on.
+pub const PREVIEW_SIZE: u32 = 2560;
+
+/// A linear, display-sized copy of a decoded image to render edits on
+/// interactively.
+pub fn preview_source(image: &DynamicImage) -> Rgb32FImage {
+    let image = if image.width().max(image.height()) > PREVIEW_SIZE {
+        image.resize(PREVIEW_SIZE, PREVIEW_SIZE, FilterType::Triangle)
+    } else {
+        image.clone()
+    };
+    to_linear(&image)
+}
+
+/// Converts any decoded image to linear RGB floats. Float images are
+/// taken to be linear already (RAW decodes); integer ones sRGB-encoded.
+pub fn to_linear(image: &DynamicImage) -> Rgb32FImage {
+    match image {
+        DynamicImage::ImageRgb32F(linear) => linear.clone(),
+        DynamicImage::ImageRgba32F(_) => image.to_rgb32f(),
+        _ => {
+            let mut linear = image.to_rgb32f();
+            linear.pixels_mut().flat_map(|p| p.0.iter_mut()).for_each(|v| *v = adjust::srgb_decode(*v));
+            linear
+        }
+    }
+}
+
+/// Applies `params` to a linear image. With `white_balance` false the
+/// white balance is left alone because it was already applied to the RAW
+/// data.
+pub fn render(image: &Rgb32FImage, params: &EditParams, white_balance: bool) -> Rgb32FImage {
+    render_with(image, params, white_balance, Quality::Full, 1.0)
+}
+
+/// `scale` is the size of `image` relative to the full-size image, for
+/// settings given in full-size pixels.
+fn render_with(image: &Rgb32FImage, params: &EditParams, white_balance: bool, quality: Quality, scale: f32) -> Rgb32FImage {
+    if params.is_default() {
+        return image.clone();
+    }
+    let mut corrected = image.clone();
+    lens::apply(&mut corrected, &params.lens);
+    let mut output = crop::apply(&corrected, &params.crop);
+    denoise::apply(&mut output, &params.noise, quality);
+    sharpen::apply(&mut output, &params.sharpening, scale);
+    let multipliers = if white_balance { params.white_balance() } else { [1.0; 3] };
+    adjust::apply(&mut output, params, multipliers);
+    tone::apply(&mut output, &params.levels, &params.curve);
+    output
+}
+
+/// Decodes `path` at full resolution and renders `params` onto it, for
+/// export. Callers resolve `params` with [`EditParams::for_photo`] first.
+/// RAW files get their white balance before demosaicing and their
+/// highlights treated in the decoder.
+pub fn load(path: &Path, params: &EditParams) -> Result<DynamicImage> {
+    let is_raw = detector::detect_image_type(path).is_ok_and(|t| t.is_raw());
+    let decoded = if is_raw {
+        RawProcessor::new()
+            .with_white_balance(params.white_balance())
+            .with_highlight_mode(params.highlight_mode)
+            .load_image(path)?
+    } else {
+        processors::get_processor(path).load_image(path)?
+    };
+    if params.is_default() {
+        return Ok(decoded);
+    }
+    Ok(DynamicImage::ImageRgb32F(render(&to_linear(&decoded), params, !is_raw)))
+}
+
+/// Renders edits for the viewer at preview quality: 8-bit RGBA in the
+/// display's colour space. `scale` is the preview's size relative to the
+/// full image. With `crop_overlay` the whole straightened image is shown
+/// with the crop drawn over it. With `proof` it is shown as that output
+/// would reproduce it.
+pub fn render_display(
+    preview: &Rgb32FImage,
+    params: &EditParams,
+    scale: f32,
+    crop_overlay: bool,
+    proof: Option<&SoftProof>,
+) -> DisplayRender {
+    let (rendered, crop) = if crop_overlay {
+        let uncropped = EditParams { crop: Crop { angle: params.crop.angle, ..Crop::default() }, ..params.clone() };
+        (render_with(preview, &uncropped, true, Quality::Preview, scale), Some(params.crop))
+    } else {
+        (render_with(preview, params, true, Quality::Preview, scale), None)
+    };
+    let (image, out_of_gamut) = match proof {
+        Some(proof) => proof.to_display(&rendered),
+        None => (color::to_display(&rendered), Vec::new()),
+    };
+    DisplayRender { image, out_of_gamut, crop }
+}
+
+/// A rendering for the viewer, kept apart from what is drawn over it so
+/// the histogram only sees the photo.
+pub struct DisplayRender {
+    pub image: image::RgbaImage,
+    /// Pixels the soft-proofed output can't reproduce, if warned about.
+    out_of_gamut: Vec<bool>,
+    /// Crop to draw over the whole straightened image.
+    crop: Option<Crop>,
+}
+
+impl DisplayRender {
+    /// The image with the gamut warning and crop overlay drawn on.
+    pub fn with_overlays(mut self) -> image::RgbaImage {
+        color::draw_gamut_warning(&mut self.image, &self.out_of_gamut);
+        if let Some(crop) = &self.crop {
+            crop::draw_overlay(&mut self.image, crop);
+        }
+        self.image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_neutral_params_are_identity() {
+        let image = Rgb32FImage::from_fn(16, 16, |x, y| image::Rgb([x as f32 / 16.0, y as f32 / 16.0, 0.25]));
+        assert_eq!(render(&image, &EditParams::default(), true), image);
+    }
+
+    #[test]
+    fn test_overlays_are_drawn_apart() {
+        let preview = Rgb32FImage::from_pixel(30, 20, image::Rgb([0.5; 3]));
+        let params = EditParams { crop: Crop { left: 0.5, ..Crop::default() }, ..Default::default() };
+        let rendered = render_display(&preview, &params, 1.0, true, None);
+        // The whole image, undimmed, for the histogram
+        assert_eq!(rendered.image.dimensions(), (30, 20));
+        let plain = rendered.image.get_pixel(0, 0).0;
+        assert_eq!(rendered.image.get_pixel(29, 10).0, plain);
+
+        let display = rendered.with_overlays();
+        assert!(display.get_pixel(0, 0)[0] < plain[0]);
+    }
+
+    #[test]
+    fn test_exposure_and_white_balance() {
+        let grey = Rgb32FImage::from_pixel(1, 1, image::Rgb([0.18, 0.18, 0.18]));
+
+        let brighter = render(&grey, &EditParams { exposure: 1.0, ..Default::default() }, true);
+        assert!((brighter.get_pixel(0, 0)[1] - 0.36).abs() < 1e-4);
+
+        let warmer = render(&grey, &EditParams { temperature: 50.0, ..Default::default() }, true);
+        let [r, _, b] = warmer.get_pixel(0, 0).0;
+        assert!(r > 0.18 && b < 0.18);
+
+        // Already applied to the RAW data
+        let raw = render(&grey, &EditParams { temperature: 50.0, ..Default::default() }, false);
+        assert_eq!(raw, grey);
+    }
+
+    #[test]
+    fn test_shadows_and_saturation() {
+        let dark = Rgb32FImage::from_pixel(1, 1, image::Rgb([0.02, 0.01, 0.01]));
+        let lifted = render(&dark, &EditParams { shadows: 100.0, ..Default::default() }, true);
+        assert!(lifted.get_pixel(0, 0)[0] > 0.02);
+
+        let grey = render(&dark, &EditParams { saturation: -100.0, ..Default::default() }, true);
+        let [r, g, b] = grey.get_pixel(0, 0).0;
+        assert!((r - g).abs() < 1e-6 && (g - b).abs() < 1e-6);
+    }
+}
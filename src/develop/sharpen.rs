@@ -0,0 +1,194 @@
+//! Unsharp-mask sharpening, used both as a develop setting (capture
+//! sharpening) and on the resized image at export (output sharpening).
+
+use std::fmt;
+use image::Rgb32FImage;
+
+use super::adjust::{srgb_decode, LUMA};
+use crate::photo::srgb_encode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sharpening {
+    /// Strength in percent; 0 is off.
+    pub amount: f32,
+    /// Blur radius in pixels of the full-size image.
+    pub radius: f32,
+    /// Differences smaller than this many 8-bit levels are left alone, so
+    /// smooth areas don't get grainy.
+    pub threshold: f32,
+    /// 0 sharpens everywhere; higher values restrict it to ever stronger edges.
+    pub masking: f32,
+}
+
+impl Default for Sharpening {
+    fn default() -> Self {
+        Self { amount: 0.0, radius: 1.0, threshold: 0.0, masking: 0.0 }
+    }
+}
+
+/// One value of [`Sharpening`], for sliders and sidecar properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharpenField {
+    Amount,
+    Radius,
+    Threshold,
+    Masking,
+}
+
+impl SharpenField {
+    pub const ALL: [SharpenField; 4] =
+        [SharpenField::Amount, SharpenField::Radius, SharpenField::Threshold, SharpenField::Masking];
+
+    /// Slider range and step.
+    pub fn range(self) -> (f32, f32, f32) {
+        match self {
+            SharpenField::Amount => (0.0, 200.0, 1.0),
+            SharpenField::Radius => (0.3, 3.0, 0.1),
+            SharpenField::Threshold => (0.0, 50.0, 1.0),
+            SharpenField::Masking => (0.0, 100.0, 1.0),
+        }
+    }
+
+    pub fn get(self, sharpening: &Sharpening) -> f32 {
+        match self {
+            SharpenField::Amount => sharpening.amount,
+            SharpenField::Radius => sharpening.radius,
+            SharpenField::Threshold => sharpening.threshold,
+            SharpenField::Masking => sharpening.masking,
+        }
+    }
+
+    pub fn set(self, sharpening: &mut Sharpening, value: f32) {
+        let (min, max, _) = self.range();
+        let value = value.clamp(min, max);
+        match self {
+            SharpenField::Amount => sharpening.amount = value,
+            SharpenField::Radius => sharpening.radius = value,
+            SharpenField::Threshold => sharpening.threshold = value,
+            SharpenField::Masking => sharpening.masking = value,
+        }
+    }
+}
+
+impl fmt::Display for SharpenField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Sharpens the luminance of a linear image. `scale` is the image's size
+/// relative to the full-size one the radius is given for.
+pub fn apply(image: &mut Rgb32FImage, sharpening: &Sharpening, scale: f32) {
+    if sharpening.amount <= 0.0 {
+        return;
+    }
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    // Work on encoded luminance, where a difference looks the same size
+    // in shadows and highlights
+    let luma: Vec<f32> = image
+        .pixels()
+        .map(|p| srgb_encode((p[0] * LUMA[0] + p[1] * LUMA[1] + p[2] * LUMA[2]).max(0.0)))
+        .collect();
+    let blurred = gaussian_blur(&luma, width, height, (sharpening.radius * scale).max(0.3));
+    let mask = edge_mask(&blurred, width, height, sharpening.masking);
+    let amount = sharpening.amount / 100.0;
+    let threshold = sharpening.threshold / 255.0;
+
+    for (i, pixel) in image.pixels_mut().enumerate() {
+        let detail = luma[i] - blurred[i];
+        if detail.abs() <= threshold || luma[i] <= 0.0 {
+            continue;
+        }
+        let weight = mask.as_ref().map_or(1.0, |mask| mask[i]);
+        let sharpened = (luma[i] + detail * amount * weight).max(0.0);
+        let ratio = srgb_decode(sharpened) / srgb_decode(luma[i]);
+        pixel.0 = pixel.0.map(|v| v * ratio);
+    }
+}
+
+/// Where to sharpen, from 0 (flat) to 1 (edge), or `None` for everywhere.
+fn edge_mask(luma: &[f32], width: usize, height: usize, masking: f32) -> Option<Vec<f32>> {
+    if masking <= 0.0 || width < 3 || height < 3 {
+        return None;
+    }
+    // Gradient that counts as a full edge at this masking
+    let level = masking / 100.0 * 0.1;
+    let mut mask = vec![0.0; luma.len()];
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let at = |dx: isize, dy: isize| luma[(y as isize + dy) as usize * width + (x as isize + dx) as usize];
+            let gx = at(1, -1) + 2.0 * at(1, 0) + at(1, 1) - at(-1, -1) - 2.0 * at(-1, 0) - at(-1, 1);
+            let gy = at(-1, 1) + 2.0 * at(0, 1) + at(1, 1) - at(-1, -1) - 2.0 * at(0, -1) - at(1, -1);
+            let gradient = (gx * gx + gy * gy).sqrt() / 8.0;
+            let t = (gradient / level).clamp(0.0, 1.0);
+            mask[y * width + x] = t * t * (3.0 - 2.0 * t);
+        }
+    }
+    Some(mask)
+}
+
+fn gaussian_blur(values: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    let reach = (sigma * 3.0).ceil() as isize;
+    let mut kernel: Vec<f32> = (-reach..=reach).map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|k| *k /= total);
+
+    let clamp = |i: isize, len: usize| i.clamp(0, len as isize - 1) as usize;
+    let mut rows = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            rows[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| weight * values[y * width + clamp(x as isize + k as isize - reach, width)])
+                .sum();
+        }
+    }
+    let mut output = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            output[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| weight * rows[clamp(y as isize + k as isize - reach, height) * width + x])
+                .sum();
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A soft vertical edge with a faint ripple on the dark side.
+    fn edge() -> Rgb32FImage {
+        Rgb32FImage::from_fn(32, 8, |x, _| {
+            let v = if x < 16 { 0.05 + 0.001 * (x % 2) as f32 } else { 0.5 };
+            image::Rgb([v; 3])
+        })
+    }
+
+    #[test]
+    fn test_sharpening_adds_contrast_at_edges() {
+        let mut image = edge();
+        apply(&mut image, &Sharpening { amount: 100.0, ..Default::default() }, 1.0);
+        // Overshoot on both sides of the edge
+        assert!(image.get_pixel(15, 4)[0] < 0.05);
+        assert!(image.get_pixel(16, 4)[0] > 0.5);
+
+        // Off by default
+        let mut unchanged = edge();
+        apply(&mut unchanged, &Sharpening::default(), 1.0);
+        assert_eq!(unchanged, edge());
+    }
+
+    #[test]
+    fn test_threshold_and_masking_spare_flat_areas() {
+        let sharpening = Sharpening { amount: 100.0, threshold: 5.0, masking: 50.0, ..Default::default() };
+        let mut image = edge();
+        apply(&mut image, &sharpening, 1.0);
+        assert_eq!(image.get_pixel(4, 4), edge().get_pixel(4, 4));
+        assert!(image.get_pixel(16, 4)[0] > 0.5);
+    }
+}
@@ -0,0 +1,189 @@
+//! Copying develop settings between photos, in whole or by group.
+
+use std::fmt;
+
+use super::EditParams;
+
+/// Settings that are copied together. Every field of [`EditParams`]
+/// belongs to exactly one group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SettingsGroup {
+    WhiteBalance,
+    Exposure,
+    /// Contrast, highlights, shadows, whites and blacks.
+    Tone,
+    /// Saturation and vibrance.
+    Color,
+    /// Levels and tone curves.
+    Curves,
+    NoiseReduction,
+    Sharpening,
+    HighlightRecovery,
+    LensCorrections,
+    Crop,
+}
+
+impl SettingsGroup {
+    pub const ALL: [SettingsGroup; 10] = [
+        SettingsGroup::WhiteBalance,
+        SettingsGroup::Exposure,
+        SettingsGroup::Tone,
+        SettingsGroup::Color,
+        SettingsGroup::Curves,
+        SettingsGroup::NoiseReduction,
+        SettingsGroup::Sharpening,
+        SettingsGroup::HighlightRecovery,
+        SettingsGroup::LensCorrections,
+        SettingsGroup::Crop,
+    ];
+
+    /// Parses the variant name, as stored in preset files.
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|group| format!("{:?}", group) == value)
+    }
+
+    /// Copies this group's settings from `from` into `to`.
+    pub fn copy(self, from: &EditParams, to: &mut EditParams) {
+        match self {
+            SettingsGroup::WhiteBalance => {
+                to.temperature = from.temperature;
+                to.tint = from.tint;
+            }
+            SettingsGroup::Exposure => to.exposure = from.exposure,
+            SettingsGroup::Tone => {
+                to.contrast = from.contrast;
+                to.highlights = from.highlights;
+                to.shadows = from.shadows;
+                to.whites = from.whites;
+                to.blacks = from.blacks;
+            }
+            SettingsGroup::Color => {
+                to.saturation = from.saturation;
+                to.vibrance = from.vibrance;
+            }
+            SettingsGroup::Curves => {
+                to.levels = from.levels;
+                to.curve = from.curve.clone();
+            }
+            SettingsGroup::NoiseReduction => to.noise = from.noise,
+            SettingsGroup::Sharpening => to.sharpening = from.sharpening,
+            SettingsGroup::HighlightRecovery => to.highlight_mode = from.highlight_mode,
+            SettingsGroup::LensCorrections => to.lens = from.lens,
+            SettingsGroup::Crop => to.crop = from.crop,
+        }
+    }
+
+    /// Whether this group differs between `a` and `b`.
+    pub fn differs(self, a: &EditParams, b: &EditParams) -> bool {
+        let mut copied = a.clone();
+        self.copy(b, &mut copied);
+        copied != *a
+    }
+}
+
+impl fmt::Display for SettingsGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SettingsGroup::WhiteBalance => "White balance",
+            SettingsGroup::Exposure => "Exposure",
+            SettingsGroup::Tone => "Tone",
+            SettingsGroup::Color => "Color",
+            SettingsGroup::Curves => "Levels and curves",
+            SettingsGroup::NoiseReduction => "Noise reduction",
+            SettingsGroup::Sharpening => "Sharpening",
+            SettingsGroup::HighlightRecovery => "Highlight recovery",
+            SettingsGroup::LensCorrections => "Lens corrections",
+            SettingsGroup::Crop => "Crop",
+        })
+    }
+}
+
+/// `onto` with `groups` taken from `from`.
+pub fn paste(from: &EditParams, onto: &EditParams, groups: &[SettingsGroup]) -> EditParams {
+    let mut pasted = onto.clone();
+    for group in groups {
+        group.copy(from, &mut pasted);
+    }
+    pasted
+}
+
+/// Groups that differ between two versions of a photo's settings.
+pub fn changed(before: &EditParams, after: &EditParams) -> Vec<SettingsGroup> {
+    SettingsGroup::ALL.into_iter().filter(|group| group.differs(before, after)).collect()
+}
+
+/// Copied settings and how they are pasted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditClipboard {
+    pub copied: Option<EditParams>,
+    /// Groups that are pasted.
+    pub groups: Vec<SettingsGroup>,
+    /// Apply every change to the current photo to the other selected ones
+    /// as well.
+    pub sync: bool,
+}
+
+impl Default for EditClipboard {
+    fn default() -> Self {
+        Self { copied: None, groups: SettingsGroup::ALL.to_vec(), sync: false }
+    }
+}
+
+impl EditClipboard {
+    pub fn toggle(&mut self, group: SettingsGroup, enabled: bool) {
+        self.groups.retain(|g| *g != group);
+        if enabled {
+            self.groups.push(group);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develop::crop::Crop;
+
+    fn edited() -> EditParams {
+        let mut params = EditParams {
+            exposure: 1.5,
+            temperature: 20.0,
+            shadows: 30.0,
+            vibrance: 10.0,
+            crop: Crop { left: 0.2, ..Default::default() },
+            ..Default::default()
+        };
+        params.curve.rgb.add_point(0.5, 0.6);
+        params.sharpening.amount = 40.0;
+        params.lens.distortion = Some(10.0);
+        params
+    }
+
+    #[test]
+    fn test_paste_selected_groups() {
+        let source = edited();
+        let target = EditParams { exposure: -1.0, contrast: 15.0, ..Default::default() };
+
+        let pasted = paste(&source, &target, &[SettingsGroup::WhiteBalance, SettingsGroup::Curves]);
+        assert_eq!(pasted.temperature, 20.0);
+        assert_eq!(pasted.curve, source.curve);
+        assert_eq!((pasted.exposure, pasted.contrast), (-1.0, 15.0));
+        assert_eq!(pasted.crop, Crop::default());
+
+        // The groups cover every setting
+        assert_eq!(paste(&source, &target, &SettingsGroup::ALL), source);
+    }
+
+    #[test]
+    fn test_changed_groups() {
+        let before = edited();
+        let mut after = before.clone();
+        after.exposure = 2.0;
+        after.crop.angle = 1.0;
+        assert_eq!(changed(&before, &after), vec![SettingsGroup::Exposure, SettingsGroup::Crop]);
+        assert!(changed(&before, &before).is_empty());
+
+        let mut clipboard = EditClipboard::default();
+        clipboard.toggle(SettingsGroup::Crop, false);
+        assert_eq!(clipboard.groups.len(), SettingsGroup::ALL.len() - 1);
+    }
+}
@@ -0,0 +1,388 @@
+//! Levels and tone curves. Both work on sRGB-encoded values, where the
+//! points of a curve mean what they look like, and are applied after the
+//! basic adjustments.
+
+use std::fmt;
+use image::Rgb32FImage;
+
+use super::adjust::srgb_decode;
+use crate::photo::srgb_encode;
+
+/// Input and output range remapping with a midtone gamma.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Levels {
+    pub input_black: f32,
+    pub input_white: f32,
+    /// Above 1 brightens the midtones.
+    pub gamma: f32,
+    pub output_black: f32,
+    pub output_white: f32,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self { input_black: 0.0, input_white: 1.0, gamma: 1.0, output_black: 0.0, output_white: 1.0 }
+    }
+}
+
+impl Levels {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn apply(&self, value: f32) -> f32 {
+        let range = (self.input_white - self.input_black).max(1e-4);
+        let normalized = ((value - self.input_black) / range).clamp(0.0, 1.0);
+        let corrected = normalized.powf(1.0 / self.gamma);
+        self.output_black + corrected * (self.output_white - self.output_black)
+    }
+}
+
+/// One value of [`Levels`], for sliders and sidecar properties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelsField {
+    InputBlack,
+    InputWhite,
+    Gamma,
+    OutputBlack,
+    OutputWhite,
+}
+
+impl LevelsField {
+    pub const ALL: [LevelsField; 5] = [
+        LevelsField::InputBlack,
+        LevelsField::InputWhite,
+        LevelsField::Gamma,
+        LevelsField::OutputBlack,
+        LevelsField::OutputWhite,
+    ];
+
+    /// Slider range and step.
+    pub fn range(self) -> (f32, f32, f32) {
+        match self {
+            LevelsField::Gamma => (0.1, 5.0, 0.01),
+            _ => (0.0, 1.0, 0.005),
+        }
+    }
+
+    pub fn get(self, levels: &Levels) -> f32 {
+        match self {
+            LevelsField::InputBlack => levels.input_black,
+            LevelsField::InputWhite => levels.input_white,
+            LevelsField::Gamma => levels.gamma,
+            LevelsField::OutputBlack => levels.output_black,
+            LevelsField::OutputWhite => levels.output_white,
+        }
+    }
+
+    /// Sets the value, keeping the input black point below the white point.
+    pub fn set(self, levels: &mut Levels, value: f32) {
+        let (min, max, _) = self.range();
+        let value = value.clamp(min, max);
+        match self {
+            LevelsField::InputBlack => levels.input_black = value.min(levels.input_white - 0.01),
+            LevelsField::InputWhite => levels.input_white = value.max(levels.input_black + 0.01),
+            LevelsField::Gamma => levels.gamma = value,
+            LevelsField::OutputBlack => levels.output_black = value,
+            LevelsField::OutputWhite => levels.output_white = value,
+        }
+    }
+}
+
+impl fmt::Display for LevelsField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Closest two points of a curve may get horizontally.
+const MIN_GAP: f32 = 0.01;
+
+/// Monotone cubic spline through control points in [0, 1].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve {
+    /// Sorted by x; the first and last are the end points.
+    points: Vec<(f32, f32)>,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self { points: vec![(0.0, 0.0), (1.0, 1.0)] }
+    }
+}
+
+impl Curve {
+    pub fn is_identity(&self) -> bool {
+        self.points.iter().all(|(x, y)| x == y)
+    }
+
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points
+    }
+
+    /// Adds a point and returns its index. A point too close to an
+    /// existing one moves that one instead.
+    pub fn add_point(&mut self, x: f32, y: f32) -> usize {
+        let x = x.clamp(0.0, 1.0);
+        if let Some(index) = self.points.iter().position(|p| (p.0 - x).abs() < MIN_GAP) {
+            self.move_point(index, x, y);
+            return index;
+        }
+        let index = self.points.iter().position(|p| p.0 > x).unwrap_or(self.points.len());
+        self.points.insert(index, (x, y.clamp(0.0, 1.0)));
+        index
+    }
+
+    /// Moves a point, keeping it between its neighbours.
+    pub fn move_point(&mut self, index: usize, x: f32, y: f32) {
+        let min = if index == 0 { 0.0 } else { self.points[index - 1].0 + MIN_GAP };
+        let max = self.points.get(index + 1).map_or(1.0, |p| p.0 - MIN_GAP);
+        self.points[index] = (x.clamp(min, max.max(min)), y.clamp(0.0, 1.0));
+    }
+
+    /// Removes a point; the end points stay.
+    pub fn remove_point(&mut self, index: usize) {
+        if index > 0 && index + 1 < self.points.len() {
+            self.points.remove(index);
+        }
+    }
+
+    /// Value of the curve at `x`, flat beyond the end points.
+    pub fn eval(&self, x: f32) -> f32 {
+        let points = &self.points;
+        let (first, last) = (points[0], points[points.len() - 1]);
+        if x <= first.0 {
+            return first.1;
+        }
+        if x >= last.0 {
+            return last.1;
+        }
+        let i = points.iter().rposition(|p| p.0 <= x).unwrap_or(0).min(points.len() - 2);
+        let tangents = self.tangents();
+        let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+        let h = x1 - x0;
+        let t = (x - x0) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        let y = (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+            + (t3 - 2.0 * t2 + t) * h * tangents[i]
+            + (-2.0 * t3 + 3.0 * t2) * y1
+            + (t3 - t2) * h * tangents[i + 1];
+        y.clamp(0.0, 1.0)
+    }
+
+    /// Fritsch-Carlson tangents, which keep the spline from overshooting
+    /// between points.
+    fn tangents(&self) -> Vec<f32> {
+        let points = &self.points;
+        let slopes: Vec<f32> = points.windows(2).map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0)).collect();
+        let mut tangents = Vec::with_capacity(points.len());
+        tangents.push(slopes[0]);
+        for pair in slopes.windows(2) {
+            let (before, after) = (pair[0], pair[1]);
+            if before * after <= 0.0 {
+                tangents.push(0.0);
+            } else {
+                // Harmonic mean
+                tangents.push(2.0 / (1.0 / before + 1.0 / after));
+            }
+        }
+        tangents.push(slopes[slopes.len() - 1]);
+        tangents
+    }
+
+    /// Inverse of `Display`. Needs at least two points in increasing order.
+    pub fn parse(value: &str) -> Option<Self> {
+        let points = value
+            .split_whitespace()
+            .map(|point| {
+                let (x, y) = point.split_once(',')?;
+                Some((x.parse::<f32>().ok()?.clamp(0.0, 1.0), y.parse::<f32>().ok()?.clamp(0.0, 1.0)))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let increasing = points.windows(2).all(|w| w[0].0 < w[1].0);
+        (points.len() >= 2 && increasing).then_some(Self { points })
+    }
+}
+
+/// Space separated `x,y` pairs, as stored in sidecars.
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let points: Vec<String> = self.points.iter().map(|(x, y)| format!("{},{}", x, y)).collect();
+        f.write_str(&points.join(" "))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CurveChannel {
+    #[default]
+    Rgb,
+    Red,
+    Green,
+    Blue,
+}
+
+impl CurveChannel {
+    pub const ALL: [CurveChannel; 4] = [CurveChannel::Rgb, CurveChannel::Red, CurveChannel::Green, CurveChannel::Blue];
+}
+
+impl fmt::Display for CurveChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CurveChannel::Rgb => f.write_str("RGB"),
+            channel => write!(f, "{:?}", channel),
+        }
+    }
+}
+
+/// A master curve followed by one per channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToneCurve {
+    pub rgb: Curve,
+    pub red: Curve,
+    pub green: Curve,
+    pub blue: Curve,
+}
+
+impl ToneCurve {
+    pub fn is_identity(&self) -> bool {
+        CurveChannel::ALL.iter().all(|&c| self.get(c).is_identity())
+    }
+
+    pub fn get(&self, channel: CurveChannel) -> &Curve {
+        match channel {
+            CurveChannel::Rgb => &self.rgb,
+            CurveChannel::Red => &self.red,
+            CurveChannel::Green => &self.green,
+            CurveChannel::Blue => &self.blue,
+        }
+    }
+
+    pub fn get_mut(&mut self, channel: CurveChannel) -> &mut Curve {
+        match channel {
+            CurveChannel::Rgb => &mut self.rgb,
+            CurveChannel::Red => &mut self.red,
+            CurveChannel::Green => &mut self.green,
+            CurveChannel::Blue => &mut self.blue,
+        }
+    }
+}
+
+/// Master levels followed by levels per channel, like [`ToneCurve`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ChannelLevels {
+    pub rgb: Levels,
+    pub red: Levels,
+    pub green: Levels,
+    pub blue: Levels,
+}
+
+impl ChannelLevels {
+    pub fn is_identity(&self) -> bool {
+        CurveChannel::ALL.iter().all(|&c| self.get(c).is_identity())
+    }
+
+    pub fn get(&self, channel: CurveChannel) -> &Levels {
+        match channel {
+            CurveChannel::Rgb => &self.rgb,
+            CurveChannel::Red => &self.red,
+            CurveChannel::Green => &self.green,
+            CurveChannel::Blue => &self.blue,
+        }
+    }
+
+    pub fn get_mut(&mut self, channel: CurveChannel) -> &mut Levels {
+        match channel {
+            CurveChannel::Rgb => &mut self.rgb,
+            CurveChannel::Red => &mut self.red,
+            CurveChannel::Green => &mut self.green,
+            CurveChannel::Blue => &mut self.blue,
+        }
+    }
+}
+
+/// Entries in the lookup tables the curves are baked into.
+const LUT_SIZE: usize = 4096;
+
+/// Applies levels and then the curves to a linear image.
+pub fn apply(image: &mut Rgb32FImage, levels: &ChannelLevels, curves: &ToneCurve) {
+    if levels.is_identity() && curves.is_identity() {
+        return;
+    }
+    let channels = [
+        (&levels.red, &curves.red),
+        (&levels.green, &curves.green),
+        (&levels.blue, &curves.blue),
+    ];
+    let luts: Vec<Vec<f32>> = channels
+        .iter()
+        .map(|(channel_levels, channel_curve)| {
+            (0..LUT_SIZE)
+                .map(|i| {
+                    let encoded = i as f32 / (LUT_SIZE - 1) as f32;
+                    let leveled = channel_levels.apply(levels.rgb.apply(encoded));
+                    srgb_decode(channel_curve.eval(curves.rgb.eval(leveled)))
+                })
+                .collect()
+        })
+        .collect();
+
+    for pixel in image.pixels_mut() {
+        for (value, lut) in pixel.0.iter_mut().zip(&luts) {
+            let position = srgb_encode(value.clamp(0.0, 1.0)) * (LUT_SIZE - 1) as f32;
+            let i = (position as usize).min(LUT_SIZE - 2);
+            let fraction = position - i as f32;
+            *value = lut[i] + (lut[i + 1] - lut[i]) * fraction;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_curve_points() {
+        let mut curve = Curve::default();
+        assert!(curve.is_identity());
+        let index = curve.add_point(0.5, 0.7);
+        assert_eq!(index, 1);
+        assert!((curve.eval(0.5) - 0.7).abs() < 1e-6);
+        // Monotone: no overshoot past the neighbouring points
+        assert!((0..=100).map(|i| curve.eval(i as f32 / 100.0)).all(|y| (0.0..=1.0).contains(&y)));
+
+        // Points can't be dragged past their neighbours
+        curve.move_point(1, 1.5, 0.7);
+        assert!(curve.points()[1].0 < 1.0);
+
+        assert_eq!(Curve::parse(&curve.to_string()), Some(curve.clone()));
+        curve.remove_point(0);
+        assert_eq!(curve.points().len(), 3);
+        curve.remove_point(1);
+        assert!(curve.is_identity());
+        assert_eq!(Curve::parse("0.5,0 0.2,1"), None);
+    }
+
+    #[test]
+    fn test_levels_and_curves() {
+        let levels = Levels { input_black: 0.2, input_white: 0.8, ..Default::default() };
+        assert_eq!(levels.apply(0.1), 0.0);
+        assert!((levels.apply(0.5) - 0.5).abs() < 1e-6);
+
+        let mut image = Rgb32FImage::from_pixel(1, 1, image::Rgb([0.18, 0.18, 0.18]));
+        let mut curves = ToneCurve::default();
+        curves.red.add_point(0.5, 0.8);
+        apply(&mut image, &ChannelLevels::default(), &curves);
+        let [r, g, b] = image.get_pixel(0, 0).0;
+        assert!(r > 0.18);
+        assert!((g - 0.18).abs() < 1e-3 && (b - 0.18).abs() < 1e-3);
+
+        // Levels on one channel leave the others alone
+        let mut image = Rgb32FImage::from_pixel(1, 1, image::Rgb([0.18, 0.18, 0.18]));
+        let mut levels = ChannelLevels::default();
+        levels.get_mut(CurveChannel::Blue).gamma = 2.0;
+        apply(&mut image, &levels, &ToneCurve::default());
+        let [r, g, b] = image.get_pixel(0, 0).0;
+        assert!(b > 0.18);
+        assert!((r - 0.18).abs() < 1e-3 && (g - 0.18).abs() < 1e-3);
+    }
+}
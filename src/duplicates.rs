@@ -0,0 +1,257 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use tracing::{debug, info};
+use walkdir::WalkDir;
+
+use crate::grouping;
+use crate::photo::{self, ExifData};
+use crate::processors::detector;
+
+/// Maximum Hamming distance between perceptual hashes for two images to be
+/// reported as near duplicates. Tighter than burst grouping since these
+/// should be the same frame, not neighbouring ones.
+const SIMILAR_DISTANCE: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateKind {
+    /// Byte-for-byte identical files.
+    Exact,
+    /// Different files showing the same picture, e.g. a JPEG exported from a RAW.
+    Similar,
+}
+
+#[derive(Debug, Clone)]
+pub struct DuplicateFile {
+    pub path: PathBuf,
+    pub size: u64,
+    pub exif: Option<ExifData>,
+}
+
+#[derive(Debug, Clone)]
+pub struct DuplicateGroup {
+    pub kind: DuplicateKind,
+    pub files: Vec<DuplicateFile>,
+}
+
+impl DuplicateGroup {
+    /// The file to keep unless the user picks another: the largest, which
+    /// is usually the original rather than a re-encoded copy.
+    pub fn suggested_keep(&self) -> usize {
+        self.files
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, file)| file.size)
+            .map_or(0, |(i, _)| i)
+    }
+}
+
+/// Recursively lists the images under `folders`.
+pub fn collect_images(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = folders
+        .iter()
+        .flat_map(|folder| WalkDir::new(folder).follow_links(false))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file() && detector::has_image_extension(entry.path()))
+        .map(|entry| entry.into_path())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// BLAKE3 hash of a file's contents, as hex.
+pub fn content_hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Scans `folders` for exact and near-duplicate images.
+pub fn find_duplicates(folders: &[PathBuf]) -> Vec<DuplicateGroup> {
+    let paths = collect_images(folders);
+    info!("Scanning {} images for duplicates", paths.len());
+
+    // Only files of equal size can be identical, so hash just those
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for path in &paths {
+        if let Ok(metadata) = std::fs::metadata(path) {
+            by_size.entry(metadata.len()).or_default().push(path.clone());
+        }
+    }
+
+    let mut by_hash: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for candidates in by_size.into_values().filter(|c| c.len() > 1) {
+        for path in candidates {
+            match content_hash(&path) {
+                Ok(hash) => by_hash.entry(hash).or_default().push(path),
+                Err(e) => debug!("Skipping {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    let mut exact_copies: HashSet<PathBuf> = HashSet::new();
+    for mut files in by_hash.into_values().filter(|f| f.len() > 1) {
+        files.sort();
+        // Near-duplicate detection only needs one copy of each content
+        exact_copies.extend(files[1..].iter().cloned());
+        groups.push(group_of(DuplicateKind::Exact, files));
+    }
+
+    let unique: Vec<&PathBuf> = paths.iter().filter(|p| !exact_copies.contains(*p)).collect();
+    let hashes: Vec<Option<u64>> = unique
+        .iter()
+        .map(|path| match photo::load_preview(path) {
+            Ok(image) => Some(grouping::perceptual_hash(&image)),
+            Err(e) => {
+                debug!("Can't hash {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect();
+
+    // Union-find over every pair of similar images
+    let mut parent: Vec<usize> = (0..unique.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for (a, b) in similar_pairs(&hashes) {
+        // A RAW and its in-camera JPEG are a pair, not a duplicate
+        if is_pair(unique[a], unique[b]) {
+            continue;
+        }
+        let (root_a, root_b) = (root(&mut parent, a), root(&mut parent, b));
+        parent[root_b] = root_a;
+    }
+
+    let mut similar: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+    for (i, path) in unique.iter().enumerate() {
+        let r = root(&mut parent, i);
+        similar.entry(r).or_default().push((*path).clone());
+    }
+    groups.extend(
+        similar
+            .into_values()
+            .filter(|files| files.len() > 1)
+            .map(|files| group_of(DuplicateKind::Similar, files)),
+    );
+
+    groups.sort_by(|a, b| a.files[0].path.cmp(&b.files[0].path));
+    info!("Found {} duplicate groups", groups.len());
+    groups
+}
+
+/// Index pairs of hashes within `SIMILAR_DISTANCE` of each other.
+///
+/// The hashes are cut into `SIMILAR_DISTANCE + 1` bands; two hashes that
+/// differ in at most that many bits agree completely on at least one band,
+/// so only hashes sharing a band need comparing.
+fn similar_pairs(hashes: &[Option<u64>]) -> Vec<(usize, usize)> {
+    const BANDS: u32 = SIMILAR_DISTANCE + 1;
+    let mut pairs = HashSet::new();
+    for band in 0..BANDS {
+        let (start, end) = (64 * band / BANDS, 64 * (band + 1) / BANDS);
+        let mask = (u64::MAX >> (64 - (end - start))) << start;
+        let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            if let Some(hash) = hash {
+                buckets.entry(hash & mask).or_default().push(i);
+            }
+        }
+        for bucket in buckets.values() {
+            for (n, &a) in bucket.iter().enumerate() {
+                for &b in &bucket[n + 1..] {
+                    let (hash_a, hash_b) = (hashes[a].unwrap_or_default(), hashes[b].unwrap_or_default());
+                    if grouping::hamming_distance(hash_a, hash_b) <= SIMILAR_DISTANCE {
+                        pairs.insert((a, b));
+                    }
+                }
+            }
+        }
+    }
+    let mut pairs: Vec<_> = pairs.into_iter().collect();
+    pairs.sort_unstable();
+    pairs
+}
+
+fn group_of(kind: DuplicateKind, paths: Vec<PathBuf>) -> DuplicateGroup {
+    let files = paths
+        .into_iter()
+        .map(|path| DuplicateFile {
+            size: std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0),
+            exif: ExifData::read(&path).ok(),
+            path,
+        })
+        .collect();
+    DuplicateGroup { kind, files }
+}
+
+fn is_pair(a: &Path, b: &Path) -> bool {
+    a.parent() == b.parent()
+        && a.file_stem().map(|s| s.to_ascii_lowercase()) == b.file_stem().map(|s| s.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_duplicates_across_folders() {
+        let root = tempfile::tempdir().unwrap();
+        let (card, archive) = (root.path().join("card"), root.path().join("archive"));
+        std::fs::create_dir_all(&card).unwrap();
+        std::fs::create_dir_all(&archive).unwrap();
+        std::fs::write(card.join("DSCF0001.JPG"), b"same bytes").unwrap();
+        std::fs::write(archive.join("DSCF0001.JPG"), b"same bytes").unwrap();
+        std::fs::write(archive.join("DSCF0002.JPG"), b"other byte").unwrap();
+
+        let groups = find_duplicates(&[card.clone(), archive.clone()]);
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].kind, DuplicateKind::Exact);
+        assert_eq!(groups[0].files.len(), 2);
+    }
+
+    #[test]
+    fn test_similar_pairs_match_exhaustive_search() {
+        // Hashes clustered around a few bases, so there are pairs within
+        // and just beyond the distance
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let bases: Vec<u64> = (0..4).map(|_| next()).collect();
+        let mut hashes: Vec<Option<u64>> = (0..200)
+            .map(|i| {
+                let flips = next() % 7;
+                Some((0..flips).fold(bases[i % 4], |hash, _| hash ^ (1 << (next() % 64))))
+            })
+            .collect();
+        hashes[17] = None;
+
+        let mut expected = Vec::new();
+        for a in 0..hashes.len() {
+            for b in a + 1..hashes.len() {
+                if let (Some(x), Some(y)) = (hashes[a], hashes[b]) {
+                    if grouping::hamming_distance(x, y) <= SIMILAR_DISTANCE {
+                        expected.push((a, b));
+                    }
+                }
+            }
+        }
+        assert!(!expected.is_empty());
+        assert_eq!(similar_pairs(&hashes), expected);
+    }
+}
@@ -0,0 +1,769 @@
+use std::fmt;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use exif::{experimental::Writer, Context as IfdContext, Field, In, Tag, Value};
+use image::{imageops::FilterType, DynamicImage, ImageOutputFormat};
+use tiff::encoder::{colortype::{self, ColorType}, TiffValue};
+use tracing::{debug, info, warn};
+
+use crate::color::ColorSpace;
+use crate::develop::{self, sharpen::{self, Sharpening}};
+use crate::photo::{self, ExifData};
+use crate::processors::detector;
+use crate::rename::Template;
+use crate::sidecar;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Jpeg,
+    Png,
+    Tiff,
+    /// Written lossless; the lossy encoder needs libwebp.
+    WebP,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 4] = [ExportFormat::Jpeg, ExportFormat::Png, ExportFormat::Tiff, ExportFormat::WebP];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::Png => "png",
+            ExportFormat::Tiff => "tif",
+            ExportFormat::WebP => "webp",
+        }
+    }
+
+    pub fn supports_16_bit(self) -> bool {
+        matches!(self, ExportFormat::Png | ExportFormat::Tiff)
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportFormat::Jpeg => "JPEG",
+            ExportFormat::Png => "PNG",
+            ExportFormat::Tiff => "TIFF",
+            ExportFormat::WebP => "WebP",
+        })
+    }
+}
+
+/// Output size. Shrinking modes never enlarge a smaller image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resize {
+    Original,
+    /// Longest side in pixels.
+    LongEdge(u32),
+    /// Fit within a box, keeping the aspect ratio.
+    Fit { width: u32, height: u32 },
+    Percent(f32),
+}
+
+impl Resize {
+    /// Parses the size field of the export panel: empty for the original
+    /// size, `2048` for the long edge, `1920x1080` for a box or `50%`.
+    pub fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.is_empty() || value.eq_ignore_ascii_case("original") {
+            return Some(Resize::Original);
+        }
+        if let Some(percent) = value.strip_suffix('%') {
+            return percent.trim().parse().ok().filter(|p: &f32| *p > 0.0).map(Resize::Percent);
+        }
+        if let Some((width, height)) = value.split_once(['x', 'X']) {
+            let (width, height) = (width.trim().parse().ok()?, height.trim().parse().ok()?);
+            return (width > 0 && height > 0).then_some(Resize::Fit { width, height });
+        }
+        value.parse().ok().filter(|edge| *edge > 0).map(Resize::LongEdge)
+    }
+
+    /// Output dimensions for an image of `width` x `height`.
+    pub fn target_size(self, width: u32, height: u32) -> (u32, u32) {
+        let scale = match self {
+            Resize::Original => 1.0,
+            Resize::LongEdge(edge) => (edge as f64 / width.max(height) as f64).min(1.0),
+            Resize::Fit { width: box_width, height: box_height } => {
+                (box_width as f64 / width as f64).min(box_height as f64 / height as f64).min(1.0)
+            }
+            Resize::Percent(percent) => percent as f64 / 100.0,
+        };
+        let scaled = |size: u32| ((size as f64 * scale).round() as u32).max(1);
+        (scaled(width), scaled(height))
+    }
+}
+
+impl fmt::Display for Resize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resize::Original => Ok(()),
+            Resize::LongEdge(edge) => write!(f, "{}", edge),
+            Resize::Fit { width, height } => write!(f, "{}x{}", width, height),
+            Resize::Percent(percent) => write!(f, "{}%", percent),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMode {
+    /// Copy the camera's EXIF data.
+    All,
+    /// Copy EXIF data but leave out the location.
+    WithoutGps,
+    None,
+}
+
+impl MetadataMode {
+    pub const ALL: [MetadataMode; 3] = [MetadataMode::All, MetadataMode::WithoutGps, MetadataMode::None];
+}
+
+impl fmt::Display for MetadataMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MetadataMode::All => "All metadata",
+            MetadataMode::WithoutGps => "Without location",
+            MetadataMode::None => "No metadata",
+        })
+    }
+}
+
+/// Sharpening of the resized output, making up for the softness that
+/// downscaling and the output medium add.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputSharpening {
+    Off,
+    Low,
+    Standard,
+    High,
+}
+
+impl OutputSharpening {
+    pub const ALL: [OutputSharpening; 4] =
+        [OutputSharpening::Off, OutputSharpening::Low, OutputSharpening::Standard, OutputSharpening::High];
+
+    /// Settings for an output of `width` x `height`: the radius grows with
+    /// the output size, so a large print gets a wider halo than a web image
+    /// viewed at 100%.
+    pub fn settings(self, width: u32, height: u32) -> Option<Sharpening> {
+        let amount = match self {
+            OutputSharpening::Off => return None,
+            OutputSharpening::Low => 40.0,
+            OutputSharpening::Standard => 70.0,
+            OutputSharpening::High => 100.0,
+        };
+        let radius = (width.max(height) as f32 / 3000.0).sqrt().clamp(0.5, 1.5);
+        Some(Sharpening { amount, radius, threshold: 2.0, masking: 0.0 })
+    }
+}
+
+impl fmt::Display for OutputSharpening {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutputSharpening::Off => "No output sharpening",
+            OutputSharpening::Low => "Low sharpening",
+            OutputSharpening::Standard => "Standard sharpening",
+            OutputSharpening::High => "High sharpening",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportSettings {
+    pub format: ExportFormat,
+    /// JPEG quality, 1-100.
+    pub quality: u8,
+    /// 16 bits per channel for PNG and TIFF; other formats are always 8-bit.
+    pub sixteen_bit: bool,
+    /// Colour space of the output, whose profile is embedded.
+    pub color_space: ColorSpace,
+    pub resize: Resize,
+    /// Applied after resizing.
+    pub sharpening: OutputSharpening,
+    pub metadata: MetadataMode,
+    /// File name without extension.
+    pub name: Template,
+}
+
+/// Ready-made combinations of export settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportPreset {
+    /// Small JPEGs for sharing online, without location.
+    Web,
+    /// Full-size JPEGs with all metadata.
+    FullJpeg,
+    /// Full-size 16-bit TIFFs for printing or further editing.
+    Print,
+    /// `Web` with output sharpening.
+    WebSharpened,
+    /// `Print` with output sharpening.
+    PrintSharpened,
+    /// `Print` in Adobe RGB, for printers and labs that take the wider gamut.
+    PrintAdobeRgb,
+}
+
+impl ExportPreset {
+    pub const ALL: [ExportPreset; 6] = [
+        ExportPreset::Web,
+        ExportPreset::WebSharpened,
+        ExportPreset::FullJpeg,
+        ExportPreset::Print,
+        ExportPreset::PrintSharpened,
+        ExportPreset::PrintAdobeRgb,
+    ];
+
+    pub fn settings(self) -> ExportSettings {
+        let name = Template::parse("{name}").expect("valid template");
+        match self {
+            ExportPreset::Web => ExportSettings {
+                format: ExportFormat::Jpeg,
+                quality: 85,
+                sixteen_bit: false,
+                color_space: ColorSpace::Srgb,
+                resize: Resize::LongEdge(2048),
+                sharpening: OutputSharpening::Off,
+                metadata: MetadataMode::WithoutGps,
+                name,
+            },
+            ExportPreset::FullJpeg => ExportSettings {
+                format: ExportFormat::Jpeg,
+                quality: 95,
+                sixteen_bit: false,
+                color_space: ColorSpace::Srgb,
+                resize: Resize::Original,
+                sharpening: OutputSharpening::Off,
+                metadata: MetadataMode::All,
+                name,
+            },
+            ExportPreset::Print => ExportSettings {
+                format: ExportFormat::Tiff,
+                quality: 100,
+                sixteen_bit: true,
+                color_space: ColorSpace::Srgb,
+                resize: Resize::Original,
+                sharpening: OutputSharpening::Off,
+                metadata: MetadataMode::All,
+                name,
+            },
+            ExportPreset::WebSharpened => ExportSettings {
+                sharpening: OutputSharpening::Standard,
+                ..ExportPreset::Web.settings()
+            },
+            ExportPreset::PrintSharpened => ExportSettings {
+                sharpening: OutputSharpening::High,
+                ..ExportPreset::Print.settings()
+            },
+            ExportPreset::PrintAdobeRgb => ExportSettings {
+                color_space: ColorSpace::AdobeRgb,
+                ..ExportPreset::Print.settings()
+            },
+        }
+    }
+}
+
+impl fmt::Display for ExportPreset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportPreset::Web => "Web (2048px JPEG)",
+            ExportPreset::FullJpeg => "Full-size JPEG",
+            ExportPreset::Print => "Print (16-bit TIFF)",
+            ExportPreset::WebSharpened => "Web, sharpened (2048px JPEG)",
+            ExportPreset::PrintSharpened => "Print, sharpened (16-bit TIFF)",
+            ExportPreset::PrintAdobeRgb => "Print (16-bit Adobe RGB TIFF)",
+        })
+    }
+}
+
+/// A photo to export.
+#[derive(Debug, Clone)]
+pub struct ExportSource {
+    /// Where the develop settings and the name come from: the photo's
+    /// primary file, or the key of a virtual copy.
+    pub key: PathBuf,
+    /// The file whose pixels are exported; the RAW of a RAW+JPEG pair.
+    pub file: PathBuf,
+    pub exif: Option<ExifData>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExportReport {
+    pub exported: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl ExportReport {
+    pub fn summary(&self) -> String {
+        let mut summary = format!("Exported {} photos", self.exported.len());
+        if let Some((path, error)) = self.failed.first() {
+            summary.push_str(&format!("; {} failed (first: {}: {})", self.failed.len(), path.display(), error));
+        }
+        summary
+    }
+}
+
+/// Exports `photos` (in counter order) into `destination`. A photo that
+/// fails doesn't stop the rest.
+pub fn export(photos: &[ExportSource], destination: &Path, settings: &ExportSettings) -> ExportReport {
+    let mut report = ExportReport::default();
+    for (i, source) in photos.iter().enumerate() {
+        match export_photo(source, destination, settings, i + 1) {
+            Ok(target) => report.exported.push(target),
+            Err(e) => {
+                warn!("Failed to export {}: {:#}", source.key.display(), e);
+                report.failed.push((source.key.clone(), format!("{:#}", e)));
+            }
+        }
+    }
+    info!("{}", report.summary());
+    report
+}
+
+/// Decodes `source.file` (RAW files go through the RAW processor), applies
+/// the develop settings of `source.key`, turns it upright, resizes and
+/// sharpens it and writes it to `destination`. Returns the new file.
+pub fn export_photo(source: &ExportSource, destination: &Path, settings: &ExportSettings, counter: usize) -> Result<PathBuf> {
+    let (file, exif) = (&source.file, source.exif.as_ref());
+    let edits = sidecar::read(&source.key).ok().flatten().map(|s| s.edits).unwrap_or_default();
+    let is_raw = detector::detect_image_type(file).is_ok_and(|t| t.is_raw());
+    let image = develop::load(file, &edits.for_photo(exif, is_raw))?;
+    let image = photo::orient(image, exif.and_then(|e| e.orientation).unwrap_or(1));
+
+    let (width, height) = settings.resize.target_size(image.width(), image.height());
+    let image = if (width, height) == (image.width(), image.height()) {
+        image
+    } else {
+        debug!("Resizing {} to {}x{}", file.display(), width, height);
+        image.resize_exact(width, height, FilterType::Lanczos3)
+    };
+    let image = match settings.sharpening.settings(width, height) {
+        Some(sharpening) => {
+            let mut linear = develop::to_linear(&image);
+            sharpen::apply(&mut linear, &sharpening, 1.0);
+            DynamicImage::ImageRgb32F(linear)
+        }
+        None => image,
+    };
+
+    let metadata = match settings.metadata {
+        MetadataMode::None => Vec::new(),
+        mode => exif_fields(file, mode).unwrap_or_else(|e| {
+            debug!("Not copying metadata of {}: {}", file.display(), e);
+            Vec::new()
+        }),
+    };
+    let encoded = encode(&image, settings, &metadata)?;
+
+    let target = free_name(destination, &settings.name.render(&source.key, exif, counter), settings.format.extension())?;
+    std::fs::write(&target, encoded).with_context(|| format!("Failed to write {}", target.display()))?;
+    debug!("Exported {} -> {}", file.display(), target.display());
+    Ok(target)
+}
+
+/// Converts `image` to the output colour space and encodes it with the
+/// space's profile and the EXIF fields `metadata` embedded.
+fn encode(image: &DynamicImage, settings: &ExportSettings, metadata: &[Field]) -> Result<Vec<u8>> {
+    // RAW decodes are linear floats; 16-bit output keeps their full precision
+    let image = settings.color_space.encode(image)?;
+    let sixteen_bit = settings.sixteen_bit && settings.format.supports_16_bit();
+    let icc = settings.color_space.icc();
+    let (width, height) = (image.width(), image.height());
+    let format = match settings.format {
+        ExportFormat::Jpeg => ImageOutputFormat::Jpeg(settings.quality.clamp(1, 100)),
+        ExportFormat::Png => ImageOutputFormat::Png,
+        // image's TIFF encoder can't embed a profile
+        ExportFormat::Tiff if sixteen_bit => {
+            return encode_tiff::<colortype::RGB16>(width, height, image.to_rgb16().as_raw(), &icc, metadata);
+        }
+        ExportFormat::Tiff => {
+            return encode_tiff::<colortype::RGB8>(width, height, image.to_rgb8().as_raw(), &icc, metadata);
+        }
+        ExportFormat::WebP => ImageOutputFormat::WebP,
+    };
+    let image = if sixteen_bit {
+        DynamicImage::ImageRgb16(image.to_rgb16())
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format).context("Failed to encode image")?;
+    let encoded = embed_icc(encoded.into_inner(), settings.format, &icc, (width, height))?;
+    if metadata.is_empty() {
+        return Ok(encoded);
+    }
+    embed_exif(encoded, settings.format, &exif_block(metadata)?)
+}
+
+/// TIFF tags holding the sample format and an embedded ICC profile.
+const TIFF_SAMPLE_FORMAT: u16 = 339;
+const TIFF_ICC_PROFILE: u16 = 34675;
+
+/// Writes an uncompressed TIFF of `data`, laid out as `C`, with the ICC
+/// profile `icc` and the EXIF fields `metadata`. The EXIF writer lays out
+/// the file so the fields land in their proper directories.
+pub fn encode_tiff<C: ColorType>(width: u32, height: u32, data: &[C::Inner], icc: &[u8], metadata: &[Field]) -> Result<Vec<u8>>
+where
+    [C::Inner]: TiffValue,
+{
+    let primary = |tag, value| Field { tag, ifd_num: In::PRIMARY, value };
+    let layout = [
+        primary(Tag::ImageWidth, Value::Long(vec![width])),
+        primary(Tag::ImageLength, Value::Long(vec![height])),
+        primary(Tag::BitsPerSample, Value::Short(C::BITS_PER_SAMPLE.to_vec())),
+        primary(Tag::Compression, Value::Short(vec![1])),
+        primary(Tag::PhotometricInterpretation, Value::Short(vec![C::TIFF_VALUE.to_u16()])),
+        primary(Tag::SamplesPerPixel, Value::Short(vec![C::BITS_PER_SAMPLE.len() as u16])),
+        primary(Tag::RowsPerStrip, Value::Long(vec![height])),
+        primary(Tag::PlanarConfiguration, Value::Short(vec![1])),
+        primary(
+            Tag(IfdContext::Tiff, TIFF_SAMPLE_FORMAT),
+            Value::Short(C::SAMPLE_FORMAT.iter().map(|format| format.to_u16()).collect()),
+        ),
+        // Readers expect the profile as UNDEFINED
+        primary(Tag(IfdContext::Tiff, TIFF_ICC_PROFILE), Value::Undefined(icc.to_vec(), 0)),
+    ];
+    // Samples are written in the machine's byte order, which the header names
+    let samples = data.data();
+    let strips = [&samples[..]];
+
+    let mut writer = Writer::new();
+    for field in layout.iter().chain(metadata) {
+        writer.push_field(field);
+    }
+    writer.set_strips(&strips, In::PRIMARY);
+    let mut encoded = Cursor::new(Vec::new());
+    writer.write(&mut encoded, cfg!(target_endian = "little")).context("Failed to encode TIFF")?;
+    Ok(encoded.into_inner())
+}
+
+/// The source's EXIF fields worth copying into an export.
+///
+/// Only the main image's standard tags are carried over: thumbnails,
+/// maker notes and private tags are tied to the original file's layout.
+/// Orientation is reset since the pixels have already been turned.
+fn exif_fields(source: &Path, mode: MetadataMode) -> Result<Vec<Field>> {
+    let exif = photo::read_exif_fields(source)?;
+    Ok(exif
+        .fields()
+        .filter(|f| f.ifd_num == In::PRIMARY && f.tag.description().is_some())
+        .filter(|f| !is_layout_tag(f.tag))
+        .filter(|f| mode == MetadataMode::All || f.tag.context() != IfdContext::Gps)
+        .map(|f| {
+            if f.tag == Tag::Orientation {
+                Field { tag: f.tag, ifd_num: f.ifd_num, value: Value::Short(vec![1]) }
+            } else {
+                f.clone()
+            }
+        })
+        .collect())
+}
+
+/// An EXIF block (TIFF structure) holding `fields`.
+fn exif_block(fields: &[Field]) -> Result<Vec<u8>> {
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut block = Cursor::new(Vec::new());
+    writer.write(&mut block, false).context("Failed to write EXIF")?;
+    Ok(block.into_inner())
+}
+
+/// Tags describing the source file's pixel data rather than the photo.
+fn is_layout_tag(tag: Tag) -> bool {
+    matches!(
+        tag,
+        Tag::ImageWidth
+            | Tag::ImageLength
+            | Tag::BitsPerSample
+            | Tag::Compression
+            | Tag::PhotometricInterpretation
+            | Tag::SamplesPerPixel
+            | Tag::PlanarConfiguration
+            | Tag::RowsPerStrip
+            | Tag::PixelXDimension
+            | Tag::PixelYDimension
+            | Tag::MakerNote
+    )
+}
+
+/// Inserts an EXIF block into an encoded image. JPEG gets an APP1 segment,
+/// PNG an `eXIf` chunk and WebP an `EXIF` chunk. TIFFs get theirs from
+/// [`encode_tiff`].
+fn embed_exif(encoded: Vec<u8>, format: ExportFormat, block: &[u8]) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::Jpeg => {
+            let length = block.len() + 8;
+            if length > u16::MAX as usize {
+                bail!("EXIF data too large for JPEG");
+            }
+            let mut out = Vec::with_capacity(encoded.len() + length + 2);
+            // Right after the SOI marker
+            out.extend_from_slice(&encoded[..2]);
+            out.extend_from_slice(&[0xFF, 0xE1]);
+            out.extend_from_slice(&(length as u16).to_be_bytes());
+            out.extend_from_slice(b"Exif\0\0");
+            out.extend_from_slice(block);
+            out.extend_from_slice(&encoded[2..]);
+            Ok(out)
+        }
+        ExportFormat::Png => {
+            let mut out = encoded;
+            out.splice(PNG_AFTER_IHDR..PNG_AFTER_IHDR, png_chunk(b"eXIf", block));
+            Ok(out)
+        }
+        ExportFormat::WebP => {
+            // The extended layout `embed_icc` gave the file has a flag for
+            // EXIF, whose chunk follows the image
+            if encoded.get(12..16) != Some(b"VP8X") {
+                bail!("WebP EXIF needs the extended file layout");
+            }
+            let mut out = encoded;
+            out[20] |= 0x08;
+            out.extend(riff_chunk(b"EXIF", block));
+            let size = (out.len() - 8) as u32;
+            out[4..8].copy_from_slice(&size.to_le_bytes());
+            Ok(out)
+        }
+        ExportFormat::Tiff => bail!("TIFF EXIF is written by the encoder"),
+    }
+}
+
+/// Inserts an ICC profile into an encoded image: APP2 segments for JPEG,
+/// an `iCCP` chunk for PNG and an `ICCP` chunk for WebP, which takes the
+/// extended file layout. TIFFs get theirs from [`encode_tiff`].
+fn embed_icc(encoded: Vec<u8>, format: ExportFormat, icc: &[u8], (width, height): (u32, u32)) -> Result<Vec<u8>> {
+    match format {
+        ExportFormat::Jpeg => {
+            // Each segment holds the marker name, its number and the count
+            let chunks: Vec<&[u8]> = icc.chunks(u16::MAX as usize - 16).collect();
+            if chunks.len() > u8::MAX as usize {
+                bail!("ICC profile too large for JPEG");
+            }
+            let mut out = Vec::with_capacity(encoded.len() + icc.len() + chunks.len() * 18);
+            out.extend_from_slice(&encoded[..2]);
+            for (i, chunk) in chunks.iter().enumerate() {
+                out.extend_from_slice(&[0xFF, 0xE2]);
+                out.extend_from_slice(&(chunk.len() as u16 + 16).to_be_bytes());
+                out.extend_from_slice(b"ICC_PROFILE\0");
+                out.extend_from_slice(&[i as u8 + 1, chunks.len() as u8]);
+                out.extend_from_slice(chunk);
+            }
+            out.extend_from_slice(&encoded[2..]);
+            Ok(out)
+        }
+        ExportFormat::Png => {
+            // Profile name, then compression method 0
+            let mut data = b"ICC profile\0\0".to_vec();
+            data.extend(zlib_stored(icc));
+            let mut out = encoded;
+            out.splice(PNG_AFTER_IHDR..PNG_AFTER_IHDR, png_chunk(b"iCCP", &data));
+            Ok(out)
+        }
+        ExportFormat::WebP => {
+            // Simple files start with the image chunk, extended ones with VP8X
+            let (flags, chunks) = match encoded.get(12..16) {
+                Some(b"VP8X") => (encoded[20], &encoded[30..]),
+                Some(_) => (0, &encoded[12..]),
+                None => bail!("Invalid WebP data"),
+            };
+            let mut vp8x = vec![flags | 0x20, 0, 0, 0];
+            vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            let mut out = b"RIFF\0\0\0\0WEBP".to_vec();
+            out.extend(riff_chunk(b"VP8X", &vp8x));
+            out.extend(riff_chunk(b"ICCP", icc));
+            out.extend_from_slice(chunks);
+            let size = (out.len() - 8) as u32;
+            out[4..8].copy_from_slice(&size.to_le_bytes());
+            Ok(out)
+        }
+        ExportFormat::Tiff => Ok(encoded),
+    }
+}
+
+/// Where ancillary PNG chunks go: after the signature (8 bytes) and IHDR
+/// (25 bytes).
+const PNG_AFTER_IHDR: usize = 33;
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+    chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+    chunk
+}
+
+/// A RIFF chunk as used by WebP, padded to an even length.
+fn riff_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+/// `data` as a zlib stream of uncompressed blocks; profiles are small
+/// enough not to bother compressing.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut blocks: Vec<&[u8]> = data.chunks(u16::MAX as usize).collect();
+    if blocks.is_empty() {
+        blocks.push(&[]);
+    }
+    let mut out = vec![0x78, 0x01];
+    for (i, block) in blocks.iter().enumerate() {
+        out.push((i + 1 == blocks.len()) as u8);
+        out.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65_521;
+        (a, (b + a) % 65_521)
+    });
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+/// CRC-32 as used by PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// `destination/stem.ext`, or `stem_2.ext`, ... if that is taken.
+fn free_name(destination: &Path, stem: &str, extension: &str) -> Result<PathBuf> {
+    if !destination.is_dir() {
+        bail!("{} is not a folder", destination.display());
+    }
+    for attempt in 1..1000 {
+        let name = if attempt == 1 { format!("{}.{}", stem, extension) } else { format!("{}_{}.{}", stem, attempt, extension) };
+        let path = destination.join(name);
+        if !path.exists() {
+            return Ok(path);
+        }
+    }
+    bail!("No free name for {} in {}", stem, destination.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_size() {
+        assert_eq!(Resize::LongEdge(2048).target_size(6000, 4000), (2048, 1365));
+        assert_eq!(Resize::LongEdge(2048).target_size(1000, 800), (1000, 800));
+        assert_eq!(Resize::Fit { width: 1000, height: 1000 }.target_size(4000, 6000), (667, 1000));
+        assert_eq!(Resize::Percent(50.0).target_size(6000, 4000), (3000, 2000));
+
+        assert_eq!(Resize::parse(""), Some(Resize::Original));
+        assert_eq!(Resize::parse("1920x1080"), Some(Resize::Fit { width: 1920, height: 1080 }));
+        assert_eq!(Resize::parse("50%"), Some(Resize::Percent(50.0)));
+        assert_eq!(Resize::parse("big"), None);
+    }
+
+    #[test]
+    fn test_sixteen_bit_keeps_raw_precision() {
+        // A dark linear ramp, as a RAW decode would produce
+        let ramp = image::Rgb32FImage::from_fn(1024, 1, |x, _| image::Rgb([x as f32 / 1024.0 * 0.05; 3]));
+        let settings = ExportSettings { resize: Resize::Original, ..ExportPreset::Print.settings() };
+        let encoded = encode(&DynamicImage::ImageRgb32F(ramp), &settings, &[]).unwrap();
+
+        let decoded = image::load_from_memory(&encoded).unwrap().into_rgb16();
+        let mut levels: Vec<u16> = decoded.pixels().map(|p| p[0]).collect();
+        levels.dedup();
+        // 8 bits would leave about 60 distinct levels here
+        assert!(levels.len() > 1000, "only {} levels", levels.len());
+    }
+
+    #[test]
+    fn test_embedded_profile() {
+        use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+        use image::{GenericImageView, ImageDecoder};
+
+        let image = DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(5, 3, image::Rgb([0.2, 0.5, 0.1])));
+        let icc = ColorSpace::AdobeRgb.icc();
+        for format in ExportFormat::ALL {
+            let settings = ExportSettings { format, ..ExportPreset::PrintAdobeRgb.settings() };
+            let encoded = encode(&image, &settings, &[]).unwrap();
+            let reader = Cursor::new(&encoded[..]);
+            let profile = match format {
+                ExportFormat::Jpeg => JpegDecoder::new(reader).unwrap().icc_profile(),
+                ExportFormat::Png => PngDecoder::new(reader).unwrap().icc_profile(),
+                ExportFormat::Tiff => TiffDecoder::new(reader).unwrap().icc_profile(),
+                ExportFormat::WebP => WebPDecoder::new(reader).unwrap().icc_profile(),
+            };
+            assert_eq!(profile.as_ref(), Some(&icc), "{}", format);
+            assert_eq!(image::load_from_memory(&encoded).unwrap().dimensions(), (5, 3), "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_export_strips_gps() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+
+        // A portrait source with a camera model and a location
+        let fields = [
+            Field { tag: Tag::Model, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"X-T3".to_vec()]) },
+            Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) },
+            Field { tag: Tag::GPSLatitudeRef, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"N".to_vec()]) },
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut block = Cursor::new(Vec::new());
+        writer.write(&mut block, false).unwrap();
+        let image = DynamicImage::new_rgb8(300, 200);
+        let plain = encode(&image, &ExportPreset::FullJpeg.settings(), &[]).unwrap();
+        let file = dir.join("DSCF0001.jpg");
+        std::fs::write(&file, embed_exif(plain, ExportFormat::Jpeg, &block.into_inner()).unwrap()).unwrap();
+        let source = ExportSource { key: file.clone(), exif: ExifData::read(&file).ok(), file };
+
+        let settings = ExportSettings { resize: Resize::LongEdge(150), ..ExportPreset::Web.settings() };
+        let target = export_photo(&source, dir, &settings, 1).unwrap();
+
+        // The source itself takes the plain name
+        assert_eq!(target.file_name().unwrap(), "DSCF0001_2.jpg");
+        assert_eq!(image::image_dimensions(&target).unwrap(), (100, 150));
+        let exported = photo::read_exif_fields(&target).unwrap();
+
+        assert!(exported.get_field(Tag::Model, In::PRIMARY).is_some());
+        assert!(exported.get_field(Tag::GPSLatitudeRef, In::PRIMARY).is_none());
+        assert_eq!(exported.get_field(Tag::Orientation, In::PRIMARY).and_then(|f| f.value.get_uint(0)), Some(1));
+    }
+
+    #[test]
+    fn test_metadata_in_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = DynamicImage::ImageRgb32F(image::Rgb32FImage::from_pixel(5, 3, image::Rgb([0.2, 0.5, 0.1])));
+        let metadata = [
+            Field { tag: Tag::Model, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"X-T3".to_vec()]) },
+            Field { tag: Tag::ExposureTime, ifd_num: In::PRIMARY, value: Value::Rational(vec![(1, 250).into()]) },
+        ];
+        for format in ExportFormat::ALL {
+            let settings = ExportSettings { format, ..ExportPreset::Print.settings() };
+            let target = dir.path().join(format!("out.{}", format.extension()));
+            std::fs::write(&target, encode(&image, &settings, &metadata).unwrap()).unwrap();
+
+            let exported = photo::read_exif_fields(&target).unwrap();
+            let model = exported.get_field(Tag::Model, In::PRIMARY).map(|f| f.display_value().to_string());
+            assert_eq!(model.as_deref(), Some("\"X-T3\""), "{}", format);
+            assert!(exported.get_field(Tag::ExposureTime, In::PRIMARY).is_some(), "{}", format);
+            assert_eq!(image::open(&target).unwrap().into_rgb8().dimensions(), (5, 3), "{}", format);
+        }
+    }
+}
@@ -0,0 +1,563 @@
+use std::borrow::Cow;
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use anyhow::{anyhow, bail, Context, Result};
+use tracing::{debug, info, warn};
+
+use crate::processors::detector;
+use crate::sidecar::{self, CopyIndex};
+
+/// A file moved to the trash, with what's needed to put it back.
+#[derive(Debug, Clone)]
+pub struct TrashedFile {
+    pub original: PathBuf,
+    pub trashed: PathBuf,
+    /// The `.trashinfo` file describing it.
+    pub info: PathBuf,
+}
+
+/// All files on disk belonging to a photo: its representations (RAW, JPEG)
+/// and any XMP sidecars, including those of its virtual copies, without
+/// duplicates. `copies` must cover the photo's folder.
+pub fn files_of(representations: &[PathBuf], copies: &CopyIndex) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for path in representations {
+        let sidecars = sidecar::existing_sidecars(path).into_iter().chain(sidecar::copy_sidecars(path, copies));
+        for file in std::iter::once(path.clone()).chain(sidecars) {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+/// Files to trash along with `paths`, which may be only some of a photo's
+/// files: their sidecars and virtual copies, except those a file staying
+/// behind, like the other half of a RAW+JPEG pair, still uses.
+pub fn files_to_remove(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let staying: Vec<PathBuf> = paths
+        .iter()
+        .flat_map(|path| partners(path))
+        .filter(|partner| !paths.contains(partner))
+        .collect();
+    // Partners share the folder, so one scan covers both
+    let copies = CopyIndex::scan(paths);
+    let still_used = files_of(&staying, &copies);
+    files_of(paths, &copies).into_iter().filter(|file| !still_used.contains(file)).collect()
+}
+
+/// Other images in the same folder with the same base name as `path`.
+fn partners(path: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(stem)) = (path.parent(), path.file_stem()) else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|other| {
+            other != path
+                && other.file_stem().is_some_and(|s| s.eq_ignore_ascii_case(stem))
+                && detector::has_image_extension(other)
+        })
+        .collect()
+}
+
+/// Whether `a` and `b` are the same folder, however they are spelled.
+pub fn same_folder(a: &Path, b: &Path) -> bool {
+    match (std::fs::canonicalize(a), std::fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Moves `paths` to the freedesktop.org trash, so they can be restored from
+/// any file manager. Files on the home partition go to
+/// `$XDG_DATA_HOME/Trash`, others to the trash at the top of their own
+/// partition so nothing is copied across devices.
+///
+/// Either all files are trashed or, on error, none are.
+pub fn trash(paths: &[PathBuf]) -> Result<Vec<TrashedFile>> {
+    let home_trash = home_trash_dir()?;
+    trash_with(paths, |path| trash_dir_for(path, &home_trash))
+}
+
+#[cfg(test)]
+fn trash_in(trash_dir: &Path, paths: &[PathBuf]) -> Result<Vec<TrashedFile>> {
+    trash_with(paths, |_| Ok(trash_dir.to_path_buf()))
+}
+
+fn trash_with(paths: &[PathBuf], trash_dir: impl Fn(&Path) -> Result<PathBuf>) -> Result<Vec<TrashedFile>> {
+    let mut trashed = Vec::new();
+    for path in paths {
+        match trash_dir(path).and_then(|dir| trash_file(&dir, path)) {
+            Ok(file) => trashed.push(file),
+            Err(e) => {
+                for file in trashed.iter().rev() {
+                    if let Err(e) = restore(file) {
+                        warn!("Failed to restore {} after error: {}", file.original.display(), e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(trashed)
+}
+
+fn trash_file(trash_dir: &Path, path: &Path) -> Result<TrashedFile> {
+    let files_dir = trash_dir.join("files");
+    let info_dir = trash_dir.join("info");
+    create_private_dir(&files_dir)
+        .and_then(|_| create_private_dir(&info_dir))
+        .with_context(|| format!("Failed to create trash at {}", trash_dir.display()))?;
+
+    let original = std::fs::canonicalize(path)
+        .with_context(|| format!("Failed to resolve {}", path.display()))?;
+    let name = original.file_name()
+        .ok_or_else(|| anyhow!("{} has no file name", original.display()))?
+        .to_string_lossy()
+        .into_owned();
+
+    // Reserve a unique name by creating the info file exclusively, as the
+    // spec requires, then move the file itself
+    for attempt in 1.. {
+        let trash_name = if attempt == 1 { name.clone() } else { numbered_name(&name, attempt) };
+        let info = info_dir.join(format!("{}.trashinfo", trash_name));
+        let mut info_file = match OpenOptions::new().write(true).create_new(true).open(&info) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to write {}", info.display())),
+        };
+        write!(
+            info_file,
+            "[Trash Info]\nPath={}\nDeletionDate={}\n",
+            percent_encode(&original),
+            iso_datetime(SystemTime::now()),
+        )?;
+
+        let trashed = files_dir.join(&trash_name);
+        if let Err(e) = move_file(&original, &trashed) {
+            let _ = std::fs::remove_file(&info);
+            return Err(e).with_context(|| format!("Failed to move {} to the trash", original.display()));
+        }
+        info!("Trashed {}", original.display());
+        return Ok(TrashedFile { original, trashed, info });
+    }
+    unreachable!()
+}
+
+/// Puts a trashed file back where it came from.
+pub fn restore(file: &TrashedFile) -> Result<()> {
+    if file.original.exists() {
+        bail!("{} already exists", file.original.display());
+    }
+    move_file(&file.trashed, &file.original)
+        .with_context(|| format!("Failed to restore {}", file.original.display()))?;
+    let _ = std::fs::remove_file(&file.info);
+    Ok(())
+}
+
+/// Moves `paths` into `dest_dir`, keeping their names. Files already there
+/// are left alone.
+pub fn move_files(paths: &[PathBuf], dest_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut plan = into_dir(paths, dest_dir)?;
+    plan.retain(|(from, _)| !from.parent().is_some_and(|dir| same_folder(dir, dest_dir)));
+    apply_moves(&plan)?;
+    Ok(plan)
+}
+
+/// Copies `paths` into `dest_dir`, keeping their names. Returns the copies.
+pub fn copy_files(paths: &[PathBuf], dest_dir: &Path) -> Result<Vec<PathBuf>> {
+    let plan = into_dir(paths, dest_dir)?;
+    check_targets(&plan)?;
+
+    let mut copied = Vec::new();
+    for (from, to) in &plan {
+        if let Err(e) = std::fs::copy(from, to) {
+            for copy in &copied {
+                let _ = std::fs::remove_file(copy);
+            }
+            return Err(e).with_context(|| format!("Failed to copy {}", from.display()));
+        }
+        copied.push(to.clone());
+    }
+    Ok(copied)
+}
+
+/// Gives the files of one photo a new base name. Extensions and sidecar
+/// suffixes are kept, so `DSCF1234.RAF.xmp` becomes `new_stem.RAF.xmp`.
+pub fn rename_files(paths: &[PathBuf], new_stem: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let plan = rename_plan(paths, new_stem)?;
+    apply_moves(&plan)?;
+    Ok(plan)
+}
+
+/// The moves `rename_files` would make, without touching the disk.
+pub fn rename_plan(paths: &[PathBuf], new_stem: &str) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if new_stem.is_empty() || new_stem.contains(['/', '\0']) || new_stem == "." || new_stem == ".." {
+        bail!("\"{}\" is not a valid file name", new_stem);
+    }
+    let old_stem = paths.first()
+        .and_then(|p| p.file_stem())
+        .ok_or_else(|| anyhow!("Nothing to rename"))?
+        .to_string_lossy()
+        .into_owned();
+
+    let plan: Vec<(PathBuf, PathBuf)> = paths
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            let rest = name.get(old_stem.len()..).unwrap_or_default();
+            (path.clone(), path.with_file_name(format!("{}{}", new_stem, rest)))
+        })
+        .collect();
+    Ok(plan)
+}
+
+/// Performs a set of moves/renames. If any of them fails, the ones already
+/// done are undone so the files end up where they started.
+///
+/// A file may be renamed to the old name of another file in the plan, e.g.
+/// when renumbering a shoot; such plans go through temporary names.
+pub fn apply_moves(plan: &[(PathBuf, PathBuf)]) -> Result<()> {
+    check_targets(plan)?;
+
+    let overlapping = plan.iter().any(|(_, to)| plan.iter().any(|(from, other)| from == to && other != to));
+    if !overlapping {
+        return move_all(plan);
+    }
+
+    let (staging, finish): (Vec<_>, Vec<_>) = plan
+        .iter()
+        .enumerate()
+        .map(|(i, (from, to))| {
+            let name = to.file_name().unwrap_or_default().to_string_lossy();
+            let temporary = to.with_file_name(format!(".photoflow-{}-{}-{}", std::process::id(), i, name));
+            ((from.clone(), temporary.clone()), (temporary, to.clone()))
+        })
+        .unzip();
+    move_all(&staging)?;
+    if let Err(e) = move_all(&finish) {
+        for (from, temporary) in staging.iter().rev() {
+            if let Err(e) = move_file(temporary, from) {
+                warn!("Failed to roll back {}: {}", from.display(), e);
+            }
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn move_all(plan: &[(PathBuf, PathBuf)]) -> Result<()> {
+    for (done, (from, to)) in plan.iter().enumerate() {
+        debug!("Moving {} -> {}", from.display(), to.display());
+        if let Err(e) = move_file(from, to) {
+            for (from, to) in plan[..done].iter().rev() {
+                if let Err(e) = move_file(to, from) {
+                    warn!("Failed to roll back {}: {}", to.display(), e);
+                }
+            }
+            return Err(e).with_context(|| format!("Failed to move {} to {}", from.display(), to.display()));
+        }
+    }
+    Ok(())
+}
+
+/// Refuses to overwrite anything, including a file the plan itself writes
+/// twice. Files the plan moves out of the way don't count.
+fn check_targets(plan: &[(PathBuf, PathBuf)]) -> Result<()> {
+    for (i, (from, to)) in plan.iter().enumerate() {
+        if from != to && to.exists() && !plan.iter().any(|(other, _)| other == to) {
+            bail!("{} already exists", to.display());
+        }
+        if plan[..i].iter().any(|(_, other)| other == to) {
+            bail!("More than one file would be named {}", to.display());
+        }
+    }
+    Ok(())
+}
+
+fn into_dir(paths: &[PathBuf], dest_dir: &Path) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if !dest_dir.is_dir() {
+        bail!("{} is not a folder", dest_dir.display());
+    }
+    paths
+        .iter()
+        .map(|path| {
+            let name = path.file_name().ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+            Ok((path.clone(), dest_dir.join(name)))
+        })
+        .collect()
+}
+
+/// Renames a file, falling back to copy and delete across filesystems.
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn home_trash_dir() -> Result<PathBuf> {
+    let data_home = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .ok_or_else(|| anyhow!("Can't find the home directory for the trash"))?;
+    Ok(data_home.join("Trash"))
+}
+
+/// Trash directories are private to their user.
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)
+}
+
+/// The trash a file goes to: the home trash if it's on the same device,
+/// else one at the top of the file's mount.
+#[cfg(unix)]
+fn trash_dir_for(path: &Path, home_trash: &Path) -> Result<PathBuf> {
+    let device = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .dev();
+    // The home trash may not exist yet; its closest existing parent is on
+    // the same device
+    let home_device = home_trash.ancestors().find_map(|dir| std::fs::metadata(dir).ok()).map(|m| m.dev());
+    if home_device == Some(device) {
+        return Ok(home_trash.to_path_buf());
+    }
+    let path = std::fs::canonicalize(path).with_context(|| format!("Failed to resolve {}", path.display()))?;
+    let top = path
+        .ancestors()
+        .skip(1)
+        .take_while(|dir| std::fs::metadata(dir).is_ok_and(|m| m.dev() == device))
+        .last()
+        .ok_or_else(|| anyhow!("Can't find the mount point of {}", path.display()))?;
+    // SAFETY: getuid has no preconditions and can't fail
+    let uid = unsafe { libc::getuid() };
+    Ok(volume_trash_dir(top, uid))
+}
+
+/// Without device numbers, everything goes to the home trash.
+#[cfg(not(unix))]
+fn trash_dir_for(_path: &Path, home_trash: &Path) -> Result<PathBuf> {
+    Ok(home_trash.to_path_buf())
+}
+
+/// `$topdir/.Trash/$uid` if the administrator set up a shared `.Trash`,
+/// which the spec requires to be sticky and not a link, else
+/// `$topdir/.Trash-$uid`.
+#[cfg(unix)]
+fn volume_trash_dir(top: &Path, uid: u32) -> PathBuf {
+    let shared = top.join(".Trash");
+    match std::fs::symlink_metadata(&shared) {
+        Ok(metadata) if metadata.is_dir() && metadata.mode() & 0o1000 != 0 => shared.join(uid.to_string()),
+        _ => top.join(format!(".Trash-{}", uid)),
+    }
+}
+
+/// `name.ext` -> `name.2.ext`, like file managers do for clashing names.
+fn numbered_name(name: &str, n: u32) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{}.{}.{}", stem, n, ext),
+        _ => format!("{}.{}", name, n),
+    }
+}
+
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path_bytes(path).iter() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The bytes of a path as the filesystem has them, which needn't be UTF-8.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
+}
+
+/// `YYYY-MM-DDThh:mm:ss`. Written in UTC since we don't track the local zone.
+pub fn iso_datetime(time: SystemTime) -> String {
+    let secs = time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Inverse of Howard Hinnant's days_from_civil
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_pair_with_sidecars() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for name in ["DSCF1234.RAF", "DSCF1234.JPG", "DSCF1234.xmp", "DSCF1234.RAF.xmp"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+
+        let pair = [dir.join("DSCF1234.JPG"), dir.join("DSCF1234.RAF")];
+        let files = files_of(&pair, &CopyIndex::scan(&pair));
+        assert_eq!(files.len(), 4);
+        rename_files(&files, "2023-05-14_smith_0001").unwrap();
+
+        for name in ["2023-05-14_smith_0001.RAF", "2023-05-14_smith_0001.JPG",
+                     "2023-05-14_smith_0001.xmp", "2023-05-14_smith_0001.RAF.xmp"] {
+            assert!(dir.join(name).exists(), "{} missing", name);
+        }
+    }
+
+    #[test]
+    fn test_conflicts_are_refused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("a.jpg"), "a").unwrap();
+        std::fs::write(dir.join("b.jpg"), "b").unwrap();
+
+        assert!(rename_files(&[dir.join("a.jpg")], "b").is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("b.jpg")).unwrap(), "b");
+    }
+
+    #[test]
+    fn test_swap_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("a.jpg"), "a").unwrap();
+        std::fs::write(dir.join("b.jpg"), "b").unwrap();
+
+        apply_moves(&[(dir.join("a.jpg"), dir.join("b.jpg")), (dir.join("b.jpg"), dir.join("a.jpg"))]).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("a.jpg")).unwrap(), "b");
+        assert_eq!(std::fs::read_to_string(dir.join("b.jpg")).unwrap(), "a");
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_trash_and_restore() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let trash = dir.join("Trash");
+        let photo = dir.join("photo 1.jpg");
+        std::fs::write(&photo, "x").unwrap();
+
+        let trashed = trash_in(&trash, std::slice::from_ref(&photo)).unwrap();
+        assert!(!photo.exists());
+        let info = std::fs::read_to_string(&trashed[0].info).unwrap();
+        assert!(info.contains("photo%201.jpg"));
+
+        restore(&trashed[0]).unwrap();
+        assert!(photo.exists());
+        assert!(!trashed[0].info.exists());
+    }
+
+    #[test]
+    fn test_trashing_one_of_a_pair_keeps_shared_sidecars() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for name in ["DSCF1234.RAF", "DSCF1234.JPG", "DSCF1234.xmp", "DSCF1234.JPG.xmp", "DSCF1234_01.RAF.xmp"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+
+        // The RAW stays, with the sidecar it shares and its virtual copy
+        let files = files_to_remove(&[dir.join("DSCF1234.JPG")]);
+        assert_eq!(files, vec![dir.join("DSCF1234.JPG"), dir.join("DSCF1234.JPG.xmp")]);
+
+        let both = files_to_remove(&[dir.join("DSCF1234.JPG"), dir.join("DSCF1234.RAF")]);
+        assert_eq!(both.len(), 5);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_move_into_own_folder_is_a_no_op() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("a.jpg"), "a").unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(dir, &link).unwrap();
+
+        assert!(move_files(&[dir.join("a.jpg")], &link).unwrap().is_empty());
+        assert!(move_files(&[dir.join("a.jpg")], dir).unwrap().is_empty());
+        assert_eq!(std::fs::read_to_string(dir.join("a.jpg")).unwrap(), "a");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_trash_dir_per_mount() {
+        let tmp = tempfile::tempdir().unwrap();
+        let top = tmp.path();
+        assert_eq!(volume_trash_dir(top, 1000), top.join(".Trash-1000"));
+
+        // A shared .Trash only counts with the sticky bit set
+        let shared = top.join(".Trash");
+        std::fs::create_dir(&shared).unwrap();
+        assert_eq!(volume_trash_dir(top, 1000), top.join(".Trash-1000"));
+        std::fs::set_permissions(&shared, std::os::unix::fs::PermissionsExt::from_mode(0o1777)).unwrap();
+        assert_eq!(volume_trash_dir(top, 1000), shared.join("1000"));
+
+        // Files on the home trash's device use the home trash, even before it exists
+        let photo = top.join("a.jpg");
+        std::fs::write(&photo, "a").unwrap();
+        let home_trash = top.join("home/.local/share/Trash");
+        assert_eq!(trash_dir_for(&photo, &home_trash).unwrap(), home_trash);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_percent_encode_keeps_raw_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/photos/caf\xe9 1.jpg"));
+        assert_eq!(percent_encode(path), "/photos/caf%E9%201.jpg");
+    }
+
+    #[test]
+    fn test_iso_datetime() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_684_087_331);
+        assert_eq!(iso_datetime(time), "2023-05-14T18:02:11");
+    }
+}
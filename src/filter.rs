@@ -0,0 +1,316 @@
+use thiserror::Error;
+
+use crate::photo::ExifData;
+
+/// Errors produced while parsing a filter query.
+#[derive(Debug, Error, PartialEq)]
+pub enum FilterError {
+    #[error("unknown field `{0}`")]
+    UnknownField(String),
+    #[error("missing value for `{0}`")]
+    MissingValue(String),
+    #[error("`{field}` expects a number, got `{value}`")]
+    InvalidNumber { field: String, value: String },
+    #[error("`{0}` can't be compared with < or >")]
+    NotComparable(String),
+    #[error("unterminated quote")]
+    UnterminatedQuote,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Camera,
+    Lens,
+    FocalLength,
+    Aperture,
+    Iso,
+    Date,
+    Rating,
+    Label,
+    Keyword,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "camera" | "body" | "model" => Some(Field::Camera),
+            "lens" => Some(Field::Lens),
+            "focal" | "fl" => Some(Field::FocalLength),
+            "aperture" | "f" => Some(Field::Aperture),
+            "iso" => Some(Field::Iso),
+            "date" => Some(Field::Date),
+            "rating" | "stars" => Some(Field::Rating),
+            "label" | "color" => Some(Field::Label),
+            "keyword" | "tag" => Some(Field::Keyword),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(self) -> bool {
+        matches!(self, Field::FocalLength | Field::Aperture | Field::Iso | Field::Rating)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn holds<T: PartialOrd>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            Op::Eq => lhs == rhs,
+            Op::Lt => lhs < rhs,
+            Op::Le => lhs <= rhs,
+            Op::Gt => lhs > rhs,
+            Op::Ge => lhs >= rhs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Term {
+    /// Case-insensitive match on a text field.
+    Text { field: Field, value: String },
+    Number { field: Field, op: Op, value: f32 },
+    NumberRange { field: Field, min: f32, max: f32 },
+    /// Dates compare on the prefix of the EXIF datetime, so `2023-05`
+    /// matches the whole month.
+    Date { op: Op, value: String },
+    DateRange { from: String, to: String },
+    /// A bare word, matched against camera, lens and keywords.
+    Any(String),
+}
+
+/// A parsed metadata query such as `iso>3200 camera:"X-T3" rating>=3`.
+///
+/// Terms are separated by whitespace and must all match. Supported fields
+/// are `camera`, `lens`, `focal`, `aperture` (or `f`), `iso`, `date`,
+/// `rating`, `label` and `keyword`. Numeric fields and dates accept
+/// `:`/`=`, `<`, `<=`, `>`, `>=`, and ranges such as `focal:24-70` or
+/// `date:2023-05-01..2023-05-31`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Filter {
+    terms: Vec<Term>,
+}
+
+impl Filter {
+    pub fn parse(query: &str) -> Result<Self, FilterError> {
+        let terms = tokenize(query)?
+            .into_iter()
+            .map(|token| parse_term(&token))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { terms })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    pub fn matches(&self, exif: &ExifData) -> bool {
+        self.terms.iter().all(|term| term_matches(term, exif))
+    }
+}
+
+/// Splits a query on whitespace, keeping double-quoted sections together.
+fn tokenize(query: &str) -> Result<Vec<String>, FilterError> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if in_quotes {
+        return Err(FilterError::UnterminatedQuote);
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
+
+fn parse_term(token: &str) -> Result<Term, FilterError> {
+    let Some(split) = token.find([':', '=', '<', '>']) else {
+        return Ok(Term::Any(token.to_lowercase()));
+    };
+
+    let name = &token[..split];
+    let rest = &token[split..];
+    let (op, value) = if let Some(v) = rest.strip_prefix("<=") {
+        (Op::Le, v)
+    } else if let Some(v) = rest.strip_prefix(">=") {
+        (Op::Ge, v)
+    } else if let Some(v) = rest.strip_prefix('<') {
+        (Op::Lt, v)
+    } else if let Some(v) = rest.strip_prefix('>') {
+        (Op::Gt, v)
+    } else {
+        (Op::Eq, &rest[1..])
+    };
+
+    let field = Field::from_name(name)
+        .ok_or_else(|| FilterError::UnknownField(name.to_string()))?;
+    if value.is_empty() {
+        return Err(FilterError::MissingValue(name.to_string()));
+    }
+
+    if field == Field::Date {
+        if let (Op::Eq, Some((from, to))) = (op, value.split_once("..")) {
+            return Ok(Term::DateRange { from: normalize_date(from), to: normalize_date(to) });
+        }
+        return Ok(Term::Date { op, value: normalize_date(value) });
+    }
+
+    if field.is_numeric() {
+        let number = |v: &str| {
+            parse_number(v).ok_or_else(|| FilterError::InvalidNumber {
+                field: name.to_string(),
+                value: v.to_string(),
+            })
+        };
+        // `-` can't be a sign here since none of these fields go negative
+        if let (Op::Eq, Some((min, max))) = (op, value.split_once('-')) {
+            return Ok(Term::NumberRange { field, min: number(min)?, max: number(max)? });
+        }
+        return Ok(Term::Number { field, op, value: number(value)? });
+    }
+
+    if op != Op::Eq {
+        return Err(FilterError::NotComparable(name.to_string()));
+    }
+    Ok(Term::Text { field, value: value.to_lowercase() })
+}
+
+/// Parses a number, ignoring the unit decorations people tend to type
+/// (`35mm`, `f/2.8`).
+fn parse_number(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value.strip_prefix("f/").unwrap_or(value);
+    let value = value.strip_suffix("mm").unwrap_or(value);
+    value.parse().ok()
+}
+
+/// Accepts both `2023-05-01` and EXIF-style `2023:05:01`.
+fn normalize_date(value: &str) -> String {
+    value.replace(':', "-")
+}
+
+fn number_value(field: Field, exif: &ExifData) -> Option<f32> {
+    match field {
+        Field::FocalLength => exif.focal_length,
+        Field::Aperture => exif.f_number,
+        Field::Iso => exif.iso.map(|iso| iso as f32),
+        // Unrated photos behave as zero stars
+        Field::Rating => Some(exif.rating.unwrap_or(0) as f32),
+        _ => None,
+    }
+}
+
+fn contains(haystack: Option<&str>, needle: &str) -> bool {
+    haystack.is_some_and(|h| h.to_lowercase().contains(needle))
+}
+
+fn term_matches(term: &Term, exif: &ExifData) -> bool {
+    match term {
+        Term::Text { field, value } => match field {
+            Field::Camera => contains(exif.camera().as_deref(), value),
+            Field::Lens => contains(exif.lens.as_deref(), value),
+            Field::Label => exif.label.as_deref().is_some_and(|l| l.to_lowercase() == *value),
+            Field::Keyword => exif.keywords.iter().any(|k| k.to_lowercase() == *value),
+            _ => false,
+        },
+        Term::Number { field, op, value } => match number_value(*field, exif) {
+            // Allow for rounding in rational EXIF values, e.g. f/2.8 stored as 28/10
+            Some(actual) if *op == Op::Eq => (actual - value).abs() < 0.05,
+            Some(actual) => op.holds(actual, *value),
+            None => false,
+        },
+        Term::NumberRange { field, min, max } => number_value(*field, exif)
+            .is_some_and(|actual| actual >= *min && actual <= *max),
+        Term::Date { op, value } => exif.datetime.as_deref()
+            .is_some_and(|dt| op.holds(date_prefix(dt, value), value.as_str())),
+        Term::DateRange { from, to } => exif.datetime.as_deref().is_some_and(|dt| {
+            date_prefix(dt, from) >= from.as_str() && date_prefix(dt, to) <= to.as_str()
+        }),
+        Term::Any(word) => {
+            contains(exif.camera().as_deref(), word)
+                || contains(exif.lens.as_deref(), word)
+                || exif.keywords.iter().any(|k| k.to_lowercase().contains(word.as_str()))
+        }
+    }
+}
+
+/// Truncates an EXIF datetime to the precision of the query value.
+fn date_prefix<'a>(datetime: &'a str, value: &str) -> &'a str {
+    datetime.get(..value.len()).unwrap_or(datetime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xt3() -> ExifData {
+        ExifData {
+            make: Some("FUJIFILM".to_string()),
+            model: Some("X-T3".to_string()),
+            lens: Some("XF23mmF1.4 R".to_string()),
+            f_number: Some(1.4),
+            iso: Some(6400),
+            focal_length: Some(23.0),
+            datetime: Some("2023-05-14 18:02:11".to_string()),
+            rating: Some(4),
+            keywords: vec!["Wedding".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_combined_query() {
+        let filter = Filter::parse(r#"iso>3200 camera:"X-T3" rating>=3"#).unwrap();
+        assert!(filter.matches(&xt3()));
+
+        let filter = Filter::parse("iso>6400").unwrap();
+        assert!(!filter.matches(&xt3()));
+    }
+
+    #[test]
+    fn test_ranges_and_dates() {
+        assert!(Filter::parse("focal:16-35").unwrap().matches(&xt3()));
+        assert!(!Filter::parse("focal:24-70").unwrap().matches(&xt3()));
+        assert!(Filter::parse("f<=f/2.8").unwrap().matches(&xt3()));
+        assert!(Filter::parse("date:2023-05").unwrap().matches(&xt3()));
+        assert!(Filter::parse("date>=2023:05:14").unwrap().matches(&xt3()));
+        assert!(!Filter::parse("date>2023-05-14").unwrap().matches(&xt3()));
+        assert!(Filter::parse("date:2023-05-01..2023-05-31").unwrap().matches(&xt3()));
+    }
+
+    #[test]
+    fn test_text_and_missing_fields() {
+        assert!(Filter::parse("keyword:wedding").unwrap().matches(&xt3()));
+        assert!(Filter::parse("fuji").unwrap().matches(&xt3()));
+        assert!(!Filter::parse("label:red").unwrap().matches(&xt3()));
+        assert!(Filter::parse("rating:0").unwrap().matches(&ExifData::default()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Filter::parse("shutter:1/250"), Err(FilterError::UnknownField("shutter".to_string())));
+        assert_eq!(Filter::parse("camera>x"), Err(FilterError::NotComparable("camera".to_string())));
+        assert_eq!(Filter::parse(r#"lens:"23mm"#), Err(FilterError::UnterminatedQuote));
+        assert!(matches!(Filter::parse("iso>high"), Err(FilterError::InvalidNumber { .. })));
+    }
+}
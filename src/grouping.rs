@@ -0,0 +1,130 @@
+use image::{imageops::FilterType, DynamicImage};
+
+use crate::photo::ExifData;
+
+/// How photos are collapsed into stacks in the photo list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupingSettings {
+    pub enabled: bool,
+    /// Maximum time between consecutive frames of a burst, in seconds.
+    pub max_gap: f64,
+    /// Additionally require consecutive frames to look alike.
+    pub use_similarity: bool,
+    /// Maximum Hamming distance between the perceptual hashes of two
+    /// frames for them to count as similar.
+    pub max_distance: u32,
+}
+
+impl Default for GroupingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_gap: 2.0,
+            use_similarity: false,
+            max_distance: 10,
+        }
+    }
+}
+
+/// Splits `order` (indices in display order) into stacks of consecutive
+/// photos taken within `max_gap` seconds of each other.
+///
+/// Photos without a capture time always stand alone. When similarity is
+/// enabled, frames whose hash is known must also be within `max_distance`
+/// of the previous frame; frames that haven't been loaded yet (no hash)
+/// are grouped on time alone.
+pub fn group(
+    order: &[usize],
+    metadata: &[Option<ExifData>],
+    hashes: &[Option<u64>],
+    settings: &GroupingSettings,
+) -> Vec<Vec<usize>> {
+    let mut stacks: Vec<Vec<usize>> = Vec::new();
+    if !settings.enabled {
+        return order.iter().map(|&i| vec![i]).collect();
+    }
+
+    let timestamp = |i: usize| metadata[i].as_ref().and_then(ExifData::timestamp);
+    for &index in order {
+        let joins_previous = stacks.last().and_then(|stack| stack.last()).is_some_and(|&previous| {
+            let close_in_time = match (timestamp(previous), timestamp(index)) {
+                (Some(a), Some(b)) => (b - a).abs() <= settings.max_gap,
+                _ => false,
+            };
+            let similar = match (settings.use_similarity, hashes[previous], hashes[index]) {
+                (true, Some(a), Some(b)) => hamming_distance(a, b) <= settings.max_distance,
+                _ => true,
+            };
+            close_in_time && similar
+        });
+
+        match stacks.last_mut() {
+            Some(stack) if joins_previous => stack.push(index),
+            _ => stacks.push(vec![index]),
+        }
+    }
+    stacks
+}
+
+/// 64-bit difference hash: the image is shrunk to 9x8 greyscale and each
+/// bit records whether a pixel is brighter than its right-hand neighbour.
+/// Robust to scaling, exposure tweaks and recompression.
+pub fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let left = small.get_pixel(x, y)[0];
+            let right = small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | (left > right) as u64;
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(datetime: &str, subsec: u32) -> Option<ExifData> {
+        Some(ExifData {
+            datetime: Some(datetime.to_string()),
+            subsec: Some(subsec),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_burst_grouping() {
+        let metadata = vec![
+            at("2023-05-14 18:02:10", 0),
+            at("2023-05-14 18:02:10", 500_000_000),
+            at("2023-05-14 18:02:11", 900_000_000),
+            at("2023-05-14 18:05:00", 0),
+            None,
+            at("2023-05-14 18:05:01", 0),
+        ];
+        let hashes = vec![None; metadata.len()];
+        let settings = GroupingSettings { enabled: true, ..Default::default() };
+
+        let stacks = group(&[0, 1, 2, 3, 4, 5], &metadata, &hashes, &settings);
+        assert_eq!(stacks, vec![vec![0, 1, 2], vec![3], vec![4], vec![5]]);
+
+        let disabled = group(&[0, 1], &metadata, &hashes, &GroupingSettings::default());
+        assert_eq!(disabled, vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn test_similarity_splits_bursts() {
+        let metadata = vec![at("2023-05-14 18:02:10", 0); 3];
+        let hashes = vec![Some(0), Some(0b111), Some(u64::MAX)];
+        let settings = GroupingSettings { enabled: true, use_similarity: true, ..Default::default() };
+
+        let stacks = group(&[0, 1, 2], &metadata, &hashes, &settings);
+        assert_eq!(stacks, vec![vec![0, 1], vec![2]]);
+    }
+}
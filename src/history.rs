@@ -0,0 +1,242 @@
+use std::path::PathBuf;
+use anyhow::Result;
+use tracing::{info, warn};
+
+use crate::fileops::{self, TrashedFile};
+use crate::sidecar::{self, Sidecar};
+
+/// How many operations can be undone.
+const MAX_HISTORY: usize = 200;
+
+/// A change to files on disk that can be undone and redone.
+///
+/// Operations refer to files by absolute path rather than by position in the
+/// photo list, so they stay valid after switching folders.
+#[derive(Debug, Clone)]
+pub enum Operation {
+    /// Sidecar metadata (rating, keywords) changed, per sidecar file.
+    /// `None` where the file didn't exist.
+    Metadata {
+        /// The images whose sidecars these are.
+        files: Vec<PathBuf>,
+        before: Vec<(PathBuf, Option<Sidecar>)>,
+        after: Vec<(PathBuf, Option<Sidecar>)>,
+    },
+    /// Files were renamed or moved, as (from, to) pairs.
+    Moved(Vec<(PathBuf, PathBuf)>),
+    Trashed(Vec<TrashedFile>),
+}
+
+/// Files that appeared and disappeared when an operation was applied or
+/// reverted, so the photo list can follow along.
+#[derive(Debug, Default)]
+pub struct FileChanges {
+    pub moved: Vec<(PathBuf, PathBuf)>,
+    pub removed: Vec<PathBuf>,
+    pub added: Vec<PathBuf>,
+    /// Files whose sidecar metadata changed.
+    pub metadata: Vec<PathBuf>,
+}
+
+impl Operation {
+    pub fn description(&self) -> String {
+        match self {
+            Operation::Metadata { files, .. } => format!("metadata change on {} files", files.len()),
+            Operation::Moved(moves) => format!("move of {} files", moves.len()),
+            Operation::Trashed(files) => format!("trashing of {} files", files.len()),
+        }
+    }
+
+    /// Reverses the operation on disk. Returns the operation that redoes
+    /// it, which differs from `self` for trashing since restored files get
+    /// new names when trashed again.
+    fn undo(self) -> Result<(Operation, FileChanges)> {
+        match self {
+            Operation::Metadata { files, before, after } => {
+                write_sidecars(&before)?;
+                let changes = FileChanges { metadata: files.clone(), ..Default::default() };
+                Ok((Operation::Metadata { files, before, after }, changes))
+            }
+            Operation::Moved(moves) => {
+                let reversed: Vec<(PathBuf, PathBuf)> = moves.iter().rev().map(|(from, to)| (to.clone(), from.clone())).collect();
+                fileops::apply_moves(&reversed)?;
+                Ok((Operation::Moved(moves), FileChanges { moved: reversed, ..Default::default() }))
+            }
+            Operation::Trashed(files) => {
+                for (restored, file) in files.iter().enumerate() {
+                    if let Err(e) = fileops::restore(file) {
+                        // Put back the ones already restored so the trash stays consistent
+                        let originals: Vec<PathBuf> = files[..restored].iter().map(|f| f.original.clone()).collect();
+                        let _ = fileops::trash(&originals);
+                        return Err(e);
+                    }
+                }
+                let added = files.iter().map(|f| f.original.clone()).collect();
+                Ok((Operation::Trashed(files), FileChanges { added, ..Default::default() }))
+            }
+        }
+    }
+
+    /// Applies the operation again after it was undone. Returns the
+    /// operation to record for undoing it once more.
+    fn redo(self) -> Result<(Operation, FileChanges)> {
+        match self {
+            Operation::Metadata { files, before, after } => {
+                write_sidecars(&after)?;
+                let changes = FileChanges { metadata: files.clone(), ..Default::default() };
+                Ok((Operation::Metadata { files, before, after }, changes))
+            }
+            Operation::Moved(moves) => {
+                fileops::apply_moves(&moves)?;
+                let changes = FileChanges { moved: moves.clone(), ..Default::default() };
+                Ok((Operation::Moved(moves), changes))
+            }
+            Operation::Trashed(files) => {
+                let originals: Vec<PathBuf> = files.iter().map(|f| f.original.clone()).collect();
+                let trashed = fileops::trash(&originals)?;
+                Ok((Operation::Trashed(trashed), FileChanges { removed: originals, ..Default::default() }))
+            }
+        }
+    }
+}
+
+fn write_sidecars(sidecars: &[(PathBuf, Option<Sidecar>)]) -> Result<()> {
+    for (path, sidecar) in sidecars {
+        sidecar::restore_file(path, sidecar.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Applies `edit` to the sidecars of `files`. Files sharing a sidecar, like
+/// a RAW+JPEG pair, get it edited once. Returns the operation to record,
+/// or `None` if nothing changed.
+pub fn edit_sidecars(files: &[PathBuf], edit: impl Fn(&mut Sidecar)) -> Result<Option<Operation>> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for file in files {
+        let path = sidecar::sidecar_path(file);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+
+    let mut before = Vec::new();
+    let mut after = Vec::new();
+    for path in paths {
+        let old = sidecar::read_file(&path)?;
+        let mut new = old.clone().unwrap_or_default();
+        edit(&mut new);
+        if old.as_ref().unwrap_or(&Sidecar::default()) == &new {
+            continue;
+        }
+        if let Err(e) = sidecar::write_file(&path, &new) {
+            // Don't leave half the selection changed
+            if let Err(e) = write_sidecars(&before) {
+                warn!("Failed to roll back sidecars: {}", e);
+            }
+            return Err(e);
+        }
+        before.push((path.clone(), old));
+        after.push((path, Some(new)));
+    }
+
+    Ok((!after.is_empty()).then(|| Operation::Metadata { files: files.to_vec(), before, after }))
+}
+
+/// Undo and redo stacks for library operations.
+#[derive(Debug, Default)]
+pub struct History {
+    undo: Vec<Operation>,
+    redo: Vec<Operation>,
+}
+
+impl History {
+    /// Records an operation that has just been performed.
+    pub fn record(&mut self, operation: Operation) {
+        self.undo.push(operation);
+        if self.undo.len() > MAX_HISTORY {
+            self.undo.remove(0);
+        }
+        self.redo.clear();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Undoes the most recent operation. Returns `None` if there is nothing
+    /// to undo. A failed operation is dropped from the history since its
+    /// files are no longer where it expects them.
+    pub fn undo(&mut self) -> Option<Result<FileChanges>> {
+        let operation = self.undo.pop()?;
+        info!("Undoing {}", operation.description());
+        Some(operation.undo().map(|(redo, changes)| {
+            self.redo.push(redo);
+            changes
+        }))
+    }
+
+    /// Redoes the most recently undone operation.
+    pub fn redo(&mut self) -> Option<Result<FileChanges>> {
+        let operation = self.redo.pop()?;
+        info!("Redoing {}", operation.description());
+        Some(operation.redo().map(|(undo, changes)| {
+            self.undo.push(undo);
+            changes
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undo_redo_rename() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (from, to) = (dir.join("DSCF0001.JPG"), dir.join("beach.JPG"));
+        std::fs::write(&from, b"jpeg").unwrap();
+
+        let mut history = History::default();
+        let moves = vec![(from.clone(), to.clone())];
+        fileops::apply_moves(&moves).unwrap();
+        history.record(Operation::Moved(moves));
+
+        let undone = history.undo().unwrap().unwrap();
+        assert_eq!(undone.moved, vec![(to.clone(), from.clone())]);
+        assert!(from.exists() && !to.exists());
+        assert!(history.can_redo());
+
+        history.redo().unwrap().unwrap();
+        assert!(to.exists() && !from.exists());
+        assert!(history.undo().is_some() && history.undo().is_none());
+    }
+
+    #[test]
+    fn test_undo_edit_of_shared_sidecar() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pair = [tmp.path().join("DSCF0001.JPG"), tmp.path().join("DSCF0001.RAF")];
+        let sidecar_path = tmp.path().join("DSCF0001.xmp");
+        let mut history = History::default();
+
+        // A new sidecar goes away again on undo
+        let operation = edit_sidecars(&pair, |s| s.rating = Some(2)).unwrap().unwrap();
+        history.record(operation);
+        history.undo().unwrap().unwrap();
+        assert!(!sidecar_path.exists());
+        history.redo().unwrap().unwrap();
+        assert_eq!(sidecar::read_file(&sidecar_path).unwrap().unwrap().rating, Some(2));
+
+        // Both files share the sidecar, so its old value is what undo restores
+        let operation = edit_sidecars(&pair, |s| s.rating = Some(5)).unwrap().unwrap();
+        history.record(operation);
+        history.undo().unwrap().unwrap();
+        assert_eq!(sidecar::read(&pair[1]).unwrap().unwrap().rating, Some(2));
+
+        assert!(edit_sidecars(&pair, |s| s.rating = Some(2)).unwrap().is_none());
+    }
+}
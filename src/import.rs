@@ -0,0 +1,418 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::duplicates;
+use crate::fileops;
+use crate::photo::{self, ExifData};
+use crate::presets::Preset;
+use crate::processors::detector;
+use crate::rename::Template;
+use crate::sidecar::{self, CopyIndex};
+
+/// Where and how photos are copied off a card.
+#[derive(Debug, Clone)]
+pub struct ImportSettings {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// Folder for each photo below `destination`; `/` separates levels.
+    pub folders: Template,
+    /// New file name, or `None` to keep the camera's names.
+    pub rename: Option<Template>,
+    /// Develop preset every imported photo starts out with.
+    pub preset: Option<Preset>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    /// Files copied, at their new location.
+    pub imported: Vec<PathBuf>,
+    /// Source files whose contents already exist in the destination.
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, String)>,
+    /// Imported files that something went wrong with afterwards, such as
+    /// applying the preset.
+    pub warnings: Vec<(PathBuf, String)>,
+}
+
+impl ImportReport {
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "Imported {} files, skipped {} already imported",
+            self.imported.len(),
+            self.skipped.len()
+        );
+        if let Some((path, error)) = self.failed.first() {
+            summary.push_str(&format!(
+                "; {} failed (first: {}: {})",
+                self.failed.len(),
+                path.display(),
+                error
+            ));
+        }
+        if let Some((path, warning)) = self.warnings.first() {
+            summary.push_str(&format!(
+                "; {} warnings (first: {}: {})",
+                self.warnings.len(),
+                path.display(),
+                warning
+            ));
+        }
+        summary
+    }
+}
+
+/// Files already in the destination tree, by size, so that only files of
+/// matching size need hashing to tell whether a photo was imported before.
+struct ExistingFiles {
+    by_size: HashMap<u64, Vec<PathBuf>>,
+    hashes: HashMap<PathBuf, String>,
+}
+
+impl ExistingFiles {
+    fn scan(root: &Path) -> Self {
+        let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+        for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if let Ok(metadata) = entry.metadata() {
+                by_size.entry(metadata.len()).or_default().push(entry.into_path());
+            }
+        }
+        Self { by_size, hashes: HashMap::new() }
+    }
+
+    /// A file in the destination with these contents, if there is one.
+    fn find(&mut self, size: u64, hash: &str) -> Option<PathBuf> {
+        let candidates = self.by_size.get(&size)?;
+        for candidate in candidates {
+            let known = match self.hashes.get(candidate) {
+                Some(known) => known,
+                None => match duplicates::content_hash(candidate) {
+                    Ok(computed) => self.hashes.entry(candidate.clone()).or_insert(computed),
+                    Err(e) => {
+                        debug!("Can't hash {}: {}", candidate.display(), e);
+                        continue;
+                    }
+                },
+            };
+            if known == hash {
+                return Some(candidate.clone());
+            }
+        }
+        None
+    }
+
+    fn add(&mut self, path: PathBuf, size: u64, hash: String) {
+        self.by_size.entry(size).or_default().push(path.clone());
+        self.hashes.insert(path, hash);
+    }
+}
+
+/// Copies the photos under `settings.source` into date folders below
+/// `settings.destination`.
+///
+/// Images are recognised by their contents. RAW+JPEG pairs and their
+/// sidecars travel together and keep a common name, also when part of a
+/// pair was imported before. Files whose contents are already somewhere in
+/// the destination are skipped, and every copy is checked against the
+/// source's hash before it gets its final name. A file that fails doesn't
+/// stop the rest.
+pub fn import(settings: &ImportSettings) -> Result<ImportReport> {
+    if !settings.source.is_dir() {
+        bail!("{} is not a folder", settings.source.display());
+    }
+    std::fs::create_dir_all(&settings.destination)
+        .with_context(|| format!("Failed to create {}", settings.destination.display()))?;
+
+    let mut photos: Vec<(Vec<PathBuf>, ExifData)> = photo::group_representations(find_images(&settings.source))
+        .into_iter()
+        .map(|representations| {
+            let exif = capture_info(&representations[0]);
+            (representations, exif)
+        })
+        .collect();
+    // Counters follow capture order, not the camera's file numbering
+    photos.sort_by(|(a, a_exif), (b, b_exif)| a_exif.capture_time().cmp(&b_exif.capture_time()).then_with(|| a[0].cmp(&b[0])));
+    info!("Importing {} photos from {}", photos.len(), settings.source.display());
+
+    let copies = CopyIndex::scan(photos.iter().map(|(representations, _)| &representations[0]));
+    let mut existing = ExistingFiles::scan(&settings.destination);
+    let mut report = ImportReport::default();
+    let mut counter = 0;
+    for (representations, exif) in &photos {
+        let mut pending = Vec::new();
+        // Where an earlier import put one of the photo's images
+        let mut imported_as = None;
+        for file in fileops::files_of(representations, &copies) {
+            let size = match std::fs::metadata(&file) {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    report.failed.push((file, e.to_string()));
+                    continue;
+                }
+            };
+            match duplicates::content_hash(&file) {
+                Ok(hash) => match existing.find(size, &hash) {
+                    Some(copy) => {
+                        if representations.contains(&file) && imported_as.is_none() {
+                            imported_as = Some(copy);
+                        }
+                        report.skipped.push(file);
+                    }
+                    None => pending.push((file, size, hash)),
+                },
+                Err(e) => report.failed.push((file, format!("{:#}", e))),
+            }
+        }
+        if pending.is_empty() {
+            continue;
+        }
+
+        let (folder, stem) = match imported_as {
+            // The rest of a photo joins the files imported before
+            Some(copy) => (
+                copy.parent().map(Path::to_path_buf).unwrap_or_default(),
+                copy.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+            ),
+            None => {
+                counter += 1;
+                let primary = &representations[0];
+                let folder = destination_folder(&settings.destination, &settings.folders.render(primary, Some(exif), counter));
+                let stem = match &settings.rename {
+                    Some(template) => template.render(primary, Some(exif), counter),
+                    None => primary.file_stem().unwrap_or_default().to_string_lossy().into_owned(),
+                };
+                (folder, stem)
+            }
+        };
+        let files: Vec<PathBuf> = pending.iter().map(|(file, _, _)| file.clone()).collect();
+        let targets = match free_targets(&files, &folder, &stem) {
+            Ok(targets) => targets,
+            Err(e) => {
+                for file in files {
+                    report.failed.push((file, format!("{:#}", e)));
+                }
+                continue;
+            }
+        };
+
+        let mut copied = Vec::new();
+        for ((file, size, hash), target) in pending.into_iter().zip(targets) {
+            match copy_verified(&file, &target, &hash) {
+                Ok(()) => {
+                    debug!("Imported {} -> {}", file.display(), target.display());
+                    existing.add(target.clone(), size, hash);
+                    copied.push(target.clone());
+                    report.imported.push(target);
+                }
+                Err(e) => {
+                    warn!("Failed to import {}: {:#}", file.display(), e);
+                    report.failed.push((file, format!("{:#}", e)));
+                }
+            }
+        }
+        if let (Some(preset), Some(image)) = (&settings.preset, copied.iter().find(|f| detector::has_image_extension(f))) {
+            if let Err(e) = apply_preset(image, preset) {
+                warn!("Failed to apply preset to {}: {:#}", image.display(), e);
+                report.warnings.push((image.clone(), format!("Preset not applied: {:#}", e)));
+            }
+        }
+    }
+
+    info!("{}", report.summary());
+    Ok(report)
+}
+
+/// The images under `source`, recognised by their contents so that files
+/// named like images but holding something else stay behind.
+fn find_images(source: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = WalkDir::new(source)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            let image = detector::is_image_file(path);
+            if !image && detector::has_image_extension(path) {
+                debug!("Not importing {}: not an image", path.display());
+            }
+            image
+        })
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// Applies `preset` to the settings in the sidecar of an imported photo,
+/// which may have come along from the card.
+fn apply_preset(image: &Path, preset: &Preset) -> Result<()> {
+    let mut sidecar = sidecar::read(image)?.unwrap_or_default();
+    sidecar.edits = preset.apply(&sidecar.edits);
+    sidecar::write(image, &sidecar)
+}
+
+/// EXIF data for placing a photo; the file's modification time stands in
+/// for the capture time when the camera didn't record one.
+fn capture_info(path: &Path) -> ExifData {
+    let mut exif = ExifData::read(path).unwrap_or_default();
+    if exif.datetime.is_none() {
+        if let Ok(modified) = std::fs::metadata(path).and_then(|m| m.modified()) {
+            exif.datetime = Some(fileops::iso_datetime(modified).replace('T', " "));
+        }
+    }
+    exif
+}
+
+fn destination_folder(root: &Path, rendered: &str) -> PathBuf {
+    rendered
+        .split('/')
+        .map(str::trim)
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .fold(root.to_path_buf(), |path, part| path.join(part))
+}
+
+/// Paths in `folder` for `files` renamed to `stem`. If a different file
+/// already has that name, `stem_2`, `stem_3`, ... are tried so the files of
+/// a photo keep sharing one name.
+fn free_targets(files: &[PathBuf], folder: &Path, stem: &str) -> Result<Vec<PathBuf>> {
+    for attempt in 1..1000 {
+        let stem = if attempt == 1 { stem.to_string() } else { format!("{}_{}", stem, attempt) };
+        let targets: Vec<PathBuf> = fileops::rename_plan(files, &stem)?
+            .into_iter()
+            .map(|(_, to)| folder.join(to.file_name().unwrap_or_default()))
+            .collect();
+        if targets.iter().all(|t| !t.exists()) {
+            return Ok(targets);
+        }
+    }
+    bail!("No free name for {} in {}", stem, folder.display())
+}
+
+/// Copies `from` to `to` under a temporary name, checks the copy against
+/// `hash` and only then gives it its final name.
+fn copy_verified(from: &Path, to: &Path, hash: &str) -> Result<()> {
+    let folder = to.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(folder).with_context(|| format!("Failed to create {}", folder.display()))?;
+
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let partial = folder.join(format!(".{}.part", name));
+    let result = (|| {
+        std::fs::copy(from, &partial).with_context(|| format!("Failed to copy {}", from.display()))?;
+        // Make sure the bytes we verify are the ones on the disk
+        File::open(&partial)?.sync_all()?;
+        let copied = duplicates::content_hash(&partial)?;
+        if copied != hash {
+            bail!("Checksum mismatch after copying {}", from.display());
+        }
+        std::fs::rename(&partial, to).with_context(|| format!("Failed to name {}", to.display()))
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develop::{sync::SettingsGroup, EditParams};
+
+    /// Writes a file that passes for an image of its type, with `name` in
+    /// it to keep contents apart.
+    fn write_image(path: &Path, name: &str) {
+        let header: &[u8] = if detector::has_raw_extension(path) { b"FUJIFILMCCD-RAW " } else { b"\xFF\xD8\xFF\xE0" };
+        std::fs::write(path, [header, name.as_bytes(), &[0; 16]].concat()).unwrap();
+    }
+
+    #[test]
+    fn test_import_skips_already_imported() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (card, library) = (root.join("card/DCIM/100FUJI"), root.join("library"));
+        std::fs::create_dir_all(&card).unwrap();
+        for name in ["DSCF0001.JPG", "DSCF0001.RAF", "DSCF0002.JPG"] {
+            write_image(&card.join(name), name);
+        }
+        // Named like a photo, but isn't one
+        std::fs::write(card.join("._DSCF0001.JPG"), "resource fork").unwrap();
+
+        let settings = ImportSettings {
+            source: root.join("card"),
+            destination: library.clone(),
+            folders: Template::parse("{year}/{year}-{month}-{day}").unwrap(),
+            rename: Some(Template::parse("shoot_{counter:3}").unwrap()),
+            preset: Some(Preset::new("Warm", &EditParams { temperature: 30.0, ..Default::default() }, &[SettingsGroup::WhiteBalance])),
+        };
+        let first = import(&settings).unwrap();
+        assert_eq!(first.imported.len(), 3);
+        assert!(first.failed.is_empty());
+        for target in &first.imported {
+            assert!(target.exists());
+            assert_eq!(target.parent().unwrap().parent().unwrap().parent(), Some(library.as_path()));
+        }
+        let names: Vec<String> = first.imported.iter().map(|p| p.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert!(names.contains(&"shoot_001.JPG".to_string()) && names.contains(&"shoot_001.RAF".to_string()));
+        let edits = sidecar::read(&first.imported[0]).unwrap().unwrap().edits;
+        assert_eq!(edits.temperature, 30.0);
+
+        write_image(&card.join("DSCF0003.JPG"), "new");
+        let second = import(&settings).unwrap();
+
+        assert_eq!(second.skipped.len(), 3);
+        assert_eq!(second.imported.len(), 1);
+    }
+
+    #[test]
+    fn test_rest_of_pair_joins_earlier_import() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (card, library) = (root.join("card"), root.join("library"));
+        std::fs::create_dir_all(&card).unwrap();
+        write_image(&card.join("DSCF0001.JPG"), "jpeg");
+
+        let mut settings = ImportSettings {
+            source: card.clone(),
+            destination: library.clone(),
+            folders: Template::parse("{year}").unwrap(),
+            rename: Some(Template::parse("shoot_{counter:3}").unwrap()),
+            preset: None,
+        };
+        let first = import(&settings).unwrap();
+        assert_eq!(first.imported.len(), 1);
+
+        write_image(&card.join("DSCF0001.RAF"), "raw");
+        settings.rename = Some(Template::parse("trip_{counter:3}").unwrap());
+        let second = import(&settings).unwrap();
+        assert_eq!(second.skipped.len(), 1);
+        assert_eq!(second.imported, vec![first.imported[0].with_file_name("shoot_001.RAF")]);
+    }
+
+    #[test]
+    fn test_preset_failure_is_a_warning() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let (card, library) = (root.join("card"), root.join("library"));
+        std::fs::create_dir_all(&card).unwrap();
+        write_image(&card.join("DSCF0001.JPG"), "jpeg");
+        // Something in the way of the sidecar the preset is written to
+        std::fs::create_dir_all(library.join("photos/shoot_001.xmp")).unwrap();
+
+        let settings = ImportSettings {
+            source: card,
+            destination: library.clone(),
+            folders: Template::parse("photos").unwrap(),
+            rename: Some(Template::parse("shoot_{counter:3}").unwrap()),
+            preset: Some(Preset::new("Warm", &EditParams { temperature: 30.0, ..Default::default() }, &[SettingsGroup::WhiteBalance])),
+        };
+        let report = import(&settings).unwrap();
+        assert_eq!(report.imported, vec![library.join("photos/shoot_001.JPG")]);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].0, report.imported[0]);
+    }
+}
@@ -0,0 +1,400 @@
+//! Lens calibration data in lensfun's XML format, read from a local
+//! database file (`~/.config/photoflow/lensfun.xml`). Several lensfun
+//! files can simply be concatenated into it.
+
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use thiserror::Error;
+use tracing::{debug, warn};
+
+use crate::config;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum LensfunError {
+    #[error("Unknown {kind} model \"{model}\"")]
+    UnknownModel { kind: &'static str, model: String },
+    #[error("{element} without a focal length")]
+    MissingFocal { element: &'static str },
+}
+
+/// Geometric distortion: where an undistorted radius `r` ends up in the
+/// photo, with radii normalised so half the shorter side is 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distortion {
+    /// `r * (1 - k1 + k1 r²)`
+    Poly3 { k1: f32 },
+    /// `r * (1 + k1 r² + k2 r⁴)`
+    Poly5 { k1: f32, k2: f32 },
+    /// `r * (a r³ + b r² + c r + 1 - a - b - c)`
+    PtLens { a: f32, b: f32, c: f32 },
+}
+
+impl Distortion {
+    pub fn distort(&self, r: f32) -> f32 {
+        match *self {
+            Distortion::Poly3 { k1 } => r * (1.0 - k1 + k1 * r * r),
+            Distortion::Poly5 { k1, k2 } => r * (1.0 + k1 * r * r + k2 * r.powi(4)),
+            Distortion::PtLens { a, b, c } => r * (a * r.powi(3) + b * r * r + c * r + 1.0 - a - b - c),
+        }
+    }
+
+    /// Blends two calibrations of the same model; otherwise takes the
+    /// nearer one.
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        match (self, other) {
+            (Distortion::Poly3 { k1 }, Distortion::Poly3 { k1: k1b }) => Distortion::Poly3 { k1: mix(k1, k1b) },
+            (Distortion::Poly5 { k1, k2 }, Distortion::Poly5 { k1: k1b, k2: k2b }) => {
+                Distortion::Poly5 { k1: mix(k1, k1b), k2: mix(k2, k2b) }
+            }
+            (Distortion::PtLens { a, b, c }, Distortion::PtLens { a: a2, b: b2, c: c2 }) => {
+                Distortion::PtLens { a: mix(a, a2), b: mix(b, b2), c: mix(c, c2) }
+            }
+            _ if t < 0.5 => self,
+            _ => other,
+        }
+    }
+}
+
+/// Lateral chromatic aberration as lensfun's `poly3` model: the red and
+/// blue radii relative to green are `r * (v + c r + b r²)`, stored as
+/// `[v, c, b]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tca {
+    pub red: [f32; 3],
+    pub blue: [f32; 3],
+}
+
+impl Tca {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let mix = |a: [f32; 3], b: [f32; 3]| [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t);
+        Tca { red: mix(self.red, other.red), blue: mix(self.blue, other.blue) }
+    }
+}
+
+/// Vignetting as lensfun's `pa` model: brightness falls off by
+/// `1 + k1 r² + k2 r⁴ + k3 r⁶`, with half the diagonal at r = 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vignetting {
+    pub k: [f32; 3],
+}
+
+impl Vignetting {
+    pub fn falloff(&self, r: f32) -> f32 {
+        let r2 = r * r;
+        1.0 + self.k[0] * r2 + self.k[1] * r2 * r2 + self.k[2] * r2 * r2 * r2
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        Vignetting { k: [0, 1, 2].map(|i| self.k[i] + (other.k[i] - self.k[i]) * t) }
+    }
+}
+
+/// Corrections for one lens at one focal length and aperture.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Calibration {
+    pub distortion: Option<Distortion>,
+    pub tca: Option<Tca>,
+    pub vignetting: Option<Vignetting>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lens {
+    pub maker: String,
+    /// Model names, e.g. in several languages; the first one is shown.
+    pub models: Vec<String>,
+    distortion: Vec<(f32, Distortion)>,
+    tca: Vec<(f32, Tca)>,
+    /// (focal length, aperture, calibration)
+    vignetting: Vec<(f32, f32, Vignetting)>,
+}
+
+impl Lens {
+    pub fn name(&self) -> &str {
+        self.models.first().map_or("", String::as_str)
+    }
+
+    /// Interpolates the calibration for a focal length (mm) and, for
+    /// vignetting, an f-number.
+    pub fn calibration(&self, focal: f32, aperture: Option<f32>) -> Calibration {
+        // Vignetting is measured per aperture; use the nearest one measured
+        let vignetting = aperture
+            .and_then(|aperture| {
+                let distance = |f: f32| (f / aperture).ln().abs();
+                self.vignetting.iter().map(|v| v.1).min_by(|a, b| distance(*a).total_cmp(&distance(*b)))
+            })
+            .or_else(|| self.vignetting.iter().map(|v| v.1).reduce(f32::max));
+        let vignetting: Vec<(f32, Vignetting)> = self
+            .vignetting
+            .iter()
+            .filter(|v| Some(v.1) == vignetting)
+            .map(|&(focal, _, v)| (focal, v))
+            .collect();
+        Calibration {
+            distortion: interpolate(&self.distortion, focal, Distortion::lerp),
+            tca: interpolate(&self.tca, focal, Tca::lerp),
+            vignetting: interpolate(&vignetting, focal, Vignetting::lerp),
+        }
+    }
+}
+
+/// Linear interpolation between the entries measured either side of
+/// `focal`, clamped to the measured range.
+fn interpolate<T: Copy>(entries: &[(f32, T)], focal: f32, lerp: fn(T, T, f32) -> T) -> Option<T> {
+    let below = entries.iter().filter(|e| e.0 <= focal).max_by(|a, b| a.0.total_cmp(&b.0));
+    let above = entries.iter().filter(|e| e.0 >= focal).min_by(|a, b| a.0.total_cmp(&b.0));
+    match (below, above) {
+        (Some(a), Some(b)) if b.0 > a.0 => Some(lerp(a.1, b.1, (focal - a.0) / (b.0 - a.0))),
+        (Some(a), _) | (None, Some(a)) => Some(a.1),
+        (None, None) => None,
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LensDatabase {
+    lenses: Vec<Lens>,
+}
+
+impl LensDatabase {
+    pub fn parse(xml: &str) -> Result<Self, LensfunError> {
+        let xml = strip_comments(xml);
+        let mut lenses = Vec::new();
+        for block in blocks(&xml, "lens") {
+            let calibration = blocks(block, "calibration").into_iter().next().unwrap_or("");
+            let mut lens = Lens {
+                maker: blocks(block, "maker").first().map(|m| unescape(m.trim())).unwrap_or_default(),
+                models: blocks(block, "model").iter().map(|m| unescape(m.trim())).collect(),
+                distortion: Vec::new(),
+                tca: Vec::new(),
+                vignetting: Vec::new(),
+            };
+            // Entries in models we don't implement (lensfun also has `acm`,
+            // `none` and more) are left out rather than failing the database
+            for tag in tags(calibration, "distortion") {
+                match parse_distortion(tag) {
+                    Ok(distortion) => lens.distortion.push((focal(tag, "distortion")?, distortion)),
+                    Err(e) => debug!("Skipping calibration of {}: {}", lens.name(), e),
+                }
+            }
+            for tag in tags(calibration, "tca") {
+                match parse_tca(tag) {
+                    Ok(tca) => lens.tca.push((focal(tag, "tca")?, tca)),
+                    Err(e) => debug!("Skipping calibration of {}: {}", lens.name(), e),
+                }
+            }
+            for tag in tags(calibration, "vignetting") {
+                let aperture = number(tag, "aperture").unwrap_or(0.0);
+                match parse_vignetting(tag) {
+                    Ok(vignetting) => lens.vignetting.push((focal(tag, "vignetting")?, aperture, vignetting)),
+                    Err(e) => debug!("Skipping calibration of {}: {}", lens.name(), e),
+                }
+            }
+            if !lens.models.is_empty() {
+                lenses.push(lens);
+            }
+        }
+        Ok(Self { lenses })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let xml = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Self::parse(&xml)?)
+    }
+
+    /// Finds the lens EXIF calls `name`. Names are compared ignoring case
+    /// and spacing. Failing an exact match, the longest model name that is
+    /// part of `name` wins, then the shortest one that contains it: some
+    /// cameras add the maker, and the database often does.
+    pub fn find(&self, name: &str) -> Option<&Lens> {
+        let name = normalize(name);
+        if name.is_empty() {
+            return None;
+        }
+        let models = || {
+            self.lenses
+                .iter()
+                .flat_map(|lens| lens.models.iter().map(move |m| (lens, normalize(m))))
+                .filter(|(_, model)| !model.is_empty())
+        };
+        models()
+            .find(|(_, model)| *model == name)
+            .or_else(|| models().filter(|(_, model)| name.contains(model.as_str())).max_by_key(|(_, m)| m.len()))
+            .or_else(|| models().filter(|(_, model)| model.contains(name.as_str())).min_by_key(|(_, m)| m.len()))
+            .map(|(lens, _)| lens)
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Where the database is read from.
+pub fn database_path() -> Result<PathBuf> {
+    Ok(config::config_dir()?.join("lensfun.xml"))
+}
+
+/// The local database, read on first use. Missing or broken files give an
+/// empty database, so photos simply get no profile.
+pub fn database() -> &'static LensDatabase {
+    static DATABASE: Lazy<LensDatabase> = Lazy::new(|| {
+        let loaded = database_path().and_then(|path| {
+            if !path.exists() {
+                debug!("No lens database at {}", path.display());
+                return Ok(LensDatabase::default());
+            }
+            LensDatabase::load(&path)
+        });
+        loaded.unwrap_or_else(|e| {
+            warn!("Lens corrections unavailable: {:#}", e);
+            LensDatabase::default()
+        })
+    });
+    &DATABASE
+}
+
+fn strip_comments(xml: &str) -> String {
+    let mut output = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(start) = rest.find("<!--") {
+        output.push_str(&rest[..start]);
+        rest = rest[start..].find("-->").map_or("", |end| &rest[start + end + 3..]);
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Contents of each `<name ...>...</name>` element.
+fn blocks<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let close = format!("</{}>", name);
+    tag_starts(xml, name)
+        .into_iter()
+        .filter_map(|start| {
+            let open_end = start + xml[start..].find('>')? + 1;
+            if xml[..open_end].ends_with("/>") {
+                return None;
+            }
+            let end = open_end + xml[open_end..].find(&close)?;
+            Some(&xml[open_end..end])
+        })
+        .collect()
+}
+
+/// Start tags (`<name a="1" ...>`) of every `name` element.
+fn tags<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    tag_starts(xml, name).into_iter().filter_map(|start| Some(&xml[start..start + xml[start..].find('>')?])).collect()
+}
+
+/// Offsets of each `<name` that opens a `name` element, not a longer one.
+fn tag_starts(xml: &str, name: &str) -> Vec<usize> {
+    let open = format!("<{}", name);
+    xml.match_indices(&open)
+        .map(|(i, _)| i)
+        .filter(|&i| xml[i + open.len()..].starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()))
+        .collect()
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!(" {}=", name);
+    let start = tag.find(&key)? + key.len();
+    let quote = tag[start..].chars().next()?;
+    let value = &tag[start + 1..];
+    Some(&value[..value.find(quote)?])
+}
+
+fn number(tag: &str, name: &str) -> Option<f32> {
+    attribute(tag, name)?.trim().parse().ok()
+}
+
+fn focal(tag: &str, element: &'static str) -> Result<f32, LensfunError> {
+    number(tag, "focal").ok_or(LensfunError::MissingFocal { element })
+}
+
+fn parse_distortion(tag: &str) -> Result<Distortion, LensfunError> {
+    let k = |name| number(tag, name).unwrap_or(0.0);
+    match attribute(tag, "model").unwrap_or("") {
+        "poly3" => Ok(Distortion::Poly3 { k1: k("k1") }),
+        "poly5" => Ok(Distortion::Poly5 { k1: k("k1"), k2: k("k2") }),
+        "ptlens" => Ok(Distortion::PtLens { a: k("a"), b: k("b"), c: k("c") }),
+        model => Err(LensfunError::UnknownModel { kind: "distortion", model: model.to_string() }),
+    }
+}
+
+fn parse_tca(tag: &str) -> Result<Tca, LensfunError> {
+    let k = |name, default| number(tag, name).unwrap_or(default);
+    match attribute(tag, "model").unwrap_or("") {
+        "poly3" => Ok(Tca { red: [k("vr", 1.0), k("cr", 0.0), k("br", 0.0)], blue: [k("vb", 1.0), k("cb", 0.0), k("bb", 0.0)] }),
+        "linear" => Ok(Tca { red: [k("kr", 1.0), 0.0, 0.0], blue: [k("kb", 1.0), 0.0, 0.0] }),
+        model => Err(LensfunError::UnknownModel { kind: "TCA", model: model.to_string() }),
+    }
+}
+
+fn parse_vignetting(tag: &str) -> Result<Vignetting, LensfunError> {
+    let k = |name| number(tag, name).unwrap_or(0.0);
+    match attribute(tag, "model").unwrap_or("") {
+        "pa" => Ok(Vignetting { k: [k("k1"), k("k2"), k("k3")] }),
+        model => Err(LensfunError::UnknownModel { kind: "vignetting", model: model.to_string() }),
+    }
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATABASE: &str = r#"<lensdatabase version="2">
+    <!-- <lens><model>Commented out</model></lens> -->
+    <lens>
+        <maker>Fujifilm</maker>
+        <model>XF18-55mmF2.8-4 R LM OIS</model>
+        <model lang="en">XF 18-55mm f/2.8-4 R LM OIS</model>
+        <mount>Fujifilm X</mount>
+        <cropfactor>1.53</cropfactor>
+        <calibration>
+            <distortion model="ptlens" focal="18" a="0.01" b="-0.04" c="0.02"/>
+            <distortion model="ptlens" focal="55" a="0.0" b="0.02" c="0.0"/>
+            <tca model="poly3" focal="18" vr="1.0004" vb="0.9997"/>
+            <vignetting model="pa" focal="18" aperture="2.8" distance="10" k1="-0.6" k2="0.3" k3="-0.1"/>
+            <vignetting model="pa" focal="18" aperture="8" distance="10" k1="-0.2" k2="0" k3="0"/>
+        </calibration>
+    </lens>
+</lensdatabase>"#;
+
+    #[test]
+    fn test_parse_and_interpolate() {
+        let database = LensDatabase::parse(DATABASE).unwrap();
+        assert_eq!(database.lenses.len(), 1);
+        let lens = database.find("XF18-55mmF2.8-4 R LM OIS").unwrap();
+        assert_eq!(lens.maker, "Fujifilm");
+
+        let calibration = lens.calibration(36.5, Some(7.1));
+        assert_eq!(calibration.distortion, Some(Distortion::PtLens { a: 0.005, b: -0.01, c: 0.01 }));
+        // Outside the measured range the nearest entry is used
+        assert_eq!(calibration.tca.unwrap().red[0], 1.0004);
+        assert_eq!(calibration.vignetting, Some(Vignetting { k: [-0.2, 0.0, 0.0] }));
+    }
+
+    #[test]
+    fn test_find_by_exif_name() {
+        let database = LensDatabase::parse(DATABASE).unwrap();
+        assert!(database.find("Fujifilm  xf18-55mmf2.8-4 r lm ois").is_some());
+        assert!(database.find("XF18-55mm").is_some());
+        assert!(database.find("XF35mmF1.4 R").is_none());
+        assert!(database.find("").is_none());
+
+        // Distortion in a model we don't know is skipped, the rest still used
+        let unknown = DATABASE.replace("ptlens", "acm");
+        let database = LensDatabase::parse(&unknown).unwrap();
+        let calibration = database.find("XF18-55mm").unwrap().calibration(36.5, Some(7.1));
+        assert_eq!(calibration.distortion, None);
+        assert!(calibration.tca.is_some());
+    }
+}
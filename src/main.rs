@@ -1,18 +1,60 @@
 use iced::{
-    executor,
-    widget::{button, column, container, row, text},
-    Application, Command, Element, Length, Settings, Theme,
+    event, executor,
+    keyboard::{self, KeyCode},
+    subscription,
+    widget::{button, checkbox, column, container, pick_list, row, slider, text, text_input},
+    Alignment, Application, Command, Element, Event, Length, Settings, Subscription, Theme,
 };
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tracing::{info, debug};
-use image::DynamicImage;
 
+mod color;
+mod config;
+mod develop;
+mod duplicates;
+mod export;
+mod fileops;
+mod filter;
+mod grouping;
+mod history;
+mod import;
+mod lensfun;
+mod merge;
 mod photo;
+mod presets;
+mod rename;
+mod sidecar;
+mod sort;
 mod ui;
 mod processors;
 
-use photo::Photo;
-use ui::PhotoView;
+use color::{ColorSpace, ProofSettings, RenderingIntent, SoftProof};
+use develop::{
+    crop::{Crop, CropAspect, CropEdge},
+    denoise::NoiseReduction,
+    histogram::ClippingWarnings,
+    lens::{LensCorrection, LensField},
+    sharpen::SharpenField,
+    sync::{self as edit_sync, EditClipboard, SettingsGroup},
+    tone::{Curve, CurveChannel, LevelsField},
+    Adjustment, EditParams,
+};
+use duplicates::DuplicateGroup;
+use export::{ExportFormat, ExportPreset, ExportReport, ExportSettings, ExportSource, MetadataMode, OutputSharpening, Resize};
+use filter::Filter;
+use grouping::GroupingSettings;
+use history::{FileChanges, History, Operation};
+use import::{ImportReport, ImportSettings};
+use merge::HdrFormat;
+use presets::{Preset, PresetLibrary, PRESET_THUMBNAIL_SIZE};
+use photo::{ExifData, Loaded, Photo, Rendered};
+use rename::{RenamePreview, Template};
+use sidecar::{CopyIndex, Sidecar, Snapshot};
+use sort::{SortMode, SortOrder};
+use processors::{detector, highlights::HighlightMode};
+use ui::{ListEntry, PhotoView};
 
 pub fn main() -> iced::Result {
     // Initialize logging
@@ -25,22 +67,226 @@ pub fn main() -> iced::Result {
 
 #[derive(Debug)]
 struct PhotoFlow {
+    /// The folder being browsed.
+    directory: Option<PathBuf>,
+    /// The primary file of each photo; identifies the photo in messages.
+    /// For a virtual copy this is its key (see [`sidecar::copy_path`]),
+    /// which isn't among its files.
     photo_paths: Vec<PathBuf>,
+    /// All files of each photo (RAW+JPEG pairs), primary first. Virtual
+    /// copies share the files of their original.
+    representations: Vec<Vec<PathBuf>>,
     photos: Vec<Option<Photo>>,
+    /// Metadata for every photo in the directory, filled in by a background scan.
+    metadata: Vec<Option<ExifData>>,
+    /// Indices into `photo_paths` that pass the current filter.
+    visible: Vec<usize>,
+    filter_query: String,
+    filter: Filter,
+    sort_mode: SortMode,
+    sort_order: SortOrder,
+    grouping: GroupingSettings,
+    /// `visible` split into burst stacks, in display order.
+    stacks: Vec<Vec<usize>>,
+    /// Stacks the user has expanded, keyed by the path of their first photo.
+    expanded_stacks: HashSet<PathBuf>,
     current_photo: Option<usize>,
+    /// Photos ticked in the list, by primary path. File operations act on
+    /// these, or on the current photo when nothing is ticked.
+    selected: HashSet<PathBuf>,
+    rename_to: String,
+    keywords_input: String,
+    /// Undo/redo stack. Kept across folder switches; operations refer to
+    /// files by absolute path.
+    history: History,
+    batch_template: String,
+    /// What batch renaming would do with `batch_template`; `Some` while the
+    /// batch rename panel is open.
+    batch_previews: Option<Vec<RenamePreview>>,
+    /// Why `batch_template` can't be used.
+    batch_error: Option<String>,
+    /// Folder layout and optional file name template for imports.
+    import_folders: String,
+    import_rename: String,
+    importing: bool,
+    /// Whether the export panel is shown instead of the viewer.
+    export_open: bool,
+    export: ExportSettings,
+    /// The preset `export` was last loaded from.
+    export_preset: Option<ExportPreset>,
+    /// Size and file name fields of the export panel, parsed on export.
+    export_size: String,
+    export_name: String,
+    exporting: bool,
+    merging: bool,
+    /// Results of the last duplicate scan, shown instead of the viewer.
+    duplicates: Vec<DuplicateGroup>,
+    /// Index of the file to keep in each duplicate group.
+    duplicate_keep: Vec<usize>,
+    /// Curve shown in the develop panel's curve editor.
+    curve_channel: CurveChannel,
+    clipping_warnings: ClippingWarnings,
+    /// Name of the display profile, `None` for sRGB.
+    display_profile: Option<String>,
+    proofing: ProofSettings,
+    /// The output profile to soft-proof for, once one is chosen.
+    proof_profile: Option<Vec<u8>>,
+    /// Built from `proof_profile` and `proofing`.
+    soft_proof: Option<Arc<SoftProof>>,
+    /// Develop settings copied for pasting, and the sync setting.
+    clipboard: EditClipboard,
+    presets: PresetLibrary,
+    /// Name for the next snapshot of the current photo.
+    snapshot_name: String,
     photo_view: PhotoView,
     error: Option<String>,
+    /// Outcome of the last finished background job, shown when there's no error.
+    status: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 enum Message {
     LoadDirectory,
-    DirectoryLoaded(Vec<PathBuf>),
+    DirectoryLoaded(PathBuf, Vec<PathBuf>),
     PhotoSelected(usize),
     NextPhoto,
     PreviousPhoto,
+    FilterChanged(String),
+    SortModeChanged(SortMode),
+    SortOrderToggled,
+    GroupingToggled(bool),
+    BurstGapChanged(f64),
+    SimilarityToggled(bool),
+    /// Expand or collapse the stack headed by this photo.
+    ToggleStack(usize),
+    /// Switch the current photo between its JPEG and RAW files.
+    ToggleRepresentation,
+    SetRating(u8),
+    KeywordsInputChanged(String),
+    /// Live change of a develop slider on the current photo.
+    AdjustmentChanged(Adjustment, f32),
+    /// Write the current photo's develop settings to its sidecar.
+    SaveEdits,
+    ResetEdits,
+    /// Live change of a noise reduction strength.
+    LuminanceNoiseChanged(f32),
+    ColorNoiseChanged(f32),
+    /// Live change of a capture sharpening value.
+    SharpeningChanged(SharpenField, f32),
+    /// Go back to noise reduction by ISO, and save.
+    NoiseReductionAuto,
+    /// How the RAW decoder treats clipped highlights for the current photo.
+    HighlightModeSelected(HighlightMode),
+    /// Copy the current photo's develop settings.
+    CopyEdits,
+    /// Paste the copied settings onto the selection (or the current photo),
+    /// limited to the chosen groups.
+    PasteEdits,
+    PasteGroupToggled(SettingsGroup, bool),
+    /// Apply changes to the current photo to the selected ones too.
+    SyncEditsToggled(bool),
+    /// Choose a preset in the develop panel, which previews it.
+    PresetSelected(String),
+    PresetNameChanged(String),
+    /// Save the chosen groups of the current photo's settings as a preset.
+    SavePreset,
+    DeletePreset,
+    /// Apply the chosen preset to the selection (or the current photo).
+    ApplyPreset,
+    /// Preset for imported photos, by name.
+    ImportPresetSelected(Option<String>),
+    SnapshotNameChanged(String),
+    /// Save the current photo's settings as a named snapshot.
+    TakeSnapshot,
+    /// Make a snapshot the current photo's settings, by position.
+    RestoreSnapshot(usize),
+    DeleteSnapshot(usize),
+    /// Add a virtual copy of the current photo, starting from its settings.
+    CreateVirtualCopy,
+    /// Live change of a manual lens correction.
+    LensCorrectionChanged(LensField, f32),
+    /// Whether to apply the lens profile, and save.
+    LensProfileToggled(bool),
+    /// Drop the manual lens corrections and use the profile, and save.
+    ResetLensCorrections,
+    /// Show the whole image with the crop drawn over it.
+    CropOverlayToggled(bool),
+    CropAspectSelected(CropAspect),
+    /// Live change of one crop edge, as a fraction of the image.
+    CropEdgeChanged(CropEdge, f32),
+    /// Live change of the straightening angle.
+    CropAngleChanged(f32),
+    ResetCrop,
+    /// Live change of one levels value of a channel.
+    LevelsChanged(CurveChannel, LevelsField, f32),
+    HighlightClippingToggled(bool),
+    ShadowClippingToggled(bool),
+    /// Ask for the monitor's ICC profile.
+    ChooseDisplayProfile,
+    /// Use the given display profile, or sRGB with `None`.
+    DisplayProfileChosen(Option<PathBuf>),
+    SoftProofToggled(bool),
+    /// Ask for the printer or output profile to soft-proof for.
+    ChooseProofProfile,
+    ProofProfileChosen(PathBuf),
+    ProofIntentSelected(RenderingIntent),
+    GamutWarningToggled(bool),
+    /// Which curve the curve editor shows.
+    CurveChannelSelected(CurveChannel),
+    /// Live change of a tone curve from the curve editor.
+    CurveChanged(CurveChannel, Curve),
+    /// Replace the keywords of the target photos with `keywords_input`.
+    SetKeywords,
+    Undo,
+    Redo,
+    ToggleSelection(usize),
+    TrashPhotos,
+    /// Ask where to move (or copy) the target photos.
+    ChooseDestination { copy: bool },
+    TransferPhotos { paths: Vec<PathBuf>, dest: PathBuf, copy: bool },
+    RenameInputChanged(String),
+    RenamePhoto,
+    /// Open the batch rename panel for the selection (or every visible photo).
+    OpenBatchRename,
+    BatchTemplateChanged(String),
+    ApplyBatchRename,
+    CloseBatchRename,
+    MetadataLoaded(Vec<(PathBuf, Option<ExifData>)>),
+    ImportFoldersChanged(String),
+    ImportRenameChanged(String),
+    /// Ask for a card and a destination, then import.
+    StartImport,
+    ImportFinished(Result<ImportReport, String>),
+    OpenExport,
+    CloseExport,
+    ExportPresetSelected(ExportPreset),
+    ExportFormatSelected(ExportFormat),
+    ExportQualityChanged(u8),
+    ExportSixteenBitToggled(bool),
+    ExportColorSpaceSelected(ColorSpace),
+    ExportSizeChanged(String),
+    ExportSharpeningSelected(OutputSharpening),
+    ExportMetadataSelected(MetadataMode),
+    ExportNameChanged(String),
+    /// Ask for a destination folder and export the target photos there.
+    StartExport,
+    ExportFinished(ExportReport),
+    /// Ask where to save, then merge the target photos' RAW files into an
+    /// HDR image.
+    MergeHdr,
+    HdrMerged(Result<PathBuf, String>),
+    FindDuplicates,
+    DuplicatesFound(Vec<DuplicateGroup>),
+    /// Keep the given file of a duplicate group: (group, file).
+    KeepDuplicate(usize, usize),
+    CloseDuplicates,
+    /// Trash every file of a duplicate group except the one to keep.
+    TrashDuplicates(usize),
     Error(String),
-    ImageLoaded(PathBuf, Option<DynamicImage>),
+    ImageLoaded(PathBuf, Option<Loaded>),
+    /// A photo's preview, by key, rendered at a generation; `None` if the
+    /// render failed.
+    Rendered(PathBuf, u64, Option<Box<Rendered>>),
 }
 
 impl Application for PhotoFlow {
@@ -52,11 +298,51 @@ impl Application for PhotoFlow {
     fn new(_flags: ()) -> (Self, Command<Message>) {
         (
             Self {
+                directory: None,
                 photo_paths: Vec::new(),
+                representations: Vec::new(),
                 photos: Vec::new(),
+                metadata: Vec::new(),
+                visible: Vec::new(),
+                filter_query: String::new(),
+                filter: Filter::default(),
+                sort_mode: SortMode::default(),
+                sort_order: SortOrder::default(),
+                grouping: GroupingSettings::default(),
+                stacks: Vec::new(),
+                expanded_stacks: HashSet::new(),
                 current_photo: None,
+                selected: HashSet::new(),
+                rename_to: String::new(),
+                keywords_input: String::new(),
+                history: History::default(),
+                batch_template: "{year}-{month}-{day}_{counter:4}".to_string(),
+                batch_previews: None,
+                batch_error: None,
+                import_folders: "{year}/{year}-{month}-{day}".to_string(),
+                import_rename: String::new(),
+                importing: false,
+                export_open: false,
+                export: ExportPreset::Web.settings(),
+                export_preset: Some(ExportPreset::Web),
+                export_size: Resize::LongEdge(2048).to_string(),
+                export_name: "{name}".to_string(),
+                exporting: false,
+                merging: false,
+                duplicates: Vec::new(),
+                duplicate_keep: Vec::new(),
+                curve_channel: CurveChannel::default(),
+                clipping_warnings: ClippingWarnings::default(),
+                display_profile: color::display_profile_name(),
+                proofing: ProofSettings::default(),
+                proof_profile: None,
+                soft_proof: None,
+                clipboard: EditClipboard::default(),
+                presets: PresetLibrary::load(),
+                snapshot_name: String::new(),
                 photo_view: PhotoView::new(),
                 error: None,
+                status: None,
             },
             Command::none(),
         )
@@ -67,6 +353,206 @@ impl Application for PhotoFlow {
     }
 
     fn update(&mut self, message: Message) -> Command<Message> {
+        let command = self.handle(message);
+        Command::batch([command, self.start_renders()])
+    }
+
+    fn subscription(&self) -> Subscription<Message> {
+        subscription::events_with(|event, status| {
+            // Leave keys alone while a text input has focus
+            if status == event::Status::Captured {
+                return None;
+            }
+            match event {
+                Event::Keyboard(keyboard::Event::KeyPressed { key_code, modifiers }) if modifiers.command() => {
+                    match key_code {
+                        KeyCode::Z if modifiers.shift() => Some(Message::Redo),
+                        KeyCode::Z => Some(Message::Undo),
+                        KeyCode::Y => Some(Message::Redo),
+                        KeyCode::C if modifiers.shift() => Some(Message::CopyEdits),
+                        KeyCode::V if modifiers.shift() => Some(Message::PasteEdits),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }
+        })
+    }
+
+    fn view(&self) -> Element<'_, Message> {
+        let current_photo = self.current_photo
+            .and_then(|i| self.photos[i].as_ref());
+        
+        let mut undo_button = button("Undo");
+        if self.history.can_undo() {
+            undo_button = undo_button.on_press(Message::Undo);
+        }
+        let mut redo_button = button("Redo");
+        if self.history.can_redo() {
+            redo_button = redo_button.on_press(Message::Redo);
+        }
+
+        let controls = row![
+            button("Previous").on_press(Message::PreviousPhoto),
+            button("Load Directory").on_press(Message::LoadDirectory),
+            button("Next").on_press(Message::NextPhoto),
+            button("Find Duplicates").on_press(Message::FindDuplicates),
+            undo_button,
+            redo_button,
+            text_input("Filter, e.g. iso>3200 camera:\"X-T3\" rating>=3", &self.filter_query)
+                .on_input(Message::FilterChanged)
+                .width(Length::Fill),
+            text(format!("{} of {}", self.visible.len(), self.photo_paths.len())),
+            pick_list(&SortMode::ALL[..], Some(self.sort_mode), Message::SortModeChanged),
+            button(match self.sort_order {
+                SortOrder::Ascending => "↑",
+                SortOrder::Descending => "↓",
+            })
+            .on_press(Message::SortOrderToggled),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        let target_count = self.targets().len();
+        let mut merge_button = button(if self.merging { "Merging…" } else { "Merge to HDR…" });
+        if !self.merging {
+            merge_button = merge_button.on_press(Message::MergeHdr);
+        }
+        let file_ops = row![
+            text(match (self.selected.len(), target_count) {
+                (0, 0) => "No photo".to_string(),
+                (0, _) => "Current photo:".to_string(),
+                (_, n) => format!("{} selected:", n),
+            }),
+            button("Move to Trash").on_press(Message::TrashPhotos),
+            button("Move to…").on_press(Message::ChooseDestination { copy: false }),
+            button("Copy to…").on_press(Message::ChooseDestination { copy: true }),
+            text_input("New name", &self.rename_to)
+                .on_input(Message::RenameInputChanged)
+                .on_submit(Message::RenamePhoto)
+                .width(Length::Fixed(200.0)),
+            button("Rename").on_press(Message::RenamePhoto),
+            button("Batch Rename…").on_press(Message::OpenBatchRename),
+            button("Export…").on_press(Message::OpenExport),
+            merge_button,
+            text_input("Keywords, comma separated", &self.keywords_input)
+                .on_input(Message::KeywordsInputChanged)
+                .on_submit(Message::SetKeywords)
+                .width(Length::Fixed(250.0)),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        let content = if !self.duplicates.is_empty() {
+            ui::duplicates_view(&self.duplicates, &self.duplicate_keep)
+        } else if let Some(previews) = &self.batch_previews {
+            ui::batch_rename_view(&self.batch_template, self.batch_error.as_deref(), previews)
+        } else if self.export_open {
+            ui::export_view(
+                &self.export,
+                self.export_preset,
+                &self.export_size,
+                &self.export_name,
+                target_count,
+                self.exporting,
+            )
+        } else if let Some(photo) = current_photo {
+            row![self.photo_view.view(photo, self.clipping_warnings, self.proofing, self.soft_proof.as_deref()), ui::develop_panel(photo, self.curve_channel, &self.clipboard, &self.presets, &self.snapshot_name, target_count)]
+                .spacing(20)
+                .into()
+        } else {
+            text("No photo selected").into()
+        };
+
+        let error_text = if let Some(error) = &self.error {
+            Element::from(
+                container(
+                    text(error)
+                        .style(iced::theme::Text::Color(iced::Color::from_rgb(0.8, 0.0, 0.0)))
+                )
+                .padding(10)
+            )
+        } else if let Some(status) = &self.status {
+            Element::from(container(text(status)).padding(10))
+        } else {
+            Element::from(container(text("")).padding(10))
+        };
+
+        let mut entries = Vec::new();
+        for stack in &self.stacks {
+            let expanded = self.is_expanded(stack);
+            for (position, &index) in stack.iter().enumerate() {
+                if position > 0 && !expanded {
+                    break;
+                }
+                entries.push(ListEntry {
+                    index,
+                    name: self.photo_paths[index].file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                    stack_size: if position == 0 { stack.len() } else { 0 },
+                    expanded,
+                    selected: self.current_photo == Some(index),
+                    checked: self.selected.contains(&self.photo_paths[index]),
+                });
+            }
+        }
+        let mut import_button = button(if self.importing { "Importing…" } else { "Import from Card…" });
+        if !self.importing {
+            import_button = import_button.on_press(Message::StartImport);
+        }
+
+        let mut import_preset = row![
+            pick_list(
+                self.presets.names(),
+                self.presets.on_import.as_ref().map(|p| p.name.clone()),
+                |name| Message::ImportPresetSelected(Some(name)),
+            )
+                .placeholder("No preset")
+                .width(Length::Fill),
+        ]
+        .spacing(5);
+        if self.presets.on_import.is_some() {
+            import_preset = import_preset.push(button("×").on_press(Message::ImportPresetSelected(None)));
+        }
+
+        let mut display_profile = row![
+            button(text(self.display_profile.as_deref().unwrap_or("sRGB display")))
+                .on_press(Message::ChooseDisplayProfile)
+                .width(Length::Fill),
+        ]
+        .spacing(5);
+        if self.display_profile.is_some() {
+            display_profile = display_profile.push(button("×").on_press(Message::DisplayProfileChosen(None)));
+        }
+
+        let sidebar = column![
+            checkbox("Stack bursts", self.grouping.enabled, Message::GroupingToggled),
+            text(format!("Max gap: {:.1}s", self.grouping.max_gap)),
+            slider(0.5..=10.0, self.grouping.max_gap, Message::BurstGapChanged).step(0.5),
+            checkbox("Similar frames only", self.grouping.use_similarity, Message::SimilarityToggled),
+            text("Import"),
+            text_input("Folders", &self.import_folders).on_input(Message::ImportFoldersChanged),
+            text_input("File names (keep original)", &self.import_rename).on_input(Message::ImportRenameChanged),
+            import_preset,
+            import_button,
+            display_profile,
+            ui::photo_list(entries),
+        ]
+        .spacing(10)
+        .width(Length::Fixed(240.0));
+
+        let layout = column![controls, file_ops, error_text, row![sidebar, content].spacing(20)].spacing(20).padding(20);
+
+        container(layout)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .into()
+    }
+}
+
+impl PhotoFlow {
+    /// Reacts to `message`. Displays it made out of date are rendered
+    /// afterwards, see [`PhotoFlow::start_renders`].
+    fn handle(&mut self, message: Message) -> Command<Message> {
         match message {
             Message::LoadDirectory => {
                 debug!("Loading directory...");
@@ -80,19 +566,14 @@ impl Application for PhotoFlow {
                     {
                         let mut paths = Vec::new();
                         if let Ok(entries) = std::fs::read_dir(folder.path()) {
-                            for entry in entries {
-                                if let Ok(entry) = entry {
-                                    let path = entry.path();
-                                    if let Some(ext) = path.extension() {
-                                        let ext = ext.to_string_lossy().to_lowercase();
-                                        if ext == "jpg" || ext == "jpeg" || ext == "raf" || ext == "raw" {
-                                            paths.push(path);
-                                        }
-                                    }
+                            for entry in entries.flatten() {
+                                let path = entry.path();
+                                if detector::has_image_extension(&path) {
+                                    paths.push(path);
                                 }
                             }
                         }
-                        Message::DirectoryLoaded(paths)
+                        Message::DirectoryLoaded(folder.path().to_path_buf(), paths)
                     } else {
                         Message::Error("No directory selected".to_string())
                     }
@@ -100,151 +581,571 @@ impl Application for PhotoFlow {
                     Message::from,
                 )
             }
-            Message::DirectoryLoaded(paths) => {
+            Message::DirectoryLoaded(directory, paths) => {
                 debug!("Directory loaded with {} paths", paths.len());
                 self.error = None;
+                self.status = None;
+                self.directory = Some(directory);
+                self.selected.clear();
                 
                 if !paths.is_empty() {
-                    // Store paths and initialize photos vector with None
-                    let paths_len = paths.len();
-                    let first_path = paths[0].clone();
-                    self.photo_paths = paths;
+                    // RAW+JPEG pairs become a single entry
+                    let groups = photo::group_representations(paths);
+                    // Virtual copies are listed as photos of their own
+                    let copies = CopyIndex::scan(groups.iter().map(|files| &files[0]));
+                    let entries: Vec<(PathBuf, Vec<PathBuf>)> = groups
+                        .into_iter()
+                        .flat_map(|files| {
+                            let keys = std::iter::once(files[0].clone()).chain(copies.of(&files[0]).to_vec());
+                            keys.map(move |key| (key, files.clone()))
+                        })
+                        .collect();
+                    let paths_len = entries.len();
+                    (self.photo_paths, self.representations) = entries.into_iter().unzip();
                     self.photos = vec![None; paths_len];
-                    self.current_photo = Some(0);
-                    
-                    // Load only the first photo
-                    let first_path_clone = first_path.clone();
-                    return Command::perform(
+                    self.metadata = vec![None; paths_len];
+                    self.current_photo = None;
+                    self.sort_photos();
+
+                    // Read metadata for the whole directory in the background so
+                    // the filter can see photos that haven't been opened yet
+                    let scan_paths: Vec<(PathBuf, PathBuf)> = self.photo_paths
+                        .iter()
+                        .cloned()
+                        .zip(self.representations.iter().map(|files| files[0].clone()))
+                        .collect();
+                    let scan = Command::perform(
                         async move {
-                            match Photo::new(first_path.clone()) {
-                                Ok(mut photo) => {
-                                    if let Ok(image) = photo.load_image() {
-                                        photo.set_image(image);
-                                        Some(photo)
-                                    } else {
-                                        None
-                                    }
-                                },
-                                Err(_) => None
-                            }
+                            tokio::task::spawn_blocking(move || {
+                                scan_paths
+                                    .into_iter()
+                                    .map(|(path, file)| {
+                                        let exif = ExifData::read_with_sidecar(&file, &path).ok();
+                                        (path, exif)
+                                    })
+                                    .collect()
+                            })
+                            .await
+                            .unwrap_or_default()
                         },
-                        move |result| {
-                            if let Some(photo) = result {
-                                Message::ImageLoaded(first_path_clone, photo.image)
-                            } else {
-                                Message::Error(format!("Failed to load image: {}", first_path_clone.display()))
-                            }
-                        }
+                        Message::MetadataLoaded,
                     );
+
+                    return Command::batch([self.apply_filter(), scan]);
                 } else {
                     self.error = Some("No photos found in directory".to_string());
                 }
                 
                 Command::none()
             }
-            Message::PhotoSelected(index) => {
-                if index < self.photos.len() {
-                    self.current_photo = Some(index);
-                    
-                    // If photo isn't loaded yet, load it
-                    if self.photos[index].is_none() {
-                        let path = self.photo_paths[index].clone();
-                        let path_clone = path.clone();
-                        return Command::perform(
-                            async move {
-                                match Photo::new(path.clone()) {
-                                    Ok(mut photo) => {
-                                        if let Ok(image) = photo.load_image() {
-                                            photo.set_image(image.clone());
-                                            Some((photo, image))
-                                        } else {
-                                            None
-                                        }
-                                    },
-                                    Err(_) => None
-                                }
-                            },
-                            move |result| {
-                                if let Some((photo, image)) = result {
-                                    Message::ImageLoaded(path_clone, Some(image))
-                                } else {
-                                    Message::Error(format!("Failed to load image: {}", path_clone.display()))
-                                }
-                            }
-                        );
+            Message::MetadataLoaded(entries) => {
+                debug!("Metadata loaded for {} photos", entries.len());
+                for (path, exif) in entries {
+                    // The user may have switched directories while scanning
+                    if let Some(index) = self.photo_paths.iter().position(|p| p == &path) {
+                        self.metadata[index] = exif;
+                    }
+                }
+                if self.sort_mode.uses_exif() {
+                    self.sort_photos();
+                }
+                self.apply_filter()
+            }
+            Message::SortModeChanged(mode) => {
+                self.sort_mode = mode;
+                self.sort_photos();
+                self.apply_filter()
+            }
+            Message::SortOrderToggled => {
+                self.sort_order = self.sort_order.toggled();
+                self.sort_photos();
+                self.apply_filter()
+            }
+            Message::FilterChanged(query) => {
+                match Filter::parse(&query) {
+                    Ok(filter) => {
+                        self.filter = filter;
+                        self.error = None;
                     }
+                    Err(e) => self.error = Some(format!("Invalid filter: {}", e)),
                 }
+                self.filter_query = query;
+                self.apply_filter()
+            }
+            Message::GroupingToggled(enabled) => {
+                self.grouping.enabled = enabled;
+                self.regroup();
                 Command::none()
             }
+            Message::BurstGapChanged(gap) => {
+                self.grouping.max_gap = gap;
+                self.regroup();
+                Command::none()
+            }
+            Message::SimilarityToggled(enabled) => {
+                self.grouping.use_similarity = enabled;
+                self.regroup();
+                Command::none()
+            }
+            Message::ToggleStack(top) => {
+                let key = self.photo_paths[top].clone();
+                if !self.expanded_stacks.remove(&key) {
+                    self.expanded_stacks.insert(key);
+                } else if let Some(stack) = self.stacks.iter().find(|s| s.first() == Some(&top)) {
+                    // Collapsing hides the current photo; select the stack itself instead
+                    if self.current_photo.is_some_and(|c| stack.contains(&c)) {
+                        return self.select_photo(top);
+                    }
+                }
+                Command::none()
+            }
+            Message::PhotoSelected(index) => self.select_photo(index),
             Message::NextPhoto => {
-                if let Some(current) = self.current_photo {
-                    if current + 1 < self.photos.len() {
-                        let next = current + 1;
-                        if self.photos[next].is_none() {
-                            let path = self.photo_paths[next].clone();
-                            let path_clone = path.clone();
-                            return Command::perform(
-                                async move {
-                                    match Photo::new(path.clone()) {
-                                        Ok(mut photo) => {
-                                            if let Ok(image) = photo.load_image() {
-                                                photo.set_image(image);
-                                                Some(photo)
-                                            } else {
-                                                None
-                                            }
-                                        },
-                                        Err(_) => None
-                                    }
-                                },
-                                move |result| {
-                                    if let Some(photo) = result {
-                                        Message::ImageLoaded(path_clone, photo.image)
-                                    } else {
-                                        Message::Error(format!("Failed to load image: {}", path_clone.display()))
-                                    }
-                                }
-                            );
+                let navigable = self.navigable();
+                let position = self.current_photo
+                    .and_then(|current| navigable.iter().position(|&i| i == current));
+                let next = match position {
+                    Some(position) => navigable.get(position + 1),
+                    None => navigable.first(),
+                };
+                match next.copied() {
+                    Some(next) => self.select_photo(next),
+                    None => Command::none(),
+                }
+            }
+            Message::PreviousPhoto => {
+                let navigable = self.navigable();
+                let position = self.current_photo
+                    .and_then(|current| navigable.iter().position(|&i| i == current));
+                let previous = match position {
+                    Some(position) if position > 0 => navigable.get(position - 1),
+                    Some(_) => None,
+                    None => navigable.first(),
+                };
+                match previous.copied() {
+                    Some(previous) => self.select_photo(previous),
+                    None => Command::none(),
+                }
+            }
+            Message::ToggleSelection(index) => {
+                let path = self.photo_paths[index].clone();
+                if !self.selected.remove(&path) {
+                    self.selected.insert(path);
+                }
+                Command::none()
+            }
+            Message::TrashPhotos => {
+                let targets = self.targets();
+                let copies = CopyIndex::scan(targets.iter().map(|&i| &self.representations[i][0]));
+                let mut seen = HashSet::new();
+                let files: Vec<PathBuf> = targets
+                    .into_iter()
+                    .flat_map(|i| self.files_of_photo(i, &copies))
+                    .filter(|file| seen.insert(file.clone()))
+                    .collect();
+                match fileops::trash(&files) {
+                    Ok(trashed) => {
+                        self.history.record(Operation::Trashed(trashed));
+                        self.files_removed(&files)
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Failed to move to trash: {:#}", e));
+                        Command::none()
+                    }
+                }
+            }
+            Message::ChooseDestination { copy } => {
+                let paths: Vec<PathBuf> = self.targets().iter().map(|&i| self.photo_paths[i].clone()).collect();
+                if paths.is_empty() {
+                    return Command::none();
+                }
+                Command::perform(
+                    async move {
+                        let title = if copy { "Copy Photos To" } else { "Move Photos To" };
+                        match rfd::AsyncFileDialog::new().set_title(title).pick_folder().await {
+                            Some(folder) => Message::TransferPhotos { paths, dest: folder.path().to_path_buf(), copy },
+                            None => Message::Error("No folder selected".to_string()),
                         }
-                        self.current_photo = Some(next);
+                    },
+                    Message::from,
+                )
+            }
+            Message::TransferPhotos { paths, dest, copy } => {
+                let indices: Vec<usize> = paths.iter().filter_map(|path| self.index_of_photo(path)).collect();
+                let copies = CopyIndex::scan(indices.iter().map(|&i| &self.representations[i][0]));
+                let mut seen = HashSet::new();
+                let files: Vec<PathBuf> = indices
+                    .into_iter()
+                    .flat_map(|i| fileops::files_of(&self.representations[i], &copies))
+                    .filter(|file| seen.insert(file.clone()))
+                    .collect();
+                if copy {
+                    if let Err(e) = fileops::copy_files(&files, &dest) {
+                        self.error = Some(format!("Failed to copy: {:#}", e));
                     }
+                    return Command::none();
                 }
+                match fileops::move_files(&files, &dest) {
+                    Ok(moves) => {
+                        self.history.record(Operation::Moved(moves.clone()));
+                        self.apply_changes(FileChanges { moved: moves, ..Default::default() })
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Failed to move: {:#}", e));
+                        Command::none()
+                    }
+                }
+            }
+            Message::RenameInputChanged(name) => {
+                self.rename_to = name;
                 Command::none()
             }
-            Message::PreviousPhoto => {
-                if let Some(current) = self.current_photo {
-                    if current > 0 {
-                        self.current_photo = Some(current - 1);
-                        if self.photos[current - 1].is_none() {
-                            let path = self.photo_paths[current - 1].clone();
-                            let path_clone = path.clone();
-                            return Command::perform(
-                                async move {
-                                    match Photo::new(path.clone()) {
-                                        Ok(mut photo) => {
-                                            if let Ok(image) = photo.load_image() {
-                                                photo.set_image(image);
-                                                Some(photo)
-                                            } else {
-                                                None
-                                            }
-                                        },
-                                        Err(_) => None
-                                    }
-                                },
-                                move |result| {
-                                    if let Some(photo) = result {
-                                        Message::ImageLoaded(path_clone, photo.image)
-                                    } else {
-                                        Message::Error(format!("Failed to load image: {}", path_clone.display()))
-                                    }
-                                }
-                            );
+            Message::RenamePhoto => {
+                let Some(index) = self.current_photo else {
+                    return Command::none();
+                };
+                let representations = &self.representations[index];
+                let files = fileops::files_of(representations, &CopyIndex::scan(representations));
+                match fileops::rename_files(&files, self.rename_to.trim()) {
+                    Ok(moves) => {
+                        self.files_moved(&moves);
+                        self.history.record(Operation::Moved(moves));
+                        self.rename_to.clear();
+                    }
+                    Err(e) => self.error = Some(format!("Failed to rename: {:#}", e)),
+                }
+                Command::none()
+            }
+            Message::OpenBatchRename => {
+                self.batch_previews = Some(Vec::new());
+                self.update_batch_preview();
+                Command::none()
+            }
+            Message::BatchTemplateChanged(template) => {
+                self.batch_template = template;
+                self.update_batch_preview();
+                Command::none()
+            }
+            Message::ApplyBatchRename => {
+                let Some(previews) = &self.batch_previews else {
+                    return Command::none();
+                };
+                match rename::apply(previews) {
+                    Ok(moves) => {
+                        info!("Batch renamed {} files", moves.len());
+                        self.files_moved(&moves);
+                        self.history.record(Operation::Moved(moves));
+                        self.batch_previews = None;
+                        self.sort_photos();
+                        self.apply_filter()
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Failed to rename: {:#}", e));
+                        self.update_batch_preview();
+                        Command::none()
+                    }
+                }
+            }
+            Message::CloseBatchRename => {
+                self.batch_previews = None;
+                Command::none()
+            }
+            Message::ImportFoldersChanged(template) => {
+                self.import_folders = template;
+                Command::none()
+            }
+            Message::ImportRenameChanged(template) => {
+                self.import_rename = template;
+                Command::none()
+            }
+            Message::StartImport => {
+                let folders = match Template::parse(&self.import_folders) {
+                    Ok(template) => template,
+                    Err(e) => {
+                        self.error = Some(format!("Invalid folder template: {}", e));
+                        return Command::none();
+                    }
+                };
+                let rename = match self.import_rename.trim() {
+                    "" => None,
+                    template => match Template::parse(template) {
+                        Ok(template) => Some(template),
+                        Err(e) => {
+                            self.error = Some(format!("Invalid name template: {}", e));
+                            return Command::none();
                         }
+                    },
+                };
+                let preset = self.presets.on_import.clone();
+                self.importing = true;
+                Command::perform(
+                    async move {
+                        let Some(source) = rfd::AsyncFileDialog::new()
+                            .set_title("Select Card or Folder to Import From")
+                            .pick_folder()
+                            .await
+                        else {
+                            return Message::ImportFinished(Err("No source selected".to_string()));
+                        };
+                        let Some(destination) = rfd::AsyncFileDialog::new()
+                            .set_title("Select Library Folder to Import Into")
+                            .pick_folder()
+                            .await
+                        else {
+                            return Message::ImportFinished(Err("No destination selected".to_string()));
+                        };
+                        let settings = ImportSettings {
+                            source: source.path().to_path_buf(),
+                            destination: destination.path().to_path_buf(),
+                            folders,
+                            rename,
+                            preset,
+                        };
+                        let result = tokio::task::spawn_blocking(move || import::import(&settings))
+                            .await
+                            .map_err(|e| e.to_string())
+                            .and_then(|result| result.map_err(|e| format!("{:#}", e)));
+                        Message::ImportFinished(result)
+                    },
+                    Message::from,
+                )
+            }
+            Message::ImportFinished(result) => {
+                self.importing = false;
+                match result {
+                    Ok(report) if report.failed.is_empty() => self.status = Some(report.summary()),
+                    Ok(report) => self.error = Some(report.summary()),
+                    Err(e) => self.error = Some(format!("Import failed: {}", e)),
+                }
+                Command::none()
+            }
+            Message::OpenExport => {
+                self.export_open = true;
+                Command::none()
+            }
+            Message::CloseExport => {
+                self.export_open = false;
+                Command::none()
+            }
+            Message::ExportPresetSelected(preset) => {
+                self.export = preset.settings();
+                self.export_preset = Some(preset);
+                self.export_size = self.export.resize.to_string();
+                self.export_name = "{name}".to_string();
+                Command::none()
+            }
+            Message::ExportFormatSelected(format) => {
+                self.export.format = format;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportQualityChanged(quality) => {
+                self.export.quality = quality;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportSixteenBitToggled(sixteen_bit) => {
+                self.export.sixteen_bit = sixteen_bit;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportColorSpaceSelected(color_space) => {
+                self.export.color_space = color_space;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportSizeChanged(size) => {
+                self.export_size = size;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportSharpeningSelected(sharpening) => {
+                self.export.sharpening = sharpening;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportMetadataSelected(metadata) => {
+                self.export.metadata = metadata;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::ExportNameChanged(name) => {
+                self.export_name = name;
+                self.export_preset = None;
+                Command::none()
+            }
+            Message::StartExport => {
+                let Some(resize) = Resize::parse(&self.export_size) else {
+                    self.error = Some(format!("Invalid size \"{}\": use 2048, 1920x1080 or 50%", self.export_size));
+                    return Command::none();
+                };
+                let name = match Template::parse(&self.export_name) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        self.error = Some(format!("Invalid name template: {}", e));
+                        return Command::none();
                     }
+                };
+                let photos: Vec<ExportSource> = self.targets()
+                    .into_iter()
+                    .map(|i| ExportSource {
+                        key: self.photo_paths[i].clone(),
+                        file: self.export_file(i).to_path_buf(),
+                        exif: self.metadata[i].clone(),
+                    })
+                    .collect();
+                if photos.is_empty() {
+                    return Command::none();
+                }
+
+                let settings = ExportSettings { resize, name, ..self.export.clone() };
+                self.exporting = true;
+                Command::perform(
+                    async move {
+                        let Some(destination) = rfd::AsyncFileDialog::new()
+                            .set_title("Export To")
+                            .pick_folder()
+                            .await
+                        else {
+                            return Message::ExportFinished(ExportReport::default());
+                        };
+                        let destination = destination.path().to_path_buf();
+                        let report = tokio::task::spawn_blocking(move || export::export(&photos, &destination, &settings))
+                            .await
+                            .unwrap_or_default();
+                        Message::ExportFinished(report)
+                    },
+                    Message::from,
+                )
+            }
+            Message::ExportFinished(report) => {
+                self.exporting = false;
+                if !report.failed.is_empty() {
+                    self.error = Some(report.summary());
+                } else if !report.exported.is_empty() {
+                    self.status = Some(report.summary());
                 }
                 Command::none()
             }
+            Message::MergeHdr => {
+                let mut sources: Vec<PathBuf> = Vec::new();
+                for index in self.targets() {
+                    let Some(raw) = self.representations[index].iter().find(|p| detector::has_raw_extension(p)) else {
+                        self.error = Some(format!("{} has no RAW file to merge", self.photo_paths[index].display()));
+                        return Command::none();
+                    };
+                    if !sources.contains(raw) {
+                        sources.push(raw.clone());
+                    }
+                }
+                if sources.len() < 2 {
+                    self.error = Some("Select at least two RAW exposures to merge".to_string());
+                    return Command::none();
+                }
+                let Some(destination) = merge::default_destination(&sources) else {
+                    return Command::none();
+                };
+                self.merging = true;
+                Command::perform(
+                    async move {
+                        let mut dialog = rfd::AsyncFileDialog::new().set_title("Save Merged HDR As");
+                        for format in HdrFormat::ALL {
+                            dialog = dialog.add_filter(&format.to_string(), format.extensions());
+                        }
+                        if let (Some(folder), Some(name)) = (destination.parent(), destination.file_name()) {
+                            dialog = dialog.set_directory(folder).set_file_name(name.to_string_lossy().as_ref());
+                        }
+                        let Some(file) = dialog.save_file().await else {
+                            return Message::HdrMerged(Err("No file chosen".to_string()));
+                        };
+                        let destination = file.path().to_path_buf();
+                        let result = tokio::task::spawn_blocking(move || {
+                            merge::merge_hdr(&sources, &destination).map(|()| destination)
+                        })
+                        .await
+                        .map_err(|e| e.to_string())
+                        .and_then(|result| result.map_err(|e| format!("{:#}", e)));
+                        Message::HdrMerged(result)
+                    },
+                    Message::from,
+                )
+            }
+            Message::HdrMerged(result) => {
+                self.merging = false;
+                match result {
+                    Ok(path) => {
+                        self.status = Some(format!("Saved merged HDR as {}", path.display()));
+                        self.files_added(&[path])
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("HDR merge failed: {}", e));
+                        Command::none()
+                    }
+                }
+            }
+            Message::FindDuplicates => {
+                Command::perform(
+                    async {
+                        let Some(folders) = rfd::AsyncFileDialog::new()
+                            .set_title("Select Folders to Search for Duplicates")
+                            .pick_folders()
+                            .await
+                        else {
+                            return Message::Error("No folders selected".to_string());
+                        };
+                        let folders: Vec<PathBuf> = folders.iter().map(|f| f.path().to_path_buf()).collect();
+                        let groups = tokio::task::spawn_blocking(move || duplicates::find_duplicates(&folders))
+                            .await
+                            .unwrap_or_default();
+                        Message::DuplicatesFound(groups)
+                    },
+                    Message::from,
+                )
+            }
+            Message::DuplicatesFound(groups) => {
+                if groups.is_empty() {
+                    self.status = Some("No duplicates found".to_string());
+                }
+                self.duplicate_keep = groups.iter().map(DuplicateGroup::suggested_keep).collect();
+                self.duplicates = groups;
+                Command::none()
+            }
+            Message::KeepDuplicate(group, file) => {
+                if let Some(keep) = self.duplicate_keep.get_mut(group) {
+                    *keep = file;
+                }
+                Command::none()
+            }
+            Message::TrashDuplicates(group) => {
+                let keep = self.duplicate_keep.get(group).copied().unwrap_or(0);
+                let Some(duplicates) = self.duplicates.get(group) else {
+                    return Command::none();
+                };
+                let trashing: Vec<PathBuf> = duplicates.files
+                    .iter()
+                    .enumerate()
+                    .filter(|&(i, _)| i != keep)
+                    .map(|(_, file)| file.path.clone())
+                    .collect();
+                let files = fileops::files_to_remove(&trashing);
+                match fileops::trash(&files) {
+                    Ok(trashed) => {
+                        self.history.record(Operation::Trashed(trashed));
+                        self.duplicates.remove(group);
+                        self.duplicate_keep.remove(group);
+                        self.files_removed(&files)
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Failed to move to trash: {:#}", e));
+                        Command::none()
+                    }
+                }
+            }
+            Message::CloseDuplicates => {
+                self.duplicates.clear();
+                self.duplicate_keep.clear();
+                Command::none()
+            }
             Message::Error(error) => {
                 info!("Error: {}", error);
                 self.error = Some(error);
@@ -252,60 +1153,1118 @@ impl Application for PhotoFlow {
             }
             Message::ImageLoaded(path, image) => {
                 debug!("Image loaded: {}", path.display());
-                if let Some(index) = self.photo_paths.iter().position(|p| p == &path) {
+                // Virtual copies share files with their original; the current
+                // photo is the one that asked for the image
+                let current = self.current_photo.filter(|&i| self.representations[i].contains(&path));
+                if let Some(index) = current.or_else(|| self.index_of_file(&path)) {
                     // Create new photo if it doesn't exist
                     if self.photos[index].is_none() {
-                        if let Ok(mut photo) = Photo::new(path.clone()) {
-                            if let Some(img) = image {
-                                photo.set_image(img);
-                            }
+                        let representations = self.representations[index].clone();
+                        let photo = if self.is_copy(index) {
+                            Photo::virtual_copy(self.photo_paths[index].clone(), representations)
+                        } else {
+                            Photo::with_representations(representations)
+                        };
+                        if let Ok(photo) = photo {
                             self.photos[index] = Some(photo);
                         }
-                    } else if let Some(photo) = &mut self.photos[index] {
-                        if let Some(img) = image {
+                    }
+                    let proof = self.active_proof();
+                    if let Some(photo) = &mut self.photos[index] {
+                        // Drop results for a representation the user has since toggled away from
+                        if let (Some(img), true) = (image, photo.path() == path) {
+                            photo.set_clipping_warnings(self.clipping_warnings);
+                            photo.set_soft_proof(proof);
                             photo.set_image(img);
                         }
                     }
+                    if self.current_photo == Some(index) {
+                        self.update_preset_preview();
+                    }
+                    // A new perceptual hash can change the stacks
+                    if self.grouping.enabled && self.grouping.use_similarity {
+                        self.regroup();
+                    }
+                }
+                Command::none()
+            }
+            Message::Rendered(key, generation, rendered) => {
+                if let Some(photo) = self.photos.iter_mut().flatten().find(|photo| photo.key() == key) {
+                    photo.finish_render(generation, rendered.map(|rendered| *rendered));
+                }
+                Command::none()
+            }
+            Message::ToggleRepresentation => {
+                let Some(photo) = self.current_photo.and_then(|i| self.photos[i].as_mut()) else {
+                    return Command::none();
+                };
+                match photo.next_representation().map(Path::to_path_buf) {
+                    Some(next) => {
+                        photo.show_representation(&next);
+                        load_image(next, photo.edits().highlight_mode)
+                    }
+                    None => Command::none(),
+                }
+            }
+            Message::SetRating(rating) => {
+                let targets = self.targets();
+                if let Err(e) = self.edit_sidecars(&targets, |s| s.rating = Some(rating)) {
+                    self.error = Some(format!("Failed to save rating: {:#}", e));
+                }
+                Command::none()
+            }
+            Message::AdjustmentChanged(adjustment, value) => {
+                self.edit_live(|edits| adjustment.set(edits, value));
+                Command::none()
+            }
+            Message::CropOverlayToggled(overlay) => {
+                if let Some(photo) = self.current_photo.and_then(|i| self.photos[i].as_mut()) {
+                    photo.set_crop_overlay(overlay);
+                }
+                Command::none()
+            }
+            Message::LuminanceNoiseChanged(value) | Message::ColorNoiseChanged(value) => {
+                let luminance = matches!(message, Message::LuminanceNoiseChanged(_));
+                self.edit_live(|edits| {
+                    if luminance {
+                        edits.noise.luminance = Some(value);
+                    } else {
+                        edits.noise.color = Some(value);
+                    }
+                });
+                Command::none()
+            }
+            Message::SharpeningChanged(field, value) => {
+                self.edit_live(|edits| field.set(&mut edits.sharpening, value));
+                Command::none()
+            }
+            Message::LensCorrectionChanged(field, value) => {
+                self.edit_live(|edits| field.set(&mut edits.lens, Some(value)));
+                Command::none()
+            }
+            Message::LevelsChanged(channel, field, value) => {
+                self.edit_live(|edits| field.set(edits.levels.get_mut(channel), value));
+                Command::none()
+            }
+            Message::HighlightClippingToggled(show) | Message::ShadowClippingToggled(show) => {
+                if matches!(message, Message::HighlightClippingToggled(_)) {
+                    self.clipping_warnings.highlights = show;
+                } else {
+                    self.clipping_warnings.shadows = show;
+                }
+                if let Some(photo) = self.current_photo.and_then(|i| self.photos[i].as_mut()) {
+                    photo.set_clipping_warnings(self.clipping_warnings);
                 }
                 Command::none()
             }
+            Message::ChooseDisplayProfile => Command::perform(
+                async {
+                    match rfd::AsyncFileDialog::new()
+                        .set_title("Select Display Profile")
+                        .add_filter("ICC profile", &["icc", "icm", "ICC", "ICM"])
+                        .pick_file()
+                        .await
+                    {
+                        Some(file) => Message::DisplayProfileChosen(Some(file.path().to_path_buf())),
+                        None => Message::Error("No profile selected".to_string()),
+                    }
+                },
+                Message::from,
+            ),
+            Message::DisplayProfileChosen(path) => {
+                if let Err(e) = color::set_display_profile(path.as_deref()) {
+                    self.error = Some(format!("Failed to set display profile: {:#}", e));
+                }
+                self.display_profile = color::display_profile_name();
+                // Proofs are converted for the display too
+                self.update_soft_proof();
+                let proof = self.active_proof();
+                for photo in self.photos.iter_mut().flatten() {
+                    match &proof {
+                        Some(proof) => photo.set_soft_proof(Some(proof.clone())),
+                        None => photo.refresh_display(),
+                    }
+                }
+                self.update_preset_preview();
+                Command::none()
+            }
+            Message::SoftProofToggled(enabled) => {
+                self.proofing.enabled = enabled;
+                if enabled && self.proof_profile.is_none() {
+                    return self.update(Message::ChooseProofProfile);
+                }
+                self.show_soft_proof();
+                Command::none()
+            }
+            Message::ChooseProofProfile => Command::perform(
+                async {
+                    match rfd::AsyncFileDialog::new()
+                        .set_title("Select Printer or Output Profile")
+                        .add_filter("ICC profile", &["icc", "icm", "ICC", "ICM"])
+                        .pick_file()
+                        .await
+                    {
+                        Some(file) => Message::ProofProfileChosen(file.path().to_path_buf()),
+                        None => Message::SoftProofToggled(false),
+                    }
+                },
+                Message::from,
+            ),
+            Message::ProofProfileChosen(path) => {
+                match std::fs::read(&path) {
+                    Ok(icc) => {
+                        self.proof_profile = Some(icc);
+                        self.proofing.enabled = true;
+                        self.update_soft_proof();
+                    }
+                    Err(e) => self.error = Some(format!("Failed to read {}: {}", path.display(), e)),
+                }
+                self.show_soft_proof();
+                Command::none()
+            }
+            Message::ProofIntentSelected(intent) => {
+                self.proofing.intent = intent;
+                self.update_soft_proof();
+                self.show_soft_proof();
+                Command::none()
+            }
+            Message::GamutWarningToggled(gamut_warning) => {
+                self.proofing.gamut_warning = gamut_warning;
+                self.update_soft_proof();
+                self.show_soft_proof();
+                Command::none()
+            }
+            Message::CurveChannelSelected(channel) => {
+                self.curve_channel = channel;
+                Command::none()
+            }
+            Message::CurveChanged(channel, curve) => {
+                self.edit_live(|edits| *edits.curve.get_mut(channel) = curve);
+                Command::none()
+            }
+            Message::CropEdgeChanged(edge, value) => {
+                self.edit_crop(|crop, (width, height)| crop.set_edge(edge, value, width, height));
+                Command::none()
+            }
+            Message::CropAngleChanged(angle) => {
+                self.edit_crop(|crop, (width, height)| crop.set_angle(angle, width, height));
+                Command::none()
+            }
+            Message::SaveEdits
+            | Message::ResetEdits
+            | Message::CropAspectSelected(_)
+            | Message::ResetCrop
+            | Message::HighlightModeSelected(_)
+            | Message::LensProfileToggled(_)
+            | Message::ResetLensCorrections
+            | Message::NoiseReductionAuto => {
+                let Some(index) = self.current_photo else {
+                    return Command::none();
+                };
+                match message {
+                    Message::CropAspectSelected(aspect) => {
+                        self.edit_crop(|crop, (width, height)| crop.set_aspect(aspect, width, height));
+                    }
+                    Message::ResetCrop => self.edit_crop(|crop, _| *crop = Crop::default()),
+                    _ => {}
+                }
+                let Some(photo) = self.photos[index].as_mut() else {
+                    return Command::none();
+                };
+                let mut edits = photo.edits().clone();
+                match message {
+                    Message::HighlightModeSelected(mode) => edits.highlight_mode = mode,
+                    Message::NoiseReductionAuto => edits.noise = NoiseReduction::default(),
+                    Message::LensProfileToggled(enabled) => edits.lens.ignore_profile = !enabled,
+                    Message::ResetLensCorrections => {
+                        edits.lens = LensCorrection { ignore_profile: edits.lens.ignore_profile, ..Default::default() };
+                    }
+                    _ => {}
+                }
+                photo.set_edits(edits);
+                if matches!(message, Message::ResetEdits) {
+                    photo.set_edits(EditParams::default());
+                }
+                let edits = photo.edits().clone();
+                let result = if self.clipboard.sync {
+                    // Carry over only what changed since the last save, so
+                    // each photo keeps the rest of its own settings
+                    let saved = sidecar::read(photo.key()).ok().flatten().map(|s| s.edits).unwrap_or_default();
+                    let groups = edit_sync::changed(&saved, &edits);
+                    let mut indices = self.targets();
+                    if !indices.contains(&index) {
+                        indices.push(index);
+                    }
+                    self.edit_sidecars(&indices, |s| s.edits = edit_sync::paste(&edits, &s.edits, &groups))
+                } else {
+                    self.edit_sidecars(&[index], |s| s.edits = edits.clone())
+                };
+                if let Err(e) = result {
+                    self.error = Some(format!("Failed to save edits: {:#}", e));
+                }
+                self.update_preset_preview();
+                self.decode_if_needed()
+            }
+            Message::CopyEdits => {
+                if let Some(photo) = self.current_photo.and_then(|i| self.photos[i].as_ref()) {
+                    self.clipboard.copied = Some(photo.edits().clone());
+                }
+                Command::none()
+            }
+            Message::PasteEdits => {
+                let Some(copied) = self.clipboard.copied.clone() else {
+                    return Command::none();
+                };
+                let groups = self.clipboard.groups.clone();
+                let targets = self.targets();
+                if let Err(e) = self.edit_sidecars(&targets, |s| s.edits = edit_sync::paste(&copied, &s.edits, &groups)) {
+                    self.error = Some(format!("Failed to paste edits: {:#}", e));
+                }
+                self.decode_if_needed()
+            }
+            Message::PresetSelected(name) => {
+                self.presets.selected = self.presets.find(&name);
+                self.update_preset_preview();
+                Command::none()
+            }
+            Message::PresetNameChanged(name) => {
+                self.presets.name = name;
+                Command::none()
+            }
+            Message::SavePreset => {
+                let Some(photo) = self.current_photo.and_then(|i| self.photos[i].as_ref()) else {
+                    return Command::none();
+                };
+                let preset = Preset::new(&self.presets.name, photo.edits(), &self.clipboard.groups);
+                let result = presets::save(&preset).and_then(|_| self.presets.reload());
+                match result {
+                    Ok(()) => {
+                        self.presets.name.clear();
+                        self.presets.selected = Some(preset);
+                        self.update_preset_preview();
+                    }
+                    Err(e) => self.error = Some(format!("Failed to save preset: {:#}", e)),
+                }
+                Command::none()
+            }
+            Message::DeletePreset => {
+                if let Some(preset) = self.presets.selected.take() {
+                    if let Err(e) = presets::delete(&preset).and_then(|_| self.presets.reload()) {
+                        self.error = Some(format!("Failed to delete preset: {:#}", e));
+                    }
+                }
+                self.update_preset_preview();
+                Command::none()
+            }
+            Message::ApplyPreset => {
+                let Some(preset) = self.presets.selected.clone() else {
+                    return Command::none();
+                };
+                let targets = self.targets();
+                if let Err(e) = self.edit_sidecars(&targets, |s| s.edits = preset.apply(&s.edits)) {
+                    self.error = Some(format!("Failed to apply preset: {:#}", e));
+                }
+                self.update_preset_preview();
+                self.decode_if_needed()
+            }
+            Message::ImportPresetSelected(name) => {
+                self.presets.on_import = name.and_then(|name| self.presets.find(&name));
+                Command::none()
+            }
+            Message::PasteGroupToggled(group, enabled) => {
+                self.clipboard.toggle(group, enabled);
+                Command::none()
+            }
+            Message::SyncEditsToggled(sync) => {
+                self.clipboard.sync = sync;
+                Command::none()
+            }
+            Message::SnapshotNameChanged(name) => {
+                self.snapshot_name = name;
+                Command::none()
+            }
+            Message::TakeSnapshot => {
+                let Some(index) = self.current_photo else {
+                    return Command::none();
+                };
+                let Some(photo) = self.photos[index].as_ref() else {
+                    return Command::none();
+                };
+                let name = match self.snapshot_name.trim() {
+                    "" => format!("Snapshot {}", photo.snapshots().len() + 1),
+                    name => name.to_string(),
+                };
+                let snapshot = Snapshot { name, edits: photo.edits().clone() };
+                match self.edit_sidecars(&[index], |s| s.snapshots.push(snapshot.clone())) {
+                    Ok(()) => self.snapshot_name.clear(),
+                    Err(e) => self.error = Some(format!("Failed to save snapshot: {:#}", e)),
+                }
+                Command::none()
+            }
+            Message::RestoreSnapshot(position) | Message::DeleteSnapshot(position) => {
+                let Some(index) = self.current_photo else {
+                    return Command::none();
+                };
+                let restore = matches!(message, Message::RestoreSnapshot(_));
+                let result = self.edit_sidecars(&[index], |s| {
+                    if position >= s.snapshots.len() {
+                        return;
+                    }
+                    if restore {
+                        s.edits = s.snapshots[position].edits.clone();
+                    } else {
+                        s.snapshots.remove(position);
+                    }
+                });
+                if let Err(e) = result {
+                    self.error = Some(format!("Failed to update snapshots: {:#}", e));
+                }
+                self.update_preset_preview();
+                self.decode_if_needed()
+            }
+            Message::CreateVirtualCopy => {
+                let Some(index) = self.current_photo else {
+                    return Command::none();
+                };
+                let Some(photo) = self.photos[index].as_ref() else {
+                    return Command::none();
+                };
+                // Ratings, labels and keywords start out empty
+                let copy = Sidecar { edits: photo.edits().clone(), ..Default::default() };
+                let source = self.representations[index][0].clone();
+                match sidecar::create_copy(&source, &copy) {
+                    Ok(key) => {
+                        self.metadata.push(ExifData::read_with_sidecar(&source, &key).ok());
+                        self.photo_paths.push(key.clone());
+                        self.representations.push(self.representations[index].clone());
+                        self.photos.push(None);
+                        self.sort_photos();
+                        let filter = self.apply_filter();
+                        match self.photo_paths.iter().position(|p| *p == key) {
+                            Some(copy) => Command::batch([filter, self.select_photo(copy)]),
+                            None => filter,
+                        }
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("Failed to create virtual copy: {:#}", e));
+                        Command::none()
+                    }
+                }
+            }
+            Message::KeywordsInputChanged(keywords) => {
+                self.keywords_input = keywords;
+                Command::none()
+            }
+            Message::SetKeywords => {
+                let keywords: Vec<String> = self.keywords_input
+                    .split(',')
+                    .map(|k| k.trim().to_string())
+                    .filter(|k| !k.is_empty())
+                    .collect();
+                let targets = self.targets();
+                if let Err(e) = self.edit_sidecars(&targets, |s| s.keywords = keywords.clone()) {
+                    self.error = Some(format!("Failed to save keywords: {:#}", e));
+                }
+                Command::none()
+            }
+            Message::Undo | Message::Redo => {
+                let result = if matches!(message, Message::Undo) {
+                    self.history.undo()
+                } else {
+                    self.history.redo()
+                };
+                match result {
+                    Some(Ok(changes)) => self.apply_changes(changes),
+                    Some(Err(e)) => {
+                        self.error = Some(format!("Failed to undo/redo: {:#}", e));
+                        Command::none()
+                    }
+                    None => Command::none(),
+                }
+            }
         }
     }
 
-    fn view(&self) -> Element<Message> {
-        let current_photo = self.current_photo
-            .and_then(|i| self.photos[i].as_ref());
-        
-        let controls = row![
-            button("Previous").on_press(Message::PreviousPhoto),
-            button("Load Directory").on_press(Message::LoadDirectory),
-            button("Next").on_press(Message::NextPhoto),
-        ]
-        .spacing(10);
+    /// Makes `index` the current photo, loading it if it isn't loaded yet.
+    /// Renders the previews of loaded photos whose display is out of date,
+    /// away from the UI thread. While a photo renders, further changes wait
+    /// for it and are rendered together.
+    fn start_renders(&mut self) -> Command<Message> {
+        let renders = self.photos.iter_mut().flatten().filter_map(|photo| {
+            let job = photo.render_job()?;
+            let (key, generation) = (photo.key().to_path_buf(), job.generation());
+            Some(Command::perform(
+                async move { tokio::task::spawn_blocking(move || Box::new(job.run())).await.ok() },
+                move |rendered| Message::Rendered(key, generation, rendered),
+            ))
+        });
+        Command::batch(renders.collect::<Vec<_>>())
+    }
+
+    fn select_photo(&mut self, index: usize) -> Command<Message> {
+        if index >= self.photos.len() {
+            return Command::none();
+        }
+
+        self.current_photo = Some(index);
+        self.keywords_input = self.metadata[index]
+            .as_ref()
+            .map(|exif| exif.keywords.join(", "))
+            .unwrap_or_default();
+        self.update_preset_preview();
+        let proof = self.active_proof();
+        if let Some(photo) = &mut self.photos[index] {
+            photo.set_clipping_warnings(self.clipping_warnings);
+            photo.set_soft_proof(proof);
+            return Command::none();
+        }
+
+        load_image(self.representations[index][0].clone(), self.highlight_mode(index))
+    }
+
+    /// Rebuilds the soft proof after its profile, settings or the display
+    /// changed.
+    fn update_soft_proof(&mut self) {
+        let Some(icc) = &self.proof_profile else {
+            return;
+        };
+        self.soft_proof = match SoftProof::new(icc, self.proofing.intent, self.proofing.gamut_warning) {
+            Ok(proof) => Some(Arc::new(proof)),
+            Err(e) => {
+                self.error = Some(format!("Can't soft-proof: {:#}", e));
+                None
+            }
+        };
+    }
+
+    /// The soft proof to show photos with, if proofing is on.
+    fn active_proof(&self) -> Option<Arc<SoftProof>> {
+        self.soft_proof.clone().filter(|_| self.proofing.enabled)
+    }
+
+    /// Shows the current photo soft-proofed, or not, as set.
+    fn show_soft_proof(&mut self) {
+        let proof = self.active_proof();
+        if let Some(photo) = self.current_photo.and_then(|i| self.photos[i].as_mut()) {
+            photo.set_soft_proof(proof);
+        }
+    }
+
+    /// Renders the chosen preset onto the current photo for the develop panel.
+    fn update_preset_preview(&mut self) {
+        let photo = self.current_photo.and_then(|i| self.photos[i].as_ref());
+        self.presets.preview = self
+            .presets
+            .selected
+            .as_ref()
+            .zip(photo)
+            .and_then(|(preset, photo)| photo.render_thumbnail(&preset.apply(photo.edits()), PRESET_THUMBNAIL_SIZE));
+    }
+
+    /// Changes the develop settings of the current photo for display,
+    /// while a control is being moved; they are saved when it's released.
+    /// When syncing, the other selected photos that are loaded show the
+    /// changed groups as well.
+    fn edit_live(&mut self, edit: impl FnOnce(&mut EditParams)) {
+        let Some(index) = self.current_photo else {
+            return;
+        };
+        let Some(photo) = self.photos[index].as_mut() else {
+            return;
+        };
+        let before = photo.edits().clone();
+        let mut edits = before.clone();
+        edit(&mut edits);
+        photo.set_edits(edits.clone());
+        if !self.clipboard.sync {
+            return;
+        }
+        let groups = edit_sync::changed(&before, &edits);
+        for target in self.targets() {
+            if let Some(photo) = self.photos[target].as_mut().filter(|_| target != index) {
+                photo.set_edits(edit_sync::paste(&edits, photo.edits(), &groups));
+            }
+        }
+    }
+
+    /// Changes the crop of the current photo for display, given the size
+    /// of its preview.
+    fn edit_crop(&mut self, edit: impl FnOnce(&mut Crop, (u32, u32))) {
+        let Some(size) = self.current_photo.and_then(|i| self.photos[i].as_ref()).and_then(Photo::preview_size) else {
+            return;
+        };
+        self.edit_live(|edits| edit(&mut edits.crop, size));
+    }
 
-        let content = if let Some(photo) = current_photo {
-            self.photo_view.view(photo)
+    /// Finds the photo that `path` belongs to, whichever representation it
+    /// is. Originals are preferred over their virtual copies.
+    fn index_of_file(&self, path: &Path) -> Option<usize> {
+        (0..self.representations.len())
+            .filter(|&i| self.representations[i].iter().any(|p| p == path))
+            .min_by_key(|&i| self.is_copy(i))
+    }
+
+    /// Finds a photo by its entry in `photo_paths`, or else by any of its files.
+    fn index_of_photo(&self, path: &Path) -> Option<usize> {
+        self.photo_paths.iter().position(|p| p == path).or_else(|| self.index_of_file(path))
+    }
+
+    /// Whether the photo at `index` is a virtual copy of another.
+    fn is_copy(&self, index: usize) -> bool {
+        !self.representations[index].contains(&self.photo_paths[index])
+    }
+
+    /// How the photo at `index` wants its RAW decoded, from its own sidecar
+    /// when it isn't loaded yet.
+    fn highlight_mode(&self, index: usize) -> HighlightMode {
+        match &self.photos[index] {
+            Some(photo) => photo.edits().highlight_mode,
+            None => sidecar::read(&self.photo_paths[index])
+                .ok()
+                .flatten()
+                .map(|sidecar| sidecar.edits.highlight_mode)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The file exporting the photo at `index` decodes: the RAW of a
+    /// RAW+JPEG pair, otherwise its primary file. Virtual copies use the
+    /// files of their original.
+    fn export_file(&self, index: usize) -> &Path {
+        let files = &self.representations[index];
+        files
+            .iter()
+            .find(|p| detector::has_raw_extension(p))
+            .or_else(|| files.iter().find(|&p| *p == self.photo_paths[index]))
+            .or(files.first())
+            .unwrap_or(&self.photo_paths[index])
+    }
+
+    /// The files trashing the photo at `index` removes: a virtual copy is
+    /// only its sidecar, the original takes its copies along. `copies` must
+    /// cover the photo's folder.
+    fn files_of_photo(&self, index: usize, copies: &CopyIndex) -> Vec<PathBuf> {
+        if self.is_copy(index) {
+            sidecar::existing_sidecars(&self.photo_paths[index])
         } else {
-            text("No photo selected").into()
+            fileops::files_of(&self.representations[index], copies)
+        }
+    }
+
+    /// Edits the sidecars of photos: those of all their files, or of the
+    /// virtual copy.
+    fn edit_sidecars(&mut self, indices: &[usize], edit: impl Fn(&mut Sidecar)) -> anyhow::Result<()> {
+        let files: Vec<PathBuf> = indices
+            .iter()
+            .flat_map(|&i| if self.is_copy(i) { vec![self.photo_paths[i].clone()] } else { self.representations[i].clone() })
+            .collect();
+        if let Some(operation) = history::edit_sidecars(&files, edit)? {
+            self.history.record(operation);
+        }
+        self.metadata_changed(&files);
+        Ok(())
+    }
+
+    /// Re-reads metadata for photos whose sidecars changed.
+    fn metadata_changed(&mut self, files: &[PathBuf]) {
+        let mut indices: Vec<usize> = files.iter().filter_map(|f| self.index_of_photo(f)).collect();
+        indices.dedup();
+        for index in indices {
+            let exif = ExifData::read_with_sidecar(&self.representations[index][0], &self.photo_paths[index]).ok();
+            if let (Some(photo_exif), Some(exif)) = (
+                self.photos[index].as_mut().and_then(|p| p.exif_data_mut()),
+                exif.as_ref(),
+            ) {
+                *photo_exif = exif.clone();
+            }
+            if let Some(photo) = self.photos[index].as_mut() {
+                photo.reload_edits();
+            }
+            self.metadata[index] = exif;
+        }
+    }
+
+    /// Brings the photo list in line with files changed by an operation.
+    fn apply_changes(&mut self, changes: FileChanges) -> Command<Message> {
+        let in_directory = |path: &Path| match (self.directory.as_deref(), path.parent()) {
+            (Some(dir), Some(parent)) => fileops::same_folder(parent, dir),
+            _ => false,
         };
 
-        let error_text = if let Some(error) = &self.error {
-            Element::from(
-                container(
-                    text(error)
-                        .style(iced::theme::Text::Color(iced::Color::from_rgb(0.8, 0.0, 0.0)))
-                )
-                .padding(10)
-            )
+        let mut renamed = Vec::new();
+        let mut removed = changes.removed;
+        let mut added = changes.added;
+        for (from, to) in changes.moved {
+            // Sidecars of virtual copies belong to the list as well
+            let listed = self.index_of_file(&from).is_some() || self.photo_paths.contains(&from.with_extension(""));
+            match (listed, in_directory(&to)) {
+                (true, true) => renamed.push((from, to)),
+                (true, false) => removed.push(from),
+                (false, true) => added.push(to),
+                (false, false) => {}
+            }
+        }
+
+        self.files_moved(&renamed);
+        self.metadata_changed(&changes.metadata);
+        let removed_command = if removed.is_empty() { Command::none() } else { self.files_removed(&removed) };
+        let added_command = self.files_added(&added);
+        Command::batch([removed_command, added_command, self.decode_if_needed()])
+    }
+
+    /// Decodes the current photo again if its develop settings changed how
+    /// the file has to be decoded.
+    fn decode_if_needed(&self) -> Command<Message> {
+        match self.current_photo.and_then(|i| self.photos[i].as_ref()) {
+            Some(photo) if photo.needs_decode() => load_image(photo.path().to_path_buf(), photo.edits().highlight_mode),
+            _ => Command::none(),
+        }
+    }
+
+    /// Adds files that appeared in the current folder, pairing them with
+    /// existing photos of the same name.
+    fn files_added(&mut self, added: &[PathBuf]) -> Command<Message> {
+        let Some(directory) = self.directory.clone() else {
+            return Command::none();
+        };
+        let in_directory = |p: &&PathBuf| p.parent().is_some_and(|parent| fileops::same_folder(parent, &directory));
+        let new_files: Vec<PathBuf> = added
+            .iter()
+            .filter(|p| in_directory(p) && detector::has_image_extension(p))
+            .filter(|p| self.index_of_file(p).is_none())
+            .cloned()
+            .collect();
+        // Restored sidecars of virtual copies
+        let new_copies: Vec<PathBuf> = added
+            .iter()
+            .filter(in_directory)
+            .filter_map(|p| p.to_str()?.strip_suffix(".xmp").map(PathBuf::from))
+            .filter(|key| sidecar::copy_source(key).is_some() && !self.photo_paths.contains(key))
+            .collect();
+        if new_files.is_empty() && new_copies.is_empty() {
+            return Command::none();
+        }
+
+        let name = |path: &Path| (path.parent().map(Path::to_path_buf), path.file_stem().map(|s| s.to_ascii_lowercase()));
+        for group in photo::group_representations(new_files) {
+            let existing = (0..self.representations.len())
+                .find(|&i| !self.is_copy(i) && name(&self.representations[i][0]) == name(&group[0]));
+            match existing {
+                Some(index) => {
+                    let original = self.representations[index].clone();
+                    let mut files = original.clone();
+                    files.extend(group);
+                    files.sort_by_key(|p| detector::has_raw_extension(p));
+                    // Virtual copies share the files of their original
+                    for i in 0..self.representations.len() {
+                        if self.representations[i] != original {
+                            continue;
+                        }
+                        self.representations[i] = files.clone();
+                        if i == index {
+                            self.photo_paths[i] = files[0].clone();
+                        }
+                        self.photos[i] = None;
+                    }
+                }
+                None => {
+                    self.metadata.push(ExifData::read(&group[0]).ok());
+                    self.photo_paths.push(group[0].clone());
+                    self.representations.push(group);
+                    self.photos.push(None);
+                }
+            }
+        }
+        for key in new_copies {
+            let Some(index) = sidecar::copy_source(&key).and_then(|source| self.index_of_file(&source)) else {
+                continue;
+            };
+            self.metadata.push(ExifData::read_with_sidecar(&self.representations[index][0], &key).ok());
+            self.photo_paths.push(key);
+            self.representations.push(self.representations[index].clone());
+            self.photos.push(None);
+        }
+
+        self.sort_photos();
+        let reload = match self.current_photo {
+            Some(current) if self.photos[current].is_none() => {
+                self.current_photo = None;
+                self.select_photo(current)
+            }
+            _ => Command::none(),
+        };
+        Command::batch([reload, self.apply_filter()])
+    }
+
+    /// Reorders the photo list by the current sort settings, keeping the
+    /// same photo selected.
+    fn sort_photos(&mut self) {
+        let order = sort::sorted_indices(&self.photo_paths, &self.metadata, self.sort_mode, self.sort_order);
+        let current_path = self.current_photo.map(|i| self.photo_paths[i].clone());
+
+        sort::apply_order(&mut self.photo_paths, &order);
+        sort::apply_order(&mut self.representations, &order);
+        sort::apply_order(&mut self.photos, &order);
+        sort::apply_order(&mut self.metadata, &order);
+
+        self.current_photo = current_path
+            .and_then(|path| self.photo_paths.iter().position(|p| p == &path));
+    }
+
+    /// The photos file operations apply to: the ticked ones in display
+    /// order, or else the current photo.
+    fn targets(&self) -> Vec<usize> {
+        if self.selected.is_empty() {
+            return self.current_photo.into_iter().collect();
+        }
+        self.visible
+            .iter()
+            .copied()
+            .filter(|&i| self.selected.contains(&self.photo_paths[i]))
+            .collect()
+    }
+
+    /// Photos batch renaming applies to, in display order: the ticked ones,
+    /// or everything visible when nothing is ticked.
+    fn batch_targets(&self) -> Vec<usize> {
+        if self.selected.is_empty() {
+            self.visible.clone()
         } else {
-            Element::from(container(text("")).padding(10))
+            self.targets()
+        }
+    }
+
+    fn update_batch_preview(&mut self) {
+        if self.batch_previews.is_none() {
+            return;
+        }
+        match Template::parse(&self.batch_template) {
+            Ok(template) => {
+                let photos: Vec<(&[PathBuf], Option<&ExifData>)> = self.batch_targets()
+                    .into_iter()
+                    .map(|i| (&self.representations[i][..], self.metadata[i].as_ref()))
+                    .collect();
+                self.batch_previews = Some(rename::preview(&template, &photos, 1));
+                self.batch_error = None;
+            }
+            Err(e) => {
+                self.batch_previews = Some(Vec::new());
+                self.batch_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Drops files that no longer exist from the list, removing photos that
+    /// have no files left, and moves the selection on if the current photo
+    /// went away.
+    fn files_removed(&mut self, removed: &[PathBuf]) -> Command<Message> {
+        for path in removed {
+            photo::forget_cached(path);
+        }
+
+        // Decide what to show next while the old indices are still valid
+        let navigable = self.navigable();
+        let is_gone = |i: &usize| {
+            self.representations[*i].iter().all(|p| removed.contains(p))
+                || (self.is_copy(*i) && removed.contains(&sidecar::copy_sidecar(&self.photo_paths[*i])))
         };
+        let next_path = self.current_photo.and_then(|current| {
+            let position = navigable.iter().position(|&i| i == current)?;
+            navigable[position..]
+                .iter()
+                .chain(navigable[..position].iter().rev())
+                .find(|i| !is_gone(i))
+                .map(|&i| self.photo_paths[i].clone())
+        });
+
+        let keep: Vec<bool> = (0..self.photo_paths.len()).map(|i| !is_gone(&i)).collect();
+        for i in 0..self.photo_paths.len() {
+            let copy = self.is_copy(i);
+            let before = self.representations[i].len();
+            self.representations[i].retain(|p| !removed.contains(p));
+            if self.representations[i].len() != before && !self.representations[i].is_empty() {
+                // One file of a pair went away; show what's left
+                if !copy {
+                    let primary = self.representations[i][0].clone();
+                    if self.selected.remove(&self.photo_paths[i]) {
+                        self.selected.insert(primary.clone());
+                    }
+                    if self.expanded_stacks.remove(&self.photo_paths[i]) {
+                        self.expanded_stacks.insert(primary.clone());
+                    }
+                    self.photo_paths[i] = primary;
+                }
+                self.photos[i] = None;
+            }
+        }
+        let order: Vec<usize> = (0..keep.len()).filter(|&i| keep[i]).collect();
+        sort::apply_order(&mut self.photo_paths, &order);
+        sort::apply_order(&mut self.representations, &order);
+        sort::apply_order(&mut self.photos, &order);
+        sort::apply_order(&mut self.metadata, &order);
+        self.selected.retain(|p| self.photo_paths.contains(p));
 
-        let layout = column![controls, error_text, content].spacing(20).padding(20);
+        self.current_photo = next_path.and_then(|path| self.index_of_photo(&path));
+        match self.current_photo {
+            Some(current) => {
+                // Make sure the new current photo gets loaded
+                self.current_photo = None;
+                let load = self.select_photo(current);
+                Command::batch([load, self.apply_filter()])
+            }
+            None => self.apply_filter(),
+        }
+    }
 
-        container(layout)
-            .width(Length::Fill)
-            .height(Length::Fill)
-            .into()
+    /// Updates paths after files were renamed or moved within the list.
+    fn files_moved(&mut self, moves: &[(PathBuf, PathBuf)]) {
+        for (from, to) in moves {
+            photo::rename_cached(from, to);
+            if self.selected.remove(from) {
+                self.selected.insert(to.clone());
+            }
+        }
+        for i in 0..self.photo_paths.len() {
+            if self.is_copy(i) {
+                // A virtual copy's key follows its sidecar
+                let sidecar = sidecar::copy_sidecar(&self.photo_paths[i]);
+                if let Some((_, to)) = moves.iter().find(|(from, _)| *from == sidecar) {
+                    let key = to.with_extension("");
+                    if self.selected.remove(&self.photo_paths[i]) {
+                        self.selected.insert(key.clone());
+                    }
+                    self.photo_paths[i] = key;
+                }
+            }
+            for path in self.representations[i].iter_mut().chain(std::iter::once(&mut self.photo_paths[i])) {
+                if let Some((_, to)) = moves.iter().find(|(from, _)| from == path) {
+                    *path = to.clone();
+                }
+            }
+            if let Some(photo) = self.photos[i].as_mut() {
+                photo.relocate(moves);
+            }
+        }
+    }
+
+    /// Rebuilds the burst stacks from the visible photos.
+    fn regroup(&mut self) {
+        let hashes: Vec<Option<u64>> = self.photos
+            .iter()
+            .map(|photo| photo.as_ref().and_then(Photo::perceptual_hash))
+            .collect();
+        self.stacks = grouping::group(&self.visible, &self.metadata, &hashes, &self.grouping);
+    }
+
+    fn is_expanded(&self, stack: &[usize]) -> bool {
+        stack.len() < 2 || self.expanded_stacks.contains(&self.photo_paths[stack[0]])
+    }
+
+    /// The photos Next/Previous step through: collapsed stacks count as
+    /// their first photo only.
+    fn navigable(&self) -> Vec<usize> {
+        self.stacks
+            .iter()
+            .flat_map(|stack| if self.is_expanded(stack) { &stack[..] } else { &stack[..1] })
+            .copied()
+            .collect()
+    }
+
+    /// Recomputes the visible photos after the filter or metadata changed.
+    fn apply_filter(&mut self) -> Command<Message> {
+        self.visible = (0..self.photo_paths.len())
+            .filter(|&i| {
+                // Photos whose metadata hasn't been read yet only show up
+                // while no filter is active
+                self.filter.is_empty()
+                    || self.metadata[i].as_ref().is_some_and(|exif| self.filter.matches(exif))
+            })
+            .collect();
+        self.regroup();
+
+        // Keep the current photo if it still matches, otherwise move to the first match
+        match self.current_photo {
+            Some(current) if self.visible.contains(&current) => Command::none(),
+            _ => match self.visible.first() {
+                Some(&first) => self.select_photo(first),
+                None => {
+                    self.current_photo = None;
+                    Command::none()
+                }
+            },
+        }
+    }
+}
+
+/// Loads the image at `path` in the background, RAW files with
+/// `highlight_mode`, and reports it with `Message::ImageLoaded`.
+fn load_image(path: PathBuf, highlight_mode: HighlightMode) -> Command<Message> {
+    let path_clone = path.clone();
+    Command::perform(
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let decoded = photo::load_image(&path, highlight_mode).ok()?;
+                Some(Loaded::new(decoded))
+            })
+            .await
+            .ok()
+            .flatten()
+        },
+        move |result| {
+            if let Some(image) = result {
+                Message::ImageLoaded(path_clone, Some(image))
+            } else {
+                Message::Error(format!("Failed to load image: {}", path_clone.display()))
+            }
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The app browsing `dir`, as if the user had opened it.
+    fn browse(dir: &Path) -> PhotoFlow {
+        let (mut app, _) = PhotoFlow::new(());
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| detector::has_image_extension(path))
+            .collect();
+        paths.sort();
+        let _ = app.update(Message::DirectoryLoaded(dir.to_path_buf(), paths));
+        app
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_move_into_browsed_folder_keeps_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        let photo = dir.path().join("DSCF0001.JPG");
+        std::fs::write(&photo, "x").unwrap();
+        let mut app = browse(dir.path());
+
+        // The same folder, reached through a link
+        let dest = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &dest).unwrap();
+        let _ = app.update(Message::TransferPhotos { paths: vec![photo.clone()], dest, copy: false });
+        assert_eq!(app.error, None);
+        assert_eq!(app.photo_paths, vec![photo.clone()]);
+        assert!(photo.exists());
+    }
+
+    #[test]
+    fn test_selection_follows_half_removed_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let (jpeg, raw) = (dir.path().join("DSCF0001.JPG"), dir.path().join("DSCF0001.RAF"));
+        std::fs::write(&jpeg, "x").unwrap();
+        std::fs::write(&raw, "x").unwrap();
+        let mut app = browse(dir.path());
+        assert_eq!(app.photo_paths, vec![jpeg.clone()]);
+        app.selected.insert(jpeg.clone());
+
+        std::fs::remove_file(&jpeg).unwrap();
+        let _ = app.files_removed(&[jpeg]);
+        assert_eq!(app.photo_paths, vec![raw.clone()]);
+        assert_eq!(app.selected, HashSet::from([raw]));
+    }
+
+    #[test]
+    fn test_snapshots_of_pair_are_taken_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (jpeg, raw) = (dir.path().join("DSCF0001.JPG"), dir.path().join("DSCF0001.RAF"));
+        std::fs::write(&jpeg, "x").unwrap();
+        std::fs::write(&raw, "x").unwrap();
+        let mut app = browse(dir.path());
+        app.photos[0] = Photo::with_representations(app.representations[0].clone()).ok();
+        app.current_photo = Some(0);
+
+        let _ = app.update(Message::TakeSnapshot);
+        let _ = app.update(Message::TakeSnapshot);
+        // Both halves share one sidecar, which gets each snapshot once
+        let shared = dir.path().join("DSCF0001.xmp");
+        let names = |app: &PhotoFlow| app.photos[0].as_ref().unwrap().snapshots().iter().map(|s| s.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&app), vec!["Snapshot 1", "Snapshot 2"]);
+        assert_eq!(sidecar::read_file(&shared).unwrap().unwrap().snapshots.len(), 2);
+
+        let _ = app.update(Message::DeleteSnapshot(0));
+        assert_eq!(names(&app), vec!["Snapshot 2"]);
+        assert_eq!(sidecar::read(&raw).unwrap().unwrap().snapshots.len(), 1);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_added_pair_half_joins_original_and_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let (jpeg, raw) = (dir.path().join("DSCF0001.JPG"), dir.path().join("DSCF0001.RAF"));
+        std::fs::write(&jpeg, "x").unwrap();
+        let mut app = browse(dir.path());
+        app.photos[0] = Photo::with_representations(app.representations[0].clone()).ok();
+        app.current_photo = Some(0);
+        let _ = app.update(Message::CreateVirtualCopy);
+        assert_eq!(app.photo_paths.len(), 2);
+
+        std::fs::write(&raw, "x").unwrap();
+        let _ = app.files_added(std::slice::from_ref(&raw));
+        assert_eq!(app.photo_paths.len(), 2);
+        assert!(app.photo_paths.contains(&jpeg));
+        assert!(app.representations.iter().all(|files| *files == vec![jpeg.clone(), raw.clone()]));
+    }
+
+    #[test]
+    fn test_renders_run_one_at_a_time_and_drop_stale_results() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("DSCF0001.JPG");
+        std::fs::write(&path, "x").unwrap();
+        let mut photo = Photo::with_representations(vec![path]).unwrap();
+        let image = image::Rgb32FImage::from_pixel(16, 16, image::Rgb([0.2; 3]));
+        photo.set_image(Loaded::new(photo::Decoded { image: image.into(), raw_clipping: None, highlight_mode: None }));
+
+        let first = photo.render_job().unwrap();
+        photo.set_edits(EditParams { exposure: 1.0, ..photo.edits().clone() });
+        assert!(photo.render_job().is_none());
+        let (first_generation, first) = (first.generation(), first.run());
+        photo.finish_render(first_generation, Some(first.clone()));
+        assert!(photo.display_handle().is_some());
+        let first_histogram = photo.histogram().cloned();
+
+        // The edit made meanwhile renders next
+        let second = photo.render_job().unwrap();
+        let second_generation = second.generation();
+        photo.finish_render(second_generation, Some(second.run()));
+        let histogram = photo.histogram().cloned();
+        assert_ne!(histogram, first_histogram);
+        assert!(photo.render_job().is_none());
+
+        // A late older result doesn't replace it
+        photo.finish_render(first_generation, Some(first));
+        assert_eq!(photo.histogram(), histogram.as_ref());
+    }
+
+    #[test]
+    fn test_sync_shows_changes_on_selected_photos() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("DSCF0001.JPG"), dir.path().join("DSCF0002.JPG"));
+        std::fs::write(&first, "x").unwrap();
+        std::fs::write(&second, "x").unwrap();
+        let mut app = browse(dir.path());
+        for (index, path) in [&first, &second].into_iter().enumerate() {
+            app.photos[index] = Photo::with_representations(vec![path.clone()]).ok();
+            app.selected.insert(path.clone());
+        }
+        app.current_photo = Some(0);
+        app.clipboard.sync = true;
+
+        let _ = app.update(Message::AdjustmentChanged(Adjustment::Contrast, 30.0));
+        let edits = |app: &PhotoFlow, index: usize| app.photos[index].as_ref().unwrap().edits().clone();
+        assert_eq!(edits(&app, 1).contrast, 30.0);
+        // Nothing is saved before the control is released
+        assert_eq!(sidecar::read(&second).unwrap(), None);
+
+        app.clipboard.sync = false;
+        let _ = app.update(Message::AdjustmentChanged(Adjustment::Contrast, 50.0));
+        assert_eq!(edits(&app, 0).contrast, 50.0);
+        assert_eq!(edits(&app, 1).contrast, 30.0);
     }
 }
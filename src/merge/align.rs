@@ -0,0 +1,184 @@
+//! Lines up frames of one scene with median threshold bitmaps (Ward,
+//! 2003). Each frame is reduced to which pixels are brighter than its
+//! median, which doesn't depend on exposure, and the shift leaving the
+//! fewest pixels different wins, searched from coarse to fine.
+//!
+//! Only translation is found, which is what handheld brackets need. The
+//! error is measured over the part the frames share, so frames that only
+//! partly overlap, like a panorama's, can be aligned with a wider search.
+
+use image::GrayImage;
+
+/// Pixels this close to the median are left out of the comparison, as
+/// noise flips them either way.
+const NOISE: u8 = 4;
+
+/// How far around the previous level's answer each level searches. Two
+/// pixels rather than one lets a level undo a tie the coarser one broke
+/// the wrong way.
+const SEARCH: i32 = 2;
+
+/// Coarsest level's shorter side, below which the bitmaps say too little.
+const MIN_SIZE: u32 = 16;
+
+/// Where a frame lies relative to the reference: the reference's pixel
+/// (x, y) shows what the frame's pixel (x + dx, y + dy) does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Offset {
+    pub dx: i32,
+    pub dy: i32,
+}
+
+/// A frame's threshold bitmaps at halving resolutions, finest first.
+pub struct Bitmaps {
+    levels: Vec<Level>,
+}
+
+struct Level {
+    width: usize,
+    height: usize,
+    /// Brighter than the median.
+    bright: Vec<bool>,
+    /// Clear of the median by more than `NOISE`.
+    usable: Vec<bool>,
+}
+
+impl Bitmaps {
+    /// Builds up to `levels` levels from a greyscale frame. The search can
+    /// find shifts of up to 2 * (2^levels - 1) pixels.
+    pub fn new(grey: &GrayImage, levels: usize) -> Bitmaps {
+        let mut grey = grey.clone();
+        let mut bitmaps = Vec::new();
+        for level in 0..levels.max(1) {
+            if level > 0 {
+                if grey.width().min(grey.height()) / 2 < MIN_SIZE {
+                    break;
+                }
+                grey = halve(&grey);
+            }
+            bitmaps.push(Level::new(&grey));
+        }
+        Bitmaps { levels: bitmaps }
+    }
+}
+
+impl Level {
+    fn new(grey: &GrayImage) -> Level {
+        let median = median(grey);
+        Level {
+            width: grey.width() as usize,
+            height: grey.height() as usize,
+            bright: grey.pixels().map(|p| p[0] > median).collect(),
+            usable: grey.pixels().map(|p| p[0].abs_diff(median) > NOISE).collect(),
+        }
+    }
+
+    /// Share of the usable pixels both frames have that differ, with
+    /// `frame` at `offset`.
+    fn error(&self, frame: &Level, offset: Offset) -> f64 {
+        let (mut differing, mut compared) = (0u64, 0u64);
+        let xs = (-offset.dx).max(0)..(frame.width as i32 - offset.dx).min(self.width as i32);
+        for y in (-offset.dy).max(0)..(frame.height as i32 - offset.dy).min(self.height as i32) {
+            let row = y as usize * self.width;
+            let frame_row = (y + offset.dy) as usize * frame.width;
+            for x in xs.clone() {
+                let (i, j) = (row + x as usize, frame_row + (x + offset.dx) as usize);
+                if self.usable[i] && frame.usable[j] {
+                    compared += 1;
+                    differing += u64::from(self.bright[i] != frame.bright[j]);
+                }
+            }
+        }
+        if compared == 0 {
+            1.0
+        } else {
+            differing as f64 / compared as f64
+        }
+    }
+}
+
+/// How many levels frames of this size get: as many as keep the coarsest
+/// level useful, for shifts of up to about a sixteenth of the shorter side.
+pub fn levels_for(width: u32, height: u32) -> usize {
+    let mut levels = 1;
+    while (width.min(height) >> levels) >= MIN_SIZE && (1 << levels) <= width.min(height) / 16 {
+        levels += 1;
+    }
+    levels
+}
+
+/// Finds the offset of `frame` relative to `reference`.
+pub fn align(reference: &Bitmaps, frame: &Bitmaps) -> Offset {
+    let levels = reference.levels.len().min(frame.levels.len());
+    let mut offset = Offset::default();
+    for level in (0..levels).rev() {
+        let (reference, frame) = (&reference.levels[level], &frame.levels[level]);
+        let centre = Offset { dx: offset.dx * 2, dy: offset.dy * 2 };
+        // Staying put wins ties, so featureless frames aren't shifted
+        let mut best = (reference.error(frame, centre), centre);
+        for dy in -SEARCH..=SEARCH {
+            for dx in -SEARCH..=SEARCH {
+                let candidate = Offset { dx: centre.dx + dx, dy: centre.dy + dy };
+                let error = reference.error(frame, candidate);
+                if error < best.0 {
+                    best = (error, candidate);
+                }
+            }
+        }
+        offset = best.1;
+    }
+    offset
+}
+
+fn halve(grey: &GrayImage) -> GrayImage {
+    GrayImage::from_fn(grey.width() / 2, grey.height() / 2, |x, y| {
+        let sum: u32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+            .iter()
+            .map(|&(i, j)| u32::from(grey.get_pixel(2 * x + i, 2 * y + j)[0]))
+            .sum();
+        image::Luma([((sum + 2) / 4) as u8])
+    })
+}
+
+fn median(grey: &GrayImage) -> u8 {
+    let mut histogram = [0usize; 256];
+    for pixel in grey.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+    let half = (grey.width() as usize * grey.height() as usize).div_ceil(2);
+    let mut seen = 0;
+    for (value, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= half {
+            return value as u8;
+        }
+    }
+    255
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finds_shift_across_exposures() {
+        // Hard-edged blobs over a gradient, so every level has edges to
+        // line up
+        let scene = |x: i32, y: i32| {
+            let (x, y) = (x as f32, y as f32);
+            let blob = if (x / 9.0).sin() * (y / 13.0).cos() > 0.0 { 0.65 } else { 0.35 };
+            blob + 0.1 * (x / 31.0 + y / 23.0).sin()
+        };
+        let (dx, dy) = (7, -5);
+        let reference = GrayImage::from_fn(256, 192, |x, y| image::Luma([(scene(x as i32, y as i32) * 200.0) as u8]));
+        // Darker and shifted
+        let frame = GrayImage::from_fn(256, 192, |x, y| {
+            image::Luma([(scene(x as i32 - dx, y as i32 - dy) * 90.0) as u8])
+        });
+        let levels = levels_for(256, 192);
+        assert_eq!(levels, 4);
+        let offset = align(&Bitmaps::new(&reference, levels), &Bitmaps::new(&frame, levels));
+        assert_eq!(offset, Offset { dx, dy });
+        assert_eq!(align(&Bitmaps::new(&reference, levels), &Bitmaps::new(&reference, levels)), Offset::default());
+    }
+}
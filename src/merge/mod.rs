@@ -0,0 +1,552 @@
+//! Merging exposure brackets of RAW files into one high dynamic range
+//! image, written as a linear DNG or a floating-point TIFF or OpenEXR.
+//!
+//! Frames are decoded twice: once to align them and work out how their
+//! exposures relate, then one at a time into the merge, so memory doesn't
+//! grow with the number of frames.
+
+pub mod align;
+
+use std::fmt;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, GrayImage, ImageFormat, Rgb32FImage};
+use tiff::encoder::{colortype, Rational, SRational, TiffEncoder};
+use tiff::tags::Tag as TiffTag;
+use tracing::{debug, info};
+
+use crate::color;
+use crate::export;
+use crate::photo::{self, ExifData};
+use crate::processors::highlights::HighlightMode;
+use crate::processors::raw::RawProcessor;
+use align::{Bitmaps, Offset};
+
+/// Files merged HDR images can be written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HdrFormat {
+    /// Linear DNG, for developing further in other RAW editors.
+    #[default]
+    Dng,
+    Tiff,
+    OpenExr,
+}
+
+impl HdrFormat {
+    pub const ALL: [HdrFormat; 3] = [HdrFormat::Dng, HdrFormat::Tiff, HdrFormat::OpenExr];
+
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            HdrFormat::Dng => &["dng"],
+            HdrFormat::Tiff => &["tif", "tiff"],
+            HdrFormat::OpenExr => &["exr"],
+        }
+    }
+
+    /// The format a file name asks for, by extension.
+    pub fn from_path(path: &Path) -> Option<HdrFormat> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        Self::ALL.into_iter().find(|format| format.extensions().contains(&extension.as_str()))
+    }
+}
+
+impl fmt::Display for HdrFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HdrFormat::Dng => "DNG",
+            HdrFormat::Tiff => "TIFF (32-bit float)",
+            HdrFormat::OpenExr => "OpenEXR",
+        })
+    }
+}
+
+/// Where the merged image of `sources` goes by default: next to the
+/// first, named after it.
+pub fn default_destination(sources: &[PathBuf]) -> Option<PathBuf> {
+    let first = sources.first()?;
+    let stem = first.file_stem()?.to_string_lossy();
+    Some(first.with_file_name(format!("{}_HDR.{}", stem, HdrFormat::default().extensions()[0])))
+}
+
+/// Blocks of this many pixels square are averaged to compare exposures.
+const SAMPLE_BLOCK: u32 = 4;
+
+/// Share of its clipping level from which a value stops counting.
+const CLIPPING_START: f32 = 0.85;
+const CLIPPING_END: f32 = 0.95;
+
+/// Fewest pixels two frames must both expose well to be compared.
+const MIN_SAMPLES: usize = 64;
+
+/// What the first pass learns about a frame.
+struct Frame {
+    path: PathBuf,
+    /// Value the frame clipped at, if anything did.
+    white: Option<f32>,
+    bitmaps: Bitmaps,
+    /// Luminance and brightest channel, averaged over `SAMPLE_BLOCK`s.
+    samples: Vec<(f32, f32)>,
+    samples_width: u32,
+    brightness: f32,
+    offset: Offset,
+    /// Relative to the reference frame.
+    exposure: f32,
+}
+
+/// Merges bracketed RAW files into one high dynamic range image at
+/// `destination`, in the format its extension names. The result is as
+/// bright as the middle exposure, which the others are aligned to, and
+/// converted from that frame's camera colours to linear sRGB.
+pub fn merge_hdr(sources: &[PathBuf], destination: &Path) -> Result<()> {
+    let format = HdrFormat::from_path(destination)
+        .with_context(|| format!("Can't write {}: use .dng, .tif or .exr", destination.display()))?;
+    if sources.len() < 2 {
+        bail!("Select at least two exposures to merge");
+    }
+    info!("Merging {} exposures into {}", sources.len(), destination.display());
+
+    let mut frames = Vec::with_capacity(sources.len());
+    let mut size = None;
+    for path in sources {
+        let (image, white) = decode(path)?;
+        let dimensions = image.dimensions();
+        if *size.get_or_insert(dimensions) != dimensions {
+            bail!("{} has a different size than {}", path.display(), sources[0].display());
+        }
+        frames.push(analyse(path, &image, white));
+    }
+    let (width, height) = size.expect("at least two frames");
+
+    frames.sort_by(|a, b| a.brightness.total_cmp(&b.brightness));
+    let reference = frames.len() / 2;
+    let offsets: Vec<Offset> =
+        frames.iter().map(|frame| align::align(&frames[reference].bitmaps, &frame.bitmaps)).collect();
+    for (frame, offset) in frames.iter_mut().zip(offsets) {
+        debug!("{} is offset by {:?}", frame.path.display(), offset);
+        frame.offset = offset;
+    }
+    estimate_exposures(&mut frames, reference)?;
+
+    let mut merged = Merge::new(width, height);
+    for (i, frame) in frames.iter().enumerate() {
+        let (image, _) = decode(&frame.path)?;
+        // Where no frame is usable, e.g. clipped in all of them, the
+        // darkest frame still gives the best guess, and the reference
+        // covers whatever the darkest is shifted away from
+        let floor = match i {
+            0 => 1e-8,
+            i if i == reference => 1e-12,
+            _ => 0.0,
+        };
+        merged.add(&image, frame, floor);
+    }
+    let mut merged = merged.finish();
+    // Frames are merged as decoded, so clipping is judged on the sensor's
+    // own channels
+    let reference_path = &frames[reference].path;
+    let raw = rawloader::decode_file(reference_path)
+        .with_context(|| format!("Failed to read the colour matrix of {}", reference_path.display()))?;
+    let xyz_to_cam = [raw.xyz_to_cam[0], raw.xyz_to_cam[1], raw.xyz_to_cam[2]];
+    let Some(matrix) = camera_to_srgb(xyz_to_cam) else {
+        bail!("{} has no usable colour matrix", reference_path.display());
+    };
+    convert(&mut merged, &matrix);
+
+    let exif = ExifData::read(&frames[reference].path).unwrap_or_default();
+    let encoded = match format {
+        HdrFormat::Dng => encode_dng(&merged, &exif)?,
+        HdrFormat::Tiff => export::encode_tiff::<colortype::RGB32Float>(
+            width,
+            height,
+            merged.as_raw(),
+            &color::working_profile().icc().map_err(|e| anyhow::anyhow!("Failed to embed profile: {}", e))?,
+            &[],
+        )?,
+        HdrFormat::OpenExr => {
+            let mut encoded = Cursor::new(Vec::new());
+            DynamicImage::ImageRgb32F(merged)
+                .write_to(&mut encoded, ImageFormat::OpenExr)
+                .context("Failed to encode OpenEXR")?;
+            encoded.into_inner()
+        }
+    };
+    std::fs::write(destination, encoded).with_context(|| format!("Failed to write {}", destination.display()))
+}
+
+/// Decodes a RAW file as linear data with clipped highlights left as they
+/// are, and the value they clipped at.
+fn decode(path: &Path) -> Result<(Rgb32FImage, Option<f32>)> {
+    let (image, clipping) = RawProcessor::new()
+        .with_highlight_mode(HighlightMode::Clip)
+        .decode(path)
+        .with_context(|| format!("Failed to decode {}", path.display()))?;
+    let image = image.into_rgb32f();
+    let white = clipping.is_clipped().then(|| image.iter().copied().fold(0.0, f32::max));
+    Ok((image, white))
+}
+
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+fn analyse(path: &Path, image: &Rgb32FImage, white: Option<f32>) -> Frame {
+    // Bitmaps compare perceptual brightness, as the median threshold was
+    // designed for
+    let scale = 1.0 / white.unwrap_or(1.0);
+    let grey = GrayImage::from_fn(image.width(), image.height(), |x, y| {
+        let value = luminance(image.get_pixel(x, y).0) * scale;
+        image::Luma([(photo::srgb_encode(value) * 255.0).round() as u8])
+    });
+    let bitmaps = Bitmaps::new(&grey, align::levels_for(image.width(), image.height()));
+
+    let (samples_width, samples_height) = (image.width() / SAMPLE_BLOCK, image.height() / SAMPLE_BLOCK);
+    let mut samples = Vec::with_capacity(samples_width as usize * samples_height as usize);
+    for y in 0..samples_height {
+        for x in 0..samples_width {
+            let (mut sum, mut peak) = (0.0, 0.0f32);
+            for j in 0..SAMPLE_BLOCK {
+                for i in 0..SAMPLE_BLOCK {
+                    let pixel = image.get_pixel(x * SAMPLE_BLOCK + i, y * SAMPLE_BLOCK + j).0;
+                    sum += luminance(pixel);
+                    peak = peak.max(pixel[0]).max(pixel[1]).max(pixel[2]);
+                }
+            }
+            samples.push((sum / (SAMPLE_BLOCK * SAMPLE_BLOCK) as f32, peak));
+        }
+    }
+    let brightness = samples.iter().map(|&(luminance, _)| luminance).sum::<f32>() / samples.len().max(1) as f32;
+    Frame {
+        path: path.to_path_buf(),
+        white,
+        bitmaps,
+        samples,
+        samples_width,
+        brightness,
+        offset: Offset::default(),
+        exposure: 1.0,
+    }
+}
+
+/// Works out each frame's exposure from how bright the parts neighbouring
+/// frames both expose well are, which also catches shutters that weren't
+/// quite what the EXIF says. `frames` are sorted darkest first.
+fn estimate_exposures(frames: &mut [Frame], reference: usize) -> Result<()> {
+    let mut exposures = vec![1.0f32; frames.len()];
+    for i in 1..frames.len() {
+        let ratio = exposure_ratio(&frames[i - 1], &frames[i]).with_context(|| {
+            format!("{} and {} have too little in common to merge", frames[i - 1].path.display(), frames[i].path.display())
+        })?;
+        exposures[i] = exposures[i - 1] * ratio;
+    }
+    let scale = exposures[reference];
+    for (frame, exposure) in frames.iter_mut().zip(exposures) {
+        frame.exposure = exposure / scale;
+        debug!("{} has {:+.2} EV", frame.path.display(), frame.exposure.log2());
+    }
+    Ok(())
+}
+
+/// How much more light `brighter` got than `darker`: the median ratio of
+/// the samples both expose well.
+fn exposure_ratio(darker: &Frame, brighter: &Frame) -> Option<f32> {
+    let usable = |frame: &Frame, (luminance, peak): (f32, f32)| {
+        luminance > 1e-3 && peak < frame.white.unwrap_or(f32::INFINITY) * CLIPPING_START
+    };
+    let block = SAMPLE_BLOCK as i32;
+    let shift = (
+        ((brighter.offset.dx - darker.offset.dx) as f32 / block as f32).round() as i32,
+        ((brighter.offset.dy - darker.offset.dy) as f32 / block as f32).round() as i32,
+    );
+    let width = darker.samples_width as i32;
+    let height = (darker.samples.len() / darker.samples_width.max(1) as usize) as i32;
+    let mut ratios = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let (bx, by) = (x + shift.0, y + shift.1);
+            if bx < 0 || by < 0 || bx >= width || by >= height {
+                continue;
+            }
+            let dark = darker.samples[(y * width + x) as usize];
+            let bright = brighter.samples[(by * width + bx) as usize];
+            if usable(darker, dark) && usable(brighter, bright) {
+                ratios.push(bright.0 / dark.0);
+            }
+        }
+    }
+    if ratios.len() < MIN_SAMPLES {
+        return None;
+    }
+    let middle = ratios.len() / 2;
+    let (_, median, _) = ratios.select_nth_unstable_by(middle, f32::total_cmp);
+    Some(*median)
+}
+
+/// How much a value counts: brighter values are less noisy, up to where
+/// the frame clipped.
+fn weight(peak: f32, white: Option<f32>) -> f32 {
+    let signal = peak.max(0.0);
+    match white {
+        Some(white) => {
+            let t = ((peak / white - CLIPPING_START) / (CLIPPING_END - CLIPPING_START)).clamp(0.0, 1.0);
+            signal * (1.0 - t * t * (3.0 - 2.0 * t))
+        }
+        None => signal,
+    }
+}
+
+/// Weighted sums of the frames' radiance, as they are added.
+struct Merge {
+    sums: Rgb32FImage,
+    weights: Vec<f32>,
+}
+
+impl Merge {
+    fn new(width: u32, height: u32) -> Merge {
+        Merge { sums: Rgb32FImage::new(width, height), weights: vec![0.0; width as usize * height as usize] }
+    }
+
+    /// Adds `image`, taken as `frame`, counting each of its pixels at least
+    /// `floor`.
+    fn add(&mut self, image: &Rgb32FImage, frame: &Frame, floor: f32) {
+        let (width, height) = (image.width() as i32, image.height() as i32);
+        for (x, y, sum) in self.sums.enumerate_pixels_mut() {
+            let (fx, fy) = (x as i32 + frame.offset.dx, y as i32 + frame.offset.dy);
+            if fx < 0 || fy < 0 || fx >= width || fy >= height {
+                continue;
+            }
+            let pixel = image.get_pixel(fx as u32, fy as u32).0;
+            let peak = pixel[0].max(pixel[1]).max(pixel[2]);
+            let weight = weight(peak, frame.white).max(floor);
+            if weight == 0.0 {
+                continue;
+            }
+            for c in 0..3 {
+                sum[c] += weight * pixel[c] / frame.exposure;
+            }
+            self.weights[(y * image.width() + x) as usize] += weight;
+        }
+    }
+
+    fn finish(mut self) -> Rgb32FImage {
+        for (sum, weight) in self.sums.pixels_mut().zip(self.weights) {
+            if weight > 0.0 {
+                sum.0 = sum.0.map(|value| value / weight);
+            }
+        }
+        self.sums
+    }
+}
+
+const DNG_VERSION: u16 = 50706;
+const DNG_BACKWARD_VERSION: u16 = 50707;
+const UNIQUE_CAMERA_MODEL: u16 = 50708;
+const COLOR_MATRIX_1: u16 = 50721;
+const AS_SHOT_NEUTRAL: u16 = 50728;
+const WHITE_LEVEL: u16 = 50717;
+const BASELINE_EXPOSURE: u16 = 50730;
+const CALIBRATION_ILLUMINANT_1: u16 = 50778;
+const PHOTOMETRIC_LINEAR_RAW: u16 = 34892;
+const ILLUMINANT_D65: u16 = 21;
+
+/// Linear sRGB to XYZ, under D65.
+const SRGB_TO_XYZ: [[f64; 3]; 3] = [
+    [0.4124564, 0.3575761, 0.1804375],
+    [0.2126729, 0.7151522, 0.0721750],
+    [0.0193339, 0.1191920, 0.9503041],
+];
+
+/// The matrix from white balanced camera RGB, as the RAW decoder gives it,
+/// to linear sRGB, for a camera whose matrix from XYZ is `xyz_to_cam`.
+/// Rows are normalized so that white stays white. `None` when the camera
+/// has no usable matrix.
+fn camera_to_srgb(xyz_to_cam: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+    let mut srgb_to_cam = [[0.0f64; 3]; 3];
+    for (i, row) in srgb_to_cam.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| f64::from(xyz_to_cam[i][k]) * SRGB_TO_XYZ[k][j]).sum();
+        }
+        let sum: f64 = row.iter().sum();
+        if sum.abs() < 1e-6 {
+            return None;
+        }
+        row.iter_mut().for_each(|value| *value /= sum);
+    }
+    let m = srgb_to_cam;
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant: f64 = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum();
+    if determinant.abs() < 1e-9 {
+        return None;
+    }
+    // Inverse: the transposed cofactors over the determinant
+    Some(std::array::from_fn(|i| std::array::from_fn(|j| (cofactor(j, i) / determinant) as f32)))
+}
+
+/// Applies `matrix` to every pixel of `image`.
+fn convert(image: &mut Rgb32FImage, matrix: &[[f32; 3]; 3]) {
+    for pixel in image.pixels_mut() {
+        let [r, g, b] = pixel.0;
+        pixel.0 = matrix.map(|row| row[0] * r + row[1] * g + row[2] * b);
+    }
+}
+
+/// XYZ to linear sRGB, the merged data's colour space, under D65.
+const XYZ_TO_SRGB: [f64; 9] = [
+    3.2404542, -1.5371385, -0.4985314,
+    -0.9692660, 1.8760108, 0.0415560,
+    0.0556434, -0.2040259, 1.0572252,
+];
+
+/// Writes a linear (already demosaiced) 16-bit DNG. Its colour matrix says
+/// the data is linear sRGB, white balanced for D65. The brightest value is
+/// stored as white, and the baseline exposure brings the rest back to the
+/// reference frame's brightness.
+fn encode_dng(image: &Rgb32FImage, exif: &ExifData) -> Result<Vec<u8>> {
+    let camera = match (&exif.make, &exif.model) {
+        (Some(make), Some(model)) => format!("{} {}", make, model),
+        _ => "PhotoFlow HDR".to_string(),
+    };
+    let matrix: Vec<SRational> =
+        XYZ_TO_SRGB.iter().map(|&value| SRational { n: (value * 10_000_000.0).round() as i32, d: 10_000_000 }).collect();
+    let white = image.iter().copied().fold(0.0f32, f32::max);
+    let white = if white > 0.0 { white } else { 1.0 };
+    let data: Vec<u16> =
+        image.iter().map(|&value| (value.max(0.0) / white * f32::from(u16::MAX)).round() as u16).collect();
+    let baseline = SRational { n: (white.log2() * 1000.0).round() as i32, d: 1000 };
+
+    let mut encoded = Cursor::new(Vec::new());
+    {
+        let mut encoder = TiffEncoder::new(&mut encoded).context("Failed to encode DNG")?;
+        let mut dng = encoder
+            .new_image::<colortype::RGB16>(image.width(), image.height())
+            .context("Failed to encode DNG")?;
+        let tags = dng.encoder();
+        tags.write_tag(TiffTag::PhotometricInterpretation, PHOTOMETRIC_LINEAR_RAW)?;
+        tags.write_tag(TiffTag::Unknown(DNG_VERSION), &[1u8, 4, 0, 0][..])?;
+        tags.write_tag(TiffTag::Unknown(DNG_BACKWARD_VERSION), &[1u8, 4, 0, 0][..])?;
+        tags.write_tag(TiffTag::Unknown(UNIQUE_CAMERA_MODEL), camera.as_str())?;
+        // RAW decoders want a make and model even without a known camera
+        tags.write_tag(TiffTag::Make, exif.make.as_deref().unwrap_or("PhotoFlow"))?;
+        tags.write_tag(TiffTag::Model, exif.model.as_deref().unwrap_or("HDR"))?;
+        tags.write_tag(TiffTag::Unknown(WHITE_LEVEL), u16::MAX)?;
+        tags.write_tag(TiffTag::Unknown(BASELINE_EXPOSURE), baseline)?;
+        tags.write_tag(TiffTag::Unknown(COLOR_MATRIX_1), &matrix[..])?;
+        tags.write_tag(TiffTag::Unknown(CALIBRATION_ILLUMINANT_1), ILLUMINANT_D65)?;
+        tags.write_tag(TiffTag::Unknown(AS_SHOT_NEUTRAL), &[Rational { n: 1, d: 1 }, Rational { n: 1, d: 1 }, Rational { n: 1, d: 1 }][..])?;
+        dng.write_data(&data).context("Failed to encode DNG")?;
+    }
+    Ok(encoded.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame of `radiance` shot at `exposure`, clipping at 1.
+    fn frame(radiance: &Rgb32FImage, exposure: f32, offset: Offset) -> (Rgb32FImage, Option<f32>) {
+        let (width, height) = radiance.dimensions();
+        let image = Rgb32FImage::from_fn(width, height, |x, y| {
+            let (sx, sy) = ((x as i32 - offset.dx).clamp(0, width as i32 - 1), (y as i32 - offset.dy).clamp(0, height as i32 - 1));
+            image::Rgb(radiance.get_pixel(sx as u32, sy as u32).0.map(|v| (v * exposure).min(1.0)))
+        });
+        let white = image.iter().any(|&v| v >= 1.0).then_some(1.0);
+        (image, white)
+    }
+
+    #[test]
+    fn test_merge_brackets() {
+        // A textured gradient over four stops plus a window three stops
+        // brighter
+        let radiance = Rgb32FImage::from_fn(192, 128, |x, y| {
+            let (fx, fy) = (x as f32, y as f32);
+            let texture = if (fx / 9.0).sin() * (fy / 13.0).cos() > 0.0 { 1.5 } else { 1.0 };
+            let base = 0.02 * 2f32.powf(fx / 48.0) * (1.0 + 0.3 * (fy / 7.0).sin()) * texture;
+            let window = if (120..160).contains(&x) && (20..60).contains(&y) { 8.0 } else { 1.0 };
+            image::Rgb([base * window, base * window * 0.8, base * window * 0.6])
+        });
+        let shots = [(0.25, Offset { dx: 4, dy: -4 }), (1.0, Offset::default()), (4.0, Offset { dx: -8, dy: 4 })];
+        let mut frames: Vec<Frame> = Vec::new();
+        let mut images = Vec::new();
+        for (i, &(exposure, offset)) in shots.iter().enumerate() {
+            let (image, white) = frame(&radiance, exposure, offset);
+            frames.push(analyse(Path::new(&format!("{}.raf", i)), &image, white));
+            images.push(image);
+        }
+        let offsets: Vec<Offset> = frames.iter().map(|frame| align::align(&frames[1].bitmaps, &frame.bitmaps)).collect();
+        assert_eq!(offsets, shots.map(|(_, offset)| offset));
+        for (frame, offset) in frames.iter_mut().zip(offsets) {
+            frame.offset = offset;
+        }
+        estimate_exposures(&mut frames, 1).unwrap();
+        for (frame, (exposure, _)) in frames.iter().zip(shots) {
+            assert!((frame.exposure / exposure - 1.0).abs() < 0.02, "{} vs {}", frame.exposure, exposure);
+        }
+
+        let mut merge = Merge::new(192, 128);
+        for (i, (image, frame)) in images.iter().zip(&frames).enumerate() {
+            merge.add(image, frame, if i == 0 { 1e-8 } else { 0.0 });
+        }
+        let merged = merge.finish();
+        // The window, clipped in all but the darkest frame, keeps its colour
+        // and brightness
+        for (x, y) in [(150, 40), (10, 100), (100, 64)] {
+            let (expected, actual) = (radiance.get_pixel(x, y).0, merged.get_pixel(x, y).0);
+            for c in 0..3 {
+                assert!((actual[c] / expected[c] - 1.0).abs() < 0.02, "{:?} vs {:?} at {:?}", actual, expected, (x, y));
+            }
+        }
+
+        // DNG readers go by these tags; the TIFF decoder refuses linear raw
+        let dng = encode_dng(&merged, &ExifData::default()).unwrap();
+        let white = merged.iter().copied().fold(0.0f32, f32::max);
+        let fields = exif::Reader::new().read_raw(dng.clone()).unwrap();
+        let field = |tag: u16| {
+            let tag = exif::Tag(exif::Context::Tiff, tag);
+            fields.get_field(tag, exif::In::PRIMARY).map(|field| field.value.clone()).unwrap()
+        };
+        assert_eq!(field(TiffTag::PhotometricInterpretation.to_u16()).get_uint(0), Some(u32::from(PHOTOMETRIC_LINEAR_RAW)));
+        assert_eq!(field(DNG_VERSION).get_uint(1), Some(4));
+        assert_eq!(field(TiffTag::BitsPerSample.to_u16()).get_uint(0), Some(16));
+        assert_eq!(field(WHITE_LEVEL).get_uint(0), Some(65535));
+        assert!(matches!(field(COLOR_MATRIX_1), exif::Value::SRational(matrix) if matrix.len() == 9));
+        let exif::Value::SRational(baseline) = field(BASELINE_EXPOSURE) else { panic!("baseline exposure") };
+        assert!((baseline[0].to_f64() - f64::from(white.log2())).abs() < 0.001);
+
+        // And PhotoFlow opens its own result at the reference brightness
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("merged.dng");
+        std::fs::write(&path, &dng).unwrap();
+        let (reopened, _) = RawProcessor::new().decode(&path).unwrap();
+        let reopened = reopened.into_rgb32f();
+        assert_eq!(reopened.dimensions(), merged.dimensions());
+        for (x, y) in [(150, 40), (10, 100), (100, 64)] {
+            let (expected, actual) = (merged.get_pixel(x, y).0, reopened.get_pixel(x, y).0);
+            for c in 0..3 {
+                assert!((actual[c] - expected[c]).abs() < 0.001 * white, "{:?} vs {:?} at {:?}", actual, expected, (x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn test_camera_to_srgb() {
+        // A camera that sees in sRGB needs no conversion
+        let xyz_to_srgb: [[f32; 3]; 3] = std::array::from_fn(|i| std::array::from_fn(|j| XYZ_TO_SRGB[i * 3 + j] as f32));
+        let identity = camera_to_srgb(xyz_to_srgb).unwrap();
+        for (i, row) in identity.iter().enumerate() {
+            for (j, &value) in row.iter().enumerate() {
+                assert!((value - if i == j { 1.0 } else { 0.0 }).abs() < 1e-4, "{:?}", identity);
+            }
+        }
+
+        // Any other keeps white balanced white neutral
+        let camera = [[0.7, -0.1, -0.05], [-0.4, 1.2, 0.2], [-0.05, 0.1, 0.6]];
+        let mut image = Rgb32FImage::from_pixel(1, 1, image::Rgb([0.5; 3]));
+        convert(&mut image, &camera_to_srgb(camera).unwrap());
+        assert!(image.get_pixel(0, 0).0.iter().all(|&v| (v - 0.5).abs() < 1e-4), "{:?}", image.get_pixel(0, 0));
+        assert_eq!(camera_to_srgb([[0.0; 3]; 3]), None);
+    }
+}
@@ -1,158 +1,264 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use anyhow::Result;
-use image::DynamicImage;
+use anyhow::{bail, Result};
+use std::borrow::Cow;
+use iced::widget::image::Handle;
+use image::{DynamicImage, Rgb32FImage};
 use exif::{Reader, Tag, Value};
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
 use tracing::{debug, info};
 use std::time::SystemTime;
 use lru::LruCache;
 use parking_lot::Mutex;
 use once_cell::sync::Lazy;
 
-use crate::processors;
+use crate::color::SoftProof;
+use crate::develop::{self, histogram::{self, ClippingWarnings, Histogram}, EditParams};
+use crate::grouping;
+use crate::processors::{self, highlights::HighlightMode, raw::{RawClipping, RawProcessor}};
+use crate::processors::detector::{self, ImageType};
+use crate::sidecar::{self, Sidecar, Snapshot};
+
+/// Decoded images by file, with the time they were decoded.
+type ImageCache = LruCache<PathBuf, (Decoded, SystemTime)>;
 
 // Cache for loaded images
-static IMAGE_CACHE: Lazy<Arc<Mutex<LruCache<PathBuf, (DynamicImage, SystemTime)>>>> = 
+static IMAGE_CACHE: Lazy<Arc<Mutex<ImageCache>>> = 
     Lazy::new(|| Arc::new(Mutex::new(LruCache::new(std::num::NonZeroUsize::new(32).unwrap())))); // Cache up to 32 images
 
+/// Drops a file from the image cache, e.g. after it was trashed or moved away.
+pub fn forget_cached(path: &Path) {
+    IMAGE_CACHE.lock().pop(path);
+}
+
+/// Keeps a cached image when its file is renamed or moved.
+pub fn rename_cached(from: &Path, to: &Path) {
+    let mut cache = IMAGE_CACHE.lock();
+    if let Some(entry) = cache.pop(from) {
+        cache.put(to.to_path_buf(), entry);
+    }
+}
+
+/// A decoded file, with what the RAW decoder saw of the sensor data.
+#[derive(Debug, Clone)]
+pub struct Decoded {
+    pub image: DynamicImage,
+    /// `None` for files that aren't RAW.
+    pub raw_clipping: Option<RawClipping>,
+    /// How the RAW decoder treated clipped highlights.
+    pub highlight_mode: Option<HighlightMode>,
+}
+
+/// A decoded file with the copy edits are previewed on, prepared away
+/// from the UI thread by [`Loaded::new`].
+#[derive(Debug, Clone)]
+pub struct Loaded {
+    decoded: Decoded,
+    preview: Arc<Rgb32FImage>,
+    perceptual_hash: u64,
+}
+
+impl Loaded {
+    /// Does the slow part of showing a newly decoded image.
+    pub fn new(decoded: Decoded) -> Self {
+        // Edits are previewed on a smaller copy; `image` keeps full precision
+        let preview = develop::preview_source(&decoded.image);
+        let unedited = DynamicImage::ImageRgb32F(preview.clone());
+        let perceptual_hash = grouping::perceptual_hash(&encode_srgb(&unedited));
+        Loaded { decoded, preview: Arc::new(preview), perceptual_hash }
+    }
+}
+
+/// Everything needed to render a photo's preview, so it can be done away
+/// from the UI thread. See [`Photo::render_job`].
+pub struct RenderJob {
+    generation: u64,
+    preview: Arc<Rgb32FImage>,
+    params: EditParams,
+    scale: f32,
+    crop_overlay: bool,
+    clipping_warnings: ClippingWarnings,
+    soft_proof: Option<Arc<SoftProof>>,
+}
+
+impl RenderJob {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn run(self) -> Rendered {
+        let mut rendered = develop::render_display(&self.preview, &self.params, self.scale, self.crop_overlay, self.soft_proof.as_deref());
+        let histogram = Histogram::from_display(&rendered.image);
+        histogram::draw_clipping(&mut rendered.image, self.clipping_warnings);
+        let display = rendered.with_overlays();
+        Rendered { display: Handle::from_pixels(display.width(), display.height(), display.into_raw()), histogram }
+    }
+}
+
+/// A rendered preview, for [`Photo::finish_render`].
+#[derive(Debug, Clone)]
+pub struct Rendered {
+    display: Handle,
+    histogram: Histogram,
+}
+
 #[derive(Debug, Clone)]
 pub struct Photo {
+    /// The file currently being displayed.
     path: PathBuf,
+    /// Every file making up this photo, e.g. a RAW and its in-camera JPEG.
+    /// The first entry is the default representation.
+    representations: Vec<PathBuf>,
     exif_data: Option<ExifData>,
     pub image: Option<DynamicImage>,
-    rgb_data: Option<Vec<u8>>,
+    /// Linear, downscaled copy of `image` that edits are previewed on.
+    preview: Option<Arc<Rgb32FImage>>,
+    /// `preview` with `edits` applied, as 8-bit RGBA for the viewer.
+    display: Option<Handle>,
+    /// Bumped whenever what `display` should show changes.
+    generation: u64,
+    /// Generation `display` was rendered at.
+    displayed_generation: u64,
+    /// Generation `preview` was set at; older renders are of another image.
+    preview_generation: u64,
+    /// Whether a render is running; the next one waits for it.
+    rendering: bool,
+    /// Develop settings, from the sidecar.
+    edits: EditParams,
+    /// Show the whole image with the crop drawn over it instead of cropping.
+    crop_overlay: bool,
+    clipping_warnings: ClippingWarnings,
+    /// Output the preview is soft-proofed for.
+    soft_proof: Option<Arc<SoftProof>>,
+    /// Of the rendered preview, before any overlays.
+    histogram: Option<Histogram>,
+    raw_clipping: Option<RawClipping>,
+    /// Highlight mode `image` was decoded with, for RAW files.
+    highlight_mode: Option<HighlightMode>,
+    /// Perceptual hash of the loaded image, for similarity grouping.
+    perceptual_hash: Option<u64>,
+    /// Key of the virtual copy this is, see [`sidecar::copy_path`].
+    virtual_copy: Option<PathBuf>,
+    /// Saved versions of `edits`, from the sidecar.
+    snapshots: Vec<Snapshot>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ExifData {
     pub make: Option<String>,
     pub model: Option<String>,
+    pub lens: Option<String>,
     pub exposure_time: Option<String>,
     pub f_number: Option<f32>,
     pub iso: Option<u32>,
     pub focal_length: Option<f32>,
     pub datetime: Option<String>,
+    /// Sub-second part of `datetime` in nanoseconds, used to order bursts.
+    pub subsec: Option<u32>,
+    /// Star rating (0-5), from the sidecar or the EXIF `Rating` tag.
+    pub rating: Option<u8>,
+    /// Colour label from the sidecar.
+    pub label: Option<String>,
+    /// Keywords from the sidecar.
+    pub keywords: Vec<String>,
+    /// EXIF orientation (1-8), how the image must be turned to display upright.
+    pub orientation: Option<u16>,
 }
 
-impl Photo {
-    pub fn new(path: PathBuf) -> Result<Self> {
-        let mut photo = Self {
-            path,
-            exif_data: None,
-            image: None,
-            rgb_data: None,
+// Microsoft's `Rating` tag, written by most cameras that support in-camera rating
+const TAG_RATING: Tag = Tag(exif::Context::Tiff, 0x4746);
+
+impl ExifData {
+    /// Reads metadata for `path`, merging in its XMP sidecar if present.
+    pub fn read(path: &Path) -> Result<Self> {
+        Self::read_with_sidecar(path, path)
+    }
+
+    /// Reads metadata for `path`, merging in the sidecar belonging to `key`,
+    /// which differs from `path` for virtual copies.
+    pub fn read_with_sidecar(path: &Path, key: &Path) -> Result<Self> {
+        let mut data = match Self::read_exif(path) {
+            Ok(data) => data,
+            Err(e) => {
+                // Some RAW containers aren't understood by the EXIF parser,
+                // but rawloader can still tell us which camera it came from
+                debug!("EXIF parser failed ({}), falling back to rawloader", e);
+                let raw_image = rawloader::decode_file(path)?;
+                ExifData {
+                    make: Some(raw_image.make),
+                    model: Some(raw_image.model),
+                    ..Default::default()
+                }
+            }
         };
-        
-        if let Err(e) = photo.load_exif() {
-            debug!("Failed to load EXIF data: {}", e);
-            // Continue even if EXIF loading fails
+
+        match sidecar::read(key) {
+            Ok(Some(sidecar)) => data.apply_sidecar(sidecar),
+            Ok(None) => {}
+            Err(e) => debug!("Ignoring unreadable sidecar: {}", e),
         }
-        Ok(photo)
-    }
 
-    pub fn path(&self) -> &Path {
-        &self.path
+        Ok(data)
     }
 
-    pub fn exif_data(&self) -> Option<&ExifData> {
-        self.exif_data.as_ref()
+    /// Capture time as a sortable key: the EXIF datetime plus sub-seconds.
+    pub fn capture_time(&self) -> Option<(&str, u32)> {
+        self.datetime.as_deref().map(|dt| (dt, self.subsec.unwrap_or(0)))
     }
 
-    pub fn set_image(&mut self, image: DynamicImage) {
-        // Convert to RGB8 once and cache it
-        let rgb = image.to_rgb8();
-        self.rgb_data = Some(rgb.to_vec());
-        self.image = Some(image);
+    /// Capture time in seconds since the Unix epoch, for measuring gaps
+    /// between frames. The camera's local time is treated as UTC.
+    pub fn timestamp(&self) -> Option<f64> {
+        // `datetime` is formatted as "YYYY-MM-DD HH:MM:SS"
+        let datetime = self.datetime.as_deref()?;
+        let field = |range: std::ops::Range<usize>| datetime.get(range)?.parse::<i64>().ok();
+        let days = days_from_civil(field(0..4)?, field(5..7)?, field(8..10)?);
+        let seconds = days * 86_400 + field(11..13)? * 3_600 + field(14..16)? * 60 + field(17..19)?;
+        Some(seconds as f64 + self.subsec.unwrap_or(0) as f64 / 1e9)
     }
 
-    pub fn get_rgb_data(&self) -> Vec<u8> {
-        if let Some(data) = &self.rgb_data {
-            data.clone()
-        } else if let Some(img) = &self.image {
-            let rgb = img.to_rgb8();
-            rgb.to_vec()
-        } else {
-            Vec::new()
+    /// Camera make and model as a single display string.
+    pub fn camera(&self) -> Option<String> {
+        match (self.make.as_ref(), self.model.as_ref()) {
+            (Some(make), Some(model)) if model.starts_with(make.as_str()) => Some(model.clone()),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (Some(make), None) => Some(make.clone()),
+            (None, Some(model)) => Some(model.clone()),
+            (None, None) => None,
         }
     }
 
-    pub fn load_image(&self) -> Result<DynamicImage> {
-        info!("Loading image: {}", self.path.display());
-        
-        // Try to load from cache first
-        if let Some((cached_image, cached_time)) = IMAGE_CACHE.lock().get(&self.path).cloned() {
-            // Check if file has been modified
-            if let Ok(metadata) = std::fs::metadata(&self.path) {
-                if let Ok(modified) = metadata.modified() {
-                    if modified <= cached_time {
-                        debug!("Loading image from cache: {}", self.path.display());
-                        return Ok(cached_image);
-                    }
-                }
-            }
+    /// Overrides library fields with the values from a sidecar.
+    pub fn apply_sidecar(&mut self, sidecar: Sidecar) {
+        if sidecar.rating.is_some() {
+            self.rating = sidecar.rating;
         }
-        
-        // Not in cache, load using processor
-        let processor = processors::get_processor(&self.path);
-        let image = processor.load_image(&self.path)?;
-        
-        // Add to cache
-        if let Ok(metadata) = std::fs::metadata(&self.path) {
-            if let Ok(modified) = metadata.modified() {
-                IMAGE_CACHE.lock().put(self.path.clone(), (image.clone(), modified));
-            }
+        if sidecar.label.is_some() {
+            self.label = sidecar.label;
+        }
+        if !sidecar.keywords.is_empty() {
+            self.keywords = sidecar.keywords;
         }
-        
-        Ok(image)
     }
 
-
-    fn load_exif(&mut self) -> Result<()> {
-        debug!("Loading metadata from: {:?}", self.path);
-        
-        // First try to load metadata from rawloader for RAW files
-        if let Ok(raw_image) = rawloader::decode_file(&self.path) {
-            debug!("Got metadata from rawloader");
-            let data = ExifData {
-                make: Some(raw_image.make),
-                model: Some(raw_image.model),
-                exposure_time: None, // TODO: Add these from rawloader
-                f_number: None,
-                iso: None,
-                focal_length: None,
-                datetime: None,
-            };
-            self.exif_data = Some(data);
-            return Ok(());
-        }
-        
-        // Fall back to EXIF for non-RAW files
-        debug!("Falling back to EXIF parser");
-        let file = File::open(&self.path)?;
-        let mut bufreader = BufReader::new(&file);
-        let exif = Reader::new().read_from_container(&mut bufreader)?;
-
-        let mut data = ExifData {
-            make: None,
-            model: None,
-            exposure_time: None,
-            f_number: None,
-            iso: None,
-            focal_length: None,
-            datetime: None,
-        };
+    fn read_exif(path: &Path) -> Result<Self> {
+        let exif = read_exif_fields(path)?;
+        let mut data = ExifData::default();
 
         // Process all fields
         for field in exif.fields() {
             debug!("Found EXIF field: {:?} = {:?}", field.tag, field.value);
             match field.tag {
                 Tag::Make => {
-                    data.make = Some(field.value.display_as(field.tag).to_string());
+                    data.make = ascii(&field.value);
                 }
                 Tag::Model => {
-                    data.model = Some(field.value.display_as(field.tag).to_string());
+                    data.model = ascii(&field.value);
+                }
+                Tag::LensModel => {
+                    data.lens = ascii(&field.value);
                 }
                 Tag::ExposureTime => {
                     if let Value::Rational(rationals) = &field.value {
@@ -168,10 +274,10 @@ impl Photo {
                         }
                     }
                 }
-                Tag::ISOSpeed => {
-                    if let Value::Short(v) = &field.value {
-                        data.iso = v.first().map(|&x| x as u32);
-                    }
+                // Cameras normally only write PhotographicSensitivity;
+                // don't let a later ISOSpeed field clobber it
+                Tag::PhotographicSensitivity | Tag::ISOSpeed if data.iso.is_none() => {
+                    data.iso = field.value.get_uint(0);
                 }
                 Tag::FocalLength => {
                     if let Value::Rational(rationals) = &field.value {
@@ -183,12 +289,489 @@ impl Photo {
                 Tag::DateTimeOriginal => {
                     data.datetime = Some(field.value.display_as(field.tag).to_string());
                 }
+                Tag::SubSecTimeOriginal => {
+                    data.subsec = ascii(&field.value).and_then(|s| parse_subsec(&s));
+                }
+                Tag::Orientation => {
+                    data.orientation = field.value.get_uint(0)
+                        .filter(|o| (1..=8).contains(o))
+                        .map(|o| o as u16);
+                }
+                TAG_RATING => {
+                    data.rating = field.value.get_uint(0)
+                        .filter(|r| *r <= 5)
+                        .map(|r| r as u8);
+                }
                 _ => {}
             }
         }
 
         debug!("Extracted EXIF data: {:?}", data);
-        self.exif_data = Some(data);
+        Ok(data)
+    }
+}
+
+/// Reads the raw EXIF fields of an image.
+pub fn read_exif_fields(path: &Path) -> Result<exif::Exif> {
+    if detector::detect_image_type(path)? == ImageType::RawFuji {
+        // RAF isn't a TIFF container; the EXIF lives in the embedded preview JPEG
+        let jpeg = read_raf_preview(path)?;
+        Ok(Reader::new().read_from_container(&mut Cursor::new(jpeg))?)
+    } else {
+        let file = File::open(path)?;
+        let mut bufreader = BufReader::new(&file);
+        Ok(Reader::new().read_from_container(&mut bufreader)?)
+    }
+}
+
+/// Applies the sRGB transfer curve to linear float images, such as RAW
+/// decodes, so they can be quantised for display or 8/16-bit files. Integer
+/// images are already encoded and are returned as they are.
+pub fn encode_srgb(image: &DynamicImage) -> Cow<'_, DynamicImage> {
+    match image {
+        DynamicImage::ImageRgb32F(linear) => {
+            let mut encoded = linear.clone();
+            encoded.pixels_mut().flat_map(|p| p.0.iter_mut()).for_each(|v| *v = srgb_encode(*v));
+            Cow::Owned(DynamicImage::ImageRgb32F(encoded))
+        }
+        DynamicImage::ImageRgba32F(linear) => {
+            let mut encoded = linear.clone();
+            // Alpha stays linear
+            encoded.pixels_mut().flat_map(|p| p.0.iter_mut().take(3)).for_each(|v| *v = srgb_encode(*v));
+            Cow::Owned(DynamicImage::ImageRgba32F(encoded))
+        }
+        _ => Cow::Borrowed(image),
+    }
+}
+
+/// The sRGB curve for one linear value, clipped to 0-1.
+pub fn srgb_encode(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Turns an image upright according to its EXIF orientation.
+pub fn orient(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Returns the first string of an ASCII value, without quotes or padding.
+fn ascii(value: &Value) -> Option<String> {
+    if let Value::Ascii(strings) = value {
+        strings.first()
+            .map(|s| String::from_utf8_lossy(s).trim_end_matches(['\0', ' ']).to_string())
+            .filter(|s| !s.is_empty())
+    } else {
+        None
+    }
+}
+
+/// Groups files that share a directory and base name (`DSCF1234.RAF` and
+/// `DSCF1234.JPG`) so they can be shown as a single photo. Within a group the
+/// non-RAW file comes first since it's much faster to display.
+pub fn group_representations(paths: Vec<PathBuf>) -> Vec<Vec<PathBuf>> {
+    let mut groups: Vec<Vec<PathBuf>> = Vec::new();
+    let mut index_by_key: HashMap<(PathBuf, String), usize> = HashMap::new();
+
+    for path in paths {
+        let key = (
+            path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            path.file_stem().unwrap_or_default().to_string_lossy().to_lowercase(),
+        );
+        match index_by_key.get(&key) {
+            Some(&index) => groups[index].push(path),
+            None => {
+                index_by_key.insert(key, groups.len());
+                groups.push(vec![path]);
+            }
+        }
+    }
+
+    for group in &mut groups {
+        group.sort_by_key(|path| detector::has_raw_extension(path));
+    }
+    groups
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Howard Hinnant's algorithm: shift the year to start in March so the
+    // leap day falls at the end
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Converts an EXIF SubSecTime string ("12" meaning .12s) to nanoseconds.
+fn parse_subsec(value: &str) -> Option<u32> {
+    let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).take(9).collect();
+    if digits.is_empty() {
+        return None;
+    }
+    let scale = 10u32.pow(9 - digits.len() as u32);
+    digits.parse::<u32>().ok().map(|v| v * scale)
+}
+
+/// Decodes the file at `path`, RAW files with `highlight_mode`. Pass the
+/// mode of the photo being shown: virtual copies of one file can differ.
+pub fn load_image(path: &Path, highlight_mode: HighlightMode) -> Result<Decoded> {
+    info!("Loading image: {}", path.display());
+    
+    // Try to load from cache first
+    if let Some((cached_image, cached_time)) = IMAGE_CACHE.lock().get(path).cloned() {
+        // Check if file has been modified
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if let Ok(modified) = metadata.modified() {
+                let stale = cached_image.highlight_mode.is_some_and(|m| m != highlight_mode);
+                if modified <= cached_time && !stale {
+                    debug!("Loading image from cache: {}", path.display());
+                    return Ok(cached_image);
+                }
+            }
+        }
+    }
+    
+    // Not in cache, load using processor
+    let image = if detector::detect_image_type(path).is_ok_and(|t| t.is_raw()) {
+        let (image, clipping) = RawProcessor::new().with_highlight_mode(highlight_mode).decode(path)?;
+        Decoded { image, raw_clipping: Some(clipping), highlight_mode: Some(highlight_mode) }
+    } else {
+        let processor = processors::get_processor(path);
+        Decoded { image: processor.load_image(path)?, raw_clipping: None, highlight_mode: None }
+    };
+    
+    // Add to cache
+    if let Ok(metadata) = std::fs::metadata(path) {
+        if let Ok(modified) = metadata.modified() {
+            IMAGE_CACHE.lock().put(path.to_path_buf(), (image.clone(), modified));
+        }
+    }
+    
+    Ok(image)
+}
+
+/// Loads a quick, possibly reduced-quality version of an image for analysis
+/// such as hashing: the embedded JPEG for RAF files, the full image otherwise.
+/// Linear RAW decodes come back sRGB-encoded so they compare with JPEGs.
+pub fn load_preview(path: &Path) -> Result<DynamicImage> {
+    if detector::detect_image_type(path)? == ImageType::RawFuji {
+        if let Ok(jpeg) = read_raf_preview(path) {
+            return Ok(image::load_from_memory(&jpeg)?);
+        }
+    }
+    let image = processors::get_processor(path).load_image(path)?;
+    Ok(encode_srgb(&image).into_owned())
+}
+
+/// Largest preview JPEG we expect in a RAF; anything bigger means a
+/// corrupt header.
+const MAX_RAF_PREVIEW: u64 = 64 * 1024 * 1024;
+
+/// Reads the preview JPEG embedded in a Fuji RAF file.
+fn read_raf_preview(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    // Offset and length of the JPEG are big-endian u32s at 0x54 and 0x58
+    let mut header = [0u8; 8];
+    file.seek(SeekFrom::Start(0x54))?;
+    file.read_exact(&mut header)?;
+    let offset = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as u64;
+    if length > MAX_RAF_PREVIEW || offset + length > file.metadata()?.len() {
+        bail!("Invalid preview in {}", path.display());
+    }
+
+    let mut jpeg = vec![0u8; length as usize];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut jpeg)?;
+    Ok(jpeg)
+}
+
+impl Photo {
+    /// Creates a photo backed by several files sharing a base name, as
+    /// grouped by [`group_representations`].
+    pub fn with_representations(representations: Vec<PathBuf>) -> Result<Self> {
+        Self::build(representations, None)
+    }
+
+    /// A virtual copy of the photo made of `representations`, with its own
+    /// sidecar at `key`.
+    pub fn virtual_copy(key: PathBuf, representations: Vec<PathBuf>) -> Result<Self> {
+        Self::build(representations, Some(key))
+    }
+
+    fn build(representations: Vec<PathBuf>, virtual_copy: Option<PathBuf>) -> Result<Self> {
+        let path = representations.first()
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Photo has no files"))?;
+        let mut photo = Self {
+            path,
+            representations,
+            exif_data: None,
+            image: None,
+            preview: None,
+            display: None,
+            generation: 0,
+            displayed_generation: 0,
+            preview_generation: 0,
+            rendering: false,
+            edits: EditParams::default(),
+            crop_overlay: false,
+            clipping_warnings: ClippingWarnings::default(),
+            soft_proof: None,
+            histogram: None,
+            raw_clipping: None,
+            highlight_mode: None,
+            perceptual_hash: None,
+            virtual_copy,
+            snapshots: Vec::new(),
+        };
+        photo.reload_edits();
+        
+        if let Err(e) = photo.load_exif() {
+            debug!("Failed to load EXIF data: {}", e);
+            // Continue even if EXIF loading fails
+        }
+        Ok(photo)
+    }
+
+    /// The path the photo's sidecar belongs to: the primary file, or the
+    /// key of a virtual copy.
+    pub fn key(&self) -> &Path {
+        self.virtual_copy.as_deref().unwrap_or(&self.representations[0])
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn representations(&self) -> &[PathBuf] {
+        &self.representations
+    }
+
+    /// The representation shown after the current one, if there is more than one.
+    pub fn next_representation(&self) -> Option<&Path> {
+        if self.representations.len() < 2 {
+            return None;
+        }
+        let current = self.representations.iter().position(|p| p == &self.path).unwrap_or(0);
+        Some(&self.representations[(current + 1) % self.representations.len()])
+    }
+
+    /// Switches the displayed file. The image has to be loaded again afterwards.
+    pub fn show_representation(&mut self, path: &Path) {
+        if self.representations.iter().any(|p| p == path) {
+            self.path = path.to_path_buf();
+            self.image = None;
+            self.preview = None;
+            self.invalidate();
+            self.preview_generation = self.generation;
+            self.display = None;
+        }
+    }
+
+    /// Updates the file paths after the files were renamed or moved.
+    pub fn relocate(&mut self, moves: &[(PathBuf, PathBuf)]) {
+        for path in self.representations.iter_mut().chain(std::iter::once(&mut self.path)) {
+            if let Some((_, to)) = moves.iter().find(|(from, _)| from == path) {
+                *path = to.clone();
+            }
+        }
+        // A virtual copy moves with its sidecar
+        if let Some(key) = &self.virtual_copy {
+            let sidecar = sidecar::copy_sidecar(key);
+            if let Some((_, to)) = moves.iter().find(|(from, _)| *from == sidecar) {
+                self.virtual_copy = Some(to.with_extension(""));
+            }
+        }
+    }
+
+    pub fn exif_data(&self) -> Option<&ExifData> {
+        self.exif_data.as_ref()
+    }
+
+    pub fn exif_data_mut(&mut self) -> Option<&mut ExifData> {
+        self.exif_data.as_mut()
+    }
+
+    pub fn set_image(&mut self, loaded: Loaded) {
+        self.perceptual_hash = Some(loaded.perceptual_hash);
+        self.preview = Some(loaded.preview);
+        self.image = Some(loaded.decoded.image);
+        self.raw_clipping = loaded.decoded.raw_clipping;
+        self.highlight_mode = loaded.decoded.highlight_mode;
+        self.invalidate();
+        self.preview_generation = self.generation;
+    }
+
+    pub fn edits(&self) -> &EditParams {
+        &self.edits
+    }
+
+    /// Changes the develop settings shown, without saving them.
+    pub fn set_edits(&mut self, edits: EditParams) {
+        if edits != self.edits {
+            self.edits = edits;
+            self.invalidate();
+        }
+    }
+
+    pub fn crop_overlay(&self) -> bool {
+        self.crop_overlay
+    }
+
+    pub fn set_crop_overlay(&mut self, crop_overlay: bool) {
+        if crop_overlay != self.crop_overlay {
+            self.crop_overlay = crop_overlay;
+            self.invalidate();
+        }
+    }
+
+    pub fn set_clipping_warnings(&mut self, warnings: ClippingWarnings) {
+        if warnings != self.clipping_warnings {
+            self.clipping_warnings = warnings;
+            self.invalidate();
+        }
+    }
+
+    pub fn set_soft_proof(&mut self, proof: Option<Arc<SoftProof>>) {
+        let unchanged = match (&self.soft_proof, &proof) {
+            (Some(current), Some(proof)) => Arc::ptr_eq(current, proof),
+            (current, proof) => current.is_none() && proof.is_none(),
+        };
+        if !unchanged {
+            self.soft_proof = proof;
+            self.invalidate();
+        }
+    }
+
+    /// Renders again for a new display profile.
+    pub fn refresh_display(&mut self) {
+        self.invalidate();
+    }
+
+    pub fn histogram(&self) -> Option<&Histogram> {
+        self.histogram.as_ref()
+    }
+
+    /// Whether the image has to be decoded again for the develop settings,
+    /// e.g. after the highlight mode changed.
+    pub fn needs_decode(&self) -> bool {
+        self.highlight_mode.is_some_and(|mode| mode != self.edits.highlight_mode)
+    }
+
+    /// Whether the displayed file was decoded as RAW.
+    pub fn is_raw(&self) -> bool {
+        self.highlight_mode.is_some()
+    }
+
+    /// How much of the sensor clipped, for RAW files.
+    pub fn raw_clipping(&self) -> Option<RawClipping> {
+        self.raw_clipping
+    }
+
+    /// Size of the image edits are previewed on, once it is loaded.
+    pub fn preview_size(&self) -> Option<(u32, u32)> {
+        self.preview.as_ref().map(|p| p.dimensions())
+    }
+
+    /// Re-reads the develop settings from the sidecar, e.g. after undo.
+    pub fn reload_edits(&mut self) {
+        let sidecar = match sidecar::read(self.key()) {
+            Ok(sidecar) => sidecar.unwrap_or_default(),
+            Err(e) => {
+                debug!("Ignoring unreadable sidecar: {}", e);
+                Sidecar::default()
+            }
+        };
+        self.snapshots = sidecar.snapshots;
+        self.set_edits(sidecar.edits);
+    }
+
+    /// Marks the display as out of date; see [`Photo::render_job`].
+    fn invalidate(&mut self) {
+        self.generation += 1;
+    }
+
+    /// The render bringing the display up to date, if it is out of date
+    /// and no render is running already.
+    pub fn render_job(&mut self) -> Option<RenderJob> {
+        let preview = self.preview.as_ref()?;
+        if self.rendering || self.displayed_generation == self.generation {
+            return None;
+        }
+        self.rendering = true;
+        Some(RenderJob {
+            generation: self.generation,
+            preview: preview.clone(),
+            params: self.edits.for_photo(self.exif_data.as_ref(), self.is_raw()),
+            scale: self.image.as_ref().map_or(1.0, |image| preview.width() as f32 / image.width() as f32),
+            crop_overlay: self.crop_overlay,
+            clipping_warnings: self.clipping_warnings,
+            soft_proof: self.soft_proof.clone(),
+        })
+    }
+
+    /// Takes the result of the render job of `generation`, unless the
+    /// display already shows something newer or another image.
+    pub fn finish_render(&mut self, generation: u64, rendered: Option<Rendered>) {
+        self.rendering = false;
+        let Some(rendered) = rendered else {
+            return;
+        };
+        if generation > self.displayed_generation && generation >= self.preview_generation {
+            self.display = Some(rendered.display);
+            self.histogram = Some(rendered.histogram);
+            self.displayed_generation = generation;
+        }
+    }
+
+    /// What `edits` would look like, rendered small, e.g. to preview a
+    /// preset. `None` until the image is loaded.
+    pub fn render_thumbnail(&self, edits: &EditParams, long_edge: u32) -> Option<Handle> {
+        let (preview, image) = (self.preview.as_deref()?, self.image.as_ref()?);
+        let thumbnail_scale = long_edge as f32 / preview.width().max(preview.height()) as f32;
+        let (width, height) = (
+            ((preview.width() as f32 * thumbnail_scale).round() as u32).max(1),
+            ((preview.height() as f32 * thumbnail_scale).round() as u32).max(1),
+        );
+        let thumbnail = image::imageops::resize(preview, width, height, image::imageops::FilterType::Triangle);
+        let scale = width as f32 / image.width() as f32;
+        let display = develop::render_display(&thumbnail, &edits.for_photo(self.exif_data.as_ref(), self.is_raw()), scale, false, None).image;
+        Some(Handle::from_pixels(display.width(), display.height(), display.into_raw()))
+    }
+
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    pub fn perceptual_hash(&self) -> Option<u64> {
+        self.perceptual_hash
+    }
+
+    /// The loaded image, ready for an `Image` widget.
+    pub fn display_handle(&self) -> Option<&Handle> {
+        self.display.as_ref()
+    }
+
+    fn load_exif(&mut self) -> Result<()> {
+        debug!("Loading metadata from: {:?}", self.path);
+        self.exif_data = Some(ExifData::read_with_sidecar(&self.path, self.key())?);
         Ok(())
     }
 }
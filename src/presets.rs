@@ -0,0 +1,218 @@
+//! Named develop presets: a chosen subset of settings groups, stored as
+//! XMP files in `~/.config/photoflow/presets`.
+
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
+use iced::widget::image::Handle;
+use tracing::{debug, warn};
+
+use crate::config;
+use crate::develop::{
+    sync::{self, SettingsGroup},
+    EditParams,
+};
+use crate::sidecar::{self, Sidecar};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    /// The groups the preset sets; everything else is left alone.
+    pub groups: Vec<SettingsGroup>,
+    /// Settings of `groups`; the rest stay at their defaults.
+    pub edits: EditParams,
+    /// File the preset was loaded from; its name needn't match `name`.
+    pub path: Option<PathBuf>,
+}
+
+impl Preset {
+    /// A preset of `groups` taken from `edits`.
+    pub fn new(name: &str, edits: &EditParams, groups: &[SettingsGroup]) -> Self {
+        Self {
+            name: name.trim().to_string(),
+            groups: groups.to_vec(),
+            edits: sync::paste(edits, &EditParams::default(), groups),
+            path: None,
+        }
+    }
+
+    /// `edits` with the preset applied.
+    pub fn apply(&self, edits: &EditParams) -> EditParams {
+        sync::paste(&self.edits, edits, &self.groups)
+    }
+
+    pub fn to_xmp(&self) -> String {
+        let mut xmp = sidecar::to_xmp(&Sidecar { edits: self.edits.clone(), ..Default::default() });
+        let groups: Vec<String> = self.groups.iter().map(|g| format!("{:?}", g)).collect();
+        sidecar::set_property(&mut xmp, "photoflow:PresetName", Some(&self.name));
+        sidecar::set_property(&mut xmp, "photoflow:PresetGroups", Some(&groups.join(",")));
+        xmp
+    }
+
+    /// Reads a preset file. Without a stored name the file name is used.
+    pub fn parse(xmp: &str, file_name: &str) -> Self {
+        let groups = sidecar::property(xmp, "photoflow:PresetGroups")
+            .map(|groups| groups.split(',').filter_map(|g| SettingsGroup::parse(g.trim())).collect())
+            // Presets written by hand may leave this out
+            .unwrap_or_else(|| SettingsGroup::ALL.to_vec());
+        Self {
+            name: sidecar::property(xmp, "photoflow:PresetName")
+                .filter(|n| !n.trim().is_empty())
+                .unwrap_or_else(|| file_name.to_string()),
+            groups,
+            edits: sidecar::parse(xmp).edits,
+            path: None,
+        }
+    }
+}
+
+/// Long edge of the preview of a preset on the current photo.
+pub const PRESET_THUMBNAIL_SIZE: u32 = 240;
+
+/// The saved presets and what the UI does with them.
+#[derive(Debug, Default)]
+pub struct PresetLibrary {
+    pub presets: Vec<Preset>,
+    /// Preset chosen in the develop panel.
+    pub selected: Option<Preset>,
+    /// The selected preset rendered onto the current photo.
+    pub preview: Option<Handle>,
+    /// Name for saving a new preset.
+    pub name: String,
+    /// Preset applied to photos as they are imported.
+    pub on_import: Option<Preset>,
+}
+
+impl PresetLibrary {
+    pub fn load() -> Self {
+        let mut library = Self::default();
+        if let Err(e) = library.reload() {
+            warn!("Failed to load presets: {:#}", e);
+        }
+        library
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.presets.iter().map(|p| p.name.clone()).collect()
+    }
+
+    pub fn find(&self, name: &str) -> Option<Preset> {
+        self.presets.iter().find(|p| p.name == name).cloned()
+    }
+
+    /// Re-reads the presets directory. Chosen presets that were deleted
+    /// are dropped.
+    pub fn reload(&mut self) -> Result<()> {
+        self.presets = load_all()?;
+        self.selected = self.selected.take().and_then(|p| self.find(&p.name));
+        self.on_import = self.on_import.take().and_then(|p| self.find(&p.name));
+        if self.selected.is_none() {
+            self.preview = None;
+        }
+        Ok(())
+    }
+}
+
+pub fn presets_dir() -> Result<PathBuf> {
+    Ok(config::config_dir()?.join("presets"))
+}
+
+/// File a preset called `name` is saved to. Characters that aren't safe
+/// in file names are replaced.
+fn preset_path(dir: &Path, name: &str) -> PathBuf {
+    let stem: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_alphanumeric() || " -_.".contains(c) { c } else { '_' })
+        .collect();
+    dir.join(format!("{}.xmp", stem.trim_start_matches('.')))
+}
+
+/// All presets in `dir`, sorted by name. Unreadable files are skipped.
+pub fn load_from(dir: &Path) -> Result<Vec<Preset>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut presets = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if !path.extension().is_some_and(|e| e.eq_ignore_ascii_case("xmp")) {
+            continue;
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(xmp) => {
+                let file_name = path.file_stem().unwrap_or_default().to_string_lossy();
+                presets.push(Preset { path: Some(path.clone()), ..Preset::parse(&xmp, &file_name) });
+            }
+            Err(e) => warn!("Skipping preset {}: {}", path.display(), e),
+        }
+    }
+    presets.sort_by_key(|p| p.name.to_lowercase());
+    Ok(presets)
+}
+
+pub fn load_all() -> Result<Vec<Preset>> {
+    load_from(&presets_dir()?)
+}
+
+/// Writes `preset` to `dir`, replacing one of the same name.
+pub fn save_to(dir: &Path, preset: &Preset) -> Result<PathBuf> {
+    if preset.name.is_empty() {
+        bail!("Presets need a name");
+    }
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = preset_path(dir, &preset.name);
+    debug!("Saving preset {}", path.display());
+    std::fs::write(&path, preset.to_xmp()).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+pub fn save(preset: &Preset) -> Result<PathBuf> {
+    save_to(&presets_dir()?, preset)
+}
+
+/// Deletes the file `preset` was loaded from.
+pub fn delete(preset: &Preset) -> Result<()> {
+    let Some(path) = &preset.path else {
+        bail!("Preset \"{}\" isn't saved", preset.name);
+    };
+    std::fs::remove_file(path).with_context(|| format!("Failed to delete {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let edits = EditParams { exposure: 0.5, contrast: 20.0, vibrance: 15.0, ..Default::default() };
+        let preset = Preset::new("Punchy / Film", &edits, &[SettingsGroup::Tone, SettingsGroup::Color]);
+        assert_eq!(preset.edits.exposure, 0.0);
+
+        let path = save_to(dir, &preset).unwrap();
+        assert_eq!(path.file_name().unwrap(), "Punchy _ Film.xmp");
+        let loaded = load_from(dir).unwrap();
+        assert_eq!(loaded, vec![Preset { path: Some(path), ..preset.clone() }]);
+
+        // Only the preset's groups are applied
+        let photo = EditParams { exposure: -1.0, contrast: -50.0, ..Default::default() };
+        let applied = loaded[0].apply(&photo);
+        assert_eq!((applied.exposure, applied.contrast, applied.vibrance), (-1.0, 20.0, 15.0));
+    }
+
+    #[test]
+    fn test_delete_file_of_renamed_preset() {
+        let dir = tempfile::tempdir().unwrap();
+        // Named inside the file differently from the file itself
+        let preset = Preset::new("Warm evening", &EditParams::default(), &[SettingsGroup::WhiteBalance]);
+        let file = dir.path().join("warm.xmp");
+        std::fs::write(&file, preset.to_xmp()).unwrap();
+
+        let loaded = load_from(dir.path()).unwrap();
+        assert_eq!(loaded[0].name, "Warm evening");
+        delete(&loaded[0]).unwrap();
+        assert!(!file.exists());
+        assert!(delete(&preset).is_err());
+    }
+}
@@ -4,8 +4,40 @@ use std::io::{Read, Seek, SeekFrom};
 use anyhow::{Result, Context};
 use tracing::debug;
 
-// Size threshold for using memory mapping (32MB)
-const MMAP_THRESHOLD: u64 = 32 * 1024 * 1024;
+/// Lowercase extensions of the RAW formats we recognise. Used where opening
+/// every file for magic-number detection would be too slow, e.g. when
+/// listing a directory.
+pub const RAW_EXTENSIONS: &[&str] = &["raf", "raw", "cr2", "cr3", "nef", "arw", "rw2", "dng"];
+
+/// Whether `path` has one of the [`RAW_EXTENSIONS`].
+pub fn has_raw_extension(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| RAW_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Lowercase extensions of the non-RAW formats `StandardProcessor` handles.
+pub const STANDARD_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "tif", "tiff", "webp", "exr"];
+
+/// Whether `path` looks like an image we can open, judging by extension.
+pub fn has_image_extension(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| {
+            let ext = ext.to_string_lossy().to_lowercase();
+            STANDARD_EXTENSIONS.contains(&ext.as_str()) || RAW_EXTENSIONS.contains(&ext.as_str())
+        })
+        .unwrap_or(false)
+}
+
+/// Whether `path` holds an image, judging by its contents. RAW formats whose
+/// headers `detect_image_type` doesn't know are recognised by extension.
+pub fn is_image_file(path: &Path) -> bool {
+    match detect_image_type(path) {
+        Ok(ImageType::Unknown) => has_raw_extension(path),
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum ImageType {
@@ -65,10 +97,11 @@ pub fn detect_image_type(path: &Path) -> Result<ImageType> {
     
     // Check for TIFF (both little and big endian)
     if &buffer[0..4] == b"MM\x00*" || &buffer[0..4] == b"II*\x00" {
-        // Need to check deeper in the file for Nikon specific markers
-        let mut extended_buffer = [0u8; 4096];
+        // Need to check deeper in the file for Nikon specific markers; small
+        // TIFFs end sooner
+        let mut extended_buffer = Vec::with_capacity(4096);
         file.seek(SeekFrom::Start(0))?;
-        file.read_exact(&mut extended_buffer)?;
+        file.take(4096).read_to_end(&mut extended_buffer)?;
         
         if extended_buffer.windows(4).any(|window| window == b"NIKON") {
             debug!("Detected Nikon NEF format");
@@ -0,0 +1,219 @@
+//! Handling of clipped highlights in demosaiced, white-balanced RAW data.
+//!
+//! White balance scales the channels differently, so where the sensor
+//! saturated the channels end up at different levels and clipped whites
+//! turn pink. These modes decide what to put there instead.
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HighlightMode {
+    /// Cut every channel at the lowest saturation level: clipped areas
+    /// become neutral white.
+    #[default]
+    Clip,
+    /// Keep the brightness of the unclipped data but take the colour from
+    /// the clipped result, fading in near the clipping point.
+    Blend,
+    /// Rebuild clipped channels from the ones that still hold detail,
+    /// using the colour of nearby unclipped pixels.
+    Reconstruct,
+}
+
+impl HighlightMode {
+    pub const ALL: [HighlightMode; 3] = [HighlightMode::Clip, HighlightMode::Blend, HighlightMode::Reconstruct];
+
+    /// Inverse of `Display`.
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|mode| mode.to_string() == value)
+    }
+}
+
+impl fmt::Display for HighlightMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HighlightMode::Clip => "clip",
+            HighlightMode::Blend => "blend",
+            HighlightMode::Reconstruct => "reconstruct",
+        })
+    }
+}
+
+/// Fraction of a channel's saturation level from which it counts as clipped.
+const CLIPPED: f32 = 0.95;
+
+/// Side of the blocks nearby colour is averaged over for reconstruction.
+const BLOCK: usize = 16;
+
+/// Treats highlights in interleaved RGB data. `saturation` is the value each
+/// channel reaches where the sensor clipped, i.e. its white balance
+/// multiplier.
+pub fn recover(rgb: &mut [f32], width: usize, height: usize, saturation: [f32; 3], mode: HighlightMode) {
+    let white = saturation.iter().copied().fold(f32::INFINITY, f32::min);
+    match mode {
+        HighlightMode::Clip => {
+            for value in rgb.iter_mut() {
+                *value = value.min(white);
+            }
+        }
+        HighlightMode::Blend => {
+            for pixel in rgb.chunks_exact_mut(3) {
+                blend(pixel, saturation, white);
+            }
+        }
+        HighlightMode::Reconstruct => reconstruct(rgb, width, height, saturation, white),
+    }
+}
+
+/// How far into clipping the pixel is, from 0 (clear of it) to 1.
+fn clipping(pixel: &[f32], saturation: [f32; 3]) -> f32 {
+    let ratio = (0..3).map(|c| pixel[c] / saturation[c]).fold(0.0, f32::max);
+    let t = ((ratio - 0.9) / 0.1).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn blend(pixel: &mut [f32], saturation: [f32; 3], white: f32) {
+    let t = clipping(pixel, saturation);
+    if t == 0.0 {
+        return;
+    }
+    let clipped = [pixel[0].min(white), pixel[1].min(white), pixel[2].min(white)];
+    let clipped_mean = (clipped[0] + clipped[1] + clipped[2]) / 3.0;
+    let mean = (pixel[0] + pixel[1] + pixel[2]) / 3.0;
+    for c in 0..3 {
+        let blended = mean + clipped[c] - clipped_mean;
+        pixel[c] += (blended - pixel[c]) * t;
+    }
+}
+
+fn reconstruct(rgb: &mut [f32], width: usize, height: usize, saturation: [f32; 3], white: f32) {
+    let ratios = colour_ratios(rgb, width, height, saturation);
+    let blocks_x = width.div_ceil(BLOCK);
+
+    for (i, pixel) in rgb.chunks_exact_mut(3).enumerate() {
+        let clipped: [bool; 3] = std::array::from_fn(|c| pixel[c] >= saturation[c] * CLIPPED);
+        if !clipped.contains(&true) {
+            continue;
+        }
+        if !clipped.contains(&false) {
+            // Nothing left to rebuild from
+            blend(pixel, saturation, white);
+            continue;
+        }
+        let (x, y) = (i % width, i / width);
+        let ratio = ratios[(y / BLOCK) * blocks_x + x / BLOCK];
+        // Rebuild from the brightest channel that still has detail
+        let reference = (0..3).filter(|&c| !clipped[c]).max_by(|&a, &b| pixel[a].total_cmp(&pixel[b])).unwrap_or(1);
+        if ratio[reference] <= f32::EPSILON {
+            // The block's colour says nothing about the other channels
+            blend(pixel, saturation, white);
+            continue;
+        }
+        for c in (0..3).filter(|&c| clipped[c]) {
+            let estimate = pixel[reference] * ratio[c] / ratio[reference];
+            pixel[c] = pixel[c].max(estimate);
+        }
+    }
+}
+
+/// Average colour of each block's unclipped pixels, as channel / green.
+/// Blocks without any take the average of their neighbours.
+fn colour_ratios(rgb: &[f32], width: usize, height: usize, saturation: [f32; 3]) -> Vec<[f32; 3]> {
+    let (blocks_x, blocks_y) = (width.div_ceil(BLOCK), height.div_ceil(BLOCK));
+    let mut sums = vec![[0.0f32; 3]; blocks_x * blocks_y];
+    let mut counts = vec![0u32; blocks_x * blocks_y];
+    for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+        let unclipped = (0..3).all(|c| pixel[c] < saturation[c] * 0.9);
+        if unclipped && pixel[1] > 0.01 {
+            let block = (i / width / BLOCK) * blocks_x + (i % width) / BLOCK;
+            for c in 0..3 {
+                sums[block][c] += pixel[c] / pixel[1];
+            }
+            counts[block] += 1;
+        }
+    }
+    let mut ratios: Vec<Option<[f32; 3]>> = sums
+        .iter()
+        .zip(&counts)
+        .map(|(sum, &count)| (count > 0).then(|| sum.map(|s| s / count as f32)))
+        .collect();
+
+    // Spread known colours into blocks that are clipped throughout
+    for _ in 0..blocks_x.max(blocks_y) {
+        if ratios.iter().all(Option::is_some) {
+            break;
+        }
+        let previous = ratios.clone();
+        for (block, ratio) in ratios.iter_mut().enumerate().filter(|(_, r)| r.is_none()) {
+            let (bx, by) = ((block % blocks_x) as isize, (block / blocks_x) as isize);
+            let neighbours: Vec<[f32; 3]> = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                .iter()
+                .map(|(dx, dy)| (bx + dx, by + dy))
+                .filter(|&(x, y)| x >= 0 && y >= 0 && (x as usize) < blocks_x && (y as usize) < blocks_y)
+                .filter_map(|(x, y)| previous[y as usize * blocks_x + x as usize])
+                .collect();
+            if !neighbours.is_empty() {
+                let mut average = [0.0; 3];
+                for neighbour in &neighbours {
+                    for c in 0..3 {
+                        average[c] += neighbour[c] / neighbours.len() as f32;
+                    }
+                }
+                *ratio = Some(average);
+            }
+        }
+    }
+    ratios.into_iter().map(|r| r.unwrap_or([1.0; 3])).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clip_and_blend_are_neutral() {
+        let saturation = [2.0, 1.0, 1.5];
+        // A fully saturated photosite after white balance
+        let mut rgb = vec![2.0, 1.0, 1.5];
+        recover(&mut rgb, 1, 1, saturation, HighlightMode::Clip);
+        assert_eq!(rgb, vec![1.0, 1.0, 1.0]);
+
+        let mut rgb = vec![2.0, 1.0, 1.5];
+        recover(&mut rgb, 1, 1, saturation, HighlightMode::Blend);
+        assert!((rgb[0] - rgb[1]).abs() < 1e-5 && (rgb[1] - rgb[2]).abs() < 1e-5);
+        assert!(rgb[0] > 1.0);
+
+        // Well below clipping nothing changes
+        let mut rgb = vec![0.5, 0.3, 0.2];
+        recover(&mut rgb, 1, 1, saturation, HighlightMode::Blend);
+        assert_eq!(rgb, vec![0.5, 0.3, 0.2]);
+    }
+
+    #[test]
+    fn test_reconstruct_uses_nearby_colour() {
+        let saturation = [1.0, 2.0, 2.0];
+        let (width, height) = (32, 16);
+        // Left half: red is 1.5x green. Right half: red clipped, green brighter.
+        let mut rgb: Vec<f32> = (0..width * height)
+            .flat_map(|i| if i % width < 16 { [0.6, 0.4, 0.2] } else { [1.0, 0.9, 0.45] })
+            .collect();
+        recover(&mut rgb, width, height, saturation, HighlightMode::Reconstruct);
+        let right = &rgb[(width - 1) * 3..width * 3];
+        assert!((right[0] - 1.35).abs() < 1e-3);
+        assert_eq!(right[1], 0.9);
+        // Unclipped pixels are left alone
+        assert_eq!(&rgb[..3], &[0.6, 0.4, 0.2]);
+    }
+
+    #[test]
+    fn test_reconstruct_without_reference_colour() {
+        let saturation = [1.0, 1.0, 1.0];
+        let (width, height) = (32, 16);
+        // The unclipped colour has no blue, the clipped pixels only blue left
+        let mut rgb: Vec<f32> = (0..width * height)
+            .flat_map(|i| if i % width < 16 { [0.6, 0.4, 0.0] } else { [1.0, 1.0, 0.1] })
+            .collect();
+        recover(&mut rgb, width, height, saturation, HighlightMode::Reconstruct);
+        assert!(rgb.iter().all(|v| v.is_finite()));
+    }
+}
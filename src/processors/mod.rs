@@ -1,4 +1,5 @@
 pub mod raw;
+pub mod highlights;
 pub mod standard;
 pub mod detector;
 #[cfg(test)]
@@ -12,6 +13,7 @@ use tracing::{debug, error};
 /// Trait for image processors
 pub trait ImageProcessor {
     /// Check if this processor can handle the given file
+    #[allow(dead_code)]
     fn can_handle(&self, path: &Path) -> bool;
     
     /// Load and process the image
@@ -4,14 +4,47 @@ use image::DynamicImage;
 use rawloader::{decode_file, RawImageData};
 use tracing::{info, debug, error};
 
-use crate::photo::ExifData;
-use super::{ImageProcessor, detector::{self, ImageType}};
+use crate::photo;
+use super::{ImageProcessor, detector};
+use super::highlights::{self, HighlightMode};
 
-pub struct RawProcessor;
+pub struct RawProcessor {
+    /// Multipliers applied on top of the camera's as-shot white balance.
+    white_balance: [f32; 3],
+    highlight_mode: HighlightMode,
+}
 
 impl RawProcessor {
     pub fn new() -> Self {
-        RawProcessor
+        RawProcessor { white_balance: [1.0; 3], highlight_mode: HighlightMode::default() }
+    }
+
+    pub fn with_highlight_mode(mut self, mode: HighlightMode) -> Self {
+        self.highlight_mode = mode;
+        self
+    }
+
+    /// Adjusts the white balance relative to as shot. It is applied to the
+    /// sensor data before demosaicing, which avoids colour fringes that
+    /// correcting it afterwards would cause.
+    pub fn with_white_balance(mut self, multipliers: [f32; 3]) -> Self {
+        self.white_balance = multipliers;
+        self
+    }
+}
+
+/// Share of the red, green and blue photosites that reached the sensor's
+/// white level. Detail there is lost no matter how the file is developed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RawClipping {
+    pub red: f32,
+    pub green: f32,
+    pub blue: f32,
+}
+
+impl RawClipping {
+    pub fn is_clipped(&self) -> bool {
+        self.red > 0.0 || self.green > 0.0 || self.blue > 0.0
     }
 }
 
@@ -24,6 +57,13 @@ impl ImageProcessor for RawProcessor {
     }
     
     fn load_image(&self, path: &Path) -> Result<DynamicImage> {
+        self.decode(path).map(|(image, _)| image)
+    }
+}
+
+impl RawProcessor {
+    /// Decodes the RAW file, also reporting how much of the sensor clipped.
+    pub fn decode(&self, path: &Path) -> Result<(DynamicImage, RawClipping)> {
         info!("Loading RAW image: {}", path.display());
         
         if !path.exists() {
@@ -50,237 +90,279 @@ impl ImageProcessor for RawProcessor {
         
         // Convert raw image data to RGB with format-specific adjustments
         info!("Converting RAW data to RGB...");
-        // Update EXIF data from rawloader metadata
-        let _exif = ExifData {
-            make: Some(raw_image.make.clone()),
-            model: Some(raw_image.model.clone()),
-            exposure_time: None, // TODO: Add these from rawloader
-            f_number: None,
-            iso: None,
-            focal_length: None,
-            datetime: None,
+        // Floating point RAWs (e.g. some DNGs) use the same scale as the levels
+        let data: Vec<f32> = match raw_image.data {
+            RawImageData::Integer(data) => data.into_iter().map(f32::from).collect(),
+            RawImageData::Float(data) => data,
         };
-        
-        let rgb_data = match raw_image.data {
-            RawImageData::Integer(data) => {
-                debug!("Converting integer RAW data");
-                let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
-                
-                // Get black and white levels
-                let black_level = raw_image.blacklevels[0] as f32;
-                let white_level = raw_image.whitelevels[0] as f32;
-                let range = white_level - black_level;
-                debug!("Black level: {}, White level: {}, Range: {}", black_level, white_level, range);
-                
-                // Get white balance coefficients
-                let wb_coeffs = raw_image.wb_coeffs;
-                debug!("WB coeffs: R={}, G={}, B={}", wb_coeffs[0], wb_coeffs[1], wb_coeffs[2]);
-                
-                // Get CFA pattern info
-                let cfa = raw_image.cfa.clone();
-                debug!("CFA pattern: width={}, height={}", cfa.width, cfa.height);
-                debug!("CFA pattern string: {}", raw_image.cfa.name);
-                
-                // Sample some raw values
-                debug!("Raw value samples:");
-                for y in [0, height as usize / 2, height as usize - 1] {
-                    for x in [0, width as usize / 2, width as usize - 1] {
-                        let pixel_idx = y * width as usize + x;
-                        let raw_value = data[pixel_idx];
-                        debug!("  ({}, {}): {}", x, y, raw_value);
+
+        // Photosites per colour, and how many of them sat at the white level
+        let mut photosites = [0usize; 3];
+        let mut clipped = [0usize; 3];
+        // Where each channel ends up for a saturated photosite
+        let saturation: [f32; 3];
+
+        let rgb_data = if raw_image.cpp == 3 {
+            // Already demosaiced, like a linear DNG: only levels and white
+            // balance to apply, and the DNG's baseline exposure
+            debug!("Converting linear RAW data");
+            let black_level = raw_image.blacklevels[0] as f32;
+            let white_level = raw_image.whitelevels[0] as f32;
+            let range = white_level - black_level;
+            let wb_coeffs = as_shot_white_balance(raw_image.wb_coeffs);
+            let wb_coeffs: [f32; 3] = std::array::from_fn(|c| wb_coeffs[c] * self.white_balance[c]);
+            let gain = 2f32.powf(baseline_exposure(path));
+            saturation = wb_coeffs.map(|coeff| coeff * gain);
+            let mut rgb: Vec<f32> = data
+                .iter()
+                .enumerate()
+                .map(|(i, &raw_value)| {
+                    let color = i % 3;
+                    photosites[color] += 1;
+                    if raw_value >= white_level {
+                        clipped[color] += 1;
                     }
+                    ((raw_value - black_level) / range).clamp(0.0, 1.0) * saturation[color]
+                })
+                .collect();
+            highlights::recover(&mut rgb, width as usize, height as usize, saturation, self.highlight_mode);
+            rgb
+        } else {
+            debug!("Converting RAW data");
+            let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+            
+            // Get black and white levels
+            let black_level = raw_image.blacklevels[0] as f32;
+            let white_level = raw_image.whitelevels[0] as f32;
+            let range = white_level - black_level;
+            debug!("Black level: {}, White level: {}, Range: {}", black_level, white_level, range);
+            
+            // Get white balance coefficients
+            let wb_coeffs = as_shot_white_balance(raw_image.wb_coeffs);
+            debug!("WB coeffs: R={}, G={}, B={}", wb_coeffs[0], wb_coeffs[1], wb_coeffs[2]);
+            let wb_coeffs = [
+                wb_coeffs[0] * self.white_balance[0],
+                wb_coeffs[1] * self.white_balance[1],
+                wb_coeffs[2] * self.white_balance[2],
+            ];
+            saturation = wb_coeffs;
+            
+            // Get CFA pattern info
+            let cfa = raw_image.cfa.clone();
+            debug!("CFA pattern: width={}, height={}", cfa.width, cfa.height);
+            debug!("CFA pattern string: {}", raw_image.cfa.name);
+            
+            // Sample some raw values
+            debug!("Raw value samples:");
+            for y in [0, height as usize / 2, height as usize - 1] {
+                for x in [0, width as usize / 2, width as usize - 1] {
+                    let pixel_idx = y * width as usize + x;
+                    let raw_value = data[pixel_idx];
+                    debug!("  ({}, {}): {}", x, y, raw_value);
                 }
-                
-                // Create buffers for each color channel
-                let mut red = vec![0.0f32; (width * height) as usize];
-                let mut green = vec![0.0f32; (width * height) as usize];
-                let mut blue = vec![0.0f32; (width * height) as usize];
-                
-                // First pass: Fill in known values
-                for y in 0..height as usize {
-                    for x in 0..width as usize {
-                        let pixel_idx = y * width as usize + x;
-                        let raw_value = data[pixel_idx] as f32;
-                        
-                        // Normalize value using black and white levels
-                        let mut normalized = (raw_value - black_level) / range;
-                        normalized = normalized.clamp(0.0, 1.0);
-                        
-                        // For X-Trans sensors, the pattern repeats every 6x6 pixels
-                        let cfa_x = x % 6;
-                        let cfa_y = y % 6;
-                        
-                        // X-Trans pattern (0=R, 1=G, 2=B)
-                        let color = match (cfa_x, cfa_y) {
-                            // Row 0
-                            (0, 0) => 2, (1, 0) => 1, (2, 0) => 1, (3, 0) => 2, (4, 0) => 1, (5, 0) => 1,
-                            // Row 1
-                            (0, 1) => 1, (1, 1) => 2, (2, 1) => 0, (3, 1) => 1, (4, 1) => 0, (5, 1) => 2,
-                            // Row 2
-                            (0, 2) => 1, (1, 2) => 0, (2, 2) => 1, (3, 2) => 2, (4, 2) => 1, (5, 2) => 0,
-                            // Row 3
-                            (0, 3) => 2, (1, 3) => 1, (2, 3) => 1, (3, 3) => 2, (4, 3) => 1, (5, 3) => 1,
-                            // Row 4
-                            (0, 4) => 1, (1, 4) => 2, (2, 4) => 0, (3, 4) => 1, (4, 4) => 0, (5, 4) => 2,
-                            // Row 5
-                            (0, 5) => 1, (1, 5) => 0, (2, 5) => 1, (3, 5) => 2, (4, 5) => 1, (5, 5) => 0,
-                            _ => unreachable!()
-                        };
-                        
-                        // Apply white balance
-                        let wb_coeff = match color {
-                            0 => wb_coeffs[0], // Red
-                            1 => wb_coeffs[1], // Green
-                            2 => wb_coeffs[2], // Blue
-                            _ => 1.0,
-                        };
-                        
-                        let color_value = normalized * wb_coeff;
-                        
-                        // Store in appropriate channel
-                        match color {
-                            0 => red[pixel_idx] = color_value,
-                            1 => green[pixel_idx] = color_value,
-                            2 => blue[pixel_idx] = color_value,
-                            _ => {},
-                        }
+            }
+            
+            // Create buffers for each color channel
+            let mut red = vec![0.0f32; (width * height) as usize];
+            let mut green = vec![0.0f32; (width * height) as usize];
+            let mut blue = vec![0.0f32; (width * height) as usize];
+            
+            // First pass: Fill in known values
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    let pixel_idx = y * width as usize + x;
+                    let raw_value = data[pixel_idx] as f32;
+                    
+                    // Normalize value using black and white levels
+                    let mut normalized = (raw_value - black_level) / range;
+                    normalized = normalized.clamp(0.0, 1.0);
+                    
+                    // Colour of this photosite (0=R, 1=G, 2=B), from the sensor's
+                    // own pattern; a second green is counted as green
+                    let color = match cfa.color_at(y, x) {
+                        3 => 1,
+                        color => color,
+                    };
+                    
+                    photosites[color] += 1;
+                    if raw_value >= white_level {
+                        clipped[color] += 1;
                     }
-                }
-                
-                // Sample some normalized values
-                debug!("Normalized value samples after first pass:");
-                for y in [0, height as usize / 2, height as usize - 1] {
-                    for x in [0, width as usize / 2, width as usize - 1] {
-                        let pixel_idx = y * width as usize + x;
-                        debug!("  ({}, {}): R={:.3}, G={:.3}, B={:.3}", 
-                            x, y, red[pixel_idx], green[pixel_idx], blue[pixel_idx]);
+
+                    // Apply white balance
+                    let wb_coeff = match color {
+                        0 => wb_coeffs[0], // Red
+                        1 => wb_coeffs[1], // Green
+                        2 => wb_coeffs[2], // Blue
+                        _ => 1.0,
+                    };
+                    
+                    let color_value = normalized * wb_coeff;
+                    
+                    // Store in appropriate channel
+                    match color {
+                        0 => red[pixel_idx] = color_value,
+                        1 => green[pixel_idx] = color_value,
+                        2 => blue[pixel_idx] = color_value,
+                        _ => {},
                     }
                 }
-                
-                // Second pass: Simple bilinear interpolation for missing colors
-                for y in 1..(height as usize - 1) {
-                    for x in 1..(width as usize - 1) {
-                        let pixel_idx = y * width as usize + x;
-                        let cfa_x = x % cfa.width;
-                        let cfa_y = y % cfa.height;
-                        let color = cfa.color_at(cfa_x, cfa_y);
-                        
-                        // For each missing color at this pixel, average the neighbors
-                        match color {
-                            0 => { // Red pixel - interpolate G and B
-                                if green[pixel_idx] == 0.0 {
-                                    let neighbors = [
-                                        green[pixel_idx - 1],
-                                        green[pixel_idx + 1],
-                                        green[pixel_idx - width as usize],
-                                        green[pixel_idx + width as usize],
-                                    ];
-                                    let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
-                                    if valid_count > 0 {
-                                        green[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
-                                    }
+            }
+            
+            // Sample some normalized values
+            debug!("Normalized value samples after first pass:");
+            for y in [0, height as usize / 2, height as usize - 1] {
+                for x in [0, width as usize / 2, width as usize - 1] {
+                    let pixel_idx = y * width as usize + x;
+                    debug!("  ({}, {}): R={:.3}, G={:.3}, B={:.3}", 
+                        x, y, red[pixel_idx], green[pixel_idx], blue[pixel_idx]);
+                }
+            }
+            
+            // Second pass: Simple bilinear interpolation for missing colors
+            for y in 1..(height as usize - 1) {
+                for x in 1..(width as usize - 1) {
+                    let pixel_idx = y * width as usize + x;
+                    let color = match cfa.color_at(y, x) {
+                        3 => 1,
+                        color => color,
+                    };
+                    
+                    // For each missing color at this pixel, average the neighbors
+                    match color {
+                        0 => { // Red pixel - interpolate G and B
+                            if green[pixel_idx] == 0.0 {
+                                let neighbors = [
+                                    green[pixel_idx - 1],
+                                    green[pixel_idx + 1],
+                                    green[pixel_idx - width as usize],
+                                    green[pixel_idx + width as usize],
+                                ];
+                                let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
+                                if valid_count > 0 {
+                                    green[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
                                 }
-                                if blue[pixel_idx] == 0.0 {
-                                    let neighbors = [
-                                        blue[pixel_idx - 1 - width as usize],
-                                        blue[pixel_idx - 1 + width as usize],
-                                        blue[pixel_idx + 1 - width as usize],
-                                        blue[pixel_idx + 1 + width as usize],
-                                    ];
-                                    let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
-                                    if valid_count > 0 {
-                                        blue[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
-                                    }
+                            }
+                            if blue[pixel_idx] == 0.0 {
+                                let neighbors = [
+                                    blue[pixel_idx - 1 - width as usize],
+                                    blue[pixel_idx - 1 + width as usize],
+                                    blue[pixel_idx + 1 - width as usize],
+                                    blue[pixel_idx + 1 + width as usize],
+                                ];
+                                let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
+                                if valid_count > 0 {
+                                    blue[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
                                 }
-                            },
-                            1 => { // Green pixel - interpolate R and B
-                                if red[pixel_idx] == 0.0 {
-                                    let neighbors = [
-                                        red[pixel_idx - 1],
-                                        red[pixel_idx + 1],
-                                        red[pixel_idx - width as usize],
-                                        red[pixel_idx + width as usize],
-                                    ];
-                                    let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
-                                    if valid_count > 0 {
-                                        red[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
-                                    }
+                            }
+                        },
+                        1 => { // Green pixel - interpolate R and B
+                            if red[pixel_idx] == 0.0 {
+                                let neighbors = [
+                                    red[pixel_idx - 1],
+                                    red[pixel_idx + 1],
+                                    red[pixel_idx - width as usize],
+                                    red[pixel_idx + width as usize],
+                                ];
+                                let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
+                                if valid_count > 0 {
+                                    red[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
                                 }
-                                if blue[pixel_idx] == 0.0 {
-                                    let neighbors = [
-                                        blue[pixel_idx - 1],
-                                        blue[pixel_idx + 1],
-                                        blue[pixel_idx - width as usize],
-                                        blue[pixel_idx + width as usize],
-                                    ];
-                                    let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
-                                    if valid_count > 0 {
-                                        blue[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
-                                    }
+                            }
+                            if blue[pixel_idx] == 0.0 {
+                                let neighbors = [
+                                    blue[pixel_idx - 1],
+                                    blue[pixel_idx + 1],
+                                    blue[pixel_idx - width as usize],
+                                    blue[pixel_idx + width as usize],
+                                ];
+                                let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
+                                if valid_count > 0 {
+                                    blue[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
                                 }
-                            },
-                            2 => { // Blue pixel - interpolate R and G
-                                if red[pixel_idx] == 0.0 {
-                                    let neighbors = [
-                                        red[pixel_idx - 1 - width as usize],
-                                        red[pixel_idx - 1 + width as usize],
-                                        red[pixel_idx + 1 - width as usize],
-                                        red[pixel_idx + 1 + width as usize],
-                                    ];
-                                    let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
-                                    if valid_count > 0 {
-                                        red[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
-                                    }
+                            }
+                        },
+                        2 => { // Blue pixel - interpolate R and G
+                            if red[pixel_idx] == 0.0 {
+                                let neighbors = [
+                                    red[pixel_idx - 1 - width as usize],
+                                    red[pixel_idx - 1 + width as usize],
+                                    red[pixel_idx + 1 - width as usize],
+                                    red[pixel_idx + 1 + width as usize],
+                                ];
+                                let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
+                                if valid_count > 0 {
+                                    red[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
                                 }
-                                if green[pixel_idx] == 0.0 {
-                                    let neighbors = [
-                                        green[pixel_idx - 1],
-                                        green[pixel_idx + 1],
-                                        green[pixel_idx - width as usize],
-                                        green[pixel_idx + width as usize],
-                                    ];
-                                    let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
-                                    if valid_count > 0 {
-                                        green[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
-                                    }
+                            }
+                            if green[pixel_idx] == 0.0 {
+                                let neighbors = [
+                                    green[pixel_idx - 1],
+                                    green[pixel_idx + 1],
+                                    green[pixel_idx - width as usize],
+                                    green[pixel_idx + width as usize],
+                                ];
+                                let valid_count = neighbors.iter().filter(|&&v| v > 0.0).count();
+                                if valid_count > 0 {
+                                    green[pixel_idx] = neighbors.iter().filter(|&&v| v > 0.0).sum::<f32>() / valid_count as f32;
                                 }
-                            },
-                            _ => {},
-                        }
+                            }
+                        },
+                        _ => {},
                     }
                 }
-                
-                // Sample some normalized values after interpolation
-                debug!("Normalized value samples after interpolation:");
-                for y in [0, height as usize / 2, height as usize - 1] {
-                    for x in [0, width as usize / 2, width as usize - 1] {
-                        let pixel_idx = y * width as usize + x;
-                        debug!("  ({}, {}): R={:.3}, G={:.3}, B={:.3}", 
-                            x, y, red[pixel_idx], green[pixel_idx], blue[pixel_idx]);
-                    }
-                }
-                
-                // Final pass: Convert to RGB bytes with gamma correction
-                let gamma = 2.2;
-                for i in 0..(width * height) as usize {
-                    let r = (red[i].powf(1.0 / gamma) * 255.0) as u8;
-                    let g = (green[i].powf(1.0 / gamma) * 255.0) as u8;
-                    let b = (blue[i].powf(1.0 / gamma) * 255.0) as u8;
-                    rgb.extend_from_slice(&[r, g, b]);
+            }
+            
+            // Sample some normalized values after interpolation
+            debug!("Normalized value samples after interpolation:");
+            for y in [0, height as usize / 2, height as usize - 1] {
+                for x in [0, width as usize / 2, width as usize - 1] {
+                    let pixel_idx = y * width as usize + x;
+                    debug!("  ({}, {}): R={:.3}, G={:.3}, B={:.3}", 
+                        x, y, red[pixel_idx], green[pixel_idx], blue[pixel_idx]);
                 }
-                rgb
-            },
-            RawImageData::Float(_data) => {
-                // Similar process for float data
-                vec![0; (width * height * 3) as usize] // TODO: Implement float handling
-            },
+            }
+            
+            // Final pass: interleave the channels and treat clipped highlights.
+            // Values stay linear (and may exceed 1.0 after white balance); the
+            // display and export paths apply the transfer curve when quantising.
+            for i in 0..(width * height) as usize {
+                rgb.extend_from_slice(&[red[i], green[i], blue[i]]);
+            }
+            highlights::recover(&mut rgb, width as usize, height as usize, saturation, self.highlight_mode);
+            rgb
         };
         
-        debug!("Creating RGB image from RAW data");
-        let rgb_image = image::RgbImage::from_raw(width, height, rgb_data)
+        debug!("Creating linear RGB image from RAW data");
+        let rgb_image = image::Rgb32FImage::from_raw(width, height, rgb_data)
             .context("Failed to create image from raw data")?;
             
         debug!("Successfully created RGB image: {}x{}", width, height);
-        Ok(DynamicImage::ImageRgb8(rgb_image))
+        let share = |c: usize| clipped[c] as f32 / photosites[c].max(1) as f32;
+        let clipping = RawClipping { red: share(0), green: share(1), blue: share(2) };
+        Ok((DynamicImage::ImageRgb32F(rgb_image), clipping))
+    }
+}
+
+/// Stops a DNG asks to be brightened by, zero for other files.
+fn baseline_exposure(path: &Path) -> f32 {
+    let tag = exif::Tag(exif::Context::Tiff, 50730);
+    photo::read_exif_fields(path)
+        .ok()
+        .and_then(|fields| match fields.get_field(tag, exif::In::PRIMARY).map(|field| &field.value) {
+            Some(exif::Value::SRational(values)) => values.first().map(|value| value.to_f64() as f32),
+            _ => None,
+        })
+        .unwrap_or(0.0)
+}
+
+/// The camera's white balance multipliers scaled so green is 1. Cameras
+/// that don't record one (NaN or zero coefficients) get a neutral balance.
+fn as_shot_white_balance(coeffs: [f32; 4]) -> [f32; 3] {
+    let green = coeffs[1];
+    if !(green.is_finite() && green > 0.0) {
+        return [1.0; 3];
     }
+    let scaled = |c: f32| if c.is_finite() && c > 0.0 { c / green } else { 1.0 };
+    [scaled(coeffs[0]), 1.0, scaled(coeffs[2])]
 }
@@ -1,10 +1,13 @@
 use std::path::Path;
 use anyhow::{Context, Result};
-use image::DynamicImage;
-use tracing::{info, debug};
+use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageResult};
+use tracing::{info, debug, warn};
 use std::fs::File;
 use memmap2::Mmap;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Cursor, Seek};
+
+use crate::color;
 
 // Size threshold for using memory mapping (32MB)
 const MMAP_THRESHOLD: u64 = 32 * 1024 * 1024;
@@ -41,15 +44,45 @@ impl ImageProcessor for StandardProcessor {
         let metadata = file.metadata()?;
         
         // Use memory mapping for large files
-        if metadata.len() > MMAP_THRESHOLD {
+        let (image, profile) = if metadata.len() > MMAP_THRESHOLD {
             debug!("Using memory mapping for large image: {} bytes", metadata.len());
             let mmap = unsafe { Mmap::map(&file)? };
-            image::load_from_memory(&mmap).context("Failed to load image from memory map")
+            decode(Cursor::new(&mmap[..]), image::guess_format(&mmap)?)
+                .context("Failed to load image from memory map")?
         } else {
             // Use buffered reader for smaller files
             let reader = BufReader::new(file);
-            image::load(reader, image::ImageFormat::from_path(path)?)
-                .context("Failed to load image from file")
+            decode(reader, ImageFormat::from_path(path)?)
+                .context("Failed to load image from file")?
+        };
+
+        // Edits work in linear sRGB; other colour spaces are converted
+        let Some(icc) = profile else {
+            return Ok(image);
+        };
+        match color::to_working(&image, &icc) {
+            Ok(Some(linear)) => Ok(DynamicImage::ImageRgb32F(linear)),
+            Ok(None) => Ok(image),
+            Err(e) => {
+                warn!("Ignoring embedded profile of {}: {:#}", path.display(), e);
+                Ok(image)
+            }
         }
     }
 }
+
+/// Decodes an image along with its embedded ICC profile, for the formats
+/// that can carry one.
+fn decode<R: BufRead + Seek>(reader: R, format: ImageFormat) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    fn with_profile<'a>(mut decoder: impl ImageDecoder<'a>) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+        let profile = decoder.icc_profile();
+        Ok((DynamicImage::from_decoder(decoder)?, profile))
+    }
+    match format {
+        ImageFormat::Jpeg => with_profile(JpegDecoder::new(reader)?),
+        ImageFormat::Png => with_profile(PngDecoder::new(reader)?),
+        ImageFormat::Tiff => with_profile(TiffDecoder::new(reader)?),
+        ImageFormat::WebP => with_profile(WebPDecoder::new(reader)?),
+        _ => Ok((image::load(reader, format)?, None)),
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use thiserror::Error;
+
+use crate::fileops;
+use crate::sidecar::CopyIndex;
+use crate::photo::ExifData;
+
+/// Errors produced while parsing a naming template.
+#[derive(Debug, Error, PartialEq)]
+pub enum TemplateError {
+    #[error("unknown field `{{{0}}}`")]
+    UnknownField(String),
+    #[error("`{{counter:{0}}}` needs a number of digits")]
+    InvalidWidth(String),
+    #[error("missing `}}`")]
+    UnterminatedField,
+    #[error("the template is empty")]
+    Empty,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Date,
+    Camera,
+    Make,
+    Model,
+    Lens,
+    Iso,
+    Focal,
+    Aperture,
+    /// The original file name without extension.
+    Name,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "year" => Some(Field::Year),
+            "month" => Some(Field::Month),
+            "day" => Some(Field::Day),
+            "hour" => Some(Field::Hour),
+            "minute" => Some(Field::Minute),
+            "second" => Some(Field::Second),
+            "date" => Some(Field::Date),
+            "camera" => Some(Field::Camera),
+            "make" => Some(Field::Make),
+            "model" => Some(Field::Model),
+            "lens" => Some(Field::Lens),
+            "iso" => Some(Field::Iso),
+            "focal" => Some(Field::Focal),
+            "aperture" => Some(Field::Aperture),
+            "name" | "original" => Some(Field::Name),
+            _ => None,
+        }
+    }
+
+    fn value(self, original: &Path, exif: Option<&ExifData>) -> Option<String> {
+        // `datetime` is formatted as "YYYY-MM-DD HH:MM:SS"
+        let datetime = |range: std::ops::Range<usize>| {
+            exif.and_then(|e| e.datetime.as_deref()).and_then(|dt| dt.get(range)).map(str::to_string)
+        };
+        match self {
+            Field::Year => datetime(0..4),
+            Field::Month => datetime(5..7),
+            Field::Day => datetime(8..10),
+            Field::Hour => datetime(11..13),
+            Field::Minute => datetime(14..16),
+            Field::Second => datetime(17..19),
+            Field::Date => datetime(0..10),
+            Field::Camera => exif?.camera(),
+            Field::Make => exif?.make.clone(),
+            Field::Model => exif?.model.clone(),
+            Field::Lens => exif?.lens.clone(),
+            Field::Iso => exif?.iso.map(|iso| iso.to_string()),
+            Field::Focal => exif?.focal_length.map(|f| format!("{}mm", f.round())),
+            Field::Aperture => exif?.f_number.map(|f| format!("f{}", f)),
+            Field::Name => original.file_stem().map(|s| s.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+    /// Sequence number, zero-padded to `width` digits.
+    Counter { width: usize },
+}
+
+/// A file naming template such as `{year}-{month}-{day}_client_{counter:4}`.
+///
+/// Text outside braces is copied as is. Fields are EXIF values (date parts,
+/// `camera`, `lens`, `iso`, ...), the original `name`, or a `counter`.
+/// Fields the photo has no value for render as `unknown`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            if open > 0 {
+                parts.push(Part::Text(rest[..open].to_string()));
+            }
+            let close = rest[open..].find('}').ok_or(TemplateError::UnterminatedField)? + open;
+            let field = rest[open + 1..close].trim();
+            parts.push(match field.split_once(':') {
+                Some((name, width)) if name.eq_ignore_ascii_case("counter") => Part::Counter {
+                    width: width.trim().parse().map_err(|_| TemplateError::InvalidWidth(width.to_string()))?,
+                },
+                _ if field.eq_ignore_ascii_case("counter") => Part::Counter { width: 1 },
+                _ => Part::Field(Field::from_name(field).ok_or_else(|| TemplateError::UnknownField(field.to_string()))?),
+            });
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+
+        if parts.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        Ok(Self { parts })
+    }
+
+    /// Renders the template for one photo. The result has no extension.
+    pub fn render(&self, original: &Path, exif: Option<&ExifData>, counter: usize) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => name.push_str(text),
+                Part::Field(field) => {
+                    let value = field.value(original, exif).unwrap_or_else(|| "unknown".to_string());
+                    name.push_str(&sanitize(&value));
+                }
+                Part::Counter { width } => name.push_str(&format!("{:0width$}", counter, width = width)),
+            }
+        }
+        name
+    }
+}
+
+/// Keeps EXIF values like `XF16-55mmF2.8 R LM WR` or `1/250` from turning
+/// into paths.
+fn sanitize(value: &str) -> String {
+    value
+        .trim()
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '\0') { '-' } else { c })
+        .collect()
+}
+
+/// What batch renaming would do to one photo.
+#[derive(Debug, Clone)]
+pub struct RenamePreview {
+    /// The photo's primary file.
+    pub from: PathBuf,
+    /// Its new name, with extension.
+    pub to: String,
+    /// All files of the photo (representations and sidecars) and their new paths.
+    pub moves: Vec<(PathBuf, PathBuf)>,
+    /// Why the photo can't be renamed like this.
+    pub conflict: Option<String>,
+}
+
+/// Works out the new names for `photos` (representations plus metadata, in
+/// counter order) and flags names that clash with each other or with files
+/// already on disk.
+pub fn preview(template: &Template, photos: &[(&[PathBuf], Option<&ExifData>)], first_counter: usize) -> Vec<RenamePreview> {
+    let copies = CopyIndex::scan(photos.iter().map(|(representations, _)| &representations[0]));
+    let mut previews: Vec<RenamePreview> = photos
+        .iter()
+        .enumerate()
+        .map(|(i, (representations, exif))| {
+            let from = representations[0].clone();
+            let stem = template.render(&from, *exif, first_counter + i);
+            let files = fileops::files_of(representations, &copies);
+            let (moves, conflict) = match fileops::rename_plan(&files, &stem) {
+                Ok(moves) => (moves, None),
+                Err(e) => (Vec::new(), Some(e.to_string())),
+            };
+            let to = moves.first().and_then(|(_, to)| to.file_name()).map_or(stem, |n| n.to_string_lossy().into_owned());
+            RenamePreview { from, to, moves, conflict }
+        })
+        .collect();
+
+    let sources: HashSet<&PathBuf> = previews.iter().flat_map(|p| p.moves.iter().map(|(from, _)| from)).collect();
+    let mut claimed: HashMap<PathBuf, usize> = HashMap::new();
+    let mut conflicts = Vec::new();
+    for (i, preview) in previews.iter().enumerate() {
+        for (from, to) in &preview.moves {
+            if let Some(&other) = claimed.get(to) {
+                conflicts.push((i, format!("Same name as {}", file_name(&previews[other].from))));
+                conflicts.push((other, format!("Same name as {}", file_name(&preview.from))));
+            } else if from != to && to.exists() && !sources.contains(to) {
+                conflicts.push((i, format!("{} already exists", file_name(to))));
+            }
+            claimed.insert(to.clone(), i);
+        }
+    }
+    for (i, conflict) in conflicts {
+        previews[i].conflict.get_or_insert(conflict);
+    }
+    previews
+}
+
+/// Renames everything in `previews` in one go. If any rename fails, the
+/// files already renamed are put back.
+pub fn apply(previews: &[RenamePreview]) -> Result<Vec<(PathBuf, PathBuf)>> {
+    if let Some(conflict) = previews.iter().find_map(|p| p.conflict.as_ref()) {
+        bail!("Can't rename: {}", conflict);
+    }
+    let plan: Vec<(PathBuf, PathBuf)> = previews
+        .iter()
+        .flat_map(|p| p.moves.iter().cloned())
+        .filter(|(from, to)| from != to)
+        .collect();
+    fileops::apply_moves(&plan)?;
+    Ok(plan)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let exif = ExifData {
+            make: Some("FUJIFILM".to_string()),
+            model: Some("X-T3".to_string()),
+            lens: Some("XF16-55mmF2.8 R LM WR".to_string()),
+            iso: Some(640),
+            datetime: Some("2023-05-14 18:02:10".to_string()),
+            ..Default::default()
+        };
+        let original = Path::new("/photos/DSCF1234.RAF");
+
+        let template = Template::parse("{year}-{month}-{day}_smith_{counter:4}").unwrap();
+        assert_eq!(template.render(original, Some(&exif), 7), "2023-05-14_smith_0007");
+
+        let template = Template::parse("{name} {camera} ISO{iso} {lens}").unwrap();
+        assert_eq!(template.render(original, Some(&exif), 1), "DSCF1234 FUJIFILM X-T3 ISO640 XF16-55mmF2.8 R LM WR");
+        assert_eq!(Template::parse("{date}").unwrap().render(original, None, 1), "unknown");
+
+        assert_eq!(Template::parse("{shutter}"), Err(TemplateError::UnknownField("shutter".to_string())));
+        assert_eq!(Template::parse("{counter:x}"), Err(TemplateError::InvalidWidth("x".to_string())));
+        assert_eq!(Template::parse("{year"), Err(TemplateError::UnterminatedField));
+    }
+
+    #[test]
+    fn test_preview_conflicts() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        for name in ["a.jpg", "b.jpg", "c.jpg", "taken.jpg"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        let (a, b, c) = ([dir.join("a.jpg")], [dir.join("b.jpg")], [dir.join("c.jpg")]);
+
+        // No counter: every photo gets the same name
+        let same = preview(&Template::parse("shoot").unwrap(), &[(&a, None), (&b, None)], 1);
+        assert!(same.iter().all(|p| p.conflict.is_some()));
+
+        let taken = preview(&Template::parse("taken").unwrap(), &[(&a, None)], 1);
+        assert_eq!(taken[0].conflict.as_deref(), Some("taken.jpg already exists"));
+
+        // Shifting names along the batch is fine since the old names are freed
+        let shifted = preview(&Template::parse("{counter}").unwrap(), &[(&a, None), (&b, None), (&c, None)], 1);
+        assert!(shifted.iter().all(|p| p.conflict.is_none()));
+        let numbered = apply(&shifted).unwrap();
+        assert_eq!(numbered.len(), 3);
+        let rotate: Vec<[PathBuf; 1]> = ["1.jpg", "2.jpg", "3.jpg"].iter().map(|n| [dir.join(n)]).collect();
+        let template = Template::parse("{counter}").unwrap();
+        let rotated = preview(&template, &[(&rotate[2], None), (&rotate[0], None), (&rotate[1], None)], 1);
+        apply(&rotated).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("1.jpg")).unwrap(), "c.jpg");
+        assert_eq!(std::fs::read_to_string(dir.join("2.jpg")).unwrap(), "a.jpg");
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use tracing::debug;
+
+use crate::develop::{
+    crop::{Crop, CropAspect, CropEdge},
+    lens::LensField,
+    sharpen::{SharpenField, Sharpening},
+    tone::{Curve, CurveChannel, LevelsField},
+    Adjustment, EditParams,
+};
+use crate::processors::highlights::HighlightMode;
+
+/// Library metadata kept in an XMP sidecar next to the image file.
+///
+/// Only the handful of properties PhotoFlow understands are read; anything
+/// else written by other tools is ignored.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sidecar {
+    pub rating: Option<u8>,
+    pub label: Option<String>,
+    pub keywords: Vec<String>,
+    /// Develop settings, stored in PhotoFlow's own namespace.
+    pub edits: EditParams,
+    /// Saved versions of `edits`, oldest first.
+    pub snapshots: Vec<Snapshot>,
+}
+
+/// A named version of a photo's develop settings to go back to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub name: String,
+    pub edits: EditParams,
+}
+
+/// Candidate sidecar locations for an image, in lookup order.
+///
+/// `DSCF1234.xmp` is the convention used by Lightroom and Capture One and is
+/// shared by RAW+JPEG pairs; `DSCF1234.RAF.xmp` is what darktable writes.
+fn candidates(path: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![path.with_extension("xmp")];
+    if let Some(name) = path.file_name() {
+        let mut full = name.to_os_string();
+        full.push(".xmp");
+        paths.push(path.with_file_name(full));
+    }
+    paths
+}
+
+/// Returns the existing sidecar for `path`, if any.
+pub fn find_sidecar(path: &Path) -> Option<PathBuf> {
+    candidates(path).into_iter().find(|p| p.is_file())
+}
+
+/// All sidecars that exist for `path`, in case both conventions are in use.
+pub fn existing_sidecars(path: &Path) -> Vec<PathBuf> {
+    candidates(path).into_iter().filter(|p| p.is_file()).collect()
+}
+
+/// The sidecar file `path` reads from and writes to: the existing one, or
+/// where a new one goes.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    find_sidecar(path).unwrap_or_else(|| path.with_extension("xmp"))
+}
+
+/// Reads the sidecar for `path`. Returns `Ok(None)` when there is none.
+pub fn read(path: &Path) -> Result<Option<Sidecar>> {
+    match find_sidecar(path) {
+        Some(sidecar_path) => read_file(&sidecar_path),
+        None => Ok(None),
+    }
+}
+
+/// Reads the sidecar file at `sidecar_path`. Returns `Ok(None)` when it
+/// doesn't exist.
+pub fn read_file(sidecar_path: &Path) -> Result<Option<Sidecar>> {
+    debug!("Reading sidecar: {}", sidecar_path.display());
+    match std::fs::read_to_string(sidecar_path) {
+        Ok(xmp) => Ok(Some(parse(&xmp))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read sidecar {}", sidecar_path.display())),
+    }
+}
+
+/// Writes `sidecar` to the sidecar file for `path`, creating one next to it
+/// if none exists. Existing files are patched in place so that anything
+/// other tools stored there survives.
+pub fn write(path: &Path, sidecar: &Sidecar) -> Result<()> {
+    write_file(&sidecar_path(path), sidecar)
+}
+
+/// Writes `sidecar` to the sidecar file at `sidecar_path`, like [`write`].
+pub fn write_file(sidecar_path: &Path, sidecar: &Sidecar) -> Result<()> {
+    let existing = match std::fs::read_to_string(sidecar_path) {
+        Ok(xmp) => xmp,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => EMPTY_PACKET.to_string(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read sidecar {}", sidecar_path.display())),
+    };
+
+    debug!("Writing sidecar: {}", sidecar_path.display());
+    std::fs::write(sidecar_path, patch(&existing, sidecar))
+        .with_context(|| format!("Failed to write sidecar {}", sidecar_path.display()))
+}
+
+/// Puts the sidecar file at `sidecar_path` back to `state`, deleting it
+/// for `None`.
+pub fn restore_file(sidecar_path: &Path, state: Option<&Sidecar>) -> Result<()> {
+    match state {
+        Some(sidecar) => write_file(sidecar_path, sidecar),
+        None => match std::fs::remove_file(sidecar_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove sidecar {}", sidecar_path.display()))
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+/// Key of virtual copy `number` of `path`: `DSCF1234.RAF` becomes
+/// `DSCF1234_01.RAF`. Like darktable's duplicates, the key doesn't exist
+/// on disk and the copy lives only in its sidecar, `DSCF1234_01.RAF.xmp`.
+pub fn copy_path(path: &Path, number: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}_{:02}.{}", stem, number, ext.to_string_lossy()),
+        None => format!("{}_{:02}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+/// Splits a virtual copy key into the original's stem and the copy
+/// number, e.g. `("DSCF1234", 1)` for `DSCF1234_01.RAF`.
+fn copy_number(key: &Path) -> Option<(String, u32)> {
+    let stem = key.file_stem()?.to_str()?;
+    let (source_stem, number) = stem.rsplit_once('_')?;
+    if number.len() < 2 || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((source_stem.to_string(), number.parse().ok()?))
+}
+
+/// The image a virtual copy key belongs to, or `None` if `key` isn't one.
+pub fn copy_source(key: &Path) -> Option<PathBuf> {
+    if key.exists() {
+        return None;
+    }
+    let (stem, _) = copy_number(key)?;
+    let name = match key.extension() {
+        Some(ext) => format!("{}.{}", stem, ext.to_string_lossy()),
+        None => stem,
+    };
+    Some(key.with_file_name(name)).filter(|source| source.is_file())
+}
+
+/// The virtual copies in some folders, found by reading each folder once
+/// rather than once per photo.
+#[derive(Debug, Default)]
+pub struct CopyIndex {
+    /// Keys by the image they copy, in order of their number.
+    copies: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl CopyIndex {
+    /// Reads the folders `paths` are in.
+    pub fn scan<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Self {
+        let mut dirs: Vec<PathBuf> = paths.into_iter().filter_map(|p| p.as_ref().parent().map(Path::to_path_buf)).collect();
+        dirs.sort();
+        dirs.dedup();
+
+        let mut copies: HashMap<PathBuf, Vec<(u32, PathBuf)>> = HashMap::new();
+        for dir in dirs {
+            let readable = if dir.as_os_str().is_empty() { Path::new(".") } else { dir.as_path() };
+            let Ok(entries) = std::fs::read_dir(readable) else {
+                continue;
+            };
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let file_name = entry.file_name();
+                let Some(key) = file_name.to_str().and_then(|name| name.strip_suffix(".xmp")).map(|key| dir.join(key)) else {
+                    continue;
+                };
+                let Some((stem, number)) = copy_number(&key) else {
+                    continue;
+                };
+                // A real file named like a copy isn't one
+                if key.exists() {
+                    continue;
+                }
+                let source = match key.extension() {
+                    Some(ext) => key.with_file_name(format!("{}.{}", stem, ext.to_string_lossy())),
+                    None => key.with_file_name(stem),
+                };
+                if copy_path(&source, number) == key {
+                    copies.entry(source).or_default().push((number, key));
+                }
+            }
+        }
+        let copies = copies
+            .into_iter()
+            .map(|(source, mut keys)| {
+                keys.sort();
+                (source, keys.into_iter().map(|(_, key)| key).collect())
+            })
+            .collect();
+        Self { copies }
+    }
+
+    /// Keys of the virtual copies of `path`, which must be in one of the
+    /// scanned folders.
+    pub fn of(&self, path: &Path) -> &[PathBuf] {
+        self.copies.get(path).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Creates a virtual copy of `path` holding `sidecar` and returns its key.
+pub fn create_copy(path: &Path, sidecar: &Sidecar) -> Result<PathBuf> {
+    let key = (1..)
+        .map(|number| copy_path(path, number))
+        .find(|key| !key.exists() && find_sidecar(key).is_none())
+        .expect("copy numbers are unbounded");
+    let sidecar_path = copy_sidecar(&key);
+    debug!("Creating virtual copy: {}", sidecar_path.display());
+    std::fs::write(&sidecar_path, to_xmp(sidecar))
+        .with_context(|| format!("Failed to write sidecar {}", sidecar_path.display()))?;
+    Ok(key)
+}
+
+/// The sidecar a virtual copy lives in: its key plus `.xmp`.
+pub fn copy_sidecar(key: &Path) -> PathBuf {
+    let mut name = key.file_name().unwrap_or_default().to_os_string();
+    name.push(".xmp");
+    key.with_file_name(name)
+}
+
+/// Sidecars of the virtual copies of `path`, which move with it.
+pub fn copy_sidecars(path: &Path, copies: &CopyIndex) -> Vec<PathBuf> {
+    copies.of(path).iter().flat_map(|key| existing_sidecars(key)).collect()
+}
+
+/// A fresh XMP packet holding `sidecar`, for files that aren't sidecars
+/// of an image.
+pub fn to_xmp(sidecar: &Sidecar) -> String {
+    patch(EMPTY_PACKET, sidecar)
+}
+
+const EMPTY_PACKET: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="PhotoFlow">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="">
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+const NS_XMP: (&str, &str) = ("xmp", "http://ns.adobe.com/xap/1.0/");
+const NS_DC: (&str, &str) = ("dc", "http://purl.org/dc/elements/1.1/");
+const NS_PHOTOFLOW: (&str, &str) = ("photoflow", "http://ns.photoflow.org/develop/1.0/");
+
+/// Writes develop settings as `photoflow:{prefix}{setting}` properties.
+/// Neutral values are left out so untouched photos stay clean.
+fn write_edits(xmp: &mut String, prefix: &str, edits: &EditParams) {
+    let name = |setting: &dyn std::fmt::Display| format!("photoflow:{}{}", prefix, setting);
+    for adjustment in Adjustment::ALL {
+        let value = Some(adjustment.get(edits)).filter(|v| *v != 0.0);
+        set_property(xmp, &name(&adjustment), value.map(|v| v.to_string()).as_deref());
+    }
+    let noise = edits.noise;
+    set_property(xmp, &name(&"LuminanceNoise"), noise.luminance.map(|v| v.to_string()).as_deref());
+    set_property(xmp, &name(&"ColorNoise"), noise.color.map(|v| v.to_string()).as_deref());
+
+    let sharpening = Some(&edits.sharpening).filter(|s| **s != Sharpening::default());
+    for field in SharpenField::ALL {
+        let value = sharpening.map(|s| field.get(s).to_string());
+        set_property(xmp, &name(&format!("Sharpen{}", field)), value.as_deref());
+    }
+
+    let mode = Some(edits.highlight_mode).filter(|m| *m != HighlightMode::default());
+    set_property(xmp, &name(&"HighlightMode"), mode.map(|m| m.to_string()).as_deref());
+
+    let lens = &edits.lens;
+    set_property(xmp, &name(&"LensProfile"), lens.ignore_profile.then_some("False"));
+    for field in LensField::ALL {
+        let value = field.get(lens).map(|v| v.to_string());
+        set_property(xmp, &name(&format!("Lens{}", field)), value.as_deref());
+    }
+
+    let crop = Some(&edits.crop).filter(|c| !c.is_default());
+    for edge in CropEdge::ALL {
+        let value = crop.map(|c| c.edge(edge).to_string());
+        set_property(xmp, &name(&format!("Crop{}", edge)), value.as_deref());
+    }
+    set_property(xmp, &name(&"CropAngle"), crop.map(|c| c.angle.to_string()).as_deref());
+    set_property(xmp, &name(&"CropAspect"), crop.map(|c| c.aspect.to_string()).as_deref());
+
+    for channel in CurveChannel::ALL {
+        let levels = Some(edits.levels.get(channel)).filter(|l| !l.is_identity());
+        for field in LevelsField::ALL {
+            let value = levels.map(|l| field.get(l).to_string());
+            set_property(xmp, &name(&format!("Levels{}{}", channel, field)), value.as_deref());
+        }
+    }
+    for channel in CurveChannel::ALL {
+        let curve = Some(edits.curve.get(channel)).filter(|c| !c.is_identity());
+        set_property(xmp, &name(&format!("Curve{}", channel)), curve.map(|c| c.to_string()).as_deref());
+    }
+}
+
+/// Applies the properties of `sidecar` to an existing XMP packet.
+fn patch(xmp: &str, sidecar: &Sidecar) -> String {
+    let mut xmp = open_description(xmp);
+    ensure_namespace(&mut xmp, NS_XMP);
+    ensure_namespace(&mut xmp, NS_DC);
+
+    set_property(&mut xmp, "xmp:Rating", sidecar.rating.map(|r| r.to_string()).as_deref());
+    set_property(&mut xmp, "xmp:Label", sidecar.label.as_deref());
+    set_list(&mut xmp, "dc:subject", &sidecar.keywords);
+
+    if !sidecar.edits.is_default() || !sidecar.snapshots.is_empty() {
+        ensure_namespace(&mut xmp, NS_PHOTOFLOW);
+    }
+    write_edits(&mut xmp, "", &sidecar.edits);
+
+    // Snapshots are numbered from 1; clear out any beyond the current count
+    let stored = (1..).take_while(|n| property(&xmp, &format!("photoflow:Snapshot{}Name", n)).is_some()).count();
+    for n in 1..=stored.max(sidecar.snapshots.len()) {
+        let snapshot = sidecar.snapshots.get(n - 1);
+        let prefix = format!("Snapshot{}", n);
+        set_property(&mut xmp, &format!("photoflow:{}Name", prefix), snapshot.map(|s| s.name.as_str()));
+        write_edits(&mut xmp, &prefix, snapshot.map_or(&EditParams::default(), |s| &s.edits));
+    }
+    xmp
+}
+
+/// Expands a self-closing `<rdf:Description .../>` so elements can be added to it.
+fn open_description(xmp: &str) -> String {
+    let Some(start) = xmp.find("<rdf:Description") else {
+        return EMPTY_PACKET.to_string();
+    };
+    let Some(end) = xmp[start..].find('>').map(|i| i + start) else {
+        return EMPTY_PACKET.to_string();
+    };
+    if xmp[..end].ends_with('/') {
+        format!("{}>\n  </rdf:Description>{}", &xmp[..end - 1], &xmp[end + 1..])
+    } else {
+        xmp.to_string()
+    }
+}
+
+/// Byte offset of the `>` closing the `<rdf:Description` start tag.
+fn description_tag_end(xmp: &str) -> usize {
+    let start = xmp.find("<rdf:Description").unwrap_or(0);
+    xmp[start..].find('>').map_or(xmp.len(), |i| i + start)
+}
+
+fn ensure_namespace(xmp: &mut String, (prefix, uri): (&str, &str)) {
+    if !xmp.contains(&format!("xmlns:{}=", prefix)) {
+        let end = description_tag_end(xmp);
+        xmp.insert_str(end, &format!("\n    xmlns:{}=\"{}\"", prefix, uri));
+    }
+}
+
+fn remove_property(xmp: &mut String, name: &str) {
+    let attr = format!("{}=\"", name);
+    if let Some(start) = xmp.find(&attr) {
+        if let Some(len) = xmp[start + attr.len()..].find('"') {
+            // Take the whitespace before the attribute with it
+            let trimmed = xmp[..start].trim_end().len();
+            xmp.replace_range(trimmed..start + attr.len() + len + 1, "");
+        }
+    }
+
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    if let Some(start) = xmp.find(&open) {
+        if let Some(end) = xmp[start..].find(&close).map(|i| i + start + close.len()) {
+            let trimmed = xmp[..start].trim_end().len();
+            xmp.replace_range(trimmed..end, "");
+        }
+    }
+}
+
+pub(crate) fn set_property(xmp: &mut String, name: &str, value: Option<&str>) {
+    remove_property(xmp, name);
+    if let Some(value) = value {
+        let end = description_tag_end(xmp);
+        xmp.insert_str(end, &format!("\n    {}=\"{}\"", name, escape(value)));
+    }
+}
+
+fn set_list(xmp: &mut String, name: &str, items: &[String]) {
+    remove_property(xmp, name);
+    if items.is_empty() {
+        return;
+    }
+
+    let mut element = format!("\n   <{}>\n    <rdf:Bag>", name);
+    for item in items {
+        element.push_str(&format!("\n     <rdf:li>{}</rdf:li>", escape(item)));
+    }
+    element.push_str(&format!("\n    </rdf:Bag>\n   </{}>", name));
+
+    let insert_at = description_tag_end(xmp) + 1;
+    xmp.insert_str(insert_at.min(xmp.len()), &element);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Extracts the properties we care about from an XMP packet.
+pub fn parse(xmp: &str) -> Sidecar {
+    let rating = property(xmp, "xmp:Rating")
+        .and_then(|v| v.trim().parse::<i32>().ok())
+        // -1 means "rejected" in Lightroom; treat it as unrated here
+        .filter(|r| (0..=5).contains(r))
+        .map(|r| r as u8);
+    let label = property(xmp, "xmp:Label").filter(|l| !l.is_empty());
+    let keywords = list_items(xmp, "dc:subject");
+
+    let edits = parse_edits(xmp, "");
+    let snapshots = (1..)
+        .map_while(|n| {
+            let prefix = format!("Snapshot{}", n);
+            let name = property(xmp, &format!("photoflow:{}Name", prefix))?;
+            Some(Snapshot { name, edits: parse_edits(xmp, &prefix) })
+        })
+        .collect();
+
+    Sidecar { rating, label, keywords, edits, snapshots }
+}
+
+/// Reads the develop settings written by [`write_edits`] with `prefix`.
+fn parse_edits(xmp: &str, prefix: &str) -> EditParams {
+    let name = |setting: &dyn std::fmt::Display| format!("photoflow:{}{}", prefix, setting);
+    let number = |setting: &dyn std::fmt::Display| property(xmp, &name(setting)).and_then(|v| v.trim().parse::<f32>().ok());
+
+    let mut edits = EditParams::default();
+    for adjustment in Adjustment::ALL {
+        if let Some(value) = number(&adjustment) {
+            adjustment.set(&mut edits, value);
+        }
+    }
+    edits.noise.luminance = number(&"LuminanceNoise").map(|v| v.clamp(0.0, 100.0));
+    edits.noise.color = number(&"ColorNoise").map(|v| v.clamp(0.0, 100.0));
+    for field in SharpenField::ALL {
+        if let Some(value) = number(&format!("Sharpen{}", field)) {
+            field.set(&mut edits.sharpening, value);
+        }
+    }
+    edits.highlight_mode = property(xmp, &name(&"HighlightMode"))
+        .and_then(|m| HighlightMode::parse(m.trim()))
+        .unwrap_or_default();
+    edits.lens.ignore_profile = property(xmp, &name(&"LensProfile")).is_some_and(|v| v.trim().eq_ignore_ascii_case("false"));
+    for field in LensField::ALL {
+        field.set(&mut edits.lens, number(&format!("Lens{}", field)));
+    }
+    edits.crop = parse_crop(xmp, prefix);
+    for channel in CurveChannel::ALL {
+        for field in LevelsField::ALL {
+            if let Some(value) = number(&format!("Levels{}{}", channel, field)) {
+                field.set(edits.levels.get_mut(channel), value);
+            }
+        }
+    }
+    for channel in CurveChannel::ALL {
+        if let Some(curve) = property(xmp, &name(&format!("Curve{}", channel))).and_then(|v| Curve::parse(&v)) {
+            *edits.curve.get_mut(channel) = curve;
+        }
+    }
+    edits
+}
+
+fn parse_crop(xmp: &str, prefix: &str) -> Crop {
+    let name = |setting: &str| format!("photoflow:{}{}", prefix, setting);
+    let number = |setting: &str| property(xmp, &name(setting)).and_then(|v| v.trim().parse::<f32>().ok());
+    let mut crop = Crop::default();
+    if let (Some(left), Some(top), Some(right), Some(bottom)) =
+        (number("CropLeft"), number("CropTop"), number("CropRight"), number("CropBottom"))
+    {
+        // Anything inverted or out of range is ignored rather than trusted
+        if 0.0 <= left && left < right && right <= 1.0 && 0.0 <= top && top < bottom && bottom <= 1.0 {
+            (crop.left, crop.top, crop.right, crop.bottom) = (left, top, right, bottom);
+        }
+    }
+    crop.angle = number("CropAngle").map_or(0.0, |a| a.clamp(-45.0, 45.0));
+    crop.aspect = property(xmp, &name("CropAspect")).and_then(|a| CropAspect::parse(a.trim())).unwrap_or_default();
+    crop
+}
+
+/// Finds a simple property written either as an attribute
+/// (`xmp:Rating="3"`) or as an element (`<xmp:Rating>3</xmp:Rating>`).
+pub(crate) fn property(xmp: &str, name: &str) -> Option<String> {
+    let attr = format!("{}=\"", name);
+    if let Some(start) = xmp.find(&attr) {
+        let rest = &xmp[start + attr.len()..];
+        let end = rest.find('"')?;
+        return Some(unescape(&rest[..end]));
+    }
+
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = xmp.find(&open)? + open.len();
+    let end = xmp[start..].find(&close)? + start;
+    Some(unescape(xmp[start..end].trim()))
+}
+
+/// Collects the `rdf:li` entries of a bag/seq property such as `dc:subject`.
+fn list_items(xmp: &str, name: &str) -> Vec<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let Some(start) = xmp.find(&open).map(|i| i + open.len()) else {
+        return Vec::new();
+    };
+    let Some(end) = xmp[start..].find(&close).map(|i| i + start) else {
+        return Vec::new();
+    };
+
+    let mut items = Vec::new();
+    let mut rest = &xmp[start..end];
+    while let Some(li) = rest.find("<rdf:li") {
+        rest = &rest[li..];
+        let Some(content_start) = rest.find('>').map(|i| i + 1) else { break };
+        let Some(content_end) = rest.find("</rdf:li>") else { break };
+        let item = unescape(rest[content_start..content_end].trim());
+        if !item.is_empty() {
+            items.push(item);
+        }
+        rest = &rest[content_end + "</rdf:li>".len()..];
+    }
+    items
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::develop::{denoise::NoiseReduction, lens::LensCorrection};
+
+    #[test]
+    fn test_roundtrip_new_packet() {
+        let mut sidecar = Sidecar {
+            rating: Some(4),
+            label: Some("Red".to_string()),
+            keywords: vec!["wedding".to_string(), "R&D".to_string()],
+            edits: EditParams {
+                exposure: 0.35,
+                shadows: -20.0,
+                noise: NoiseReduction { luminance: Some(0.0), color: Some(35.0) },
+                sharpening: Sharpening { amount: 60.0, masking: 20.0, ..Default::default() },
+                highlight_mode: HighlightMode::Reconstruct,
+                lens: LensCorrection { ignore_profile: true, distortion: Some(12.0), ca_blue: Some(-3.5), ..Default::default() },
+                crop: Crop { left: 0.1, right: 0.9, angle: -2.5, aspect: CropAspect::Ratio(3, 2), ..Default::default() },
+                ..Default::default()
+            },
+            snapshots: vec![
+                Snapshot { name: "Before".to_string(), edits: EditParams::default() },
+                Snapshot { name: "Warm".to_string(), edits: EditParams { temperature: 25.0, ..Default::default() } },
+            ],
+        };
+        sidecar.edits.levels.rgb.gamma = 1.2;
+        sidecar.edits.levels.red.input_white = 0.9;
+        sidecar.edits.curve.blue.add_point(0.25, 0.3);
+        let xmp = patch(EMPTY_PACKET, &sidecar);
+        assert_eq!(parse(&xmp), sidecar);
+
+        // Deleting a snapshot doesn't leave the old last one behind
+        let mut fewer = sidecar.clone();
+        fewer.snapshots.remove(0);
+        let xmp = patch(&xmp, &fewer);
+        assert_eq!(parse(&xmp), fewer);
+        assert!(!xmp.contains("photoflow:Snapshot2"));
+
+        let cleared = patch(&xmp, &Sidecar::default());
+        assert_eq!(parse(&cleared), Sidecar::default());
+        assert!(!cleared.contains("rdf:Bag"));
+        assert!(!cleared.contains("photoflow:Crop"));
+    }
+
+    #[test]
+    fn test_virtual_copies() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let raw = dir.join("DSCF1234.RAF");
+        std::fs::write(&raw, b"raw").unwrap();
+        // A real file that looks like a copy key isn't one
+        std::fs::write(dir.join("DSCF1234_01.RAF"), b"raw").unwrap();
+
+        let edits = Sidecar { rating: Some(2), ..Default::default() };
+        let key = create_copy(&raw, &edits).unwrap();
+        assert_eq!(key, dir.join("DSCF1234_02.RAF"));
+        assert!(dir.join("DSCF1234_02.RAF.xmp").is_file());
+        assert_eq!(read(&key).unwrap(), Some(edits));
+        assert_eq!(copy_source(&key), Some(raw.clone()));
+        assert_eq!(copy_source(&dir.join("DSCF1234_01.RAF")), None);
+        let copies = CopyIndex::scan([&raw]);
+        assert_eq!(copies.of(&raw), std::slice::from_ref(&key));
+        assert_eq!(copy_sidecars(&raw, &copies), vec![dir.join("DSCF1234_02.RAF.xmp")]);
+    }
+
+    #[test]
+    fn test_patch_keeps_foreign_data() {
+        let darktable = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:darktable="http://darktable.sf.net/"
+    xmp:Rating="1"
+    darktable:history_end="3"/>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+        let xmp = patch(darktable, &Sidecar { rating: Some(5), ..Default::default() });
+        assert_eq!(parse(&xmp).rating, Some(5));
+        assert!(xmp.contains(r#"darktable:history_end="3""#));
+        assert!(xmp.contains("</rdf:Description>"));
+    }
+}
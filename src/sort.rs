@@ -0,0 +1,228 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::photo::ExifData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortMode {
+    /// File name, with runs of digits compared numerically.
+    #[default]
+    Name,
+    /// EXIF `DateTimeOriginal`, ties broken by `SubSecTimeOriginal`.
+    CaptureTime,
+    /// File modification time.
+    Modified,
+    Size,
+    Camera,
+}
+
+impl SortMode {
+    pub const ALL: [SortMode; 5] = [
+        SortMode::Name,
+        SortMode::CaptureTime,
+        SortMode::Modified,
+        SortMode::Size,
+        SortMode::Camera,
+    ];
+
+    /// Whether this mode needs the metadata scan to have finished.
+    pub fn uses_exif(self) -> bool {
+        matches!(self, SortMode::CaptureTime | SortMode::Camera)
+    }
+}
+
+impl fmt::Display for SortMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SortMode::Name => "Name",
+            SortMode::CaptureTime => "Capture time",
+            SortMode::Modified => "Modified",
+            SortMode::Size => "Size",
+            SortMode::Camera => "Camera",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+impl SortOrder {
+    pub fn toggled(self) -> Self {
+        match self {
+            SortOrder::Ascending => SortOrder::Descending,
+            SortOrder::Descending => SortOrder::Ascending,
+        }
+    }
+
+    fn apply(self, ordering: Ordering) -> Ordering {
+        match self {
+            SortOrder::Ascending => ordering,
+            SortOrder::Descending => ordering.reverse(),
+        }
+    }
+}
+
+/// Everything a sort mode might look at for one photo.
+struct SortKey<'a> {
+    name: String,
+    exif: Option<&'a ExifData>,
+    modified: Option<SystemTime>,
+    size: Option<u64>,
+}
+
+/// Returns the order in which `paths` should be shown, as indices into it.
+///
+/// Photos missing the sort key (no EXIF date, unreadable file) always go
+/// last; ties are broken by file name so the result is stable.
+pub fn sorted_indices(
+    paths: &[PathBuf],
+    metadata: &[Option<ExifData>],
+    mode: SortMode,
+    order: SortOrder,
+) -> Vec<usize> {
+    let keys: Vec<SortKey> = paths
+        .iter()
+        .zip(metadata)
+        .map(|(path, exif)| {
+            let file = match mode {
+                SortMode::Modified | SortMode::Size => std::fs::metadata(path).ok(),
+                _ => None,
+            };
+            SortKey {
+                name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                exif: exif.as_ref(),
+                modified: file.as_ref().and_then(|m| m.modified().ok()),
+                size: file.as_ref().map(|m| m.len()),
+            }
+        })
+        .collect();
+
+    let mut indices: Vec<usize> = (0..paths.len()).collect();
+    indices.sort_by(|&a, &b| {
+        let (a, b) = (&keys[a], &keys[b]);
+        let ordering = match mode {
+            SortMode::Name => order.apply(natural_cmp(&a.name, &b.name)),
+            SortMode::CaptureTime => missing_last(
+                a.exif.and_then(|e| e.capture_time()),
+                b.exif.and_then(|e| e.capture_time()),
+                order,
+            ),
+            SortMode::Modified => missing_last(a.modified, b.modified, order),
+            SortMode::Size => missing_last(a.size, b.size, order),
+            SortMode::Camera => missing_last(
+                a.exif.and_then(|e| e.camera()),
+                b.exif.and_then(|e| e.camera()),
+                order,
+            ),
+        };
+        ordering.then_with(|| natural_cmp(&a.name, &b.name))
+    });
+    indices
+}
+
+fn missing_last<T: Ord>(a: Option<T>, b: Option<T>, order: SortOrder) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => order.apply(a.cmp(&b)),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Compares file names the way people expect: `DSCF9.JPG` before
+/// `DSCF10.JPG`, and case-insensitively.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                // Compare by magnitude first, then by length so "007" sorts after "7"
+                let x_trimmed = x.trim_start_matches('0');
+                let y_trimmed = y.trim_start_matches('0');
+                let ordering = x_trimmed.len().cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().copied().filter(|c| c.is_ascii_digit()) {
+        number.push(c);
+        chars.next();
+    }
+    number
+}
+
+/// Rearranges `items` so that `items[i]` becomes the old `items[order[i]]`.
+pub fn apply_order<T>(items: &mut Vec<T>, order: &[usize]) {
+    let mut old: Vec<Option<T>> = items.drain(..).map(Some).collect();
+    items.extend(order.iter().filter_map(|&i| old[i].take()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["DSCF10.JPG", "dscf9.jpg", "DSCF100.JPG", "DSCF0010.JPG", "DSCF9a.JPG"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["dscf9.jpg", "DSCF9a.JPG", "DSCF10.JPG", "DSCF0010.JPG", "DSCF100.JPG"]);
+    }
+
+    #[test]
+    fn test_capture_time_with_subsec_and_missing() {
+        let paths: Vec<PathBuf> = ["a.jpg", "b.jpg", "c.jpg", "d.jpg"].iter().map(PathBuf::from).collect();
+        let exif = |datetime: &str, subsec| Some(ExifData {
+            datetime: Some(datetime.to_string()),
+            subsec,
+            ..Default::default()
+        });
+        let metadata = vec![
+            exif("2023-05-14 18:02:11", Some(500_000_000)),
+            None,
+            exif("2023-05-14 18:02:11", Some(100_000_000)),
+            exif("2023-05-14 18:02:10", None),
+        ];
+
+        let ascending = sorted_indices(&paths, &metadata, SortMode::CaptureTime, SortOrder::Ascending);
+        assert_eq!(ascending, vec![3, 2, 0, 1]);
+
+        let descending = sorted_indices(&paths, &metadata, SortMode::CaptureTime, SortOrder::Descending);
+        assert_eq!(descending, vec![0, 2, 3, 1]);
+    }
+
+    #[test]
+    fn test_apply_order() {
+        let mut items = vec!['a', 'b', 'c'];
+        apply_order(&mut items, &[2, 0, 1]);
+        assert_eq!(items, vec!['c', 'a', 'b']);
+    }
+}
@@ -1,10 +1,30 @@
 use iced::{
-    advanced::image::Handle,
-    widget::{column, container, text, Image},
-    Element, Length,
+    mouse,
+    widget::{
+        button, canvas, checkbox, column, container, pick_list, radio, row, scrollable, slider, text, text_input,
+        Image,
+    },
+    Color, Element, Length, Point, Rectangle, Renderer, Size, Theme,
 };
 
+use crate::color::{ColorSpace, ProofSettings, RenderingIntent, SoftProof};
+use crate::develop::{
+    crop::{CropAspect, CropEdge},
+    denoise::NoiseReduction,
+    histogram::{ClippingWarnings, Histogram},
+    lens::LensField,
+    sharpen::SharpenField,
+    sync::{EditClipboard, SettingsGroup},
+    tone::{Curve, CurveChannel, LevelsField},
+    Adjustment,
+};
+use crate::duplicates::{DuplicateGroup, DuplicateKind};
+use crate::export::{ExportFormat, ExportPreset, ExportSettings, MetadataMode, OutputSharpening};
+use crate::lensfun;
 use crate::photo::Photo;
+use crate::presets::{PresetLibrary, PRESET_THUMBNAIL_SIZE};
+use crate::rename::RenamePreview;
+use crate::processors::{detector, highlights::HighlightMode};
 use crate::Message;
 
 #[derive(Debug, Default)]
@@ -15,7 +35,15 @@ impl PhotoView {
         Self {}
     }
 
-    pub fn view(&self, photo: &Photo) -> Element<Message> {
+    /// `soft_proof` is the proof `proofing` has been set up with, for its
+    /// profile's name.
+    pub fn view(
+        &self,
+        photo: &Photo,
+        clipping: ClippingWarnings,
+        proofing: ProofSettings,
+        soft_proof: Option<&SoftProof>,
+    ) -> Element<'_, Message> {
         let mut info = column![];
 
         // Add filename
@@ -23,17 +51,30 @@ impl PhotoView {
             text(format!("File: {}", photo.path().file_name().unwrap_or_default().to_string_lossy()))
                 .size(16),
         );
+        if photo.key() != photo.representations()[0] {
+            info = info.push(text(format!(
+                "Virtual copy: {}",
+                photo.key().file_name().unwrap_or_default().to_string_lossy()
+            )));
+        }
+        if photo.representations().len() > 1 {
+            let others: Vec<String> = photo.representations()
+                .iter()
+                .filter(|p| p.as_path() != photo.path())
+                .map(|p| p.file_name().unwrap_or_default().to_string_lossy().into_owned())
+                .collect();
+            info = info.push(text(format!("Paired with: {}", others.join(", "))));
+        }
 
         // Add EXIF data if available
         if let Some(exif) = photo.exif_data() {
-            let make_model = match (exif.make.as_ref(), exif.model.as_ref()) {
-                (Some(make), Some(model)) => format!("{} {}", make, model),
-                (Some(make), None) => make.clone(),
-                (None, Some(model)) => model.clone(),
-                (None, None) => String::from("Unknown Camera"),
-            };
+            let make_model = exif.camera().unwrap_or_else(|| String::from("Unknown Camera"));
             info = info.push(text(make_model));
 
+            if let Some(lens) = &exif.lens {
+                info = info.push(text(lens));
+            }
+
             if let Some(datetime) = &exif.datetime {
                 info = info.push(text(format!("Date: {}", datetime)));
             }
@@ -55,21 +96,94 @@ impl PhotoView {
             if !settings.is_empty() {
                 info = info.push(text(settings.join(" • ")));
             }
+
+            let mut library = Vec::new();
+            if let Some(rating) = exif.rating {
+                library.push(format!("{}{}", "★".repeat(rating as usize), "☆".repeat(5 - rating as usize)));
+            }
+            if let Some(label) = &exif.label {
+                library.push(label.clone());
+            }
+            if !exif.keywords.is_empty() {
+                library.push(exif.keywords.join(", "));
+            }
+            if !library.is_empty() {
+                info = info.push(text(library.join(" • ")));
+            }
+        }
+
+        // Rating and representation controls
+        let rating = photo.exif_data().and_then(|exif| exif.rating).unwrap_or(0);
+        let mut controls = row![].spacing(5);
+        for stars in 1..=5u8 {
+            let label = if stars <= rating { "★" } else { "☆" };
+            // Clicking the current rating again clears it
+            let new_rating = if stars == rating { 0 } else { stars };
+            controls = controls.push(button(label).on_press(Message::SetRating(new_rating)));
+        }
+        if photo.next_representation().is_some() {
+            let kind = if detector::has_raw_extension(photo.path()) { "RAW" } else { "JPEG" };
+            controls = controls.push(
+                button(text(format!("Showing {}", kind))).on_press(Message::ToggleRepresentation),
+            );
         }
+        info = info.push(controls);
 
         // Create the image widget
-        let image_widget = if let Some(img) = &photo.image {
-            // The image should already be in RGB8 format
-            Image::new(Handle::from_memory(photo.get_rgb_data()))
+        let image_widget: Element<Message> = if let Some(handle) = photo.display_handle() {
+            Image::new(handle.clone())
                 .width(Length::Fill)
                 .height(Length::Fill)
+                .into()
         } else {
-            Image::new(Handle::from_memory(Vec::new()))
-                .width(Length::Fill)
-                .height(Length::Fill)
+            text("Loading…").into()
         };
 
-        let content = column!(info, image_widget).spacing(20);
+        let mut levels = column![].spacing(5);
+        if let Some(histogram) = photo.histogram() {
+            levels = levels.push(
+                canvas(HistogramView { histogram: histogram.clone() })
+                    .width(Length::Fixed(256.0))
+                    .height(Length::Fixed(100.0)),
+            );
+        }
+        levels = levels.push(
+            row![
+                checkbox("Highlight clipping", clipping.highlights, Message::HighlightClippingToggled),
+                checkbox("Shadow clipping", clipping.shadows, Message::ShadowClippingToggled),
+            ]
+            .spacing(10),
+        );
+        let mut proof = row![checkbox("Soft proof", proofing.enabled, Message::SoftProofToggled)].spacing(10);
+        if let Some(soft_proof) = soft_proof {
+            proof = proof.push(button(text(soft_proof.name())).on_press(Message::ChooseProofProfile));
+        }
+        levels = levels.push(proof);
+        if proofing.enabled && soft_proof.is_some() {
+            levels = levels.push(
+                row![
+                    pick_list(&RenderingIntent::ALL[..], Some(proofing.intent), Message::ProofIntentSelected),
+                    checkbox("Gamut warning", proofing.gamut_warning, Message::GamutWarningToggled),
+                ]
+                .spacing(10),
+            );
+        }
+        if let Some(raw) = photo.raw_clipping() {
+            let summary = format!(
+                "RAW clipping: R {:.1}% G {:.1}% B {:.1}%",
+                raw.red * 100.0,
+                raw.green * 100.0,
+                raw.blue * 100.0,
+            );
+            levels = levels.push(if raw.is_clipped() {
+                text(summary).style(Color::from_rgb(0.9, 0.2, 0.2))
+            } else {
+                text(summary)
+            });
+        }
+
+        let header = row![container(info).width(Length::Fill), levels].spacing(20);
+        let content = column!(header, image_widget).spacing(20);
 
         container(content)
             .padding(10)
@@ -78,3 +192,673 @@ impl PhotoView {
             .into()
     }
 }
+
+/// RGB and luminance histogram of the displayed image.
+struct HistogramView {
+    histogram: Histogram,
+}
+
+impl canvas::Program<Message> for HistogramView {
+    type State = ();
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        renderer: &Renderer,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: mouse::Cursor,
+    ) -> Vec<canvas::Geometry> {
+        let mut frame = canvas::Frame::new(renderer, bounds.size());
+        let size = frame.size();
+        frame.fill_rectangle(Point::ORIGIN, size, Color::from_rgb(0.12, 0.12, 0.12));
+
+        let peak = self.histogram.peak() as f32;
+        let channels = [
+            (&self.histogram.luma, Color::from_rgba(0.8, 0.8, 0.8, 0.5)),
+            (&self.histogram.red, Color::from_rgba(1.0, 0.2, 0.2, 0.4)),
+            (&self.histogram.green, Color::from_rgba(0.2, 1.0, 0.2, 0.4)),
+            (&self.histogram.blue, Color::from_rgba(0.3, 0.4, 1.0, 0.4)),
+        ];
+        let bin_width = size.width / 256.0;
+        for (bins, color) in channels {
+            let area = canvas::Path::new(|builder| {
+                builder.move_to(Point::new(0.0, size.height));
+                for (i, &count) in bins.iter().enumerate() {
+                    let height = (count as f32 / peak).min(1.0) * size.height;
+                    builder.line_to(Point::new(i as f32 * bin_width, size.height - height));
+                    builder.line_to(Point::new((i + 1) as f32 * bin_width, size.height - height));
+                }
+                builder.line_to(Point::new(size.width, size.height));
+                builder.close();
+            });
+            frame.fill(&area, color);
+        }
+
+        vec![frame.into_geometry()]
+    }
+}
+
+/// One row of the photo list.
+pub struct ListEntry {
+    pub index: usize,
+    pub name: String,
+    /// Size of the stack this row heads; 1 for a single photo and 0 for the
+    /// other members of an expanded stack.
+    pub stack_size: usize,
+    pub expanded: bool,
+    /// Whether this is the photo being viewed.
+    pub selected: bool,
+    /// Whether the photo is ticked for file operations.
+    pub checked: bool,
+}
+
+/// The scrollable list of photos, with burst stacks collapsed to one row.
+pub fn photo_list<'a>(entries: Vec<ListEntry>) -> Element<'a, Message> {
+    let mut list = column![].spacing(2);
+
+    for entry in entries {
+        let style = if entry.selected {
+            iced::theme::Button::Primary
+        } else {
+            iced::theme::Button::Text
+        };
+        let name = button(text(entry.name).size(14))
+            .on_press(Message::PhotoSelected(entry.index))
+            .style(style)
+            .width(Length::Fill);
+
+        let index = entry.index;
+        let tick = checkbox("", entry.checked, move |_| Message::ToggleSelection(index));
+        let row = match entry.stack_size {
+            0 => row![tick, text("").width(Length::Fixed(48.0)), name],
+            1 => row![tick, name],
+            size => {
+                let arrow = if entry.expanded { "▾" } else { "▸" };
+                row![
+                    tick,
+                    button(text(format!("{} {}", arrow, size)).size(14))
+                        .on_press(Message::ToggleStack(entry.index))
+                        .width(Length::Fixed(48.0)),
+                    name,
+                ]
+            }
+        };
+        list = list.push(row.spacing(4));
+    }
+
+    scrollable(list).height(Length::Fill).into()
+}
+
+/// Duplicate scan results, one block per group with a choice of which
+/// file to keep.
+pub fn duplicates_view<'a>(groups: &'a [DuplicateGroup], keep: &[usize]) -> Element<'a, Message> {
+    let mut list = column![
+        row![
+            text(format!("{} duplicate groups", groups.len())).size(20),
+            button("Close").on_press(Message::CloseDuplicates),
+        ]
+        .spacing(20),
+    ]
+    .spacing(20);
+
+    for (group_index, group) in groups.iter().enumerate() {
+        let title = match group.kind {
+            DuplicateKind::Exact => format!("Identical files ({})", group.files.len()),
+            DuplicateKind::Similar => format!("Similar images ({})", group.files.len()),
+        };
+        let mut block = column![
+            row![
+                text(title).size(16),
+                button("Trash others").on_press(Message::TrashDuplicates(group_index)),
+            ]
+            .spacing(20),
+        ]
+        .spacing(5);
+
+        for (file_index, file) in group.files.iter().enumerate() {
+            let mut details = vec![format!("{:.1} MB", file.size as f64 / (1024.0 * 1024.0))];
+            if let Some(exif) = &file.exif {
+                details.extend(exif.camera());
+                details.extend(exif.datetime.clone());
+                if let Some(rating) = exif.rating {
+                    details.push(format!("{}★", rating));
+                }
+            }
+
+            block = block.push(
+                row![
+                    radio(
+                        "Keep",
+                        file_index,
+                        keep.get(group_index).copied(),
+                        move |file| Message::KeepDuplicate(group_index, file),
+                    ),
+                    column![
+                        text(file.path.display().to_string()).size(14),
+                        text(details.join(" • ")).size(12),
+                    ],
+                ]
+                .spacing(10),
+            );
+        }
+        list = list.push(block);
+    }
+
+    scrollable(list).height(Length::Fill).into()
+}
+
+/// Template input and a table of old and new names for batch renaming.
+pub fn batch_rename_view<'a>(template: &str, error: Option<&str>, previews: &'a [RenamePreview]) -> Element<'a, Message> {
+    let conflicts = previews.iter().filter(|p| p.conflict.is_some()).count();
+    let mut apply = button("Rename All").style(iced::theme::Button::Primary);
+    if error.is_none() && conflicts == 0 && !previews.is_empty() {
+        apply = apply.on_press(Message::ApplyBatchRename);
+    }
+
+    let status = match (error, conflicts) {
+        (Some(error), _) => text(error).style(Color::from_rgb(0.8, 0.0, 0.0)),
+        (None, 0) => text(format!("{} photos", previews.len())),
+        (None, n) => text(format!("{} of {} photos have conflicting names", n, previews.len()))
+            .style(Color::from_rgb(0.8, 0.0, 0.0)),
+    };
+
+    let mut table = column![].spacing(4);
+    for preview in previews {
+        let from = preview.from.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let mut entry = row![
+            text(from).size(14).width(Length::FillPortion(1)),
+            text("→").size(14),
+            text(&preview.to).size(14).width(Length::FillPortion(1)),
+        ]
+        .spacing(10);
+        if let Some(conflict) = &preview.conflict {
+            entry = entry.push(text(conflict).size(14).style(Color::from_rgb(0.8, 0.0, 0.0)).width(Length::FillPortion(1)));
+        }
+        table = table.push(entry);
+    }
+
+    column![
+        row![
+            text("Batch rename").size(20),
+            apply,
+            button("Cancel").on_press(Message::CloseBatchRename),
+        ]
+        .spacing(20),
+        text_input("{year}-{month}-{day}_client_{counter:4}", template)
+            .on_input(Message::BatchTemplateChanged)
+            .on_submit(Message::ApplyBatchRename),
+        text("Fields: {year} {month} {day} {hour} {minute} {second} {date} {camera} {make} {model} \
+              {lens} {iso} {focal} {aperture} {name} {counter} {counter:4}").size(12),
+        status,
+        scrollable(table).height(Length::Fill),
+    ]
+    .spacing(10)
+    .into()
+}
+
+/// Export options for the target photos.
+pub fn export_view<'a>(
+    settings: &ExportSettings,
+    preset: Option<ExportPreset>,
+    size: &str,
+    name: &str,
+    count: usize,
+    exporting: bool,
+) -> Element<'a, Message> {
+    let mut export = button(if exporting { "Exporting…" } else { "Export…" }).style(iced::theme::Button::Primary);
+    if !exporting && count > 0 {
+        export = export.on_press(Message::StartExport);
+    }
+
+    let mut options = column![
+        row![
+            text(format!("Export {} photos", count)).size(20),
+            export,
+            button("Close").on_press(Message::CloseExport),
+        ]
+        .spacing(20),
+        row![
+            text("Preset"),
+            pick_list(&ExportPreset::ALL[..], preset, Message::ExportPresetSelected),
+        ]
+        .spacing(10),
+        row![
+            text("Format"),
+            pick_list(&ExportFormat::ALL[..], Some(settings.format), Message::ExportFormatSelected),
+        ]
+        .spacing(10),
+    ]
+    .spacing(10)
+    .max_width(500);
+
+    if settings.format == ExportFormat::Jpeg {
+        options = options.push(row![
+            text(format!("Quality: {}", settings.quality)),
+            slider(1..=100, settings.quality, Message::ExportQualityChanged),
+        ]
+        .spacing(10));
+    }
+    if settings.format.supports_16_bit() {
+        options = options.push(checkbox("16 bits per channel", settings.sixteen_bit, Message::ExportSixteenBitToggled));
+    }
+    options = options.push(row![
+        text("Colour space"),
+        pick_list(&ColorSpace::ALL[..], Some(settings.color_space), Message::ExportColorSpaceSelected),
+    ]
+    .spacing(10));
+
+    options
+        .push(row![
+            text("Size"),
+            text_input("Original, 2048, 1920x1080 or 50%", size).on_input(Message::ExportSizeChanged),
+        ]
+        .spacing(10))
+        .push(pick_list(&OutputSharpening::ALL[..], Some(settings.sharpening), Message::ExportSharpeningSelected))
+        .push(row![
+            text("Metadata"),
+            pick_list(&MetadataMode::ALL[..], Some(settings.metadata), Message::ExportMetadataSelected),
+        ]
+        .spacing(10))
+        .push(row![
+            text("File name"),
+            text_input("{name}", name).on_input(Message::ExportNameChanged),
+        ]
+        .spacing(10))
+        .into()
+}
+
+/// Sliders for the develop settings of the current photo. Changes show
+/// immediately and are saved when a slider is released.
+/// `targets` is how many photos pasting and presets apply to.
+pub fn develop_panel<'a>(
+    photo: &Photo,
+    curve_channel: CurveChannel,
+    clipboard: &EditClipboard,
+    presets: &PresetLibrary,
+    snapshot_name: &str,
+    targets: usize,
+) -> Element<'a, Message> {
+    let edits = photo.edits();
+    let mut paste = button(text(format!("Paste ({})", targets)));
+    if clipboard.copied.is_some() {
+        paste = paste.on_press(Message::PasteEdits);
+    }
+    let mut panel = column![
+        row![
+            text("Develop").size(20),
+            button("Reset").on_press(Message::ResetEdits),
+            button("Virtual Copy").on_press(Message::CreateVirtualCopy),
+        ]
+        .spacing(20),
+        row![button("Copy").on_press(Message::CopyEdits), paste].spacing(10),
+        checkbox("Sync changes to selection", clipboard.sync, Message::SyncEditsToggled),
+    ]
+    .spacing(5)
+    .width(Length::Fixed(260.0));
+    panel = panel.push(text("Groups to paste or save as preset:").size(14));
+    for group in SettingsGroup::ALL {
+        panel = panel.push(
+            checkbox(group.to_string(), clipboard.groups.contains(&group), move |v| {
+                Message::PasteGroupToggled(group, v)
+            })
+            .size(14),
+        );
+    }
+
+    let mut apply = button(text(format!("Apply ({})", targets)));
+    let mut delete = button("Delete");
+    if presets.selected.is_some() {
+        apply = apply.on_press(Message::ApplyPreset);
+        delete = delete.on_press(Message::DeletePreset);
+    }
+    panel = panel
+        .push(text("Presets").size(20))
+        .push(
+            pick_list(presets.names(), presets.selected.as_ref().map(|p| p.name.clone()), Message::PresetSelected)
+                .placeholder("Choose a preset")
+                .width(Length::Fill),
+        )
+        .push(row![apply, delete].spacing(10));
+    if let Some(preview) = &presets.preview {
+        panel = panel.push(Image::new(preview.clone()).width(Length::Fixed(PRESET_THUMBNAIL_SIZE as f32)));
+    }
+    panel = panel.push(
+        row![
+            text_input("New preset name", &presets.name)
+                .on_input(Message::PresetNameChanged)
+                .on_submit(Message::SavePreset),
+            button("Save").on_press(Message::SavePreset),
+        ]
+        .spacing(10),
+    );
+
+    panel = panel.push(text("Snapshots").size(20));
+    for (i, snapshot) in photo.snapshots().iter().enumerate() {
+        panel = panel.push(
+            row![
+                text(&snapshot.name).size(14).width(Length::Fill),
+                button("Restore").on_press(Message::RestoreSnapshot(i)),
+                button("×").on_press(Message::DeleteSnapshot(i)),
+            ]
+            .spacing(10),
+        );
+    }
+    panel = panel.push(
+        row![
+            text_input("Snapshot name", snapshot_name)
+                .on_input(Message::SnapshotNameChanged)
+                .on_submit(Message::TakeSnapshot),
+            button("Take").on_press(Message::TakeSnapshot),
+        ]
+        .spacing(10),
+    );
+
+    for adjustment in Adjustment::ALL {
+        let (min, max, step) = adjustment.range();
+        let value = adjustment.get(edits);
+        let label = if adjustment == Adjustment::Exposure {
+            format!("{}: {:+.2} EV", adjustment, value)
+        } else {
+            format!("{}: {:+.0}", adjustment, value)
+        };
+        panel = panel
+            .push(text(label).size(14))
+            .push(
+                slider(min..=max, value, move |v| Message::AdjustmentChanged(adjustment, v))
+                    .step(step)
+                    .on_release(Message::SaveEdits),
+            );
+    }
+
+    let iso = photo.exif_data().and_then(|e| e.iso).filter(|_| photo.is_raw());
+    let (luminance, color) = NoiseReduction::defaults(iso);
+    let noise = [
+        ("Luminance noise", edits.noise.luminance, luminance, Message::LuminanceNoiseChanged as fn(f32) -> Message),
+        ("Color noise", edits.noise.color, color, Message::ColorNoiseChanged),
+    ];
+    panel = panel.push(
+        row![
+            text("Noise Reduction").size(20),
+            button("Auto").on_press(Message::NoiseReductionAuto),
+        ]
+        .spacing(20),
+    );
+    for (label, value, default, on_change) in noise {
+        let label = match (value, iso) {
+            (Some(value), _) => format!("{}: {:.0}", label, value),
+            (None, Some(iso)) => format!("{}: {:.0} (auto for ISO {})", label, default, iso),
+            (None, None) => format!("{}: {:.0} (auto)", label, default),
+        };
+        panel = panel
+            .push(text(label).size(14))
+            .push(slider(0.0..=100.0, value.unwrap_or(default), on_change).on_release(Message::SaveEdits));
+    }
+
+    panel = panel.push(text("Sharpening").size(20));
+    for field in SharpenField::ALL {
+        let (min, max, step) = field.range();
+        let value = field.get(&edits.sharpening);
+        let label = if field == SharpenField::Radius {
+            format!("{}: {:.1} px", field, value)
+        } else {
+            format!("{}: {:.0}", field, value)
+        };
+        panel = panel
+            .push(text(label).size(14))
+            .push(
+                slider(min..=max, value, move |v| Message::SharpeningChanged(field, v))
+                    .step(step)
+                    .on_release(Message::SaveEdits),
+            );
+    }
+
+    if photo.is_raw() {
+        panel = panel
+            .push(text("Highlights").size(14))
+            .push(pick_list(&HighlightMode::ALL[..], Some(edits.highlight_mode), Message::HighlightModeSelected));
+    }
+
+    let lens = edits.lens;
+    // Camera JPEGs and TIFFs were already corrected in camera
+    let profile = photo
+        .exif_data()
+        .filter(|_| photo.is_raw())
+        .and_then(|e| e.lens.as_deref())
+        .and_then(|name| lensfun::database().find(name));
+    panel = panel.push(
+        row![
+            text("Lens Corrections").size(20),
+            button("Profile").on_press(Message::ResetLensCorrections),
+        ]
+        .spacing(20),
+    );
+    if photo.is_raw() {
+        panel = panel
+            .push(text(profile.map_or("No profile for this lens".to_string(), |p| format!("{} {}", p.maker, p.name()))).size(14))
+            .push(checkbox("Use lens profile", !lens.ignore_profile, Message::LensProfileToggled));
+    }
+    for field in LensField::ALL {
+        let value = field.get(&lens);
+        let label = match value {
+            Some(value) => format!("{}: {:+.0}", field, value),
+            None if profile.is_some() && !lens.ignore_profile => format!("{}: profile", field),
+            None => format!("{}: off", field),
+        };
+        panel = panel
+            .push(text(label).size(14))
+            .push(
+                slider(-100.0..=100.0, value.unwrap_or(0.0), move |v| Message::LensCorrectionChanged(field, v))
+                    .on_release(Message::SaveEdits),
+            );
+    }
+
+    let crop = edits.crop;
+    panel = panel
+        .push(
+            row![
+                text("Crop").size(20),
+                button("Reset").on_press(Message::ResetCrop),
+            ]
+            .spacing(20),
+        )
+        .push(checkbox("Show crop", photo.crop_overlay(), Message::CropOverlayToggled))
+        .push(pick_list(&CropAspect::ALL[..], Some(crop.aspect), Message::CropAspectSelected))
+        .push(text(format!("Angle: {:+.1}°", crop.angle)).size(14))
+        .push(
+            slider(-45.0..=45.0, crop.angle, Message::CropAngleChanged)
+                .step(0.1)
+                .on_release(Message::SaveEdits),
+        );
+    for edge in CropEdge::ALL {
+        let value = crop.edge(edge);
+        panel = panel
+            .push(text(format!("{}: {:.0}%", edge, value * 100.0)).size(14))
+            .push(
+                slider(0.0..=1.0, value, move |v| Message::CropEdgeChanged(edge, v))
+                    .step(0.001)
+                    .on_release(Message::SaveEdits),
+            );
+    }
+
+    // Levels and the curve share the channel selector
+    panel = panel.push(
+        row![
+            text("Levels").size(20),
+            pick_list(&CurveChannel::ALL[..], Some(curve_channel), Message::CurveChannelSelected),
+        ]
+        .spacing(20),
+    );
+    let levels = edits.levels.get(curve_channel);
+    for field in LevelsField::ALL {
+        let (min, max, step) = field.range();
+        let value = field.get(levels);
+        panel = panel
+            .push(text(format!("{}: {:.2}", field, value)).size(14))
+            .push(
+                slider(min..=max, value, move |v| Message::LevelsChanged(curve_channel, field, v))
+                    .step(step)
+                    .on_release(Message::SaveEdits),
+            );
+    }
+
+    let curve = CurveEditor { channel: curve_channel, curve: edits.curve.get(curve_channel).clone() };
+    panel = panel
+        .push(
+            row![
+                text("Curve").size(20),
+                pick_list(&CurveChannel::ALL[..], Some(curve_channel), Message::CurveChannelSelected),
+            ]
+            .spacing(20),
+        )
+        .push(canvas(curve).width(Length::Fixed(240.0)).height(Length::Fixed(240.0)))
+        .push(text("Click to add a point, drag to move, right-click to remove").size(12));
+
+    scrollable(panel).into()
+}
+
+/// Interactive editor for one tone curve. Every change is sent as a whole
+/// new curve; releasing the mouse saves it.
+struct CurveEditor {
+    channel: CurveChannel,
+    curve: Curve,
+}
+
+/// How close, in pixels, the cursor has to be to grab a point.
+const GRAB_RADIUS: f32 = 8.0;
+
+#[derive(Default)]
+struct CurveEditorState {
+    /// Point being dragged.
+    dragging: Option<usize>,
+    /// A point was just removed; save when the button is released.
+    removed: bool,
+}
+
+impl CurveEditor {
+    /// Curve coordinates (0-1, y up) of a position within `bounds`.
+    fn to_curve(bounds: Rectangle, position: Point) -> (f32, f32) {
+        let x = (position.x - bounds.x) / bounds.width;
+        let y = 1.0 - (position.y - bounds.y) / bounds.height;
+        (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0))
+    }
+
+    fn to_frame(size: Size, (x, y): (f32, f32)) -> Point {
+        Point::new(x * size.width, (1.0 - y) * size.height)
+    }
+
+    fn point_at(&self, bounds: Rectangle, position: Point) -> Option<usize> {
+        self.curve.points().iter().position(|&point| {
+            let on_screen = Self::to_frame(bounds.size(), point);
+            let dx = on_screen.x + bounds.x - position.x;
+            let dy = on_screen.y + bounds.y - position.y;
+            (dx * dx + dy * dy).sqrt() <= GRAB_RADIUS
+        })
+    }
+
+    fn changed(&self, curve: Curve) -> Option<Message> {
+        Some(Message::CurveChanged(self.channel, curve))
+    }
+}
+
+impl canvas::Program<Message> for CurveEditor {
+    type State = CurveEditorState;
+
+    fn update(
+        &self,
+        state: &mut Self::State,
+        event: canvas::Event,
+        bounds: Rectangle,
+        cursor: mouse::Cursor,
+    ) -> (canvas::event::Status, Option<Message>) {
+        use canvas::event::Status;
+
+        let canvas::Event::Mouse(event) = event else {
+            return (Status::Ignored, None);
+        };
+        match event {
+            mouse::Event::ButtonPressed(button) => {
+                let Some(position) = cursor.position_over(bounds) else {
+                    return (Status::Ignored, None);
+                };
+                let grabbed = self.point_at(bounds, position);
+                let mut curve = self.curve.clone();
+                match button {
+                    mouse::Button::Left => {
+                        let index = grabbed.unwrap_or_else(|| {
+                            let (x, y) = Self::to_curve(bounds, position);
+                            curve.add_point(x, y)
+                        });
+                        state.dragging = Some(index);
+                        (Status::Captured, self.changed(curve))
+                    }
+                    mouse::Button::Right => match grabbed {
+                        Some(index) => {
+                            curve.remove_point(index);
+                            state.removed = true;
+                            (Status::Captured, self.changed(curve))
+                        }
+                        None => (Status::Ignored, None),
+                    },
+                    _ => (Status::Ignored, None),
+                }
+            }
+            mouse::Event::CursorMoved { position } => match state.dragging {
+                Some(index) => {
+                    let (x, y) = Self::to_curve(bounds, position);
+                    let mut curve = self.curve.clone();
+                    curve.move_point(index, x, y);
+                    (Status::Captured, self.changed(curve))
+                }
+                None => (Status::Ignored, None),
+            },
+            mouse::Event::ButtonReleased(_) if state.dragging.is_some() || state.removed => {
+                state.dragging = None;
+                state.removed = false;
+                (Status::Captured, Some(Message::SaveEdits))
+            }
+            _ => (Status::Ignored, None),
+        }
+    }
+
+    fn draw(
+        &self,
+        _state: &Self::State,
+        renderer: &Renderer,
+        _theme: &Theme,
+        bounds: Rectangle,
+        _cursor: mouse::Cursor,
+    ) -> Vec<canvas::Geometry> {
+        let mut frame = canvas::Frame::new(renderer, bounds.size());
+        let size = frame.size();
+        frame.fill_rectangle(Point::ORIGIN, size, Color::from_rgb(0.12, 0.12, 0.12));
+
+        let grid = canvas::Stroke::default().with_color(Color::from_rgb(0.3, 0.3, 0.3)).with_width(1.0);
+        for i in 1..4 {
+            let t = i as f32 / 4.0;
+            frame.stroke(&canvas::Path::line(Self::to_frame(size, (t, 0.0)), Self::to_frame(size, (t, 1.0))), grid.clone());
+            frame.stroke(&canvas::Path::line(Self::to_frame(size, (0.0, t)), Self::to_frame(size, (1.0, t))), grid.clone());
+        }
+        frame.stroke(&canvas::Path::line(Self::to_frame(size, (0.0, 0.0)), Self::to_frame(size, (1.0, 1.0))), grid);
+
+        let color = match self.channel {
+            CurveChannel::Rgb => Color::WHITE,
+            CurveChannel::Red => Color::from_rgb(0.9, 0.3, 0.3),
+            CurveChannel::Green => Color::from_rgb(0.3, 0.8, 0.3),
+            CurveChannel::Blue => Color::from_rgb(0.4, 0.5, 1.0),
+        };
+        let line = canvas::Path::new(|builder| {
+            builder.move_to(Self::to_frame(size, (0.0, self.curve.eval(0.0))));
+            for i in 1..=100 {
+                let x = i as f32 / 100.0;
+                builder.line_to(Self::to_frame(size, (x, self.curve.eval(x))));
+            }
+        });
+        frame.stroke(&line, canvas::Stroke::default().with_color(color).with_width(2.0));
+        for &point in self.curve.points() {
+            frame.fill(&canvas::Path::circle(Self::to_frame(size, point), 4.0), color);
+        }
+
+        vec![frame.into_geometry()]
+    }
+}